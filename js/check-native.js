@@ -0,0 +1,29 @@
+// Checks the parts of the native addon that differ from a plain JSON API.
+const assert = require("assert");
+const native = require("../native");
+
+const ast = native.promql_parse("x @ 1700000000", { timestamps: "bigint" });
+assert.strictEqual(ast.at, 1700000000000n);
+assert.strictEqual(native.promql_at_modifier(1700000000000n), "@ 1700000000.000");
+
+function* queries() {
+  yield "up";
+  yield "up{";
+  yield "never reached";
+}
+const seen = [];
+native.promql_parse_each(queries(), (result, index) => {
+  seen.push(result.ok);
+  return index < 1;
+});
+assert.deepStrictEqual(seen, [true, false]);
+assert.throws(() => native.promql_parse_each(5, () => {}), /iterable/);
+
+const tree = new native.PromqlAst("sum(rate(x[5m] @ 100))", { timestamps: "bigint" });
+assert.strictEqual(tree.rootKind(), "aggregate");
+assert.strictEqual(tree.childAt([0])["@type"], "call");
+assert.strictEqual(tree.childAt([9]), null);
+assert.strictEqual(tree.selectors().length, 1);
+tree.free();
+
+console.log("native addon ok");
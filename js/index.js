@@ -8,10 +8,36 @@ import("../pkg/promql_parser.js")
   .catch(console.error);
 */
 
+// usage: node js/index.js [--format json|sarif] [--policy '<selector>'] [--timings] [query...]
 const args = process.argv.slice(2);
-const { promql_parse } = require("../pkg/promql_parser_js.js");
-const query = args[0] || 'sum(rate(foo{bar="baz"}[5m])) by (x,y)';
+const { promql_parse, promql_parse_with_options, promql_sarif } = require("../pkg/promql_parser_js.js");
+const option = (name) => {
+  const i = args.indexOf(name);
+  return i < 0 ? undefined : args.splice(i, 2)[1];
+};
+const format = option("--format") || "json";
+const policy = option("--policy");
+const flag = (name) => {
+  const i = args.indexOf(name);
+  return i >= 0 && args.splice(i, 1).length > 0;
+};
+const timings = flag("--timings");
+const queries = args.length ? args : ['sum(rate(foo{bar="baz"}[5m])) by (x,y)'];
 try {
-  const parsed = promql_parse(query);
-  console.log(parsed);
+  if (format === "sarif") {
+    const sources = queries.map((query) => ({ query }));
+    const start = performance.now();
+    const sarif = promql_sarif(sources, policy);
+    const ms = performance.now() - start;
+    console.log(JSON.stringify(sarif, null, 2));
+    if (timings) {
+      console.error(JSON.stringify({ queries, timings: { phases: [{ phase: "sarif", ms }], total_ms: ms } }, null, 2));
+    }
+  } else if (timings) {
+    const parsed = promql_parse_with_options(queries[0], { timings: true });
+    console.log(parsed.ast);
+    console.error(JSON.stringify({ query: queries[0], timings: parsed.timings }, null, 2));
+  } else {
+    console.log(promql_parse(queries[0]));
+  }
 } catch(e) { console.log(e) }
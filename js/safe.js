@@ -0,0 +1,42 @@
+// The functions of promql_parser_js.js, which on a panic of the parser throw
+// an Error with code "internal-error" and load a fresh wasm instance, since
+// a panic traps the call and leaves the old one unusable.
+// usage: const { promql_parse } = require("@qxip/promql-parser-js/safe.js");
+const fs = require("fs");
+const path = require("path");
+
+const packaged = path.join(__dirname, "promql_parser_js.js");
+const file = fs.existsSync(packaged) ? packaged : path.join(__dirname, "../pkg/promql_parser_js.js");
+let wasm = require(file);
+
+const reload = () => {
+  delete require.cache[require.resolve(file)];
+  wasm = require(file);
+};
+
+const internalError = (trap) => {
+  let panic;
+  try {
+    panic = wasm.promql_last_panic();
+  } catch (_) {}
+  const error = new Error(`internal error: ${panic || trap.message}`);
+  Object.assign(error, { code: "internal-error", start: 0, end: 0, line: 1, column: 1, suggestions: [] });
+  return error;
+};
+
+for (const name of Object.keys(wasm)) {
+  if (typeof wasm[name] !== "function" || /^[A-Z]/.test(name)) {
+    exports[name] = wasm[name];
+    continue;
+  }
+  exports[name] = (...args) => {
+    try {
+      return wasm[name](...args);
+    } catch (err) {
+      if (!(err instanceof WebAssembly.RuntimeError)) throw err;
+      const error = internalError(err);
+      reload();
+      throw error;
+    }
+  };
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+use serde_json::{json, Value};
+
+/// A filter on the fields of a node, as in `[function.name="rate"]`.
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    field: Vec<String>,
+    negated: bool,
+    value: String,
+}
+
+impl Filter {
+    fn matches(&self, node: &Value) -> bool {
+        let found = self.field.iter().try_fold(node, |node, key| node.get(key));
+        let equal = match found {
+            Some(Value::String(s)) => *s == self.value,
+            Some(Value::Bool(b)) => b.to_string() == self.value,
+            Some(Value::Number(n)) => self.value.parse::<f64>().ok() == n.as_f64(),
+            Some(Value::Null) | None => self.value == "null",
+            Some(_) => false,
+        };
+        equal != self.negated
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    /// `.name`: the field `name`, or the node itself if its `@type` is `name`.
+    Name(String),
+    /// `.*` or `[*]`: every field or item.
+    Any,
+    /// `[n]`
+    Index(usize),
+    Filter(Filter),
+    /// `..`: the node and everything below it, before the next step.
+    Descendants,
+}
+
+struct Parser<'a> {
+    selector: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.selector[self.pos..]
+    }
+
+    fn error(&self, expected: &str) -> String {
+        format!("invalid selector at position {}: expected {}", self.pos, expected)
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let len = self.rest().find(|c: char| !f(c)).unwrap_or(self.rest().len());
+        self.pos += len;
+        &self.selector[start..self.pos]
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let name = self.take_while(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '@');
+        match name {
+            "" => Err(self.error("a field or node type")),
+            name => Ok(name.to_string()),
+        }
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        for quote in ["\"", "'"] {
+            if self.eat(quote) {
+                let value = self.take_while(|c| !quote.starts_with(c)).to_string();
+                return if self.eat(quote) { Ok(value) } else { Err(self.error(&format!("closing {}", quote))) };
+            }
+        }
+        match self.take_while(|c| c != ']').trim() {
+            "" => Err(self.error("a value")),
+            value => Ok(value.to_string()),
+        }
+    }
+
+    fn bracket(&mut self) -> Result<Step, String> {
+        if self.eat("*]") {
+            return Ok(Step::Any);
+        }
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        if !digits.is_empty() {
+            let index = digits.parse().map_err(|_| self.error("an index"))?;
+            return if self.eat("]") { Ok(Step::Index(index)) } else { Err(self.error("`]`")) };
+        }
+        let mut field = vec![self.name()?];
+        while self.eat(".") {
+            field.push(self.name()?);
+        }
+        let negated = self.eat("!=");
+        if !negated && !self.eat("=") {
+            return Err(self.error("`=` or `!=`"));
+        }
+        let value = self.value()?;
+        if !self.eat("]") {
+            return Err(self.error("`]`"));
+        }
+        Ok(Step::Filter(Filter { field, negated, value }))
+    }
+
+    fn steps(&mut self) -> Result<Vec<Step>, String> {
+        if !self.eat("$") {
+            return Err(self.error("`$`"));
+        }
+        let mut steps = vec![];
+        while !self.rest().is_empty() {
+            if self.eat("..") {
+                steps.push(Step::Descendants);
+            } else if self.eat("[") {
+                steps.push(self.bracket()?);
+                continue;
+            } else if !self.eat(".") {
+                return Err(self.error("`.`, `..` or `[`"));
+            }
+            if self.eat("*") {
+                steps.push(Step::Any);
+            } else if !self.rest().starts_with('[') {
+                steps.push(Step::Name(self.name()?));
+            }
+        }
+        Ok(steps)
+    }
+}
+
+/// Every value below `value` at `path`, parents first, with its path.
+fn descendants<'a>(value: &'a Value, path: String, out: &mut Vec<(String, &'a Value)>) {
+    out.push((path.clone(), value));
+    match value {
+        Value::Object(object) => {
+            for (key, field) in object {
+                descendants(field, format!("{}.{}", path, key), out);
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                descendants(item, format!("{}[{}]", path, i), out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn step<'a>(step: &Step, path: String, value: &'a Value, out: &mut Vec<(String, &'a Value)>) {
+    match (step, value) {
+        (Step::Name(name), Value::Object(object)) => match object.get(name) {
+            Some(field) => out.push((format!("{}.{}", path, name), field)),
+            None if object.get("@type").and_then(Value::as_str) == Some(name) => out.push((path, value)),
+            None => {}
+        },
+        (Step::Any, Value::Object(object)) =>
+            out.extend(object.iter().map(|(key, field)| (format!("{}.{}", path, key), field))),
+        (Step::Any, Value::Array(items)) =>
+            out.extend(items.iter().enumerate().map(|(i, item)| (format!("{}[{}]", path, i), item))),
+        (Step::Index(i), Value::Array(items)) =>
+            out.extend(items.get(*i).map(|item| (format!("{}[{}]", path, i), item))),
+        (Step::Filter(filter), Value::Object(_)) if filter.matches(value) => out.push((path, value)),
+        (Step::Filter(filter), Value::Array(items)) => out.extend(
+            items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| filter.matches(item))
+                .map(|(i, item)| (format!("{}[{}]", path, i), item)),
+        ),
+        (Step::Descendants, _) => descendants(value, path, out),
+        _ => {}
+    }
+}
+
+/// The values of the serialized `ast` that `selector` picks, with their paths,
+/// parents before their fields.
+///
+/// Selectors start at the root `$` and go on with `.field`, `[n]`, `.*` or
+/// `[*]` for every field or item, `..` for any depth, and `.type` to keep the
+/// nodes of that `@type`, as in `$.binary.lhs..vector_selector`.
+/// `[field=value]` and `[field!=value]`, with a dotted field, keep the nodes
+/// or array items whose field is or is not `value`.
+pub fn select<'a>(ast: &'a Value, selector: &str) -> Result<Vec<(String, &'a Value)>, String> {
+    let steps = Parser { selector: selector.trim(), pos: 0 }.steps()?;
+    let mut found = vec![("$".to_string(), ast)];
+    for current in &steps {
+        let mut next = vec![];
+        for (path, value) in found {
+            step(current, path, value, &mut next);
+        }
+        let mut seen = HashSet::new();
+        next.retain(|(path, _)| seen.insert(path.clone()));
+        found = next;
+    }
+    Ok(found)
+}
+
+/// `select` as `[{path, node}]`.
+pub fn select_serde(ast: &Value, selector: &str) -> Result<Value, String> {
+    let found = select(ast, selector)?;
+    Ok(Value::Array(found.into_iter().map(|(path, node)| json!({ "path": path, "node": node })).collect()))
+}
+
+
+#[test]
+fn check_ast_path() {
+    use promql_parser::parser::parse;
+    use crate::ToSerde;
+    let ast = parse("sum(rate(x[5m])) / on (a) irate(y{b=\"c\"}[1m]) + z").unwrap().to_serde();
+    let paths = |selector: &str| -> Vec<String> {
+        select(&ast, selector).unwrap().into_iter().map(|(path, _)| path).collect()
+    };
+    assert_eq!(paths("$.binary.lhs..vector_selector"), vec!["$.lhs.lhs.expr.args[0].vector", "$.lhs.rhs.args[0].vector"]);
+    assert_eq!(paths("$..call[function.name=irate].args[0]"), vec!["$.lhs.rhs.args[0]"]);
+    assert_eq!(paths("$..vector_selector.name"), vec!["$.lhs.lhs.expr.args[0].vector.name", "$.lhs.rhs.args[0].vector.name", "$.rhs.name"]);
+    assert_eq!(paths("$..matchers[name=b].value"), vec!["$.lhs.rhs.args[0].vector.matchers[0].value"]);
+    assert_eq!(paths("$.lhs.*.op"), vec!["$.lhs.lhs.op"]);
+    assert_eq!(paths("$.aggregate"), Vec::<String>::new());
+    assert_eq!(select(&ast, "$.rhs").unwrap()[0].1["name"], json!("z"));
+    assert_eq!(select(&ast, "lhs").unwrap_err(), "invalid selector at position 0: expected `$`");
+    assert!(select(&ast, "$..call[function.name=\"rate]").is_err());
+}
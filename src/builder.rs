@@ -0,0 +1,228 @@
+use promql_parser::parser;
+use promql_parser::util::parse_duration;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::format::unparen;
+use crate::printer::{duration, label_name, number, quote, selector_text, to_promql};
+
+/// A label matcher of a `QuerySpec`, with an unescaped `value`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MatcherSpec {
+    pub label: String,
+    #[serde(default = "equal")]
+    pub op: String,
+    pub value: String,
+}
+
+fn equal() -> String {
+    "=".to_string()
+}
+
+/// The right-hand side of a binary step.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Operand {
+    Number(f64),
+    Query(Box<QuerySpec>),
+}
+
+/// One operation applied to the query built so far. Exactly one of
+/// `function`, `aggregate`, `binary` and `subquery` is set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StepSpec {
+    /// A function the query is passed to, with `args` for its other
+    /// parameters; the query goes in the first place where it type checks.
+    pub function: Option<String>,
+    pub args: Vec<Value>,
+    /// An aggregation of the query, grouped `by` or `without` labels, with
+    /// the `param` of `topk`, `quantile` and the like.
+    pub aggregate: Option<String>,
+    pub by: Option<Vec<String>>,
+    pub without: Option<Vec<String>>,
+    pub param: Option<Value>,
+    /// A binary operator between the query and `rhs`, with its vector
+    /// matching clauses.
+    pub binary: Option<String>,
+    pub rhs: Option<Operand>,
+    pub bool: bool,
+    pub on: Option<Vec<String>>,
+    pub ignoring: Option<Vec<String>>,
+    pub group_left: Option<Vec<String>>,
+    pub group_right: Option<Vec<String>>,
+    /// A subquery of the query over this range, at `step` resolution.
+    pub subquery: Option<String>,
+    pub step: Option<String>,
+}
+
+/// A query described as data: a selector and the steps applied to it in
+/// order, innermost first.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct QuerySpec {
+    pub metric: Option<String>,
+    pub matchers: Vec<MatcherSpec>,
+    /// Range of a matrix selector, as in `5m`.
+    pub range: Option<String>,
+    pub offset: Option<String>,
+    pub steps: Vec<StepSpec>,
+}
+
+/// `value`, unescaped, as a PromQL string literal.
+fn string(value: &str) -> String {
+    quote(&value.replace('\\', "\\\\"))
+}
+
+fn checked_duration(text: &str) -> Result<String, String> {
+    parse_duration(text).map(|dur| duration(&dur)).map_err(|_| format!("invalid duration: {}", text))
+}
+
+fn literal(value: &Value) -> Result<String, String> {
+    match value {
+        Value::Number(n) => n.as_f64().map(number).ok_or_else(|| format!("invalid number: {}", n)),
+        Value::String(s) => Ok(string(s)),
+        other => Err(format!("arguments must be numbers or strings, got {}", other)),
+    }
+}
+
+fn label_list(labels: &[String]) -> String {
+    let names: Vec<String> = labels.iter().map(|label| label_name(label)).collect();
+    format!("({})", names.join(", "))
+}
+
+fn selector(spec: &QuerySpec) -> Result<String, String> {
+    if spec.metric.is_none() && spec.matchers.is_empty() {
+        return Err("a query needs a metric or matchers".to_string());
+    }
+    let matchers = spec
+        .matchers
+        .iter()
+        .map(|m| match m.op.as_str() {
+            "=" | "!=" | "=~" | "!~" => Ok(format!("{}{}{}", label_name(&m.label), m.op, string(&m.value))),
+            op => Err(format!("invalid matcher operator: {}", op)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut text = selector_text(spec.metric.as_deref(), matchers);
+    if let Some(range) = &spec.range {
+        text.push_str(&format!("[{}]", checked_duration(range)?));
+    }
+    if let Some(offset) = &spec.offset {
+        text.push_str(&format!(" offset {}", checked_duration(offset)?));
+    }
+    Ok(text)
+}
+
+/// `name` called with `args` and `inner` in the first place that parses.
+fn call(name: &str, args: &[Value], inner: &str) -> Result<String, String> {
+    let args = args.iter().map(literal).collect::<Result<Vec<_>, _>>()?;
+    let mut error = None;
+    for i in 0..=args.len() {
+        let mut placed = args.clone();
+        placed.insert(i, inner.to_string());
+        let text = format!("{}({})", name, placed.join(", "));
+        match parser::parse(&text) {
+            Ok(_) => return Ok(text),
+            Err(err) => error = error.or(Some(err)),
+        }
+    }
+    Err(format!("cannot pass the query to {}(): {}", name, error.unwrap_or_default()))
+}
+
+fn binary(op: &str, step: &StepSpec, lhs: &str) -> Result<String, String> {
+    let rhs = match &step.rhs {
+        Some(Operand::Number(n)) => number(*n),
+        Some(Operand::Query(spec)) => format!("({})", build_text(spec)?),
+        None => return Err(format!("binary `{}` needs a `rhs`", op)),
+    };
+    let mut modifier = String::new();
+    if step.bool {
+        modifier.push_str(" bool");
+    }
+    match (&step.on, &step.ignoring) {
+        (Some(on), None) => modifier.push_str(&format!(" on {}", label_list(on))),
+        (None, Some(ignoring)) => modifier.push_str(&format!(" ignoring {}", label_list(ignoring))),
+        (None, None) => {}
+        _ => return Err("give either `on` or `ignoring`".to_string()),
+    }
+    match (&step.group_left, &step.group_right) {
+        (Some(labels), None) => modifier.push_str(&format!(" group_left {}", label_list(labels))),
+        (None, Some(labels)) => modifier.push_str(&format!(" group_right {}", label_list(labels))),
+        (None, None) => {}
+        _ => return Err("give either `group_left` or `group_right`".to_string()),
+    }
+    Ok(format!("({}) {}{} {}", lhs, op, modifier, rhs))
+}
+
+fn apply(step: &StepSpec, inner: String) -> Result<String, String> {
+    match (&step.function, &step.aggregate, &step.binary, &step.subquery) {
+        (Some(name), None, None, None) => call(name, &step.args, &inner),
+        (None, Some(op), None, None) => {
+            let grouping = match (&step.by, &step.without) {
+                (Some(by), None) => format!(" by {}", label_list(by)),
+                (None, Some(without)) => format!(" without {}", label_list(without)),
+                (None, None) => String::new(),
+                _ => return Err("give either `by` or `without`".to_string()),
+            };
+            let param = step.param.as_ref().map(literal).transpose()?.map(|param| format!("{}, ", param));
+            Ok(format!("{}{} ({}{})", op, grouping, param.unwrap_or_default(), inner))
+        }
+        (None, None, Some(op), None) => binary(op, step, &inner),
+        (None, None, None, Some(range)) => {
+            let step = step.step.as_deref().map(checked_duration).transpose()?.unwrap_or_default();
+            Ok(format!("({})[{}:{}]", inner, checked_duration(range)?, step))
+        }
+        _ => Err("each step needs exactly one of `function`, `aggregate`, `binary` and `subquery`".to_string()),
+    }
+}
+
+fn build_text(spec: &QuerySpec) -> Result<String, String> {
+    spec.steps.iter().try_fold(selector(spec)?, |inner, step| apply(step, inner))
+}
+
+/// The PromQL query `spec` describes, checked to parse and printed without
+/// redundant parentheses.
+pub fn build(spec: &QuerySpec) -> Result<String, String> {
+    let text = build_text(spec)?;
+    let mut expr = parser::parse(&text).map_err(|err| format!("{}: {}", err, text))?;
+    unparen(&mut expr);
+    Ok(to_promql(&expr))
+}
+
+
+#[test]
+fn check_builder() {
+    use serde_json::json;
+    let build_json = |spec: Value| build(&serde_json::from_value(spec).unwrap());
+    let latency = json!({
+        "metric": "http_request_duration_seconds_bucket",
+        "matchers": [{ "label": "job", "value": "api" }, { "label": "path", "op": "=~", "value": "/v1/.+\\.json" }],
+        "range": "300s",
+        "steps": [
+            { "function": "rate" },
+            { "aggregate": "sum", "by": ["le"] },
+            { "function": "histogram_quantile", "args": [0.99] },
+            { "binary": ">", "rhs": 0.5 },
+        ],
+    });
+    assert_eq!(
+        build_json(latency),
+        Ok("histogram_quantile(0.99, sum by (le) (rate(http_request_duration_seconds_bucket{job=\"api\", path=~\"/v1/.+\\\\.json\"}[5m]))) > 0.5".to_string()),
+    );
+    let ratio = json!({
+        "metric": "errors_total",
+        "range": "5m",
+        "steps": [
+            { "function": "increase" },
+            { "binary": "/", "on": ["job"], "group_left": [], "rhs": { "metric": "up", "steps": [{ "aggregate": "topk", "param": 1, "by": ["job"] }] } },
+            { "subquery": "1h", "step": "1m" },
+            { "function": "max_over_time" },
+        ],
+    });
+    assert_eq!(
+        build_json(ratio),
+        Ok("max_over_time((increase(errors_total[5m]) / on (job) group_left () topk by (job) (1, up))[1h:1m])".to_string()),
+    );
+    assert!(build_json(json!({ "metric": "x", "steps": [{ "function": "rate" }] })).unwrap_err().starts_with("cannot pass the query to rate()"));
+    assert!(build_json(json!({ "steps": [] })).is_err());
+    assert!(build_json(json!({ "metric": "x", "steps": [{ "function": "abs", "aggregate": "sum" }] })).is_err());
+}
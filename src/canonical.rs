@@ -0,0 +1,100 @@
+use promql_parser::parser::*;
+use promql_parser::label::*;
+use crate::format::unparen;
+use crate::printer::{matcher, to_promql};
+use crate::pseudonymize::fnv1a;
+use crate::visit::{children_mut, selectors_mut};
+
+fn sort_labels(labels: &mut Labels) {
+    labels.labels.sort();
+    labels.labels.dedup();
+}
+
+fn sort_modifier(modifier: &mut Option<LabelModifier>) {
+    if let Some(LabelModifier::Include(labels) | LabelModifier::Exclude(labels)) = modifier {
+        sort_labels(labels);
+    }
+}
+
+/// `{__name__="x", ...}` as `x{...}`, matchers sorted and deduplicated.
+fn selector(vs: &mut VectorSelector) {
+    let matchers = &mut vs.matchers.matchers;
+    let names: Vec<usize> = (0..matchers.len()).filter(|i| matchers[*i].name == METRIC_NAME).collect();
+    if let (None, [i]) = (&vs.name, names.as_slice()) {
+        if matches!(matchers[*i].op, MatchOp::Equal) {
+            vs.name = Some(matchers.remove(*i).value);
+        }
+    }
+    matchers.sort_by_cached_key(|m| (m.name.clone(), matcher(m)));
+    matchers.dedup_by(|a, b| matcher(a) == matcher(b));
+}
+
+fn sort(expr: &mut Expr) {
+    match expr {
+        Expr::Aggregate(AggregateExpr { modifier, .. }) => sort_modifier(modifier),
+        Expr::Binary(BinaryExpr { modifier: Some(modifier), .. }) => {
+            sort_modifier(&mut modifier.matching);
+            match &mut modifier.card {
+                VectorMatchCardinality::ManyToOne(labels) | VectorMatchCardinality::OneToMany(labels) =>
+                    sort_labels(labels),
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+    for child in children_mut(expr) {
+        sort(child);
+    }
+}
+
+/// Rewrites `expr` into the form every spelling of the same query shares:
+/// no redundant parentheses, selectors named outside their braces, and
+/// matchers and grouping labels sorted and deduplicated. Durations and
+/// spacing are left to `to_promql`, which prints them one way only.
+pub fn canonicalize(expr: &mut Expr) {
+    unparen(expr);
+    selectors_mut(expr, &mut selector);
+    sort(expr);
+}
+
+/// `expr` printed in its canonical form.
+pub fn canonical(expr: &Expr) -> String {
+    let mut expr = expr.clone();
+    canonicalize(&mut expr);
+    to_promql(&expr)
+}
+
+/// A hash of the canonical form of `expr`, stable across releases, as 16
+/// hexadecimal digits.
+pub fn fingerprint(expr: &Expr) -> String {
+    format!("{:016x}", fnv1a(&[&canonical(expr)]))
+}
+
+/// Whether `a` and `b` are the same query up to formatting, matcher and
+/// label order and redundant parentheses.
+pub fn equivalent(a: &Expr, b: &Expr) -> bool {
+    canonical(a) == canonical(b)
+}
+
+
+#[test]
+fn check_canonical() {
+    let same = vec![
+        ("sum by (b, a) (rate(x{c=\"d\", a=~\"b\"}[5m]))", "sum  by(a,b)((rate( x{a=~'b',c=\"d\"}[300s] )))"),
+        ("{__name__=\"up\", job=\"a\"}", "up{job=\"a\", job=\"a\"}"),
+        ("a / on (y, x) group_left (z) b", "(a) / on(x, y) group_left(z) (b)"),
+        ("x[1h:]", "(x)[60m:]"),
+    ];
+    for (a, b) in same {
+        let (a, b) = (parse(a).unwrap(), parse(b).unwrap());
+        assert_eq!(canonical(&a), canonical(&b));
+        assert_eq!(fingerprint(&a), fingerprint(&b));
+        assert!(equivalent(&a, &b));
+    }
+    assert_eq!(canonical(&parse("(a + b) * {__name__=~\"c\"}").unwrap()), "(a + b) * {__name__=~\"c\"}");
+    assert!(!equivalent(&parse("rate(x[5m])").unwrap(), &parse("irate(x[5m])").unwrap()));
+    assert_ne!(fingerprint(&parse("a - (b - c)").unwrap()), fingerprint(&parse("a - b - c").unwrap()));
+    assert_eq!(fingerprint(&parse("up").unwrap()).len(), 16);
+    let normalized = canonical(&parse("sum without(b,a)((x{z='1',y!~\"2\"} offset 90s)) # c").unwrap());
+    assert_eq!(normalized, "sum without (a, b) (x{y!~\"2\", z=\"1\"} offset 1m30s)");
+}
@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, BTreeSet};
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::literals::analyze;
+use crate::matchers::{metric_name, selectors};
+use crate::printer::to_promql;
+use crate::spans::{spans, SpanTree};
+use crate::visit::{children, node_type};
+use crate::ToSerde;
+
+/// Series and label value counts of one metric.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct MetricStats {
+    pub series: f64,
+    /// Distinct values by label name among the series of the metric.
+    #[serde(default)]
+    pub labels: BTreeMap<String, f64>,
+}
+
+/// What is known of the series in the TSDB, as e.g. its `/api/v1/status/tsdb`
+/// endpoint reports.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LabelStats {
+    /// All series, for selectors without a known metric name.
+    pub series: Option<f64>,
+    /// Distinct values by label name across all series.
+    pub labels: BTreeMap<String, f64>,
+    pub metrics: BTreeMap<String, MetricStats>,
+    /// Estimates above this are reported as warnings.
+    pub max_series: Option<f64>,
+}
+
+impl LabelStats {
+    /// Distinct values of `label`, among the series of `metric` if known.
+    fn values(&self, label: &str, metric: Option<&str>) -> Option<f64> {
+        metric
+            .and_then(|metric| self.metrics.get(metric))
+            .and_then(|stats| stats.labels.get(label))
+            .or_else(|| self.labels.get(label))
+            .copied()
+            .filter(|values| *values >= 1.0)
+    }
+}
+
+/// The estimated number of result series of a node and its sub-expressions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub node: &'static str,
+    pub expr: String,
+    /// `None` when the statistics do not cover the selectors involved.
+    pub series: Option<f64>,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+    pub children: Vec<Estimate>,
+}
+
+impl ToSerde for Estimate {
+    fn to_serde(&self) -> Value {
+        json!({
+            "type": self.node,
+            "expr": self.expr,
+            "series": self.series.map(|series| series.round() as u64),
+            "start": self.start,
+            "end": self.end,
+            "children": self.children.to_serde(),
+        })
+    }
+}
+
+/// Estimates of a whole query, with warnings for nodes above `max_series`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cardinality {
+    pub root: Estimate,
+    pub warnings: Vec<String>,
+}
+
+impl ToSerde for Cardinality {
+    fn to_serde(&self) -> Value {
+        json!({
+            "series": self.root.series.map(|series| series.round() as u64),
+            "root": self.root.to_serde(),
+            "warnings": self.warnings,
+        })
+    }
+}
+
+fn min(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+/// The metric all selectors of `expr` select, if there is a single one.
+fn single_metric(expr: &Expr) -> Option<String> {
+    let names: BTreeSet<Option<String>> = selectors(expr).iter().map(metric_name).collect();
+    match names.into_iter().collect::<Vec<_>>().as_slice() {
+        [Some(name)] => Some(name.clone()),
+        _ => None,
+    }
+}
+
+struct Estimator<'a> {
+    stats: &'a LabelStats,
+}
+
+impl Estimator<'_> {
+    /// Assumes values are evenly spread over series and matchers independent;
+    /// regexes without a finite set of literal values are assumed to match
+    /// every value.
+    fn selector(&self, vs: &VectorSelector) -> Option<f64> {
+        let metric = metric_name(vs);
+        let mut series = match metric.as_ref().and_then(|metric| self.stats.metrics.get(metric)) {
+            Some(stats) => stats.series,
+            None => self.stats.series?,
+        };
+        for matcher in vs.matchers.matchers.iter().filter(|m| m.name != METRIC_NAME) {
+            let values = match self.stats.values(&matcher.name, metric.as_deref()) {
+                Some(values) => values,
+                None => continue,
+            };
+            let literals = || analyze(&matcher.value).and_then(|literals| literals.alternatives).map(|a| a.len() as f64);
+            let selectivity = match &matcher.op {
+                MatchOp::Equal if matcher.value.is_empty() => continue,
+                MatchOp::Equal => 1.0 / values,
+                MatchOp::NotEqual => 1.0 - 1.0 / values,
+                MatchOp::Re(_) => literals().map_or(1.0, |n| n.min(values) / values),
+                MatchOp::NotRe(_) => literals().map_or(1.0, |n| 1.0 - n.min(values) / values),
+            };
+            series *= selectivity;
+        }
+        Some(series)
+    }
+
+    /// Groups the series of `inner` fall into under `modifier`.
+    fn groups(&self, inner: &Expr, series: Option<f64>, modifier: &Option<LabelModifier>) -> Option<f64> {
+        let metric = single_metric(inner);
+        let product = |labels: &Labels| {
+            labels
+                .labels
+                .iter()
+                .map(|label| self.stats.values(label, metric.as_deref()))
+                .try_fold(1.0, |product, values| Some(product * values?))
+        };
+        match modifier {
+            None => Some(1.0),
+            Some(LabelModifier::Include(by)) => min(series, product(by)),
+            Some(LabelModifier::Exclude(without)) => series.map(|series| match product(without) {
+                Some(collapsed) => (series / collapsed).max(1.0),
+                None => series,
+            }),
+        }
+    }
+
+    fn series(&self, expr: &Expr, children: &[Estimate]) -> Option<f64> {
+        let child = |i: usize| children.get(i).and_then(|c| c.series);
+        match expr {
+            Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => self.selector(vs),
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) => Some(1.0),
+            Expr::Paren(_) | Expr::Unary(_) | Expr::Subquery(_) => child(0),
+            Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => {
+                let inner = child(if param.is_some() { 1 } else { 0 });
+                let groups = self.groups(expr, inner, modifier);
+                match op.id() {
+                    T_TOPK | T_BOTTOMK => {
+                        let k = param.as_ref().and_then(|param| param.scalar_value());
+                        min(inner, groups.zip(k).map(|(groups, k)| groups * k.max(0.0)))
+                    }
+                    T_COUNT_VALUES => inner,
+                    _ => groups,
+                }
+            }
+            Expr::Binary(BinaryExpr { lhs, rhs, op, modifier }) => {
+                let (left, right) = (child(0), child(1));
+                match (lhs.value_type(), rhs.value_type()) {
+                    (ValueType::Vector, ValueType::Vector) => {}
+                    (ValueType::Vector, _) => return left,
+                    (_, ValueType::Vector) => return right,
+                    _ => return Some(1.0),
+                }
+                match (op.id(), modifier.as_ref().map(|modifier| &modifier.card)) {
+                    (T_LOR, _) => left.zip(right).map(|(left, right)| left + right),
+                    (T_LUNLESS, _) | (_, Some(VectorMatchCardinality::ManyToOne(_))) => left,
+                    (_, Some(VectorMatchCardinality::OneToMany(_))) => right,
+                    _ => min(left, right),
+                }
+            }
+            Expr::Call(Call { func, args }) => match func.name {
+                "absent" | "absent_over_time" | "vector" => Some(1.0),
+                _ if func.return_type == ValueType::Scalar => Some(1.0),
+                "histogram_quantile" | "histogram_fraction" => {
+                    let inner = args.args.iter().position(|arg| arg.value_type() == ValueType::Vector).and_then(child);
+                    let buckets = args.args.last().and_then(|arg| self.stats.values(BUCKET_LABEL, single_metric(arg).as_deref()));
+                    inner.map(|inner| match buckets {
+                        Some(buckets) => (inner / buckets).max(1.0),
+                        None => inner,
+                    })
+                }
+                _ => args
+                    .args
+                    .iter()
+                    .position(|arg| matches!(arg.value_type(), ValueType::Vector | ValueType::Matrix))
+                    .and_then(child)
+                    .or(Some(1.0)),
+            },
+            Expr::Extension(_) => None,
+        }
+    }
+
+    fn estimate(&self, expr: &Expr, tree: Option<&SpanTree>) -> Estimate {
+        let child_trees: Vec<Option<&SpanTree>> = match tree {
+            Some(tree) if tree.children.len() == children(expr).len() => tree.children.iter().map(Some).collect(),
+            _ => vec![None; children(expr).len()],
+        };
+        let children: Vec<Estimate> = children(expr)
+            .into_iter()
+            .zip(child_trees)
+            .map(|(child, tree)| self.estimate(child, tree))
+            .collect();
+        Estimate {
+            node: node_type(expr),
+            expr: to_promql(expr),
+            series: self.series(expr, &children),
+            start: tree.map(|tree| tree.span.start),
+            end: tree.map(|tree| tree.span.end),
+            children,
+        }
+    }
+}
+
+fn warnings(estimate: &Estimate, max_series: f64, out: &mut Vec<String>) {
+    if let Some(series) = estimate.series.filter(|series| *series > max_series) {
+        out.push(format!(
+            "`{}` may return about {} series, above the limit of {}",
+            estimate.expr,
+            series.round(),
+            max_series,
+        ));
+    }
+    for child in &estimate.children {
+        warnings(child, max_series, out);
+    }
+}
+
+/// Estimates the number of series each node of `expr`, parsed from `query`,
+/// returns, from the series and label value counts in `stats`.
+pub fn cardinality(query: &str, expr: &Expr, stats: &LabelStats) -> Cardinality {
+    let tree = spans(query, expr);
+    let root = Estimator { stats }.estimate(expr, tree.as_ref());
+    let mut found = vec![];
+    if let Some(max_series) = stats.max_series {
+        warnings(&root, max_series, &mut found);
+    }
+    Cardinality { root, warnings: found }
+}
+
+
+#[test]
+fn check_cardinality() {
+    let stats: LabelStats = serde_json::from_value(json!({
+        "series": 1000000,
+        "labels": { "job": 20 },
+        "metrics": {
+            "http_requests_total": { "series": 2000000, "labels": { "pod": 4000, "code": 10, "job": 5 } },
+            "up": { "series": 500 },
+        },
+        "max_series": 100000,
+    })).unwrap();
+    let payloads = vec![
+        ("up", Some(500)),
+        ("http_requests_total{code=\"500\"}", Some(200000)),
+        ("http_requests_total{code=~\"5..\"}", Some(2000000)),
+        ("http_requests_total{code=~\"500|503\", job!=\"a\"}", Some(320000)),
+        ("group by (pod) (http_requests_total)", Some(4000)),
+        ("sum without (pod) (rate(http_requests_total[5m]))", Some(500)),
+        ("topk(3, sum by (job, code) (http_requests_total))", Some(3)),
+        ("sum(up) / count(up)", Some(1)),
+        ("up * on (job) group_left () sum by (job) (http_requests_total)", Some(500)),
+        ("foo", Some(1000000)),
+        ("rate(foo[5m]) > 2", Some(1000000)),
+    ];
+    for (query, expected) in payloads {
+        let found = cardinality(query, &parse(query).unwrap(), &stats);
+        assert_eq!(found.root.series.map(|series| series.round() as u64), expected, "{}", query);
+    }
+    let query = "count(group by (pod) (http_requests_total))";
+    let found = cardinality(query, &parse(query).unwrap(), &stats);
+    assert_eq!(found.root.children[0].children[0].series, Some(2000000.0));
+    assert_eq!(found.warnings.len(), 1);
+    assert_eq!(found.root.children[0].start, Some(6));
+    let unknown = cardinality("foo", &parse("foo").unwrap(), &LabelStats::default());
+    assert_eq!(unknown.root.series, None);
+}
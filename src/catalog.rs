@@ -0,0 +1,160 @@
+use promql_parser::parser::ValueType;
+use crate::experimental;
+use crate::lint::DEPRECATED_FUNCTIONS;
+
+/// The functions upstream knows, with their arguments as the Prometheus docs
+/// write them.
+const FUNCTIONS: [(&str, &str); 70] = [
+    ("abs", "v instant-vector"),
+    ("absent", "v instant-vector"),
+    ("absent_over_time", "v range-vector"),
+    ("acos", "v instant-vector"),
+    ("acosh", "v instant-vector"),
+    ("asin", "v instant-vector"),
+    ("asinh", "v instant-vector"),
+    ("atan", "v instant-vector"),
+    ("atanh", "v instant-vector"),
+    ("avg_over_time", "v range-vector"),
+    ("ceil", "v instant-vector"),
+    ("changes", "v range-vector"),
+    ("clamp", "v instant-vector, min scalar, max scalar"),
+    ("clamp_max", "v instant-vector, max scalar"),
+    ("clamp_min", "v instant-vector, min scalar"),
+    ("cos", "v instant-vector"),
+    ("cosh", "v instant-vector"),
+    ("count_over_time", "v range-vector"),
+    ("day_of_month", "v=vector(time()) instant-vector"),
+    ("day_of_week", "v=vector(time()) instant-vector"),
+    ("day_of_year", "v=vector(time()) instant-vector"),
+    ("days_in_month", "v=vector(time()) instant-vector"),
+    ("deg", "v instant-vector"),
+    ("delta", "v range-vector"),
+    ("deriv", "v range-vector"),
+    ("exp", "v instant-vector"),
+    ("floor", "v instant-vector"),
+    ("histogram_count", "v instant-vector"),
+    ("histogram_fraction", "lower scalar, upper scalar, b instant-vector"),
+    ("histogram_quantile", "φ scalar, b instant-vector"),
+    ("histogram_sum", "v instant-vector"),
+    ("holt_winters", "v range-vector, sf scalar, tf scalar"),
+    ("hour", "v=vector(time()) instant-vector"),
+    ("idelta", "v range-vector"),
+    ("increase", "v range-vector"),
+    ("irate", "v range-vector"),
+    ("label_join", "v instant-vector, dst_label string, separator string, src_label_1 string, ..."),
+    ("label_replace", "v instant-vector, dst_label string, replacement string, src_label string, regex string"),
+    ("last_over_time", "v range-vector"),
+    ("ln", "v instant-vector"),
+    ("log10", "v instant-vector"),
+    ("log2", "v instant-vector"),
+    ("max_over_time", "v range-vector"),
+    ("min_over_time", "v range-vector"),
+    ("minute", "v=vector(time()) instant-vector"),
+    ("month", "v=vector(time()) instant-vector"),
+    ("pi", ""),
+    ("predict_linear", "v range-vector, t scalar"),
+    ("present_over_time", "v range-vector"),
+    ("quantile_over_time", "φ scalar, v range-vector"),
+    ("rad", "v instant-vector"),
+    ("rate", "v range-vector"),
+    ("resets", "v range-vector"),
+    ("round", "v instant-vector, to_nearest=1 scalar"),
+    ("scalar", "v instant-vector"),
+    ("sgn", "v instant-vector"),
+    ("sin", "v instant-vector"),
+    ("sinh", "v instant-vector"),
+    ("sort", "v instant-vector"),
+    ("sort_desc", "v instant-vector"),
+    ("sqrt", "v instant-vector"),
+    ("stddev_over_time", "v range-vector"),
+    ("stdvar_over_time", "v range-vector"),
+    ("sum_over_time", "v range-vector"),
+    ("tan", "v instant-vector"),
+    ("tanh", "v instant-vector"),
+    ("time", ""),
+    ("timestamp", "v instant-vector"),
+    ("vector", "s scalar"),
+    ("year", "v=vector(time()) instant-vector"),
+];
+
+/// The aggregation operators, with their parameter if any.
+const AGGREGATORS: [(&str, &str); 12] = [
+    ("avg", "v instant-vector"),
+    ("bottomk", "k scalar, v instant-vector"),
+    ("count", "v instant-vector"),
+    ("count_values", "label string, v instant-vector"),
+    ("group", "v instant-vector"),
+    ("max", "v instant-vector"),
+    ("min", "v instant-vector"),
+    ("quantile", "φ scalar, v instant-vector"),
+    ("stddev", "v instant-vector"),
+    ("stdvar", "v instant-vector"),
+    ("sum", "v instant-vector"),
+    ("topk", "k scalar, v instant-vector"),
+];
+
+/// A function or aggregation operator a query may use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: &'static str,
+    /// As in the Prometheus docs, `rate(v range-vector)`.
+    pub signature: String,
+    /// Whether it is deprecated or needs the experimental functions flag.
+    pub note: Option<String>,
+}
+
+fn type_name(value_type: &ValueType) -> &'static str {
+    match value_type {
+        ValueType::Vector => "instant-vector",
+        ValueType::Matrix => "range-vector",
+        ValueType::Scalar => "scalar",
+        ValueType::String => "string",
+    }
+}
+
+/// Every function, upstream ones first, then the experimental ones.
+pub fn functions() -> Vec<Entry> {
+    let upstream = FUNCTIONS.iter().map(|(name, args)| Entry {
+        name,
+        signature: format!("{}({})", name, args),
+        note: DEPRECATED_FUNCTIONS
+            .iter()
+            .find(|(old, _)| old == name)
+            .map(|(_, new)| format!("deprecated, use {}", new)),
+    });
+    let experimental = experimental::FUNCTIONS.iter().map(|function| {
+        let args: Vec<&str> = function.arg_types.iter().map(type_name).collect();
+        let more = if function.variadic { ", ..." } else { "" };
+        Entry {
+            name: function.name,
+            signature: format!("{}({}{})", function.name, args.join(", "), more),
+            note: Some("experimental".to_string()),
+        }
+    });
+    upstream.chain(experimental).collect()
+}
+
+pub fn aggregators() -> Vec<Entry> {
+    AGGREGATORS
+        .iter()
+        .map(|(name, args)| Entry { name, signature: format!("{}({})", name, args), note: None })
+        .collect()
+}
+
+
+#[test]
+fn check_catalog() {
+    let functions = functions();
+    let rate = functions.iter().find(|entry| entry.name == "rate").unwrap();
+    assert_eq!((rate.signature.as_str(), &rate.note), ("rate(v range-vector)", &None));
+    let holt_winters = functions.iter().find(|entry| entry.name == "holt_winters").unwrap();
+    assert_eq!(holt_winters.note.as_deref(), Some("deprecated, use double_exponential_smoothing"));
+    let sort_by_label = functions.iter().find(|entry| entry.name == "sort_by_label").unwrap();
+    assert_eq!(sort_by_label.signature, "sort_by_label(instant-vector, string, ...)");
+    for entry in functions.iter().filter(|entry| entry.note.as_deref() != Some("experimental")) {
+        let args = if entry.signature.ends_with("()") { "" } else { "x" };
+        let error = crate::errors::try_parse(&format!("{}({})", entry.name, args)).err().map(|error| error.code);
+        assert_ne!(error, Some("unknown-function"), "{}", entry.name);
+    }
+    assert_eq!(aggregators()[1].signature, "bottomk(k scalar, v instant-vector)");
+}
@@ -0,0 +1,414 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde::Deserialize;
+use crate::lookback::LOOKBACK_DELTA;
+use crate::printer::unescape;
+use crate::visit::node_type;
+
+/// How the series table stores label sets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelsFormat {
+    /// A JSON object in a `String` column, as qryn keeps them.
+    #[default]
+    Json,
+    /// A `Map(String, String)` column.
+    Map,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampUnit {
+    S,
+    Ms,
+    #[default]
+    Ns,
+}
+
+/// Tables and columns of the samples and series, qryn's by default. Label
+/// sets include `__name__`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Schema {
+    pub samples_table: String,
+    pub series_table: String,
+    /// Column joining samples to their series in both tables.
+    pub fingerprint: String,
+    pub timestamp: String,
+    pub timestamp_unit: TimestampUnit,
+    pub value: String,
+    pub labels: String,
+    pub labels_format: LabelsFormat,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema {
+            samples_table: "samples_v3".to_string(),
+            series_table: "time_series".to_string(),
+            fingerprint: "fingerprint".to_string(),
+            timestamp: "timestamp_ns".to_string(),
+            timestamp_unit: TimestampUnit::Ns,
+            value: "value".to_string(),
+            labels: "labels".to_string(),
+            labels_format: LabelsFormat::Json,
+        }
+    }
+}
+
+/// When to evaluate: at `time`, or every `step` from `start` to `end`, in
+/// milliseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SqlOptions {
+    pub time: Option<f64>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub step: Option<f64>,
+    /// `--query.lookback-delta`, in seconds.
+    pub lookback_delta: f64,
+    pub schema: Schema,
+}
+
+impl Default for SqlOptions {
+    fn default() -> Self {
+        SqlOptions {
+            time: None,
+            start: None,
+            end: None,
+            step: None,
+            lookback_delta: LOOKBACK_DELTA.as_secs_f64(),
+            schema: Schema::default(),
+        }
+    }
+}
+
+/// A ClickHouse string literal.
+fn literal(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn float(val: f64) -> String {
+    match val {
+        val if val.is_nan() => "nan".to_string(),
+        val if val == f64::INFINITY => "inf".to_string(),
+        val if val == f64::NEG_INFINITY => "-inf".to_string(),
+        val => format!("{:?}", val),
+    }
+}
+
+/// How the samples of a window become the value of a step, and what the
+/// value is then computed from it, over `s.step_value`.
+struct Window {
+    range: i128,
+    aggregate: String,
+    having: Option<&'static str>,
+    value: String,
+    keeps_name: bool,
+}
+
+/// Every subquery returns `series_labels`, a sorted array of label name and
+/// value pairs, `step_index` and `step_value`.
+struct Translator<'a> {
+    schema: &'a Schema,
+    start: i128,
+    step: i128,
+    /// Index of the last step.
+    last: i128,
+    lookback: i128,
+}
+
+impl Translator<'_> {
+    /// The value of label `name` of a series row.
+    fn label(&self, name: &str) -> String {
+        match self.schema.labels_format {
+            LabelsFormat::Json => format!("JSONExtractString({}, {})", self.schema.labels, literal(name)),
+            LabelsFormat::Map => format!("{}[{}]", self.schema.labels, literal(name)),
+        }
+    }
+
+    fn pairs(&self) -> String {
+        match self.schema.labels_format {
+            LabelsFormat::Json => format!("arraySort(JSONExtractKeysAndValues({}, 'String'))", self.schema.labels),
+            LabelsFormat::Map => {
+                format!("arraySort(arrayZip(mapKeys({0}), mapValues({0})))", self.schema.labels)
+            }
+        }
+    }
+
+    fn condition(&self, matcher: &Matcher) -> String {
+        let value = unescape(&matcher.value);
+        let label = self.label(&matcher.name);
+        match matcher.op {
+            MatchOp::Equal => format!("{} = {}", label, literal(&value)),
+            MatchOp::NotEqual => format!("{} != {}", label, literal(&value)),
+            MatchOp::Re(_) => format!("match({}, {})", label, literal(&format!("^(?:{})$", value))),
+            MatchOp::NotRe(_) => format!("NOT match({}, {})", label, literal(&format!("^(?:{})$", value))),
+        }
+    }
+
+    /// Milliseconds of the timestamp column.
+    fn millis(&self) -> String {
+        let column = &self.schema.timestamp;
+        match self.schema.timestamp_unit {
+            TimestampUnit::S => format!("{} * 1000", column),
+            TimestampUnit::Ms => column.to_string(),
+            TimestampUnit::Ns => format!("intDiv({}, 1000000)", column),
+        }
+    }
+
+    /// `millis` in the unit of the timestamp column, rounded down.
+    fn column_time(&self, millis: i128) -> i128 {
+        match self.schema.timestamp_unit {
+            TimestampUnit::S => millis.div_euclid(1000),
+            TimestampUnit::Ms => millis,
+            TimestampUnit::Ns => millis * 1_000_000,
+        }
+    }
+
+    /// The samples of `vs` reduced per series and step over `window`: a
+    /// sample at `t` belongs to every step in `[t, t + range)`.
+    fn selector(&self, vs: &VectorSelector, window: Window) -> Result<String, String> {
+        if vs.offset.is_some() || vs.at.is_some() {
+            return Err("offsets and @ modifiers are not supported".to_string());
+        }
+        let Schema { samples_table, series_table, fingerprint, .. } = self.schema;
+        let mut conditions: Vec<String> = vs.matchers.matchers.iter().map(|m| self.condition(m)).collect();
+        if let Some(name) = &vs.name {
+            conditions.insert(0, format!("{} = {}", self.label(METRIC_NAME), literal(name)));
+        }
+        let conditions = conditions.join(" AND ");
+        let millis = self.millis();
+        let first = format!("greatest(toInt64(ceil(({} - {}) / {})), 0)", millis, self.start, self.step);
+        let after = format!("least(toInt64(ceil(({} - {}) / {})), {})", millis, self.start - window.range, self.step, self.last + 1);
+        let end = self.start + self.last * self.step;
+        let samples = format!(
+            "SELECT {fp}, step_index, {aggregate} AS step_value FROM {samples} \
+             ARRAY JOIN range({first}, {after}) AS step_index \
+             WHERE {fp} IN (SELECT {fp} FROM {series} WHERE {conditions}) \
+             AND {timestamp} > {from} AND {timestamp} <= {to} \
+             GROUP BY {fp}, step_index{having}",
+            fp = fingerprint,
+            aggregate = window.aggregate,
+            samples = samples_table,
+            series = series_table,
+            timestamp = self.schema.timestamp,
+            from = self.column_time(self.start - window.range),
+            to = self.column_time(end),
+            having = window.having.map(|having| format!(" HAVING {}", having)).unwrap_or_default(),
+        );
+        let labels = if window.keeps_name { "series.series_labels".to_string() } else { drop_name("series.series_labels") };
+        Ok(format!(
+            "SELECT {labels} AS series_labels, s.step_index AS step_index, {value} AS step_value \
+             FROM ({samples}) AS s \
+             INNER JOIN (SELECT {fp}, any({pairs}) AS series_labels FROM {series} WHERE {conditions} GROUP BY {fp}) AS series \
+             ON s.{fp} = series.{fp}",
+            value = window.value,
+            fp = fingerprint,
+            pairs = self.pairs(),
+            series = series_table,
+        ))
+    }
+
+    fn call(&self, func: &Function, args: &FunctionArgs) -> Result<String, String> {
+        let (vs, range) = match args.args.first().map(|arg| arg.as_ref()) {
+            Some(Expr::MatrixSelector(MatrixSelector { vs, range })) if args.args.len() == 1 => (vs, range.as_millis() as i128),
+            _ => return Err(format!("{}() is only supported on a range selector", func.name)),
+        };
+        let values = format!("arrayMap(x -> x.2, arraySort(x -> x.1, groupArray(({}, {}))))", self.millis(), self.schema.value);
+        // counter resets add back the value before the drop
+        let increase = "(s.step_value[-1] - s.step_value[1] + arraySum(arrayMap((a, b) -> if(b < a, a, 0), \
+                        arrayPopBack(s.step_value), arrayPopFront(s.step_value))))";
+        let plain = |aggregate: String, keeps_name: bool| Window {
+            range,
+            aggregate,
+            having: None,
+            value: "s.step_value".to_string(),
+            keeps_name,
+        };
+        let value = self.schema.value.as_str();
+        let window = match func.name {
+            "rate" | "increase" => Window {
+                range,
+                aggregate: values,
+                having: Some("length(step_value) >= 2"),
+                value: match func.name {
+                    "rate" => format!("{} / {}", increase, float(range as f64 / 1000.0)),
+                    _ => increase.to_string(),
+                },
+                keeps_name: false,
+            },
+            "sum_over_time" | "avg_over_time" | "min_over_time" | "max_over_time" => {
+                plain(format!("{}({})", func.name.trim_end_matches("_over_time"), value), false)
+            }
+            "count_over_time" => plain("toFloat64(count())".to_string(), false),
+            "last_over_time" => plain(format!("argMax({}, {})", value, self.millis()), true),
+            name => return Err(format!("{}() is not supported", name)),
+        };
+        self.selector(vs, window)
+    }
+
+    fn aggregate(&self, agg: &AggregateExpr) -> Result<String, String> {
+        let function = match agg.op.id() {
+            T_SUM | T_AVG | T_MIN | T_MAX => format!("{}(t.step_value)", agg.op),
+            T_COUNT => "toFloat64(count())".to_string(),
+            _ => return Err(format!("`{}` is not supported", agg.op)),
+        };
+        let names = |labels: &Labels| {
+            let names: Vec<String> = labels.labels.iter().map(|name| literal(name)).collect();
+            format!("[{}]", names.join(", "))
+        };
+        let labels = match &agg.modifier {
+            Some(LabelModifier::Include(by)) => format!("arrayFilter(x -> has({}, x.1), t.series_labels)", names(by)),
+            Some(LabelModifier::Exclude(without)) => format!(
+                "arrayFilter(x -> NOT has({}, x.1) AND x.1 != {}, t.series_labels)",
+                names(without),
+                literal(METRIC_NAME),
+            ),
+            None => "[]".to_string(),
+        };
+        Ok(format!(
+            "SELECT {} AS series_labels, t.step_index AS step_index, {} AS step_value FROM ({}) AS t \
+             GROUP BY series_labels, step_index",
+            labels,
+            function,
+            self.translate(&agg.expr)?,
+        ))
+    }
+
+    /// An operation between a vector and a number, in either order.
+    fn binary(&self, lhs: &Expr, op: &TokenType, rhs: &Expr, modifier: &Option<BinModifier>) -> Result<String, String> {
+        let number = |expr: &Expr| match strip_parens(expr) {
+            Expr::NumberLiteral(NumberLiteral { val }) => Some(float(*val)),
+            _ => None,
+        };
+        let (vector, left, right) = match (number(lhs), number(rhs)) {
+            (None, Some(rhs_value)) => (lhs, "t.step_value".to_string(), rhs_value),
+            (Some(lhs_value), None) => (rhs, lhs_value, "t.step_value".to_string()),
+            _ => return Err(format!("`{}` is only supported between a vector and a number", op)),
+        };
+        let inner = self.translate(vector)?;
+        let comparison = match op.id() {
+            T_EQLC => Some("="),
+            T_NEQ => Some("!="),
+            T_GTR => Some(">"),
+            T_LSS => Some("<"),
+            T_GTE => Some(">="),
+            T_LTE => Some("<="),
+            _ => None,
+        };
+        let (labels, value, filter) = match comparison {
+            Some(cmp) if modifier.as_ref().is_some_and(|m| m.return_bool) => (
+                drop_name("t.series_labels"),
+                format!("toFloat64({} {} {})", left, cmp, right),
+                String::new(),
+            ),
+            Some(cmp) => ("t.series_labels".to_string(), "t.step_value".to_string(), format!(" WHERE {} {} {}", left, cmp, right)),
+            None => {
+                let value = match op.id() {
+                    T_ADD | T_SUB | T_MUL | T_DIV | T_MOD => format!("{} {} {}", left, op, right),
+                    T_POW => format!("pow({}, {})", left, right),
+                    _ => return Err(format!("`{}` is not supported", op)),
+                };
+                (drop_name("t.series_labels"), value, String::new())
+            }
+        };
+        Ok(format!(
+            "SELECT {} AS series_labels, t.step_index AS step_index, {} AS step_value FROM ({}) AS t{}",
+            labels, value, inner, filter,
+        ))
+    }
+
+    fn translate(&self, expr: &Expr) -> Result<String, String> {
+        match expr {
+            Expr::Paren(ParenExpr { expr }) => self.translate(expr),
+            Expr::VectorSelector(vs) => self.selector(vs, Window {
+                range: self.lookback,
+                aggregate: format!("argMax({}, {})", self.schema.value, self.millis()),
+                having: None,
+                value: "s.step_value".to_string(),
+                keeps_name: true,
+            }),
+            Expr::Call(Call { func, args }) => self.call(func, args),
+            Expr::Aggregate(agg) => self.aggregate(agg),
+            Expr::Binary(BinaryExpr { lhs, op, rhs, modifier }) => self.binary(lhs, op, rhs, modifier),
+            expr => Err(format!("{} is not supported", node_type(expr).replace('_', " "))),
+        }
+    }
+}
+
+fn strip_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(ParenExpr { expr }) => strip_parens(expr),
+        expr => expr,
+    }
+}
+
+fn drop_name(labels: &str) -> String {
+    format!("arrayFilter(x -> x.1 != {}, {})", literal(METRIC_NAME), labels)
+}
+
+/// Experimental: ClickHouse SQL computing `expr` over the tables of
+/// `options.schema`, as rows of `labels` (sorted name and value pairs),
+/// `timestamp_ms` and `value`. Covers selectors, `rate`, `increase` and the
+/// `*_over_time` sums, averages, extremes and counts, `sum`, `avg`, `min`,
+/// `max` and `count` with `by` or `without`, and arithmetic and comparisons
+/// with numbers. `rate` and `increase` follow counter resets but do not
+/// extrapolate to the edges of their range as Prometheus does.
+pub fn to_sql(expr: &Expr, options: &SqlOptions) -> Result<String, String> {
+    let (start, end, step) = match (options.time, options.start, options.end, options.step) {
+        (Some(time), None, None, _) => (time, time, 1.0),
+        (None, Some(start), Some(end), Some(step)) if start <= end && step > 0.0 => (start, end, step),
+        (None, Some(_), Some(_), _) => return Err("a range needs `start` before `end` and a positive `step`".to_string()),
+        _ => return Err("give either `time`, or `start`, `end` and `step`".to_string()),
+    };
+    let (start, end, step) = (start.round() as i128, end.round() as i128, (step.round() as i128).max(1));
+    if expr.value_type() != ValueType::Vector {
+        return Err(format!("only queries returning an instant vector are supported, not a {}", expr.value_type()));
+    }
+    let translator = Translator {
+        schema: &options.schema,
+        start,
+        step,
+        last: (end - start) / step,
+        lookback: (options.lookback_delta * 1000.0).round() as i128,
+    };
+    Ok(format!(
+        "SELECT series_labels AS labels, {} + step_index * {} AS timestamp_ms, step_value AS value FROM ({}) \
+         ORDER BY labels, timestamp_ms",
+        start,
+        step,
+        translator.translate(expr)?,
+    ))
+}
+
+
+#[test]
+fn check_clickhouse() {
+    let options = SqlOptions { start: Some(0.0), end: Some(120_000.0), step: Some(60_000.0), ..SqlOptions::default() };
+    let sql = |query: &str| to_sql(&parse(query).unwrap(), &options);
+    let rate = sql("sum by (job) (rate(http_requests_total{code=~\"5\\\\d\\\\d\"}[5m])) > 0.5").unwrap();
+    assert!(rate.starts_with("SELECT series_labels AS labels, 0 + step_index * 60000 AS timestamp_ms"));
+    assert!(rate.contains("FROM samples_v3 ARRAY JOIN range(greatest(toInt64(ceil((intDiv(timestamp_ns, 1000000) - 0) / 60000)), 0), least(toInt64(ceil((intDiv(timestamp_ns, 1000000) - -300000) / 60000)), 3)) AS step_index"));
+    assert!(rate.contains("JSONExtractString(labels, '__name__') = 'http_requests_total' AND match(JSONExtractString(labels, 'code'), '^(?:5\\\\d\\\\d)$')"));
+    assert!(rate.contains("timestamp_ns > -300000000000 AND timestamp_ns <= 120000000000"));
+    assert!(rate.contains("/ 300.0 AS step_value"));
+    assert!(rate.contains("arrayFilter(x -> has(['job'], x.1), t.series_labels) AS series_labels"));
+    assert!(rate.ends_with("GROUP BY series_labels, step_index) AS t WHERE t.step_value > 0.5) ORDER BY labels, timestamp_ms"));
+    let map = SqlOptions {
+        time: Some(1000.0),
+        start: None,
+        end: None,
+        step: None,
+        schema: Schema { labels_format: LabelsFormat::Map, timestamp_unit: TimestampUnit::Ms, ..Schema::default() },
+        ..SqlOptions::default()
+    };
+    let instant = to_sql(&parse("2 * up{job!=\"a'b\"}").unwrap(), &map).unwrap();
+    assert!(instant.contains("labels['job'] != 'a\\'b'"));
+    assert!(instant.contains("2.0 * t.step_value AS step_value"));
+    assert_eq!(sql("x offset 5m").unwrap_err(), "offsets and @ modifiers are not supported");
+    assert_eq!(sql("a / b").unwrap_err(), "`/` is only supported between a vector and a number");
+    assert_eq!(sql("quantile(0.9, x)").unwrap_err(), "`quantile` is not supported");
+    assert!(sql("x[5m]").is_err());
+}
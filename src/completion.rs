@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, BTreeSet};
+use promql_parser::parser::token::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::catalog::{aggregators, functions};
+use crate::lenient::closing;
+use crate::lexemes::{kind, lex, Lexeme};
+use crate::ToSerde;
+
+/// What is known of a metric, e.g. from `/api/v1/metadata` and `/api/v1/series`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MetricMetadata {
+    #[serde(rename = "type")]
+    pub metric_type: Option<String>,
+    pub help: Option<String>,
+    /// Label names of its series, offered in its matchers instead of all.
+    pub labels: Vec<String>,
+}
+
+/// Metric and label names the caller knows of, for completing them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    pub metrics: BTreeMap<String, MetricMetadata>,
+    /// Values by label name.
+    pub labels: BTreeMap<String, Vec<String>>,
+}
+
+impl Metadata {
+    fn label_names(&self, metric: Option<&str>) -> BTreeSet<&str> {
+        match metric.and_then(|metric| self.metrics.get(metric)) {
+            Some(metadata) if !metadata.labels.is_empty() => metadata.labels.iter().map(String::as_str).collect(),
+            _ => {
+                let of_metrics = self.metrics.values().flat_map(|metadata| metadata.labels.iter());
+                self.labels.keys().chain(of_metrics).map(String::as_str).collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub label: String,
+    /// `function`, `aggregator`, `metric`, `label`, `label_value`, `keyword`,
+    /// `operator` or `duration`.
+    pub kind: &'static str,
+    /// Signature of a function or aggregator, type of a metric.
+    pub detail: Option<String>,
+    pub documentation: Option<String>,
+}
+
+/// Candidates for replacing the text from `from` to the cursor, `to`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub from: usize,
+    pub to: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+impl ToSerde for Completion {
+    fn to_serde(&self) -> Value {
+        let candidates: Vec<Value> = self
+            .candidates
+            .iter()
+            .map(|candidate| {
+                json!({
+                    "label": candidate.label,
+                    "kind": candidate.kind,
+                    "detail": candidate.detail,
+                    "documentation": candidate.documentation,
+                })
+            })
+            .collect();
+        json!({ "from": self.from, "to": self.to, "candidates": candidates })
+    }
+}
+
+const DURATIONS: [&str; 11] = ["1m", "5m", "10m", "15m", "30m", "1h", "3h", "6h", "12h", "1d", "1w"];
+const BINARY_OPERATORS: [&str; 12] = ["+", "-", "*", "/", "%", "^", "==", "!=", ">", "<", ">=", "<="];
+const MATCH_OPERATORS: [&str; 4] = ["=", "!=", "=~", "!~"];
+
+/// A bracket open before the cursor.
+enum Open {
+    /// With the two tokens before it, e.g. `sum by`.
+    Paren(Option<TokenId>, Option<TokenId>),
+    /// With the metric name before it.
+    Brace(Option<String>),
+    Bracket,
+}
+
+/// Where the cursor is, as far as completion goes.
+enum Context {
+    Expression,
+    /// After a complete expression, `by` and `without` allowed if it is an
+    /// aggregation that has no grouping yet.
+    Operator { grouping: bool },
+    /// After a binary operator, `bool` allowed if it is a comparison.
+    Operand { comparison: bool },
+    /// After the labels of `on` or `ignoring`.
+    GroupModifier,
+    Aggregator,
+    LabelName(Option<String>),
+    MatchOperator,
+    LabelValue { label: String, quoted: bool },
+    Duration,
+    At,
+    None,
+}
+
+fn is_word(id: TokenId) -> bool {
+    matches!(kind(id), "identifier" | "aggregator" | "keyword")
+}
+
+fn is_aggregator(id: Option<TokenId>) -> bool {
+    id.is_some_and(|id| kind(id) == "aggregator")
+}
+
+fn ends_expression(id: TokenId) -> bool {
+    matches!(
+        id,
+        T_RIGHT_PAREN | T_RIGHT_BRACE | T_RIGHT_BRACKET | T_IDENTIFIER | T_METRIC_IDENTIFIER | T_NUMBER | T_STRING | T_DURATION
+    )
+}
+
+/// The context of the cursor after `before`, the tokens ahead of what is
+/// being typed, `typed`.
+fn context(query: &str, before: &[Lexeme], typed: Option<&Lexeme>) -> Context {
+    let mut open = vec![];
+    let mut closed = None;
+    for (i, lexeme) in before.iter().enumerate() {
+        let back = |n: usize| i.checked_sub(n).map(|j| before[j].id);
+        match lexeme.id {
+            T_LEFT_PAREN => open.push(Open::Paren(back(1), back(2))),
+            T_LEFT_BRACE => {
+                let metric = i.checked_sub(1).map(|j| before[j]).filter(|l| l.id != T_RIGHT_PAREN && kind(l.id) == "identifier");
+                open.push(Open::Brace(metric.map(|l| l.text(query).to_string())));
+            }
+            T_LEFT_BRACKET => open.push(Open::Bracket),
+            T_RIGHT_PAREN | T_RIGHT_BRACE | T_RIGHT_BRACKET => closed = open.pop(),
+            _ => {}
+        }
+    }
+    let prev = before.last();
+    let typed_string = typed.is_some_and(|typed| typed.id == T_STRING);
+    match open.last() {
+        Some(Open::Brace(metric)) => match (prev.map(|l| l.id), typed_string) {
+            (Some(T_EQL | T_NEQ | T_EQL_REGEX | T_NEQ_REGEX), _) => {
+                let label = before.len().checked_sub(2).map(|i| before[i].text(query).to_string()).unwrap_or_default();
+                Context::LabelValue { label, quoted: typed_string }
+            }
+            (_, true) => Context::None,
+            (Some(T_LEFT_BRACE | T_COMMA), _) => Context::LabelName(metric.clone()),
+            (Some(_), false) if typed.is_none() && prev.is_some_and(|l| is_word(l.id)) => Context::MatchOperator,
+            _ => Context::None,
+        },
+        Some(Open::Bracket) => match prev.map(|l| l.id) {
+            Some(T_LEFT_BRACKET | T_COLON) => Context::Duration,
+            _ => Context::None,
+        },
+        Some(Open::Paren(Some(T_BY | T_WITHOUT | T_ON | T_IGNORING | T_GROUP_LEFT | T_GROUP_RIGHT), _)) => {
+            match prev.map(|l| l.id) {
+                Some(T_LEFT_PAREN | T_COMMA) => Context::LabelName(None),
+                _ => Context::None,
+            }
+        }
+        _ if typed_string => Context::None,
+        _ => match prev.map(|l| l.id) {
+            None | Some(T_LEFT_PAREN | T_COMMA) => Context::Expression,
+            Some(T_OFFSET) => Context::Duration,
+            Some(T_AT) => Context::At,
+            Some(T_BOOL) => Context::Expression,
+            Some(id) if is_aggregator(Some(id)) => Context::Aggregator,
+            Some(T_RIGHT_PAREN) => match closed {
+                Some(Open::Paren(Some(T_ON | T_IGNORING), _)) => Context::GroupModifier,
+                Some(Open::Paren(Some(T_GROUP_LEFT | T_GROUP_RIGHT), _)) => Context::Expression,
+                Some(Open::Paren(Some(T_BY | T_WITHOUT), before)) if is_aggregator(before) => Context::None,
+                Some(Open::Paren(before, _)) => Context::Operator { grouping: is_aggregator(before) },
+                _ => Context::Operator { grouping: false },
+            },
+            Some(id) if ends_expression(id) => Context::Operator { grouping: false },
+            Some(id) if kind(id) == "operator" => {
+                Context::Operand { comparison: matches!(id, T_EQLC | T_NEQ | T_GTR | T_LSS | T_GTE | T_LTE) }
+            }
+            _ => Context::None,
+        },
+    }
+}
+
+fn keyword(label: &str) -> Candidate {
+    Candidate { label: label.to_string(), kind: "keyword", detail: None, documentation: None }
+}
+
+fn plain(kind: &'static str, label: &str) -> Candidate {
+    Candidate { label: label.to_string(), kind, detail: None, documentation: None }
+}
+
+/// Functions, aggregators and metrics, what an expression may start with.
+fn expression_start(metadata: &Metadata) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = metadata
+        .metrics
+        .iter()
+        .map(|(name, metric)| Candidate {
+            label: name.clone(),
+            kind: "metric",
+            detail: metric.metric_type.clone(),
+            documentation: metric.help.clone(),
+        })
+        .collect();
+    let entries = aggregators().into_iter().map(|entry| (entry, "aggregator"));
+    candidates.extend(entries.chain(functions().into_iter().map(|entry| (entry, "function"))).map(|(entry, kind)| {
+        Candidate { label: entry.name.to_string(), kind, detail: Some(entry.signature), documentation: entry.note }
+    }));
+    candidates
+}
+
+fn candidates(context: Context, metadata: &Metadata) -> Vec<Candidate> {
+    match context {
+        Context::Expression => expression_start(metadata),
+        Context::Operator { grouping } => {
+            let mut candidates: Vec<Candidate> = BINARY_OPERATORS.iter().map(|op| plain("operator", op)).collect();
+            candidates.extend(["and", "or", "unless", "atan2"].iter().map(|op| plain("operator", op)));
+            if grouping {
+                candidates.extend(["by", "without"].iter().map(|word| keyword(word)));
+            }
+            candidates.extend(["offset", "@"].iter().map(|word| keyword(word)));
+            candidates
+        }
+        Context::Operand { comparison } => {
+            let words: &[&str] = if comparison { &["bool", "on", "ignoring"] } else { &["on", "ignoring"] };
+            let mut candidates: Vec<Candidate> = words.iter().map(|word| keyword(word)).collect();
+            candidates.extend(expression_start(metadata));
+            candidates
+        }
+        Context::GroupModifier => {
+            let mut candidates = vec![keyword("group_left"), keyword("group_right")];
+            candidates.extend(expression_start(metadata));
+            candidates
+        }
+        Context::Aggregator => vec![keyword("by"), keyword("without")],
+        Context::LabelName(metric) => {
+            metadata.label_names(metric.as_deref()).into_iter().map(|name| plain("label", name)).collect()
+        }
+        Context::MatchOperator => MATCH_OPERATORS.iter().map(|op| plain("operator", op)).collect(),
+        Context::LabelValue { label, quoted } => {
+            let values = metadata.labels.get(&label).into_iter().flatten();
+            let quote = |value: &String| if quoted { value.clone() } else { format!("\"{}\"", value) };
+            values.map(|value| plain("label_value", &quote(value))).collect()
+        }
+        Context::Duration => DURATIONS.iter().map(|duration| plain("duration", duration)).collect(),
+        Context::At => vec![keyword("start()"), keyword("end()")],
+        Context::None => vec![],
+    }
+}
+
+/// Completions at byte `offset` of `query`, which may be partial, from
+/// what the tokens before it allow there and the names in `metadata`.
+pub fn complete(query: &str, offset: usize, metadata: &Metadata) -> Completion {
+    let head = &query[..offset];
+    let none = Completion { from: offset, to: offset, candidates: vec![] };
+    let suffix = closing(head);
+    if suffix.starts_with('\n') {
+        return none;
+    }
+    let lexemes: Vec<Lexeme> = match lex(&format!("{}{}", head, suffix)) {
+        Ok(lexemes) => lexemes.into_iter().filter(|l| l.start < head.len()).collect(),
+        // a word being typed may not lex yet, as `5` where a duration goes
+        Err(_) => {
+            let start = head.trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':').len();
+            let rest = &head[..start];
+            match lex(&format!("{}{}", rest, closing(rest))) {
+                Ok(mut lexemes) if start < head.len() => {
+                    lexemes.retain(|l| l.start < rest.len());
+                    let id = if head[start..].starts_with(|c: char| c.is_ascii_digit()) { T_NUMBER } else { T_IDENTIFIER };
+                    lexemes.push(Lexeme { id, start, end: head.len() });
+                    lexemes
+                }
+                _ => return none,
+            }
+        }
+    };
+    let typed = lexemes
+        .last()
+        .filter(|l| l.end >= head.len() && (is_word(l.id) || matches!(l.id, T_STRING | T_NUMBER | T_DURATION)));
+    let before = &lexemes[..lexemes.len() - usize::from(typed.is_some())];
+    let from = match typed {
+        Some(typed) if typed.id == T_STRING => typed.start + 1,
+        Some(typed) => typed.start,
+        None => offset,
+    };
+    let context = context(head, before, typed);
+    if matches!(typed.map(|l| l.id), Some(T_NUMBER | T_DURATION)) && !matches!(context, Context::Duration) {
+        return none;
+    }
+    let prefix = &head[from..];
+    let candidates = candidates(context, metadata)
+        .into_iter()
+        .filter(|candidate| candidate.label.trim_start_matches('"').starts_with(prefix.trim_start_matches('"')))
+        .filter(|candidate| candidate.label != prefix)
+        .collect();
+    Completion { from, to: offset, candidates }
+}
+
+
+#[test]
+fn check_complete() {
+    let metadata: Metadata = serde_json::from_value(json!({
+        "metrics": {
+            "http_requests_total": { "type": "counter", "help": "Requests served.", "labels": ["code", "job"] },
+            "up": { "type": "gauge" },
+        },
+        "labels": { "code": ["200", "500"], "job": ["api"], "instance": [] },
+    }))
+    .unwrap();
+    let labels = |query: &str| -> (usize, Vec<String>) {
+        let completion = complete(query, query.len(), &metadata);
+        (completion.from, completion.candidates.into_iter().map(|c| c.label).collect())
+    };
+    assert_eq!(labels("sum(rate(http_req"), (9, vec!["http_requests_total".to_string()]));
+    assert_eq!(labels("histogram_q").1, ["histogram_quantile"]);
+    assert_eq!(labels("http_requests_total{").1, ["code", "job"]);
+    assert_eq!(labels("up{code=\"200\", i").1, ["instance"]);
+    assert_eq!(labels("up{code").1, Vec::<String>::new());
+    assert_eq!(labels("up{code ").1, ["=", "!=", "=~", "!~"]);
+    assert_eq!(labels("up{code=\"5"), (9, vec!["500".to_string()]));
+    assert_eq!(labels("up{job=").1, ["\"api\""]);
+    assert_eq!(labels("rate(up[1").1, ["1m", "10m", "15m", "1h", "12h", "1d", "1w"]);
+    assert_eq!(labels("rate(up[5m]) o").1, ["or", "offset"]);
+    assert_eq!(labels("sum(up) ").1.last().map(String::as_str), Some("@"));
+    assert!(labels("sum(up) w").1 == ["without"] && labels("sum by (job) (up) w").1.is_empty());
+    assert_eq!(labels("sum ").1, ["by", "without"]);
+    assert_eq!(labels("sum by (j").1, ["job"]);
+    assert_eq!(labels("up > b").1, ["bool", "bottomk"]);
+    assert_eq!(labels("up / on (job) group_").1, ["group_left", "group_right"]);
+    assert_eq!(labels("up @ ").1, ["start()", "end()"]);
+    assert!(labels("up # su").1.is_empty() && labels("rate(up[5").0 == 8);
+    let rate = complete("ra", 2, &metadata).candidates.into_iter().find(|c| c.label == "rate").unwrap();
+    assert_eq!((rate.kind, rate.detail.as_deref()), ("function", Some("rate(v range-vector)")));
+}
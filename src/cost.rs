@@ -0,0 +1,143 @@
+use promql_parser::parser::*;
+use promql_parser::label::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::visit::children;
+use crate::ToSerde;
+
+/// Step of subqueries that have none, Prometheus' default evaluation interval.
+const DEFAULT_STEP_SECONDS: f64 = 60.0;
+
+/// What each feature of a query adds to its cost score.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Weights {
+    /// Per vector or matrix selector.
+    pub selector: f64,
+    /// Per hour of samples a selector reads, its range plus the ranges of the
+    /// subqueries around it.
+    pub range_hour: f64,
+    pub regex_matcher: f64,
+    pub subquery: f64,
+    /// Per evaluation step of a subquery, its range over its step.
+    pub subquery_step: f64,
+    /// Per aggregation inside another aggregation.
+    pub nested_aggregation: f64,
+    /// Per binary operation between two vectors, which matches their series.
+    pub binary_join: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Weights {
+        Weights {
+            selector: 1.0,
+            range_hour: 1.0,
+            regex_matcher: 2.0,
+            subquery: 5.0,
+            subquery_step: 0.01,
+            nested_aggregation: 3.0,
+            binary_join: 2.0,
+        }
+    }
+}
+
+/// A cost score, with the part of it each feature accounts for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cost {
+    pub selectors: f64,
+    pub ranges: f64,
+    pub regex_matchers: f64,
+    pub subqueries: f64,
+    pub nested_aggregations: f64,
+    pub binary_joins: f64,
+}
+
+impl Cost {
+    pub fn score(&self) -> f64 {
+        self.selectors + self.ranges + self.regex_matchers + self.subqueries + self.nested_aggregations + self.binary_joins
+    }
+}
+
+impl ToSerde for Cost {
+    fn to_serde(&self) -> Value {
+        json!({
+            "score": self.score(),
+            "breakdown": {
+                "selectors": self.selectors,
+                "ranges": self.ranges,
+                "regex_matchers": self.regex_matchers,
+                "subqueries": self.subqueries,
+                "nested_aggregations": self.nested_aggregations,
+                "binary_joins": self.binary_joins,
+            },
+        })
+    }
+}
+
+struct Scorer<'a> {
+    weights: &'a Weights,
+    cost: Cost,
+}
+
+impl Scorer<'_> {
+    fn selector(&mut self, vs: &VectorSelector, range_seconds: f64) {
+        let weights = self.weights;
+        let regexes = vs.matchers.matchers.iter().filter(|m| matches!(m.op, MatchOp::Re(_) | MatchOp::NotRe(_))).count();
+        self.cost.selectors += weights.selector;
+        self.cost.regex_matchers += weights.regex_matcher * regexes as f64;
+        self.cost.ranges += weights.range_hour * range_seconds / 3600.0;
+    }
+
+    /// `range_seconds` is the range the subqueries around `expr` add to the
+    /// samples its selectors read.
+    fn add(&mut self, expr: &Expr, range_seconds: f64, in_aggregation: bool) {
+        let weights = self.weights;
+        let mut range_seconds = range_seconds;
+        match expr {
+            Expr::VectorSelector(vs) => self.selector(vs, range_seconds),
+            Expr::MatrixSelector(MatrixSelector { vs, range }) => self.selector(vs, range_seconds + range.as_secs_f64()),
+            Expr::Subquery(SubqueryExpr { range, step, .. }) => {
+                let step = step.map_or(DEFAULT_STEP_SECONDS, |step| step.as_secs_f64());
+                self.cost.subqueries += weights.subquery + weights.subquery_step * (range.as_secs_f64() / step).ceil();
+                range_seconds += range.as_secs_f64();
+            }
+            Expr::Aggregate(_) if in_aggregation => self.cost.nested_aggregations += weights.nested_aggregation,
+            Expr::Binary(BinaryExpr { lhs, rhs, .. })
+                if lhs.value_type() == ValueType::Vector && rhs.value_type() == ValueType::Vector =>
+                self.cost.binary_joins += weights.binary_join,
+            _ => {}
+        }
+        let in_aggregation = in_aggregation || matches!(expr, Expr::Aggregate(_));
+        for child in children(expr) {
+            self.add(child, range_seconds, in_aggregation);
+        }
+    }
+}
+
+/// Scores how expensive `expr` is likely to be to evaluate, from its
+/// structure alone, to gate queries before running them.
+pub fn cost(expr: &Expr, weights: &Weights) -> Cost {
+    let mut scorer = Scorer { weights, cost: Cost::default() };
+    scorer.add(expr, 0.0, false);
+    scorer.cost
+}
+
+
+#[test]
+fn check_cost() {
+    let weights = Weights::default();
+    let cheap = cost(&parse("up").unwrap(), &weights);
+    assert_eq!(cheap.score(), 1.0);
+    let query = "sum(max_over_time(rate(x{a=~\"b.*\"}[1h])[1d:1h])) / sum(avg(y))";
+    let costly = cost(&parse(query).unwrap(), &weights);
+    assert_eq!(costly, Cost {
+        selectors: 2.0,
+        ranges: 25.0,
+        regex_matchers: 2.0,
+        subqueries: 5.24,
+        nested_aggregations: 3.0,
+        binary_joins: 2.0,
+    });
+    let only_ranges = Weights { selector: 0.0, range_hour: 2.0, ..weights };
+    assert_eq!(cost(&parse("x[30m] offset 1h").unwrap(), &only_ranges).score(), 1.0);
+}
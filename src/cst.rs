@@ -0,0 +1,106 @@
+use serde_json::{json, Value};
+use crate::lexemes::{kind, lex, Lexeme};
+use crate::ToSerde;
+
+/// Text between tokens: a run of whitespace or a `#` comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub kind: &'static str,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstToken {
+    pub lexeme: Lexeme,
+    /// Trivia between the previous token and this one.
+    pub leading: Vec<Trivia>,
+}
+
+/// Every byte of a query, as tokens and the trivia around them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cst<'a> {
+    pub query: &'a str,
+    pub tokens: Vec<CstToken>,
+    pub trailing: Vec<Trivia>,
+}
+
+impl Trivia {
+    fn to_serde(&self, query: &str) -> Value {
+        json!({
+            "kind": self.kind,
+            "text": &query[self.start..self.end],
+            "start": self.start,
+            "end": self.end,
+        })
+    }
+}
+
+impl ToSerde for Cst<'_> {
+    fn to_serde(&self) -> Value {
+        let trivia = |trivia: &[Trivia]| trivia.iter().map(|t| t.to_serde(self.query)).collect::<Vec<Value>>();
+        json!({
+            "tokens": self.tokens
+                .iter()
+                .map(|token| json!({
+                    "kind": kind(token.lexeme.id),
+                    "text": token.lexeme.text(self.query),
+                    "start": token.lexeme.start,
+                    "end": token.lexeme.end,
+                    "leading_trivia": trivia(&token.leading),
+                }))
+                .collect::<Vec<Value>>(),
+            "trailing_trivia": trivia(&self.trailing),
+        })
+    }
+}
+
+/// Splits the text in `start..end` into whitespace runs and comments.
+fn trivia(query: &str, start: usize, end: usize) -> Vec<Trivia> {
+    let mut trivia: Vec<Trivia> = vec![];
+    let mut pos = start;
+    while pos < end {
+        let rest = &query[pos..end];
+        let (kind, len) = if rest.starts_with('#') {
+            ("comment", rest.find('\n').unwrap_or(rest.len()))
+        } else {
+            ("whitespace", rest.find('#').unwrap_or(rest.len()))
+        };
+        trivia.push(Trivia { kind, start: pos, end: pos + len });
+        pos += len;
+    }
+    trivia
+}
+
+pub fn cst(query: &str) -> Result<Cst<'_>, String> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    for lexeme in lex(query)? {
+        tokens.push(CstToken { lexeme, leading: trivia(query, pos, lexeme.start) });
+        pos = lexeme.end;
+    }
+    Ok(Cst { query, tokens, trailing: trivia(query, pos, query.len()) })
+}
+
+
+#[test]
+fn check_cst() {
+    let query = "  sum by (job) ( # requests\n\trate(x{a='b'}[5m])\n) # done\n";
+    let cst = cst(query).unwrap();
+    let mut text = String::new();
+    for token in &cst.tokens {
+        text.extend(token.leading.iter().map(|t| &query[t.start..t.end]));
+        text.push_str(token.lexeme.text(query));
+    }
+    text.extend(cst.trailing.iter().map(|t| &query[t.start..t.end]));
+    assert_eq!(text, query);
+    let comments: Vec<&str> = cst
+        .tokens
+        .iter()
+        .flat_map(|t| &t.leading)
+        .chain(&cst.trailing)
+        .filter(|t| t.kind == "comment")
+        .map(|t| &query[t.start..t.end])
+        .collect();
+    assert_eq!(comments, vec!["# requests", "# done"]);
+}
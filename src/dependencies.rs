@@ -0,0 +1,236 @@
+use std::collections::{BTreeMap, BTreeSet};
+use promql_parser::label::*;
+use serde_json::{json, Value};
+use crate::matchers::{metric_name, selectors};
+use crate::rules::{errors, ParsedRule, RuleError, RuleFile};
+use crate::ToSerde;
+
+/// Series Prometheus writes for every alerting rule.
+const ALERTS_METRICS: [&str; 2] = ["ALERTS", "ALERTS_FOR_STATE"];
+const ALERT_NAME: &str = "alertname";
+
+/// `from` reads `metric`, which `to` produces.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub metric: String,
+}
+
+impl ToSerde for Edge {
+    fn to_serde(&self) -> Value {
+        json!({
+            "from": self.from,
+            "to": self.to,
+            "metric": self.metric,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DependencyGraph {
+    pub rules: Vec<Value>,
+    pub edges: Vec<Edge>,
+    /// Recorded-looking metrics (`level:metric:operations`) that no rule records.
+    pub missing: Vec<(usize, String)>,
+    pub cycles: Vec<Vec<usize>>,
+    /// Rules with their dependencies first; rules on or behind a cycle are left out.
+    pub order: Vec<usize>,
+    pub errors: Vec<RuleError>,
+}
+
+impl ToSerde for DependencyGraph {
+    fn to_serde(&self) -> Value {
+        json!({
+            "rules": self.rules,
+            "edges": self.edges.to_serde(),
+            "missing": self.missing
+                .iter()
+                .map(|(rule, metric)| json!({ "rule": rule, "metric": metric }))
+                .collect::<Vec<Value>>(),
+            "cycles": self.cycles,
+            "order": self.order,
+            "errors": self.errors.to_serde(),
+        })
+    }
+}
+
+/// Rule ids by the name they produce series under.
+type Producers<'a> = BTreeMap<&'a str, Vec<usize>>;
+
+/// Rules producing each metric: recorded names, and the alerts series keyed
+/// by alert name.
+fn producers<'a>(rules: &[ParsedRule<'a>]) -> (Producers<'a>, Producers<'a>) {
+    let mut recorded = Producers::new();
+    let mut alerts = Producers::new();
+    for r in rules {
+        match (&r.rule.record, &r.rule.alert) {
+            (Some(record), _) => recorded.entry(record).or_default().push(r.id),
+            (None, Some(alert)) => alerts.entry(alert).or_default().push(r.id),
+            _ => {}
+        }
+    }
+    (recorded, alerts)
+}
+
+/// Strongly connected components that contain a cycle, by Tarjan's algorithm.
+fn cycles(nodes: usize, adjacent: &BTreeMap<usize, BTreeSet<usize>>) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        adjacent: &'a BTreeMap<usize, BTreeSet<usize>>,
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        cycles: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, v: usize) {
+            self.index[v] = Some(self.next);
+            self.low[v] = self.next;
+            self.next += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+            for &w in self.adjacent.get(&v).into_iter().flatten() {
+                match self.index[w] {
+                    None => {
+                        self.visit(w);
+                        self.low[v] = self.low[v].min(self.low[w]);
+                    }
+                    Some(index) if self.on_stack[w] => self.low[v] = self.low[v].min(index),
+                    _ => {}
+                }
+            }
+            if Some(self.low[v]) == self.index[v] {
+                let mut component = vec![];
+                while let Some(w) = self.stack.pop() {
+                    self.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                let self_loop = self.adjacent.get(&v).is_some_and(|a| a.contains(&v));
+                if component.len() > 1 || self_loop {
+                    component.sort();
+                    self.cycles.push(component);
+                }
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        adjacent,
+        index: vec![None; nodes],
+        low: vec![0; nodes],
+        stack: vec![],
+        on_stack: vec![false; nodes],
+        next: 0,
+        cycles: vec![],
+    };
+    for v in 0..nodes {
+        if tarjan.index[v].is_none() {
+            tarjan.visit(v);
+        }
+    }
+    tarjan.cycles.sort();
+    tarjan.cycles
+}
+
+/// Kahn's algorithm over "depends on" edges, lowest rule id first.
+fn topological_order(nodes: usize, adjacent: &BTreeMap<usize, BTreeSet<usize>>) -> Vec<usize> {
+    let mut pending: Vec<usize> = (0..nodes).map(|v| adjacent.get(&v).map_or(0, |deps| deps.len())).collect();
+    let mut dependents: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (from, deps) in adjacent {
+        for to in deps {
+            dependents.entry(*to).or_default().push(*from);
+        }
+    }
+    let mut ready: BTreeSet<usize> = (0..nodes).filter(|v| pending[*v] == 0).collect();
+    let mut order = vec![];
+    while let Some(v) = ready.pop_first() {
+        order.push(v);
+        for &dependent in dependents.get(&v).into_iter().flatten() {
+            pending[dependent] -= 1;
+            if pending[dependent] == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+    order
+}
+
+/// Builds the graph of which rules read series recorded (or alerts raised)
+/// by which other rules.
+pub fn dependency_graph(file: &RuleFile) -> DependencyGraph {
+    let rules = file.parsed();
+    let (recorded, alerts) = producers(&rules);
+    let mut edges = BTreeSet::new();
+    let mut missing = BTreeSet::new();
+    for r in &rules {
+        let expr = match &r.expr {
+            Ok(expr) => expr,
+            Err(_) => continue,
+        };
+        for vs in selectors(expr) {
+            let metric = match metric_name(&vs) {
+                Some(metric) => metric,
+                None => continue,
+            };
+            let targets: Vec<usize> = if ALERTS_METRICS.contains(&metric.as_str()) {
+                let alert_name = vs.matchers.find_matchers(ALERT_NAME).into_iter().find(|m| m.op == MatchOp::Equal);
+                match alert_name {
+                    Some(m) => alerts.get(m.value.as_str()).cloned().unwrap_or_default(),
+                    None => alerts.values().flatten().copied().collect(),
+                }
+            } else {
+                recorded.get(metric.as_str()).cloned().unwrap_or_default()
+            };
+            if targets.is_empty() && metric.contains(':') {
+                missing.insert((r.id, metric.clone()));
+            }
+            for to in targets {
+                edges.insert(Edge { from: r.id, to, metric: metric.clone() });
+            }
+        }
+    }
+    let mut adjacent: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    for edge in &edges {
+        adjacent.entry(edge.from).or_default().insert(edge.to);
+    }
+    DependencyGraph {
+        rules: rules.iter().map(|r| r.summary()).collect(),
+        edges: edges.into_iter().collect(),
+        missing: missing.into_iter().collect(),
+        cycles: cycles(rules.len(), &adjacent),
+        order: topological_order(rules.len(), &adjacent),
+        errors: errors(&rules),
+    }
+}
+
+
+#[test]
+fn check_dependency_graph() {
+    let file: RuleFile = serde_json::from_value(json!({
+        "groups": [{
+            "name": "a",
+            "rules": [
+                { "record": "job:errors:rate5m", "expr": "sum by (job) (rate(errors_total[5m]))" },
+                { "record": "job:ratio:rate5m", "expr": "job:errors:rate5m / job:requests:rate5m" },
+                { "alert": "HighErrors", "expr": "job:ratio:rate5m > 0.1" },
+                { "record": "x:loop", "expr": "y:loop" },
+                { "record": "y:loop", "expr": "x:loop + 1" },
+                { "record": "alerting:count", "expr": "count(ALERTS{alertname=\"HighErrors\"})" },
+                { "record": "broken", "expr": "sum(" },
+            ],
+        }],
+    }))
+    .unwrap();
+    let graph = dependency_graph(&file);
+    assert_eq!(graph.missing, vec![(1, "job:requests:rate5m".to_string())]);
+    assert_eq!(graph.cycles, vec![vec![3, 4]]);
+    assert_eq!(graph.order, vec![0, 1, 2, 5, 6]);
+    assert_eq!(graph.edges.len(), 5);
+    assert_eq!(graph.errors.len(), 1);
+}
@@ -0,0 +1,140 @@
+use promql_parser::parser::*;
+use crate::printer::{bin_modifier, grouping, number, quote, subquery_suffix, to_promql};
+use crate::visit::children;
+
+/// A node of a query diagram, numbered in preorder from the root, 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagramNode {
+    pub label: String,
+    /// Selectors and literals, which have no children.
+    pub leaf: bool,
+    pub children: Vec<usize>,
+}
+
+/// A summary of `expr` without its sub-expressions: the operator with its
+/// modifiers, the function, or the whole selector or literal.
+fn label(expr: &Expr) -> String {
+    match expr {
+        Expr::Aggregate(AggregateExpr { op, modifier, .. }) => format!("{}{}", op, grouping(modifier)).trim_end().to_string(),
+        Expr::Unary(_) => "-".to_string(),
+        Expr::Binary(BinaryExpr { op, modifier, .. }) => format!("{}{}", op, modifier.as_ref().map(bin_modifier).unwrap_or_default()),
+        Expr::Paren(_) => "( )".to_string(),
+        Expr::Subquery(sq) => format!("subquery {}", subquery_suffix(sq)),
+        Expr::NumberLiteral(NumberLiteral { val }) => number(*val),
+        Expr::StringLiteral(StringLiteral { val }) => quote(val),
+        Expr::Call(Call { func, .. }) => format!("{}()", func.name),
+        Expr::VectorSelector(_) | Expr::MatrixSelector(_) | Expr::Extension(_) => to_promql(expr),
+    }
+}
+
+/// The nodes of the diagram of `expr`, in preorder.
+pub fn nodes(expr: &Expr) -> Vec<DiagramNode> {
+    fn add(expr: &Expr, nodes: &mut Vec<DiagramNode>) -> usize {
+        let id = nodes.len();
+        let leaf = matches!(
+            expr,
+            Expr::VectorSelector(_) | Expr::MatrixSelector(_) | Expr::NumberLiteral(_) | Expr::StringLiteral(_)
+        );
+        nodes.push(DiagramNode { label: label(expr), leaf, children: vec![] });
+        let children: Vec<usize> = children(expr).into_iter().map(|child| add(child, nodes)).collect();
+        nodes[id].children = children;
+        id
+    }
+    let mut nodes = vec![];
+    add(expr, &mut nodes);
+    nodes
+}
+
+/// `text` as the inside of a double-quoted DOT string.
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A Graphviz DOT digraph of the expression tree of `expr`, parents above
+/// their operands, which keep their order left to right.
+pub fn to_dot(expr: &Expr) -> String {
+    let nodes = nodes(expr);
+    let mut dot = String::from("digraph promql {\n  ordering=out;\n  node [shape=box, fontname=\"monospace\"];\n");
+    for (id, node) in nodes.iter().enumerate() {
+        let shape = if node.leaf { ", shape=ellipse" } else { "" };
+        dot.push_str(&format!("  n{} [label=\"{}\"{}];\n", id, dot_escape(&node.label), shape));
+    }
+    for (id, node) in nodes.iter().enumerate() {
+        for child in &node.children {
+            dot.push_str(&format!("  n{} -> n{};\n", id, child));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// `text` as the inside of a double-quoted Mermaid label, with entity codes
+/// for quotes and what could be read as HTML.
+fn mermaid_escape(text: &str) -> String {
+    text.replace('#', "#35;").replace('"', "#quot;").replace('<', "#lt;").replace('>', "#gt;")
+}
+
+/// A Mermaid flowchart of the expression tree of `expr`, as GitHub and GitLab
+/// render in Markdown, leaves drawn rounded.
+pub fn to_mermaid(expr: &Expr) -> String {
+    let nodes = nodes(expr);
+    let mut chart = String::from("flowchart TD\n");
+    for (id, node) in nodes.iter().enumerate() {
+        let (open, close) = if node.leaf { ("([", "])") } else { ("[", "]") };
+        chart.push_str(&format!("  n{}{}\"{}\"{}\n", id, open, mermaid_escape(&node.label), close));
+    }
+    for (id, node) in nodes.iter().enumerate() {
+        for child in &node.children {
+            chart.push_str(&format!("  n{} --> n{}\n", id, child));
+        }
+    }
+    chart
+}
+
+
+#[test]
+fn check_to_dot() {
+    let expr = parse("sum by (job) (rate(x{code=\"200\"}[5m])) / on (job) group_left count(y) > 0.95").unwrap();
+    let expected = "digraph promql {
+  ordering=out;
+  node [shape=box, fontname=\"monospace\"];
+  n0 [label=\">\"];
+  n1 [label=\"/ on (job) group_left ()\"];
+  n2 [label=\"sum by (job)\"];
+  n3 [label=\"rate()\"];
+  n4 [label=\"x{code=\\\"200\\\"}[5m]\", shape=ellipse];
+  n5 [label=\"count\"];
+  n6 [label=\"y\", shape=ellipse];
+  n7 [label=\"0.95\", shape=ellipse];
+  n0 -> n1;
+  n0 -> n7;
+  n1 -> n2;
+  n1 -> n5;
+  n2 -> n3;
+  n3 -> n4;
+  n5 -> n6;
+}
+";
+    assert_eq!(to_dot(&expr), expected);
+}
+
+#[test]
+fn check_to_mermaid() {
+    let expr = parse("histogram_quantile(0.9, sum by (le) (rate(x{a=\"<b>\"}[5m]))) > 1").unwrap();
+    let expected = "flowchart TD
+  n0[\"#gt;\"]
+  n1[\"histogram_quantile()\"]
+  n2([\"0.9\"])
+  n3[\"sum by (le)\"]
+  n4[\"rate()\"]
+  n5([\"x{a=#quot;#lt;b#gt;#quot;}[5m]\"])
+  n6([\"1\"])
+  n0 --> n1
+  n0 --> n6
+  n1 --> n2
+  n1 --> n3
+  n3 --> n4
+  n4 --> n5
+";
+    assert_eq!(to_mermaid(&expr), expected);
+}
@@ -0,0 +1,65 @@
+use serde_json::{json, Value};
+use crate::errors::{located, ParseError};
+use crate::options::SerializeOptions;
+use crate::ToSerde;
+
+/// The dialect queries are parsed in when none is asked for.
+pub const DEFAULT_DIALECT: &str = "promql";
+
+/// A query language `promql_parse` reads, by its `dialect` option.
+pub struct Dialect {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Parses a query into a JSON AST serialized as the options say, before
+    /// they reshape it.
+    pub parse: fn(&str, &SerializeOptions) -> Result<Value, ParseError>,
+}
+
+pub const DIALECTS: &[Dialect] = &[
+    Dialect {
+        name: "promql",
+        description: "Prometheus PromQL.",
+        parse: crate::parse_promql,
+    },
+    Dialect {
+        name: "metricsql",
+        description: "VictoriaMetrics MetricsQL: WITH templates, rollup functions and the default, if and ifnot operators.",
+        parse: crate::metricsql::parse,
+    },
+    Dialect {
+        name: "logql",
+        description: "Grafana Loki LogQL: stream selectors, pipeline stages and range aggregations of log lines.",
+        parse: crate::logql::parse,
+    },
+];
+
+impl ToSerde for Dialect {
+    fn to_serde(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+        })
+    }
+}
+
+/// The dialect called `name`, the default one if `None`.
+pub fn dialect(query: &str, name: Option<&str>) -> Result<&'static Dialect, ParseError> {
+    let name = name.unwrap_or(DEFAULT_DIALECT);
+    DIALECTS.iter().find(|dialect| dialect.name == name).ok_or_else(|| {
+        let names: Vec<&str> = DIALECTS.iter().map(|dialect| dialect.name).collect();
+        let message = format!("unknown dialect `{}`, expected one of {}", name, names.join(", "));
+        located(query, message, "unknown-dialect", 0, 0)
+    })
+}
+
+
+#[test]
+fn check_dialects() {
+    assert_eq!(dialect("x", None).unwrap().name, DEFAULT_DIALECT);
+    let options = SerializeOptions { dialect: Some("promql".to_string()), ..SerializeOptions::default() };
+    let ast = crate::parse_serialized("sum(x)", &options).unwrap();
+    assert_eq!(ast["@type"], json!("aggregate"));
+    let options = SerializeOptions { dialect: Some("sql".to_string()), ..SerializeOptions::default() };
+    let error = crate::parse_serialized("sum(x)", &options).unwrap_err();
+    assert_eq!((error.code, error.message.as_str()), ("unknown-dialect", "unknown dialect `sql`, expected one of promql, metricsql, logql"));
+}
@@ -0,0 +1,198 @@
+use promql_parser::parser::*;
+use serde_json::{json, Value};
+use crate::canonical::canonical;
+use crate::spans::{spans, Span, SpanTree};
+use crate::visit::{children, children_mut, node_type};
+use crate::ToSerde;
+
+/// One side of a change: where the node is in its query and its AST.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Side {
+    /// Path of the node in the serialized AST, as in `$.lhs.args[0]`.
+    pub path: String,
+    pub node: &'static str,
+    pub text: String,
+    pub span: Option<Span>,
+}
+
+impl ToSerde for Side {
+    fn to_serde(&self) -> Value {
+        json!({
+            "path": self.path,
+            "type": self.node,
+            "text": self.text,
+            "start": self.span.map(|span| span.start),
+            "end": self.span.map(|span| span.end),
+        })
+    }
+}
+
+/// A node only in the new query, only in the old one, or in both but with
+/// different attributes or replaced by another.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(Side),
+    Removed(Side),
+    Changed(Side, Side),
+}
+
+impl ToSerde for Change {
+    fn to_serde(&self) -> Value {
+        let (kind, old, new) = match self {
+            Change::Added(new) => ("added", None, Some(new)),
+            Change::Removed(old) => ("removed", Some(old), None),
+            Change::Changed(old, new) => ("changed", Some(old), Some(new)),
+        };
+        json!({
+            "kind": kind,
+            "old": old.map(Side::to_serde),
+            "new": new.map(Side::to_serde),
+        })
+    }
+}
+
+/// A node of one of the queries being compared, with its span tree and path.
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    expr: &'a Expr,
+    tree: Option<&'a SpanTree>,
+    path: &'a str,
+}
+
+/// Keys of the children of `expr` in its serialized form, in the order of
+/// `visit::children`.
+fn child_keys(expr: &Expr) -> Vec<String> {
+    match expr {
+        Expr::Aggregate(AggregateExpr { param: Some(_), .. }) => vec!["param".to_string(), "expr".to_string()],
+        Expr::Binary(_) => vec!["lhs".to_string(), "rhs".to_string()],
+        Expr::Call(Call { args, .. }) => (0..args.args.len()).map(|i| format!("args[{}]", i)).collect(),
+        _ => children(expr).iter().map(|_| "expr".to_string()).collect(),
+    }
+}
+
+/// What `expr` is apart from its children: a function name, or its canonical
+/// text with every child replaced by a placeholder.
+fn head(expr: &Expr) -> String {
+    match expr {
+        Expr::Call(Call { func, .. }) => func.name.to_string(),
+        _ => {
+            let mut expr = expr.clone();
+            for child in children_mut(&mut expr) {
+                *child = Expr::NumberLiteral(NumberLiteral { val: 0.0 });
+            }
+            canonical(&expr)
+        }
+    }
+}
+
+struct Differ {
+    changes: Vec<Change>,
+}
+
+impl Differ {
+    fn side(node: Node) -> Side {
+        Side {
+            path: node.path.to_string(),
+            node: node_type(node.expr),
+            text: canonical(node.expr),
+            span: node.tree.map(|tree| tree.span),
+        }
+    }
+
+    /// The children of `node`, with their span trees and paths.
+    fn children<'a>(node: Node<'a>, paths: &'a [String]) -> Vec<Node<'a>> {
+        let children = children(node.expr);
+        let trees: Vec<Option<&SpanTree>> = match node.tree {
+            Some(tree) if tree.children.len() == children.len() => tree.children.iter().map(Some).collect(),
+            _ => vec![None; children.len()],
+        };
+        children
+            .into_iter()
+            .zip(trees)
+            .zip(paths)
+            .map(|((expr, tree), path)| Node { expr, tree, path })
+            .collect()
+    }
+
+    fn paths(node: Node) -> Vec<String> {
+        child_keys(node.expr).iter().map(|key| format!("{}.{}", node.path, key)).collect()
+    }
+
+    fn diff(&mut self, old: Node, new: Node) {
+        // parentheses change nothing, look through them
+        let (old_paths, new_paths) = (Self::paths(old), Self::paths(new));
+        if let Expr::Paren(_) = old.expr {
+            return self.diff(Self::children(old, &old_paths)[0], new);
+        }
+        if let Expr::Paren(_) = new.expr {
+            return self.diff(old, Self::children(new, &new_paths)[0]);
+        }
+        if canonical(old.expr) == canonical(new.expr) {
+            return;
+        }
+        let (old_children, new_children) = (Self::children(old, &old_paths), Self::children(new, &new_paths));
+        let same_type = node_type(old.expr) == node_type(new.expr);
+        let same_head = head(old.expr) == head(new.expr);
+        if !same_type || (!same_head && old_children.len() != new_children.len()) {
+            self.changes.push(Change::Changed(Self::side(old), Self::side(new)));
+            return;
+        }
+        if !same_head {
+            self.changes.push(Change::Changed(Self::side(old), Self::side(new)));
+        }
+        // calls of the same function with more or fewer optional arguments
+        for (old, new) in old_children.iter().zip(&new_children) {
+            self.diff(*old, *new);
+        }
+        for old in old_children.iter().skip(new_children.len()) {
+            self.changes.push(Change::Removed(Self::side(*old)));
+        }
+        for new in new_children.iter().skip(old_children.len()) {
+            self.changes.push(Change::Added(Self::side(*new)));
+        }
+    }
+}
+
+/// The node by node changes from `old` to `new`, parsed from `old_query` and
+/// `new_query`, outer nodes first. Nodes that only differ in spacing, matcher
+/// or label order or parentheses are the same.
+pub fn diff(old_query: &str, old: &Expr, new_query: &str, new: &Expr) -> Vec<Change> {
+    let (old_tree, new_tree) = (spans(old_query, old), spans(new_query, new));
+    let mut differ = Differ { changes: vec![] };
+    differ.diff(
+        Node { expr: old, tree: old_tree.as_ref(), path: "$" },
+        Node { expr: new, tree: new_tree.as_ref(), path: "$" },
+    );
+    differ.changes
+}
+
+
+#[test]
+fn check_diff() {
+    let summary = |old: &str, new: &str| -> Vec<String> {
+        diff(old, &parse(old).unwrap(), new, &parse(new).unwrap())
+            .iter()
+            .map(|change| match change {
+                Change::Added(new) => format!("+ {} {}", new.path, new.text),
+                Change::Removed(old) => format!("- {} {}", old.path, old.text),
+                Change::Changed(old, new) => format!("~ {} {} -> {} {}", old.path, old.text, new.path, new.text),
+            })
+            .collect()
+    };
+    assert!(summary("sum by (a, b) (x{c=\"d\", e=\"f\"})", "sum by(b,a)((x{e='f',c='d'}))").is_empty());
+    assert_eq!(summary("sum(rate(x[5m]))", "avg(irate(x[1m]))"), vec![
+        "~ $ sum(rate(x[5m])) -> $ avg(irate(x[1m]))",
+        "~ $.expr rate(x[5m]) -> $.expr irate(x[1m])",
+        "~ $.expr.args[0] x[5m] -> $.expr.args[0] x[1m]",
+    ]);
+    assert_eq!(summary("a + b", "(a) + c * 2"), vec!["~ $.rhs b -> $.rhs c * 2"]);
+    assert_eq!(summary("round(x)", "round(x, 5)"), vec!["+ $.args[1] 5"]);
+    let changes = diff("a / b", &parse("a / b").unwrap(), "a / (c)", &parse("a / (c)").unwrap());
+    match &changes[..] {
+        [Change::Changed(old, new)] => {
+            assert_eq!((old.path.as_str(), new.path.as_str()), ("$.rhs", "$.rhs.expr"));
+            assert_eq!((old.span, new.span), (Some(Span { start: 4, end: 5 }), Some(Span { start: 5, end: 6 })));
+        }
+        _ => panic!("{:?}", changes),
+    }
+}
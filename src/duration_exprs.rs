@@ -0,0 +1,348 @@
+use std::time::Duration;
+use promql_parser::parser::*;
+use promql_parser::util::parse_duration;
+use serde_json::{json, Value};
+use crate::edits::{apply, original_error, original_spans, Edit};
+use crate::errors::{located, ParseError};
+use crate::spans::{annotate, annotate_text, spans};
+use crate::{options, printer, ToSerde};
+
+/// A duration computed by the experimental duration arithmetic of newer
+/// PromQL, e.g. the `5m + 30s` of `rate(x[5m + 30s])`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DurationExpr {
+    Duration(Duration),
+    /// A plain number of seconds.
+    Number(f64),
+    Negation(Box<DurationExpr>),
+    Binary { op: char, lhs: Box<DurationExpr>, rhs: Box<DurationExpr> },
+}
+
+impl DurationExpr {
+    pub fn seconds(&self) -> f64 {
+        match self {
+            DurationExpr::Duration(dur) => dur.as_secs_f64(),
+            DurationExpr::Number(val) => *val,
+            DurationExpr::Negation(expr) => -expr.seconds(),
+            DurationExpr::Binary { op, lhs, rhs } => {
+                let (lhs, rhs) = (lhs.seconds(), rhs.seconds());
+                match op {
+                    '+' => lhs + rhs,
+                    '-' => lhs - rhs,
+                    '*' => lhs * rhs,
+                    '/' => lhs / rhs,
+                    '%' => lhs % rhs,
+                    _ => lhs.powf(rhs),
+                }
+            }
+        }
+    }
+}
+
+impl ToSerde for DurationExpr {
+    fn to_serde(&self) -> Value {
+        match self {
+            DurationExpr::Duration(dur) => json!({
+                "@type": "duration_literal",
+                "value": options::current().durations.serialize(dur, false),
+            }),
+            DurationExpr::Number(val) => json!({ "@type": "duration_number", "value": val }),
+            DurationExpr::Negation(expr) => json!({ "@type": "duration_negation", "expr": expr.to_serde() }),
+            DurationExpr::Binary { op, lhs, rhs } => json!({
+                "@type": "duration_binary",
+                "op": op.to_string(),
+                "lhs": lhs.to_serde(),
+                "rhs": rhs.to_serde(),
+            }),
+        }
+    }
+}
+
+/// Binding strength and right associativity of an operator, as in PromQL.
+fn operator(op: char) -> Option<(u8, bool)> {
+    match op {
+        '+' | '-' => Some((1, false)),
+        '*' | '/' | '%' => Some((2, false)),
+        '^' => Some((3, true)),
+        _ => None,
+    }
+}
+
+/// A recursive descent parser of a duration expression in `text`.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.pos += self.text[self.pos..].len() - self.text[self.pos..].trim_start().len();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn expr(&mut self, min: u8) -> Result<DurationExpr, String> {
+        let mut lhs = self.operand()?;
+        while let Some((op, (prec, right))) = self.peek().and_then(|op| operator(op).map(|o| (op, o))) {
+            if prec < min {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(if right { prec } else { prec + 1 })?;
+            lhs = DurationExpr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) };
+        }
+        Ok(lhs)
+    }
+
+    fn operand(&mut self) -> Result<DurationExpr, String> {
+        match self.peek() {
+            // unary minus binds looser than `^`
+            Some('-') => {
+                self.pos += 1;
+                Ok(DurationExpr::Negation(Box::new(self.expr(3)?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.expr(1)?;
+                match self.peek() {
+                    Some(')') => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err("unclosed left parenthesis in duration expression".to_string()),
+                }
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '.' => {
+                let rest = &self.text[self.pos..];
+                let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '.').unwrap_or(rest.len());
+                self.pos += len;
+                let word = &rest[..len];
+                match word.parse::<f64>() {
+                    Ok(val) if word.chars().all(|c| c.is_ascii_digit() || c == '.') => Ok(DurationExpr::Number(val)),
+                    _ => parse_duration(word)
+                        .map(DurationExpr::Duration)
+                        .map_err(|_| format!("bad duration syntax: {}", word)),
+                }
+            }
+            Some(c) => Err(format!("unexpected character in duration expression: '{}'", c)),
+            None => Err("unexpected end of duration expression".to_string()),
+        }
+    }
+}
+
+/// Parses a whole duration expression.
+pub fn parse_duration_expr(text: &str) -> Result<DurationExpr, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let expr = parser.expr(1)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(c) => Err(format!("unexpected character in duration expression: '{}'", c)),
+    }
+}
+
+/// A query with its duration expressions replaced by the durations they
+/// compute, and every range, step and offset of it, in query order, with
+/// the expression it was computed from.
+struct Rewrite {
+    text: String,
+    edits: Vec<Edit>,
+    sites: Vec<Option<DurationExpr>>,
+}
+
+/// Byte offset of the first character from `from` on that closes the bracket
+/// opened just before `from`, skipping nested ones.
+fn matching(query: &str, from: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in query[from..].char_indices() {
+        match c {
+            _ if c == close && depth == 0 => return Some(from + i),
+            _ if c == close => depth -= 1,
+            _ if c == open => depth += 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+impl Rewrite {
+    fn new(query: &str) -> Result<Rewrite, ParseError> {
+        let mut rewrite = Rewrite { text: String::new(), edits: vec![], sites: vec![] };
+        let mut chars = query.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' | '\'' | '`' => {
+                    let mut escaped = false;
+                    for (_, next) in chars.by_ref() {
+                        match next {
+                            '\\' if c != '`' && !escaped => escaped = true,
+                            _ if next == c && !escaped => break,
+                            _ => escaped = false,
+                        }
+                    }
+                }
+                '#' => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+                '[' => {
+                    let end = matching(query, i + 1, '[', ']').unwrap_or(query.len());
+                    let content = &query[i + 1..end];
+                    match content.find(':') {
+                        Some(colon) => {
+                            rewrite.site(query, i + 1, i + 1 + colon, false)?;
+                            rewrite.site(query, i + 2 + colon, end, false)?;
+                        }
+                        None => rewrite.site(query, i + 1, end, false)?,
+                    }
+                    while chars.next_if(|(j, _)| *j < end).is_some() {}
+                }
+                _ if c.is_ascii_alphanumeric() || c == '_' => {
+                    let mut end = i + c.len_utf8();
+                    while let Some((j, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_' || *c == ':') {
+                        end = j + c.len_utf8();
+                    }
+                    if &query[i..end] == "offset" {
+                        let rest = query[end..].trim_start();
+                        let start = query.len() - rest.len();
+                        let value = rest.strip_prefix('-').unwrap_or(rest).trim_start();
+                        match value.chars().next() {
+                            Some('(') => {
+                                let open = query.len() - value.len();
+                                let close = matching(query, open + 1, '(', ')').map_or(query.len(), |close| close + 1);
+                                rewrite.site(query, start, close, true)?;
+                                while chars.next_if(|(j, _)| *j < close).is_some() {}
+                            }
+                            Some(c) if c.is_ascii_digit() || c == '.' => rewrite.sites.push(None),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        rewrite.text = apply(query, &rewrite.edits);
+        Ok(rewrite)
+    }
+
+    /// Records the range, step or offset at `start..end` of `query`, replacing
+    /// it by the duration it computes if it is a duration expression.
+    fn site(&mut self, query: &str, start: usize, end: usize, offset: bool) -> Result<(), ParseError> {
+        let text = query[start..end].trim();
+        if text.is_empty() || parse_duration(text).is_ok() {
+            self.sites.push(None);
+            return Ok(());
+        }
+        let invalid = |message: String| located(query, message, "invalid-duration", start, end);
+        let expr = parse_duration_expr(text).map_err(invalid)?;
+        let seconds = expr.seconds();
+        if !seconds.is_finite() || (seconds < 0.0 && !offset) {
+            return Err(invalid(format!("duration expression {} is not a positive duration", text)));
+        }
+        let dur = Duration::from_millis((seconds.abs() * 1000.0).round() as u64);
+        let sign = if seconds < 0.0 { "-" } else { "" };
+        self.edits.push(Edit { start, end, text: format!("{}{}", sign, printer::duration(&dur)) });
+        self.sites.push(Some(expr));
+        Ok(())
+    }
+
+    /// Adds the expression of the next site to `node` as `key`, if it had one.
+    fn attach(&self, node: &mut Value, key: &str, sites: &mut std::slice::Iter<Option<DurationExpr>>) {
+        if let Some(Some(expr)) = sites.next() {
+            node[key] = expr.to_serde();
+        }
+    }
+
+    /// Walks a serialized tree in query order, adding the expressions the
+    /// ranges, steps and offsets were computed from.
+    fn annotate(&self, node: &mut Value, sites: &mut std::slice::Iter<Option<DurationExpr>>) {
+        let has_offset = |node: &Value| !node["offset"].is_null();
+        let node_type = node["@type"].as_str().map(str::to_string);
+        match node_type.as_deref() {
+            Some("vector_selector") if has_offset(node) => self.attach(node, "offset_expr", sites),
+            Some("matrix_selector") => {
+                self.attach(node, "range_expr", sites);
+                if has_offset(&node["vector"]) {
+                    self.attach(&mut node["vector"], "offset_expr", sites);
+                }
+            }
+            Some("subquery") => {
+                self.annotate(&mut node["expr"], sites);
+                self.attach(node, "range_expr", sites);
+                self.attach(node, "step_expr", sites);
+                if has_offset(node) {
+                    self.attach(node, "offset_expr", sites);
+                }
+            }
+            _ => {
+                for key in ["param", "lhs", "expr", "rhs"] {
+                    if let Some(child) = node.get_mut(key) {
+                        self.annotate(child, sites);
+                    }
+                }
+                if let Some(Value::Array(args)) = node.get_mut("args") {
+                    args.iter_mut().for_each(|arg| self.annotate(arg, sites));
+                }
+            }
+        }
+    }
+}
+
+/// `parse`s `query`, e.g. with `try_parse`, once its duration expressions
+/// have been computed: `x[5m + 30s]` parses as `x[5m30s]`, and
+/// `x offset -(1h * 2)` as `x offset -2h`.
+pub fn parse_computed(query: &str, parse: impl Fn(&str) -> Result<Expr, ParseError>) -> Result<Expr, ParseError> {
+    let rewrite = Rewrite::new(query)?;
+    if rewrite.edits.is_empty() {
+        return parse(query);
+    }
+    parse(&rewrite.text).map_err(|error| original_error(query, &rewrite.edits, error))
+}
+
+/// Serializes `expr`, parsed by `parse_computed`, like `serialize_ast`, with
+/// the expression every range, step and offset was computed from as
+/// `range_expr`, `step_expr` and `offset_expr`.
+pub fn serialize_computed(query: &str, expr: &Expr) -> Value {
+    let rewrite = match Rewrite::new(query) {
+        Ok(rewrite) if !rewrite.edits.is_empty() => rewrite,
+        _ => return crate::serialize_ast(query, expr),
+    };
+    let mut ast = expr.to_serde();
+    if let Some(tree) = spans(&rewrite.text, expr) {
+        annotate(&mut ast, &tree);
+        original_spans(&mut ast, &rewrite.edits);
+        if options::current().text {
+            annotate_text(&mut ast, query);
+        }
+    }
+    rewrite.annotate(&mut ast, &mut rewrite.sites.iter());
+    ast
+}
+
+
+#[test]
+fn check_duration_exprs() {
+    use crate::errors::try_parse;
+    use crate::printer::to_promql;
+    let payloads = vec![
+        ("rate(x[5m + 30s])", "rate(x[5m30s])"),
+        ("x[2 * (1m + 30s)] offset (1h - 2 * 15m)", "x[3m] offset 30m"),
+        ("max_over_time(x[1h:1m])[(2 ^ 3) * 1m : 30] offset -(5m * 2)", "max_over_time(x[1h:1m])[8m:30s] offset -10m"),
+        ("y offset -(5m - 10m) + z offset 1m", "y offset 5m + z offset 1m"),
+        ("sum by (offset) ({a=\"[1+1]\"} offset 10m)", "sum by (offset) ({a=\"[1+1]\"} offset 10m)"),
+    ];
+    for (query, expected) in payloads {
+        let expr = parse_computed(query, try_parse).unwrap_or_else(|err| panic!("{}: {}", query, err.message));
+        assert_eq!(to_promql(&expr), expected, "{}", query);
+    }
+    let query = "max_over_time(x[1h:1m])[(2 ^ 3) * 1m : 30] offset -(5m * 2)";
+    let ast = serialize_computed(query, &parse_computed(query, try_parse).unwrap());
+    assert_eq!((&ast["range"], &ast["step"], &ast["offset"], &ast["end"]), (&json!(480), &json!(30), &json!(-600), &json!(query.len())));
+    assert!(ast["expr"]["args"][0].get("range_expr").is_none());
+    assert_eq!(ast["step_expr"], json!({ "@type": "duration_number", "value": 30.0 }));
+    assert_eq!(ast["range_expr"]["@type"], json!("duration_binary"));
+    assert_eq!(ast["offset_expr"]["expr"]["rhs"], json!({ "@type": "duration_number", "value": 2.0 }));
+    let query = "a offset 1m / rate(b[1m * 2])";
+    let ast = serialize_computed(query, &parse_computed(query, try_parse).unwrap());
+    assert!(ast["lhs"].get("offset_expr").is_none());
+    assert_eq!(ast["rhs"]["args"][0]["range_expr"]["lhs"], json!({ "@type": "duration_literal", "value": 60 }));
+    let error = parse_computed("x[5m - 10m]", try_parse).unwrap_err();
+    assert_eq!((error.code, error.start, error.end), ("invalid-duration", 2, 10));
+    assert!(parse_computed("x[5m + ]", try_parse).is_err());
+    assert!(try_parse("x[5m + 30s]").is_err());
+}
@@ -0,0 +1,64 @@
+use serde_json::Value;
+use crate::errors::{relocated, ParseError};
+
+/// A replacement of the `start..end` byte range of a query with `text`, made
+/// to parse syntax upstream lacks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// `query` with `edits`, in order and not overlapping, applied.
+pub fn apply(query: &str, edits: &[Edit]) -> String {
+    let mut text = String::with_capacity(query.len());
+    let mut copied = 0;
+    for edit in edits {
+        text.push_str(&query[copied..edit.start]);
+        text.push_str(&edit.text);
+        copied = edit.end;
+    }
+    text.push_str(&query[copied..]);
+    text
+}
+
+/// The offset in the query of an offset in the edited text, the start of the
+/// edit for offsets within one.
+pub fn original(edits: &[Edit], offset: usize) -> usize {
+    let mut shift = 0isize;
+    for edit in edits {
+        let start = (edit.start as isize + shift) as usize;
+        if offset < start {
+            break;
+        }
+        if offset < start + edit.text.len() {
+            return edit.start;
+        }
+        shift += edit.text.len() as isize - (edit.end - edit.start) as isize;
+    }
+    (offset as isize - shift) as usize
+}
+
+/// An error about the edited text, located in `query` instead.
+pub fn original_error(query: &str, edits: &[Edit], error: ParseError) -> ParseError {
+    let (start, end) = (original(edits, error.start), original(edits, error.end));
+    relocated(query, error, start, end.max(start))
+}
+
+/// Maps the `start` and `end` offsets of a serialized tree of the edited
+/// text back to the query.
+pub fn original_spans(value: &mut Value, edits: &[Edit]) {
+    match value {
+        Value::Object(object) => {
+            for key in ["start", "end"] {
+                if let Some(offset) = object.get(key).and_then(Value::as_u64) {
+                    object.insert(key.to_string(), original(edits, offset as usize).into());
+                }
+            }
+            object.values_mut().for_each(|field| original_spans(field, edits));
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| original_spans(item, edits)),
+        _ => {}
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde_json::{json, Value};
+use crate::matchers::{by_label, contradictions, satisfiable};
+use crate::printer::{matcher, to_promql};
+use crate::spans::{spans_by_text, Span};
+use crate::ToSerde;
+
+/// Why (part of) a query can never return any series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmptyFinding {
+    pub kind: &'static str,
+    pub expr: String,
+    pub label: Option<String>,
+    pub reason: String,
+    /// Where `expr` is in the query, once located.
+    pub span: Option<Span>,
+}
+
+impl ToSerde for EmptyFinding {
+    fn to_serde(&self) -> Value {
+        json!({
+            "kind": self.kind,
+            "expr": self.expr,
+            "label": self.label,
+            "reason": self.reason,
+            "start": self.span.map(|span| span.start),
+            "end": self.span.map(|span| span.end),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmptinessReport {
+    pub always_empty: bool,
+    pub findings: Vec<EmptyFinding>,
+}
+
+impl ToSerde for EmptinessReport {
+    fn to_serde(&self) -> Value {
+        json!({
+            "always_empty": self.always_empty,
+            "findings": self.findings.to_serde(),
+        })
+    }
+}
+
+/// Matchers known to hold for every output series, by label name.
+type Constraints = BTreeMap<String, Vec<Matcher>>;
+
+struct Outcome {
+    empty: bool,
+    constraints: Constraints,
+}
+
+impl Outcome {
+    fn non_empty() -> Outcome {
+        Outcome { empty: false, constraints: Constraints::new() }
+    }
+}
+
+fn strip_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(ParenExpr { expr }) => strip_parens(expr),
+        _ => expr,
+    }
+}
+
+/// Whether `label` takes part in vector matching under `modifier`.
+fn is_matched(label: &str, modifier: &Option<BinModifier>) -> bool {
+    match modifier.as_ref().and_then(|m| m.matching.as_ref()) {
+        Some(LabelModifier::Include(on)) => on.labels.iter().any(|l| l == label),
+        Some(LabelModifier::Exclude(ignoring)) => !ignoring.labels.iter().any(|l| l == label),
+        None => true,
+    }
+}
+
+fn keep(constraints: Constraints, predicate: impl Fn(&str) -> bool) -> Constraints {
+    constraints.into_iter().filter(|(label, _)| predicate(label)).collect()
+}
+
+struct Walker {
+    findings: Vec<EmptyFinding>,
+}
+
+impl Walker {
+    fn selector(&mut self, expr: &Expr, vs: &VectorSelector) -> Outcome {
+        let conflicts = contradictions(vs);
+        for (label, matchers) in &conflicts {
+            let matchers: Vec<String> = matchers.iter().map(matcher).collect();
+            self.findings.push(EmptyFinding {
+                kind: "selector",
+                expr: to_promql(expr),
+                label: Some(label.clone()),
+                reason: format!("no value of `{}` satisfies {}", label, matchers.join(", ")),
+                span: None,
+            });
+        }
+        let mut constraints = by_label(vs);
+        constraints.remove(METRIC_NAME);
+        Outcome { empty: !conflicts.is_empty(), constraints }
+    }
+
+    fn binary(&mut self, expr: &Expr, binary: &BinaryExpr) -> Outcome {
+        let BinaryExpr { lhs, op, rhs, modifier } = binary;
+        let left = self.walk(lhs);
+        let right = self.walk(rhs);
+        match (lhs.value_type(), rhs.value_type()) {
+            (ValueType::Vector, ValueType::Vector) => {}
+            (ValueType::Vector, _) => return left,
+            (_, ValueType::Vector) => return right,
+            _ => return Outcome::non_empty(),
+        }
+        match op.id() {
+            T_LOR => {
+                return Outcome { empty: left.empty && right.empty, constraints: Constraints::new() };
+            }
+            T_LUNLESS => {
+                if !left.empty && to_promql(strip_parens(lhs)) == to_promql(strip_parens(rhs)) {
+                    self.findings.push(EmptyFinding {
+                        kind: "binary",
+                        expr: to_promql(expr),
+                        label: None,
+                        reason: "every series is removed by its identical right-hand side".to_string(),
+                        span: None,
+                    });
+                    return Outcome { empty: true, constraints: left.constraints };
+                }
+                return left;
+            }
+            _ => {}
+        }
+        let mut empty = left.empty || right.empty;
+        if !empty {
+            for (label, lhs_matchers) in &left.constraints {
+                let rhs_matchers = match right.constraints.get(label) {
+                    Some(rhs_matchers) if is_matched(label, modifier) => rhs_matchers,
+                    _ => continue,
+                };
+                let both: Vec<&Matcher> = lhs_matchers.iter().chain(rhs_matchers).collect();
+                if satisfiable(&both).is_unsat() {
+                    self.findings.push(EmptyFinding {
+                        kind: "binary",
+                        expr: to_promql(expr),
+                        label: Some(label.clone()),
+                        reason: format!(
+                            "both sides are matched on `{}` but can never share a value for it",
+                            label
+                        ),
+                        span: None,
+                    });
+                    empty = true;
+                    break;
+                }
+            }
+        }
+        let mut constraints = left.constraints;
+        for (label, matchers) in right.constraints {
+            if is_matched(&label, modifier) {
+                constraints.entry(label).or_default().extend(matchers);
+            }
+        }
+        let filters = op.id() == T_LAND
+            || (op.is_comparison_operator() && !modifier.as_ref().is_some_and(|m| m.return_bool));
+        if !filters {
+            constraints = match modifier.as_ref().and_then(|m| m.matching.as_ref()) {
+                Some(LabelModifier::Include(on)) => keep(constraints, |l| on.labels.iter().any(|o| o == l)),
+                Some(LabelModifier::Exclude(ignoring)) =>
+                    keep(constraints, |l| !ignoring.labels.iter().any(|i| i == l)),
+                None => constraints,
+            };
+        }
+        Outcome { empty, constraints }
+    }
+
+    fn walk(&mut self, expr: &Expr) -> Outcome {
+        match expr {
+            Expr::VectorSelector(vs) => self.selector(expr, vs),
+            Expr::MatrixSelector(MatrixSelector { vs, .. }) => self.selector(expr, vs),
+            Expr::Paren(ParenExpr { expr }) => self.walk(expr),
+            Expr::Unary(UnaryExpr { expr }) => self.walk(expr),
+            Expr::Subquery(SubqueryExpr { expr, .. }) => self.walk(expr),
+            Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => {
+                if let Some(param) = param {
+                    self.walk(param);
+                }
+                let inner = self.walk(expr);
+                let constraints = match (op.id(), modifier) {
+                    (T_TOPK | T_BOTTOMK, _) => inner.constraints,
+                    (_, Some(LabelModifier::Include(by))) =>
+                        keep(inner.constraints, |l| by.labels.iter().any(|b| b == l)),
+                    (_, Some(LabelModifier::Exclude(without))) =>
+                        keep(inner.constraints, |l| !without.labels.iter().any(|w| w == l)),
+                    (_, None) => Constraints::new(),
+                };
+                Outcome { empty: inner.empty, constraints }
+            }
+            Expr::Binary(binary) => self.binary(expr, binary),
+            Expr::Call(Call { func, args }) => {
+                let mut empty = false;
+                let mut constraints = Constraints::new();
+                for arg in &args.args {
+                    let outcome = self.walk(arg);
+                    if matches!(arg.value_type(), ValueType::Vector | ValueType::Matrix) {
+                        empty |= outcome.empty;
+                        constraints.extend(outcome.constraints);
+                    }
+                }
+                match func.name {
+                    // absent() turns an empty input into a result
+                    "absent" | "absent_over_time" | "vector" | "scalar" => Outcome::non_empty(),
+                    "label_replace" | "label_join" => Outcome { empty, constraints: Constraints::new() },
+                    "histogram_quantile" | "histogram_fraction" => {
+                        constraints.remove(BUCKET_LABEL);
+                        Outcome { empty, constraints }
+                    }
+                    _ => Outcome { empty, constraints },
+                }
+            }
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) | Expr::Extension(_) => Outcome::non_empty(),
+        }
+    }
+}
+
+/// Finds selectors whose matchers contradict each other and binary
+/// operations that can never produce output.
+pub fn always_empty(expr: &Expr) -> EmptinessReport {
+    let mut walker = Walker { findings: vec![] };
+    let outcome = walker.walk(expr);
+    EmptinessReport { always_empty: outcome.empty, findings: walker.findings }
+}
+
+/// `always_empty`, with the span of each finding in `query`, which `expr`
+/// was parsed from.
+pub fn always_empty_in(query: &str, expr: &Expr) -> EmptinessReport {
+    let mut report = always_empty(expr);
+    let nodes = spans_by_text(query, expr);
+    for finding in report.findings.iter_mut() {
+        finding.span = nodes.iter().find(|(text, _)| *text == finding.expr).map(|(_, span)| *span);
+    }
+    report
+}
+
+
+#[test]
+fn check_always_empty() {
+    let payloads = vec![
+        ("up{job=\"a\", job=\"b\"}", true, 1),
+        ("sum(rate(http_requests_total{code=~\"5..\", code=\"200\"}[5m]))", true, 1),
+        ("up{job=\"a\"} and up{job=\"b\"}", true, 1),
+        ("up{job=\"a\"} and ignoring(job) up{job=\"b\"}", false, 0),
+        ("up{job=\"a\"} / on(instance) up{job=\"b\"}", false, 0),
+        ("sum by (job) (up{job=\"a\"}) - sum by (job) (up{job=~\"b|c\"})", true, 1),
+        ("rate(x[5m]) unless (rate(x[5m]))", true, 1),
+        ("up{job=\"a\"} or up{job=\"b\"}", false, 0),
+        ("absent(up{job=\"a\", job=\"b\"})", false, 1),
+        ("up{job=~\"a.*\"} > 0", false, 0),
+        ("x{a=~\"\\\\d+\", a=\"5\"}", false, 0),
+        ("x{a=~\"a\\\\.b\", a=\"a.b\"}", false, 0),
+        ("x{a=\"\\x41\", a=\"A\"}", false, 0),
+    ];
+    for (query, empty, findings) in payloads {
+        let report = always_empty(&parse(query).unwrap());
+        assert_eq!(report.always_empty, empty, "{}", query);
+        assert_eq!(report.findings.len(), findings, "{}", query);
+    }
+    let query = "sum(x) + rate(y{a=\"b\", a=~\"c|d\"}[5m])";
+    let report = always_empty_in(query, &parse(query).unwrap());
+    let span = report.findings[0].span.unwrap();
+    assert_eq!(&query[span.start..span.end], "y{a=\"b\", a=~\"c|d\"}[5m]");
+}
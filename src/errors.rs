@@ -0,0 +1,288 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use serde_json::{json, Value};
+use crate::lexemes::{lex, Lexeme};
+use crate::suggest;
+use crate::ToSerde;
+
+/// What `parse` reports for any grammar error (private upstream).
+const INVALID_QUERY_INFO: &str = "invalid promql query";
+
+/// A parse error with a machine-readable code and where in the query it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    /// One of `ERROR_CODES`.
+    pub code: &'static str,
+    /// Byte range of the offending text, empty at the end of the query for
+    /// input that ends too early.
+    pub start: usize,
+    pub end: usize,
+    /// 1-based line and character column of `start`.
+    pub line: usize,
+    pub column: usize,
+    /// Names the offending text may be a misspelling of, nearest first.
+    pub suggestions: Vec<String>,
+}
+
+impl ToSerde for ParseError {
+    fn to_serde(&self) -> Value {
+        json!({
+            "message": self.message,
+            "code": self.code,
+            "start": self.start,
+            "end": self.end,
+            "line": self.line,
+            "column": self.column,
+            "suggestions": self.suggestions,
+        })
+    }
+}
+
+/// Every `code` of a `ParseError`, with what it means. Codes are stable: new
+/// ones may be added, none are renamed or removed.
+pub const ERROR_CODES: [(&str, &str); 31] = [
+    ("invalid-syntax", "the grammar does not allow the token there"),
+    ("invalid-query", "any other failure of the parser"),
+    ("unclosed-paren", "a parenthesis is never closed"),
+    ("unclosed-brace", "a brace is never closed"),
+    ("unclosed-bracket", "a bracket is never closed"),
+    ("unexpected-paren", "a closing parenthesis with no opening one"),
+    ("unexpected-brace", "a brace where none can be"),
+    ("unexpected-bracket", "a closing bracket with no opening one"),
+    ("unexpected-end", "the query ends too early"),
+    ("unexpected-character", "a character no token starts with"),
+    ("unterminated-string", "a string is never closed"),
+    ("invalid-escape", "an unknown escape sequence in a string"),
+    ("invalid-duration", "a malformed duration"),
+    ("empty-query", "there is no expression"),
+    ("unknown-function", "a call to a function that does not exist"),
+    ("experimental-function", "a call to an experimental function that is not enabled"),
+    ("wrong-argument-count", "too many or too few arguments"),
+    ("type-mismatch", "an operand or argument of the wrong type"),
+    ("missing-bool", "a comparison of scalars without `bool`"),
+    ("invalid-regex", "a regex that does not compile"),
+    ("empty-selector", "a selector with no matcher that needs a non-empty value"),
+    ("duplicate-modifier", "`offset` or `@` given twice"),
+    ("too-deep", "nested deeper than the limit"),
+    ("too-long", "longer than the limit"),
+    ("unknown-dialect", "a dialect option that names no dialect"),
+    ("unsupported-output", "an output format the dialect does not have"),
+    ("invalid-options", "options that are not of the expected shape"),
+    ("invalid-template", "malformed MetricsQL `WITH` templates"),
+    ("invalid-yaml", "a rules file that is not YAML"),
+    ("invalid-rules", "a rules file that is not as Prometheus expects it"),
+    ("internal-error", "a bug of the parser, which panicked"),
+];
+
+/// Codes by a fragment of the upstream message, first match wins.
+const CODES: [(&str, &str); 22] = [
+    ("unclosed left parenthesis", "unclosed-paren"),
+    ("too many left parentheses", "unclosed-paren"),
+    ("unexpected right parenthesis", "unexpected-paren"),
+    ("unexpected right brace", "unexpected-brace"),
+    ("unexpected left brace", "unexpected-brace"),
+    ("unexpected right bracket", "unexpected-bracket"),
+    ("unexpected end of input", "unexpected-end"),
+    ("can not be at the end", "unexpected-end"),
+    ("unterminated quoted string", "unterminated-string"),
+    ("escape sequence", "invalid-escape"),
+    ("unexpected character", "unexpected-character"),
+    ("colon", "unexpected-character"),
+    ("bad duration", "invalid-duration"),
+    ("no expression found", "empty-query"),
+    ("unknown function", "unknown-function"),
+    ("argument", "wrong-argument-count"),
+    ("expected type", "type-mismatch"),
+    ("must contain only", "type-mismatch"),
+    ("BOOL modifier", "missing-bool"),
+    ("illegal regex", "invalid-regex"),
+    ("non-empty matcher", "empty-selector"),
+    ("multiple times", "duplicate-modifier"),
+];
+
+pub fn code(message: &str) -> &'static str {
+    if message == INVALID_QUERY_INFO {
+        return "invalid-syntax";
+    }
+    CODES
+        .iter()
+        .find(|(fragment, _)| message.contains(fragment))
+        .map_or("invalid-query", |(_, code)| code)
+}
+
+/// The lexer gives no positions: the error is at the last character of the
+/// shortest prefix failing the same way, or at the end for premature ends.
+/// Prefixes past the error all fail with it, so the shortest is bisected for.
+fn lex_error_span(query: &str, message: &str) -> (usize, usize) {
+    let premature = ["unclosed", "too many left", "end of input", "at the end", "not terminated"];
+    if premature.iter().any(|fragment| message.contains(fragment)) {
+        return (query.len(), query.len());
+    }
+    let ends: Vec<usize> = query.char_indices().map(|(i, c)| i + c.len_utf8()).collect();
+    let failing = ends.partition_point(|end| lexer(&query[..*end]).err().as_deref() != Some(message));
+    match ends.get(failing) {
+        Some(&end) => (query[..end].char_indices().last().map_or(0, |(i, _)| i), end),
+        None => (0, query.len()),
+    }
+}
+
+/// Whether the grammar rejected the query, rather than a later check.
+fn syntax_error(query: &str) -> bool {
+    matches!(parse(query), Err(message) if message == INVALID_QUERY_INFO || lexer(query).is_err())
+}
+
+/// Operands and tails tried to tell whether a token prefix can still be
+/// completed into a valid query.
+const COMPLETIONS: [&str; 9] = ["", "x", "1", "(x)", "5m", "\"a\"", "=\"a\"", "(a) (x)", "() x"];
+/// Tried after the closers, for open grouping labels still missing their operand.
+const TAILS: [&str; 2] = ["", " (x)"];
+
+fn closers(lexemes: &[Lexeme]) -> String {
+    let mut open = vec![];
+    for lexeme in lexemes {
+        match lexeme.id {
+            T_LEFT_PAREN => open.push(')'),
+            T_LEFT_BRACE => open.push('}'),
+            T_LEFT_BRACKET => open.push(']'),
+            T_RIGHT_PAREN | T_RIGHT_BRACE | T_RIGHT_BRACKET => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    open.iter().rev().collect()
+}
+
+fn viable(query: &str, lexemes: &[Lexeme]) -> bool {
+    let prefix = &query[..lexemes.last().map_or(0, |l| l.end)];
+    let closers = closers(lexemes);
+    COMPLETIONS.iter().any(|completion| {
+        TAILS
+            .iter()
+            .any(|tail| !syntax_error(&format!("{} {}{}{}", prefix, completion, closers, tail)))
+    })
+}
+
+/// The first token after which the query can no longer be completed. A
+/// prefix that cannot be completed stays so as tokens are added, so it is
+/// bisected for, in a logarithmic number of parses of the query.
+fn syntax_error_span(query: &str) -> (usize, usize) {
+    let lexemes = match lex(query) {
+        Ok(lexemes) => lexemes,
+        Err(_) => return (0, query.len()),
+    };
+    let counts: Vec<usize> = (1..=lexemes.len()).collect();
+    let viable_count = counts.partition_point(|n| viable(query, &lexemes[..*n]));
+    match lexemes.get(viable_count) {
+        Some(lexeme) => (lexeme.start, lexeme.end),
+        None => (query.len(), query.len()),
+    }
+}
+
+/// Checks failing after parsing name the function, regex or modifier at fault.
+fn semantic_error_span(query: &str, message: &str) -> (usize, usize) {
+    if message.contains("end of input") {
+        return (query.len(), query.len());
+    }
+    let quoted = message.split('\'').nth(1).filter(|_| message.matches('\'').count() >= 2);
+    let lexemes = lex(query).unwrap_or_default();
+    let found = if let Some(name) = quoted {
+        // a function name, in "call to 'abs'" or "function with name 'foo'"
+        lexemes
+            .windows(2)
+            .find(|pair| pair[0].text(query) == name && pair[1].id == T_LEFT_PAREN)
+            .map(|pair| pair[0])
+    } else if let Some(regex) = message.strip_prefix("illegal regex for ") {
+        lexemes
+            .iter()
+            .find(|l| l.id == T_STRING && l.text(query).get(1..l.text(query).len() - 1) == Some(regex))
+            .copied()
+    } else if message.contains("multiple times") {
+        let id = if message.starts_with("offset") { T_OFFSET } else { T_AT };
+        lexemes.iter().filter(|l| l.id == id).nth(1).copied()
+    } else {
+        None
+    };
+    found.map_or((0, query.len()), |l| (l.start, l.end))
+}
+
+/// The 1-based line and character column of byte `offset` of `text`.
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+/// An error about the `start..end` byte range of `query`, with its line and column.
+pub fn located(query: &str, message: String, code: &'static str, start: usize, end: usize) -> ParseError {
+    debug_assert!(ERROR_CODES.iter().any(|(known, _)| *known == code), "unlisted error code {}", code);
+    let (line, column) = line_column(query, start);
+    ParseError { message, code, start, end, line, column, suggestions: vec![] }
+}
+
+/// `error` moved to the `start..end` byte range of `query`, e.g. from a part
+/// of it that was parsed apart.
+pub fn relocated(query: &str, error: ParseError, start: usize, end: usize) -> ParseError {
+    ParseError { suggestions: error.suggestions, ..located(query, error.message, error.code, start, end) }
+}
+
+/// Locates and classifies an error `parse(query)` returned.
+pub fn parse_error(query: &str, message: String) -> ParseError {
+    let (start, end) = if lexer(query).is_err() {
+        lex_error_span(query, &message)
+    } else if message == INVALID_QUERY_INFO {
+        syntax_error_span(query)
+    } else {
+        semantic_error_span(query, &message)
+    };
+    let code = code(&message);
+    let suggestions = suggest::suggestions(query, code, start, end);
+    ParseError { suggestions, ..located(query, message, code, start, end) }
+}
+
+/// `parse`, with errors located and classified.
+pub fn try_parse(query: &str) -> Result<Expr, ParseError> {
+    parse(query).map_err(|message| parse_error(query, message))
+}
+
+
+#[test]
+fn check_parse_error() {
+    let payloads = vec![
+        ("sum(rate(x[5m])", "unclosed-paren", "", (1, 16)),
+        ("x{a=1}", "unexpected-character", "1", (1, 5)),
+        ("x[5x]", "invalid-duration", "x", (1, 4)),
+        ("x @ foo", "invalid-syntax", "foo", (1, 5)),
+        ("sum by (a) (x)\n  + * y", "invalid-syntax", "*", (2, 5)),
+        ("abs(x, y)", "wrong-argument-count", "abs", (1, 1)),
+        ("1 + foo(x)", "unknown-function", "foo", (1, 5)),
+        ("x{a=~\"(\"}", "invalid-regex", "\"(\"", (1, 6)),
+        ("x offset 5m offset 1m", "duplicate-modifier", "offset", (1, 13)),
+        ("1 > 2", "missing-bool", "1 > 2", (1, 1)),
+    ];
+    assert!(CODES.iter().all(|(_, code)| ERROR_CODES.iter().any(|(known, _)| known == code)));
+    for (query, code, text, position) in payloads {
+        let error = try_parse(query).unwrap_err();
+        assert_eq!(error.code, code, "{}", query);
+        assert_eq!(&query[error.start..error.end], text, "{}", query);
+        assert_eq!((error.line, error.column), position, "{}", query);
+    }
+}
+
+
+#[test]
+fn check_parse_error_time() {
+    let operands = "rate(x{a=\"b\"}[5m]) + ".repeat(400);
+    let payloads = vec![
+        (format!("{} * * y", operands), "invalid-syntax", "*"),
+        (format!("{} $", operands), "unexpected-character", "$"),
+    ];
+    for (query, code, text) in payloads {
+        let started = std::time::Instant::now();
+        let error = try_parse(&query).unwrap_err();
+        assert!(started.elapsed() < std::time::Duration::from_secs(1), "{:?}", started.elapsed());
+        assert_eq!((error.code, &query[error.start..error.end]), (code, text));
+    }
+}
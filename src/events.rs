@@ -0,0 +1,101 @@
+use promql_parser::parser::*;
+use crate::visit::{children, node_type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Enter,
+    Leave,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Enter => "enter",
+            Phase::Leave => "leave",
+        }
+    }
+}
+
+/// A scalar field of an event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Text(String),
+    Number(f64),
+}
+
+/// A node being entered or left, with the few fields that identify it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub phase: Phase,
+    pub node_type: &'static str,
+    pub depth: usize,
+    /// Only set on `Enter`.
+    pub fields: Vec<(&'static str, Field)>,
+}
+
+fn seconds(dur: &std::time::Duration) -> Field {
+    Field::Number(dur.as_secs_f64())
+}
+
+/// The identifying fields of `expr` alone, not of its sub-expressions.
+fn fields(expr: &Expr) -> Vec<(&'static str, Field)> {
+    let name = |vs: &VectorSelector| vs.name.clone().map(|name| ("name", Field::Text(name)));
+    match expr {
+        Expr::VectorSelector(vs) => name(vs).into_iter().collect(),
+        Expr::MatrixSelector(MatrixSelector { vs, range }) =>
+            name(vs).into_iter().chain(Some(("range", seconds(range)))).collect(),
+        Expr::Aggregate(AggregateExpr { op, .. }) => vec![("op", Field::Text(op.to_string()))],
+        Expr::Binary(BinaryExpr { op, .. }) => vec![("op", Field::Text(op.to_string()))],
+        Expr::Subquery(SubqueryExpr { range, step, .. }) => {
+            let mut fields = vec![("range", seconds(range))];
+            fields.extend(step.as_ref().map(|step| ("step", seconds(step))));
+            fields
+        }
+        Expr::NumberLiteral(NumberLiteral { val }) => vec![("value", Field::Number(*val))],
+        Expr::StringLiteral(StringLiteral { val }) => vec![("value", Field::Text(val.clone()))],
+        Expr::Call(Call { func, .. }) => vec![("name", Field::Text(func.name.to_string()))],
+        Expr::Unary(_) | Expr::Paren(_) | Expr::Extension(_) => vec![],
+    }
+}
+
+fn walk(expr: &Expr, depth: usize, f: &mut impl FnMut(Event) -> bool) -> bool {
+    let node_type = node_type(expr);
+    if !f(Event { phase: Phase::Enter, node_type, depth, fields: fields(expr) }) {
+        return false;
+    }
+    for child in children(expr) {
+        if !walk(child, depth + 1, f) {
+            return false;
+        }
+    }
+    f(Event { phase: Phase::Leave, node_type, depth, fields: vec![] })
+}
+
+/// Reports entering and leaving every node of `expr`, depth first, without
+/// building a serialized tree. Stops as soon as `f` returns `false`.
+pub fn events(expr: &Expr, f: &mut impl FnMut(Event) -> bool) {
+    walk(expr, 0, f);
+}
+
+
+#[test]
+fn check_events() {
+    let expr = parse("sum(rate(x[5m])) > 1").unwrap();
+    let mut seen = vec![];
+    events(&expr, &mut |event| {
+        seen.push(format!("{} {}", event.phase.as_str(), event.node_type));
+        true
+    });
+    assert_eq!(seen, vec![
+        "enter binary", "enter aggregate", "enter call", "enter matrix_selector", "leave matrix_selector",
+        "leave call", "leave aggregate", "enter number", "leave number", "leave binary",
+    ]);
+    let mut entered = vec![];
+    events(&expr, &mut |event| {
+        entered.push(event.fields);
+        entered.len() < 4
+    });
+    assert_eq!(entered.len(), 4);
+    assert_eq!(entered[2], vec![("name", Field::Text("rate".to_string()))]);
+    assert_eq!(entered[3], vec![("name", Field::Text("x".to_string())), ("range", Field::Number(300.0))]);
+}
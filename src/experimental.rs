@@ -0,0 +1,262 @@
+use std::collections::BTreeSet;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use crate::edits::{apply, original_error, Edit};
+use crate::errors::{code, located, relocated, ParseError};
+use crate::lexemes::{lex, Lexeme};
+use crate::visit::{children_mut, walk};
+
+/// A function Prometheus only offers behind its experimental functions
+/// feature flag, unknown upstream. MetricsQL functions are described alike.
+pub struct Experimental {
+    pub name: &'static str,
+    pub arg_types: &'static [ValueType],
+    /// Whether the last argument is optional, or repeated for `sort_by_label`.
+    pub variadic: bool,
+}
+
+const VECTOR: &[ValueType] = &[ValueType::Vector];
+const MATRIX: &[ValueType] = &[ValueType::Matrix];
+
+pub const FUNCTIONS: [Experimental; 12] = [
+    Experimental { name: "double_exponential_smoothing", arg_types: &[ValueType::Matrix, ValueType::Scalar, ValueType::Scalar], variadic: false },
+    Experimental { name: "first_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "histogram_avg", arg_types: VECTOR, variadic: false },
+    Experimental { name: "histogram_stddev", arg_types: VECTOR, variadic: false },
+    Experimental { name: "histogram_stdvar", arg_types: VECTOR, variadic: false },
+    Experimental { name: "info", arg_types: &[ValueType::Vector, ValueType::Vector], variadic: true },
+    Experimental { name: "mad_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "sort_by_label", arg_types: &[ValueType::Vector, ValueType::String], variadic: true },
+    Experimental { name: "sort_by_label_desc", arg_types: &[ValueType::Vector, ValueType::String], variadic: true },
+    Experimental { name: "ts_of_last_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "ts_of_max_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "ts_of_min_over_time", arg_types: MATRIX, variadic: false },
+];
+
+pub fn function(name: &str) -> Option<&'static Experimental> {
+    FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+impl Experimental {
+    fn upstream(&self) -> Function {
+        Function {
+            name: self.name,
+            arg_types: self.arg_types.to_vec(),
+            variadic: self.variadic,
+            return_type: ValueType::Vector,
+        }
+    }
+
+    /// The checks upstream makes of calls, with its messages.
+    fn check(&self, args: &[Box<Expr>]) -> Result<(), String> {
+        let (name, expected, actual) = (self.name, self.arg_types.len(), args.len());
+        if self.variadic && actual < expected - 1 {
+            return Err(format!("expected at least {} argument(s) in call to '{}', got {}", expected - 1, name, actual));
+        }
+        // only the `sort_by_label` functions repeat their last argument
+        if self.variadic && actual > expected && !name.starts_with("sort_by_label") {
+            return Err(format!("expected at most {} argument(s) in call to '{}', got {}", expected, name, actual));
+        }
+        if !self.variadic && actual != expected {
+            return Err(format!("expected {} argument(s) in call to '{}', got {}", expected, name, actual));
+        }
+        for (i, arg) in args.iter().enumerate() {
+            let expected = self.arg_types[i.min(expected - 1)];
+            if arg.value_type() != expected {
+                let (expected, actual) = (expected.to_string(), arg.value_type().to_string());
+                return Err(format!("expected type {} in call to function '{}', got {}", expected, name, actual));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Looks up the functions upstream does not know that a query may call.
+pub type Lookup = fn(&str) -> Option<&'static Experimental>;
+
+/// A call to an experimental function, replaced by a placeholder selector.
+struct Placeholder {
+    function: &'static Experimental,
+    name: Lexeme,
+    /// Byte offset just past the closing parenthesis.
+    end: usize,
+    /// Byte ranges of the arguments.
+    args: Vec<(usize, usize)>,
+}
+
+impl Placeholder {
+    /// The call, its arguments parsed like the query.
+    fn call(&self, query: &str, functions: Lookup, parse: &impl Fn(&str) -> Result<Expr, ParseError>) -> Result<Expr, ParseError> {
+        let mut args = vec![];
+        for (start, end) in &self.args {
+            let arg = parse_functions(&query[*start..*end], functions, parse).map_err(|error| {
+                let (from, to) = (start + error.start, start + error.end);
+                relocated(query, error, from, to)
+            })?;
+            args.push(Box::new(arg));
+        }
+        self.function.check(&args).map_err(|message| {
+            let code = code(&message);
+            located(query, message, code, self.name.start, self.name.end)
+        })?;
+        Ok(Expr::Call(Call { func: self.function.upstream(), args: FunctionArgs { args } }))
+    }
+}
+
+/// The calls to experimental functions, outermost only.
+fn placeholders(query: &str, lexemes: &[Lexeme], functions: Lookup) -> Vec<Placeholder> {
+    let mut placeholders = vec![];
+    let mut i = 0;
+    while i + 1 < lexemes.len() {
+        let (name, open) = (lexemes[i], lexemes[i + 1]);
+        let function = match functions(name.text(query)) {
+            Some(function) if name.id == T_IDENTIFIER && open.id == T_LEFT_PAREN => function,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let mut depth = 0;
+        let mut args = vec![];
+        let mut arg_start = open.end;
+        let start = i;
+        // unclosed calls are left for upstream to report
+        i = lexemes.len();
+        for (j, lexeme) in lexemes.iter().enumerate().skip(start + 1) {
+            match lexeme.id {
+                T_LEFT_PAREN | T_LEFT_BRACE | T_LEFT_BRACKET => depth += 1,
+                T_RIGHT_PAREN | T_RIGHT_BRACE | T_RIGHT_BRACKET => depth -= 1,
+                T_COMMA if depth == 1 => {
+                    args.push((arg_start, lexeme.start));
+                    arg_start = lexeme.end;
+                }
+                _ => {}
+            }
+            if depth == 0 {
+                if !args.is_empty() || !query[arg_start..lexeme.start].trim().is_empty() {
+                    args.push((arg_start, lexeme.start));
+                }
+                placeholders.push(Placeholder { function, name, end: lexeme.end, args });
+                i = j + 1;
+                break;
+            }
+        }
+    }
+    placeholders
+}
+
+/// Puts the calls in place of their placeholder selectors.
+fn substitute(expr: &mut Expr, prefix: &str, calls: &mut [Option<Expr>]) -> Result<(), String> {
+    let index = |vs: &VectorSelector| vs.name.as_deref().and_then(|name| name.strip_prefix(prefix)?.parse::<usize>().ok());
+    match expr {
+        Expr::VectorSelector(vs) if index(vs).is_some() => {
+            if vs.offset.is_some() || vs.at.is_some() {
+                return Err("offset and @ modifiers must be preceded by a selector or a subquery".to_string());
+            }
+            if let Some(call) = index(vs).and_then(|index| calls.get_mut(index)).and_then(Option::take) {
+                *expr = call;
+            }
+        }
+        Expr::MatrixSelector(MatrixSelector { vs, .. }) if index(vs).is_some() =>
+            return Err("ranges only allowed for vector selectors".to_string()),
+        _ => {
+            for child in children_mut(expr) {
+                substitute(child, prefix, calls)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `parse`s `query`, e.g. with `try_parse`, with the `functions` upstream
+/// does not know: their calls are parsed separately in place of placeholder
+/// selectors.
+pub fn parse_functions(
+    query: &str,
+    functions: Lookup,
+    parse: &impl Fn(&str) -> Result<Expr, ParseError>,
+) -> Result<Expr, ParseError> {
+    let lexemes = lex(query).unwrap_or_default();
+    let placeholders = placeholders(query, &lexemes, functions);
+    if placeholders.is_empty() {
+        return parse(query);
+    }
+    let mut prefix = "__experimental_".to_string();
+    while query.contains(&prefix) {
+        prefix.push('_');
+    }
+    let edits: Vec<Edit> = placeholders
+        .iter()
+        .enumerate()
+        .map(|(n, p)| Edit { start: p.name.start, end: p.end, text: format!("{}{}", prefix, n) })
+        .collect();
+    let mut expr = parse(&apply(query, &edits)).map_err(|error| original_error(query, &edits, error))?;
+    let mut calls = placeholders
+        .iter()
+        .map(|p| p.call(query, functions, parse).map(Some))
+        .collect::<Result<Vec<Option<Expr>>, ParseError>>()?;
+    substitute(&mut expr, &prefix, &mut calls).map_err(|message| {
+        let code = code(&message);
+        located(query, message, code, 0, query.len())
+    })?;
+    Ok(expr)
+}
+
+/// `parse`s `query`, e.g. with `try_parse`, with the experimental functions.
+pub fn parse_experimental(query: &str, parse: &impl Fn(&str) -> Result<Expr, ParseError>) -> Result<Expr, ParseError> {
+    parse_functions(query, function, parse)
+}
+
+/// `parse`s `query`, with the experimental functions if `enabled`; when not,
+/// calls to them fail as `experimental-function` rather than unknown.
+pub fn parse_gated(query: &str, enabled: bool, parse: &impl Fn(&str) -> Result<Expr, ParseError>) -> Result<Expr, ParseError> {
+    if enabled {
+        return parse_experimental(query, parse);
+    }
+    parse(query).map_err(|error| match error.message.split('\'').nth(1).and_then(function) {
+        Some(function) if error.code == "unknown-function" => ParseError {
+            message: format!("function '{}' is experimental and not enabled", function.name),
+            code: "experimental-function",
+            ..error
+        },
+        _ => error,
+    })
+}
+
+/// Names of the experimental functions `expr` calls, sorted.
+pub fn features(expr: &Expr) -> Vec<&'static str> {
+    let mut names = BTreeSet::new();
+    walk(expr, &mut |expr| {
+        if let Expr::Call(Call { func, .. }) = expr {
+            names.extend(function(func.name).map(|f| f.name));
+        }
+    });
+    names.into_iter().collect()
+}
+
+
+#[test]
+fn check_parse_experimental() {
+    use crate::errors::try_parse;
+    use crate::printer::to_promql;
+    let payloads = vec![
+        "histogram_stddev(rate(http_request_duration_seconds[5m]))",
+        "sum by (job) (info(up, target_info{env=\"prod\"})) / 2",
+        "sort_by_label_desc(info(x), \"a\", \"b\")",
+        "max_over_time(mad_over_time(x[5m])[1h:1m]) > double_exponential_smoothing(y[10m], 0.5, 0.5)",
+    ];
+    for query in payloads {
+        let expr = parse_experimental(query, &try_parse).unwrap_or_else(|err| panic!("{}: {}", query, err.message));
+        assert_eq!(to_promql(&expr), query);
+        assert_eq!(crate::spans::spans(query, &expr).map(|tree| tree.span.end), Some(query.len()));
+    }
+    let expr = parse_experimental("sort_by_label(info(x)) + histogram_avg(y)", &try_parse).unwrap();
+    assert_eq!(features(&expr), vec!["histogram_avg", "info", "sort_by_label"]);
+    let error = parse_experimental("1 + info(x, y, z)", &try_parse).unwrap_err();
+    assert_eq!((error.code, error.start, error.end), ("wrong-argument-count", 4, 8));
+    let error = parse_experimental("mad_over_time(rate(x))", &try_parse).unwrap_err();
+    assert_eq!((error.code, error.start, error.end), ("type-mismatch", 14, 18));
+    assert!(parse_experimental("info(x)[5m]", &try_parse).is_err());
+    assert_eq!(parse_gated("info(x)", false, &try_parse).unwrap_err().code, "experimental-function");
+    assert_eq!(parse_gated("infos(x)", false, &try_parse).unwrap_err().code, "unknown-function");
+}
@@ -0,0 +1,262 @@
+use std::time::{Duration, UNIX_EPOCH};
+use promql_parser::label::{MatchOp, Matcher, METRIC_NAME};
+use promql_parser::parser::*;
+use crate::printer::{number, quote, unescape};
+
+/// A length of time in the largest unit it is a whole number of, `5 minutes`,
+/// or as an adjective, `5-minute`.
+fn length(duration: &Duration, adjective: bool) -> String {
+    const UNITS: [(u128, &str); 6] = [
+        (604_800_000, "week"),
+        (86_400_000, "day"),
+        (3_600_000, "hour"),
+        (60_000, "minute"),
+        (1_000, "second"),
+        (1, "millisecond"),
+    ];
+    let millis = duration.as_millis();
+    let (size, unit) = UNITS.iter().copied().find(|(size, _)| millis.is_multiple_of(*size)).unwrap_or(UNITS[5]);
+    let count = millis / size;
+    match (adjective, count) {
+        (true, _) => format!("{}-{}", count, unit),
+        (false, 1) => format!("1 {}", unit),
+        (false, _) => format!("{} {}s", count, unit),
+    }
+}
+
+/// What the `offset` and `@` modifiers of a selector or subquery change.
+fn modifiers(offset: &Option<Offset>, at: &Option<AtModifier>) -> String {
+    let mut s = String::new();
+    match offset {
+        Some(Offset::Pos(duration)) => s.push_str(&format!(" as of {} earlier", length(duration, false))),
+        Some(Offset::Neg(duration)) => s.push_str(&format!(" as of {} later", length(duration, false))),
+        None => {}
+    }
+    match at {
+        Some(AtModifier::Start) => s.push_str(" at the start of the query range"),
+        Some(AtModifier::End) => s.push_str(" at the end of the query range"),
+        Some(AtModifier::At(time)) => {
+            let seconds = match time.duration_since(UNIX_EPOCH) {
+                Ok(after) => after.as_secs_f64(),
+                Err(before) => -before.duration().as_secs_f64(),
+            };
+            s.push_str(&format!(" at Unix time {}", number(seconds)));
+        }
+        None => {}
+    }
+    s
+}
+
+fn matcher(matcher: &Matcher) -> String {
+    let value = unescape(&matcher.value);
+    match &matcher.op {
+        MatchOp::Equal => format!("{}={}", matcher.name, value),
+        MatchOp::NotEqual => format!("{}!={}", matcher.name, value),
+        MatchOp::Re(_) => format!("{} matches {}", matcher.name, value),
+        MatchOp::NotRe(_) => format!("{} does not match {}", matcher.name, value),
+    }
+}
+
+/// The series a selector picks: its metric, or `series` for none, and the
+/// other matchers.
+fn selector(vs: &VectorSelector) -> String {
+    let named = |m: &&Matcher| m.name == METRIC_NAME && m.op == MatchOp::Equal;
+    let name = vs.name.clone().or_else(|| vs.matchers.matchers.iter().find(named).map(|m| unescape(&m.value)));
+    let matchers: Vec<String> = vs
+        .matchers
+        .matchers
+        .iter()
+        .filter(|m| !(name.is_some() && named(m)))
+        .map(matcher)
+        .collect();
+    let mut s = name.unwrap_or_else(|| "series".to_string());
+    if !matchers.is_empty() {
+        s.push_str(" where ");
+        s.push_str(&matchers.join(" and "));
+    }
+    s.push_str(&modifiers(&vs.offset, &vs.at));
+    s
+}
+
+/// A range vector argument as its range and what is sampled over it.
+fn range(expr: &Expr) -> Option<(&Duration, String)> {
+    match expr {
+        Expr::MatrixSelector(MatrixSelector { vs, range }) => Some((range, selector(vs))),
+        Expr::Subquery(sq) => {
+            let step = sq.step.as_ref().map(|step| format!(" every {}", length(step, false))).unwrap_or_default();
+            Some((&sq.range, format!("{} (evaluated{}{})", explain(&sq.expr), step, modifiers(&sq.offset, &sq.at))))
+        }
+        Expr::Paren(ParenExpr { expr }) => range(expr),
+        _ => None,
+    }
+}
+
+/// What a `*_over_time` or other range function computes over its window.
+fn range_function(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "rate" => "rate",
+        "irate" => "instant rate",
+        "increase" => "increase",
+        "delta" => "change",
+        "idelta" => "last change",
+        "deriv" => "derivative",
+        "changes" => "number of changes",
+        "resets" => "number of counter resets",
+        "avg_over_time" => "average",
+        "min_over_time" => "minimum",
+        "max_over_time" => "maximum",
+        "sum_over_time" => "sum",
+        "count_over_time" => "number of samples",
+        "stddev_over_time" => "standard deviation",
+        "stdvar_over_time" => "variance",
+        "last_over_time" => "last value",
+        "present_over_time" => "presence",
+        "absent_over_time" => "absence",
+        "mad_over_time" => "median absolute deviation",
+        _ => return None,
+    })
+}
+
+fn call(name: &str, args: &[&Expr]) -> String {
+    match (name, args) {
+        (_, [arg]) if range_function(name).is_some() => match range(arg) {
+            Some((duration, of)) => format!("the {} {} of {}", length(duration, true), range_function(name).unwrap(), of),
+            None => format!("the {} of {}", range_function(name).unwrap(), explain(arg)),
+        },
+        ("quantile_over_time", [q, arg]) => match range(arg) {
+            Some((duration, of)) => format!("the {} {}-quantile of {}", length(duration, true), explain(q), of),
+            None => format!("the {}-quantile of {}", explain(q), explain(arg)),
+        },
+        ("histogram_quantile", [q, arg]) => format!("the {}-quantile of the histogram {}", explain(q), explain(arg)),
+        ("time", []) => "the current time".to_string(),
+        ("vector", [arg]) => format!("{} as a vector", explain(arg)),
+        ("scalar", [arg]) => format!("{} as a scalar", explain(arg)),
+        ("absent", [arg]) => format!("whether {} is absent", explain(arg)),
+        (_, []) => format!("{}()", name),
+        _ => {
+            let args: Vec<String> = args.iter().map(|arg| explain(arg)).collect();
+            format!("{} of {}", name, args.join(" and "))
+        }
+    }
+}
+
+fn aggregation(AggregateExpr { op, expr, param, modifier }: &AggregateExpr) -> String {
+    let param = param.as_deref().map(explain).unwrap_or_default();
+    let what = match op.to_string().as_str() {
+        "avg" => "average".to_string(),
+        "min" => "minimum".to_string(),
+        "stddev" => "standard deviation".to_string(),
+        "stdvar" => "variance".to_string(),
+        "topk" => format!("top {}", param),
+        "bottomk" => format!("bottom {}", param),
+        "limitk" => format!("{} series", param),
+        "limit_ratio" => format!("{} ratio sample", param),
+        "quantile" => format!("{}-quantile", param),
+        "count_values" => format!("count per value (as label {})", param),
+        op => op.to_string(),
+    };
+    match modifier {
+        Some(LabelModifier::Include(by)) if !by.labels.is_empty() => {
+            let grouping = match by.labels.as_slice() {
+                [label] => format!("per-{}", label),
+                labels => format!("per-({})", labels.join(", ")),
+            };
+            format!("{} {} of {}", grouping, what, explain(expr))
+        }
+        Some(LabelModifier::Exclude(without)) if !without.labels.is_empty() => {
+            format!("{} across {} of {}", what, without.labels.join(", "), explain(expr))
+        }
+        _ => format!("{} of {}", what, explain(expr)),
+    }
+}
+
+fn binary(BinaryExpr { op, lhs, rhs, modifier }: &BinaryExpr) -> String {
+    let (lhs, rhs) = (explain(lhs), explain(rhs));
+    let mut s = match op.to_string().as_str() {
+        "+" => format!("{} plus {}", lhs, rhs),
+        "-" => format!("{} minus {}", lhs, rhs),
+        "*" => format!("{} times {}", lhs, rhs),
+        "/" => format!("{} divided by {}", lhs, rhs),
+        "%" => format!("{} modulo {}", lhs, rhs),
+        "^" => format!("{} to the power of {}", lhs, rhs),
+        "atan2" => format!("the arctangent of {} and {}", lhs, rhs),
+        "and" => format!("{}, only where {} exists", lhs, rhs),
+        "or" => format!("{}, or else {}", lhs, rhs),
+        "unless" => format!("{}, except where {} exists", lhs, rhs),
+        op => {
+            let relation = match op {
+                "==" => "equal to",
+                "!=" => "not equal to",
+                ">" => "above",
+                "<" => "below",
+                ">=" => "at or above",
+                _ => "at or below",
+            };
+            match modifier {
+                Some(modifier) if modifier.return_bool => format!("whether {} is {} {} (1 or 0)", lhs, relation, rhs),
+                _ => format!("{}, compared against {} and kept where {}", lhs, rhs, relation),
+            }
+        }
+    };
+    if let Some(modifier) = modifier {
+        match &modifier.matching {
+            Some(LabelModifier::Include(on)) => s.push_str(&format!(", matched on {}", on.labels.join(", "))),
+            Some(LabelModifier::Exclude(ignoring)) if !ignoring.labels.is_empty() => {
+                s.push_str(&format!(", matched ignoring {}", ignoring.labels.join(", ")))
+            }
+            _ => {}
+        }
+        let (side, include) = match &modifier.card {
+            VectorMatchCardinality::ManyToOne(include) => ("many-to-one", include),
+            VectorMatchCardinality::OneToMany(include) => ("one-to-many", include),
+            _ => return s,
+        };
+        s.push_str(&format!(", {}", side));
+        if !include.labels.is_empty() {
+            s.push_str(&format!(" copying {}", include.labels.join(", ")));
+        }
+    }
+    s
+}
+
+/// A plain-English description of what `expr` computes, e.g. for alert
+/// pages read by people who do not know PromQL.
+pub fn explain(expr: &Expr) -> String {
+    match expr {
+        Expr::Aggregate(aggregate) => aggregation(aggregate),
+        Expr::Unary(UnaryExpr { expr }) => format!("the negative of {}", explain(expr)),
+        Expr::Binary(binary) => self::binary(binary),
+        Expr::Paren(ParenExpr { expr }) => explain(expr),
+        Expr::Subquery(_) | Expr::MatrixSelector(_) => {
+            let (duration, of) = range(expr).unwrap();
+            format!("the last {} of {}", length(duration, false), of)
+        }
+        Expr::NumberLiteral(NumberLiteral { val }) => number(*val),
+        Expr::StringLiteral(StringLiteral { val }) => quote(val),
+        Expr::VectorSelector(vs) => selector(vs),
+        Expr::Call(Call { func, args }) => {
+            let args: Vec<&Expr> = args.args.iter().map(|arg| arg.as_ref()).collect();
+            call(func.name, &args)
+        }
+        Expr::Extension(_) => crate::printer::to_promql(expr),
+    }
+}
+
+
+#[test]
+fn check_explain() {
+    let explained = |query: &str| explain(&parse(query).unwrap());
+    assert_eq!(
+        explained("max by (node) (rate(http_requests_total{code=\"200\"}[5m])) > 0.95"),
+        "per-node max of the 5-minute rate of http_requests_total where code=200, compared against 0.95 and kept where above"
+    );
+    assert_eq!(
+        explained("histogram_quantile(0.99, sum without (pod) (rate(x_bucket{job=~\"api|web\"}[90s] offset 1d)))"),
+        "the 0.99-quantile of the histogram sum across pod of the 90-second rate of x_bucket where job matches api|web as of 1 day earlier"
+    );
+    assert_eq!(
+        explained("max_over_time(deriv(up[1m])[1h:30s]) / on (job) group_left (team) topk(3, {__name__=\"info\"}) >= bool 1"),
+        "whether the 1-hour maximum of the 1-minute derivative of up (evaluated every 30 seconds) divided by top 3 of info, matched on job, many-to-one copying team is at or above 1 (1 or 0)"
+    );
+    assert_eq!(explained("-x @ start() offset 5m"), "the negative of x as of 5 minutes earlier at the start of the query range");
+}
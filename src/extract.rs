@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use promql_parser::parser::*;
+use promql_parser::label::*;
+use serde_json::{json, Value};
+use crate::experimental;
+use crate::lint::DEPRECATED_FUNCTIONS;
+use crate::literals::analyze;
+use crate::matchers::{metric_name, selectors};
+use crate::printer::{matcher, to_promql};
+use crate::spans::{spans, Span, SpanTree};
+use crate::visit::{children, walk};
+use crate::ToSerde;
+
+/// The metric names a query selects.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricNames {
+    /// Names given literally: before the braces, as `__name__="..."` or as
+    /// the finite alternatives of a `__name__=~"..."` regex.
+    pub names: BTreeSet<String>,
+    /// `__name__` regexes that match names that cannot be listed.
+    pub patterns: BTreeSet<String>,
+}
+
+impl ToSerde for MetricNames {
+    fn to_serde(&self) -> Value {
+        json!({
+            "names": self.names,
+            "patterns": self.patterns,
+        })
+    }
+}
+
+pub fn metric_names(expr: &Expr) -> MetricNames {
+    let mut found = MetricNames::default();
+    for vs in selectors(expr) {
+        found.names.extend(vs.name.clone());
+        for m in vs.matchers.matchers.iter().filter(|m| m.name == METRIC_NAME) {
+            match m.op {
+                MatchOp::Equal => {
+                    found.names.insert(m.value.clone());
+                }
+                MatchOp::Re(_) => match analyze(&m.value).and_then(|literals| literals.alternatives) {
+                    Some(alternatives) => found.names.extend(alternatives),
+                    None => {
+                        found.patterns.insert(matcher(m));
+                    }
+                },
+                // exclusions do not name the metrics selected
+                MatchOp::NotEqual | MatchOp::NotRe(_) => {}
+            }
+        }
+    }
+    found
+}
+
+/// Label names a query uses, with the contexts they appear in: `matcher`,
+/// `by`, `without`, `on`, `ignoring`, `group_left`, `group_right` or the
+/// name of the function taking them as string arguments.
+pub type LabelNames = BTreeMap<String, BTreeSet<&'static str>>;
+
+/// Whether argument `i` of `function` is a label name.
+fn is_label_arg(function: &str, i: usize) -> bool {
+    match function {
+        "label_replace" => i == 1 || i == 3,
+        "label_join" => i == 1 || i >= 3,
+        "sort_by_label" | "sort_by_label_desc" => i >= 1,
+        _ => false,
+    }
+}
+
+fn string(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::StringLiteral(StringLiteral { val }) => Some(val),
+        Expr::Paren(ParenExpr { expr }) => string(expr),
+        _ => None,
+    }
+}
+
+pub fn label_names(expr: &Expr) -> LabelNames {
+    let mut found = LabelNames::new();
+    let mut add = |labels: &[String], context: &'static str| {
+        for label in labels {
+            found.entry(label.clone()).or_default().insert(context);
+        }
+    };
+    walk(expr, &mut |expr| match expr {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => {
+            let names: Vec<String> = vs.matchers.matchers.iter().map(|m| m.name.clone()).collect();
+            add(&names, "matcher");
+        }
+        Expr::Aggregate(AggregateExpr { param, modifier, .. }) => {
+            match modifier {
+                Some(LabelModifier::Include(by)) => add(&by.labels, "by"),
+                Some(LabelModifier::Exclude(without)) => add(&without.labels, "without"),
+                None => {}
+            }
+            // only `count_values` takes a string, the label of its output
+            if let Some(label) = param.as_deref().and_then(string) {
+                add(&[label.to_string()], "count_values");
+            }
+        }
+        Expr::Binary(BinaryExpr { modifier: Some(modifier), .. }) => {
+            match &modifier.matching {
+                Some(LabelModifier::Include(on)) => add(&on.labels, "on"),
+                Some(LabelModifier::Exclude(ignoring)) => add(&ignoring.labels, "ignoring"),
+                None => {}
+            }
+            match &modifier.card {
+                VectorMatchCardinality::ManyToOne(labels) => add(&labels.labels, "group_left"),
+                VectorMatchCardinality::OneToMany(labels) => add(&labels.labels, "group_right"),
+                _ => {}
+            }
+        }
+        Expr::Call(Call { func, args }) => {
+            for (_, arg) in args.args.iter().enumerate().filter(|(i, _)| is_label_arg(func.name, *i)) {
+                if let Some(label) = string(arg) {
+                    add(&[label.to_string()], func.name);
+                }
+            }
+        }
+        _ => {}
+    });
+    found
+}
+
+/// A vector or matrix selector, without the tree around it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub vs: VectorSelector,
+    /// The range of a matrix selector.
+    pub range: Option<Duration>,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+impl ToSerde for Selector {
+    fn to_serde(&self) -> Value {
+        json!({
+            "metric": metric_name(&self.vs),
+            "matchers": self.vs.matchers.to_serde(),
+            "range": self.range.to_serde(),
+            "offset": self.vs.offset.to_serde(),
+            "at": self.vs.at.to_serde(),
+            "start": self.start,
+            "end": self.end,
+        })
+    }
+}
+
+/// Calls `f` with every node of `expr` and its span, if known, outer nodes
+/// first and in query order.
+fn walk_spanned<'a>(expr: &'a Expr, tree: Option<&SpanTree>, f: &mut impl FnMut(&'a Expr, Option<Span>)) {
+    f(expr, tree.map(|tree| tree.span));
+    let children = children(expr);
+    let trees: Vec<Option<&SpanTree>> = match tree {
+        Some(tree) if tree.children.len() == children.len() => tree.children.iter().map(Some).collect(),
+        _ => vec![None; children.len()],
+    };
+    for (child, tree) in children.into_iter().zip(trees) {
+        walk_spanned(child, tree, f);
+    }
+}
+
+/// Every vector and matrix selector of `expr`, parsed from `query`, in query
+/// order.
+pub fn flat_selectors(query: &str, expr: &Expr) -> Vec<Selector> {
+    let mut found = vec![];
+    walk_spanned(expr, spans(query, expr).as_ref(), &mut |expr, span| {
+        let (start, end) = (span.map(|span| span.start), span.map(|span| span.end));
+        match expr {
+            Expr::VectorSelector(vs) => found.push(Selector { vs: vs.clone(), range: None, start, end }),
+            Expr::MatrixSelector(MatrixSelector { vs, range }) =>
+                found.push(Selector { vs: vs.clone(), range: Some(*range), start, end }),
+            _ => {}
+        }
+    });
+    found
+}
+
+/// A function call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: &'static str,
+    pub args: usize,
+    pub deprecated: bool,
+    pub experimental: bool,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+impl ToSerde for FunctionCall {
+    fn to_serde(&self) -> Value {
+        json!({
+            "name": self.name,
+            "args": self.args,
+            "deprecated": self.deprecated,
+            "experimental": self.experimental,
+            "start": self.start,
+            "end": self.end,
+        })
+    }
+}
+
+/// Every function call of `expr`, parsed from `query`, outer calls first.
+pub fn function_calls(query: &str, expr: &Expr) -> Vec<FunctionCall> {
+    let mut found = vec![];
+    walk_spanned(expr, spans(query, expr).as_ref(), &mut |expr, span| {
+        if let Expr::Call(Call { func, args }) = expr {
+            found.push(FunctionCall {
+                name: func.name,
+                args: args.args.len(),
+                deprecated: DEPRECATED_FUNCTIONS.iter().any(|(old, _)| *old == func.name),
+                experimental: experimental::function(func.name).is_some(),
+                start: span.map(|span| span.start),
+                end: span.map(|span| span.end),
+            });
+        }
+    });
+    found
+}
+
+/// An aggregation with how it groups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregation {
+    pub op: String,
+    /// `by`, `without`, or `None` when aggregating everything into one series.
+    pub grouping: Option<&'static str>,
+    pub labels: Vec<String>,
+    /// The parameter of `topk`, `quantile`, `count_values`, ..., as PromQL.
+    pub param: Option<String>,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+impl ToSerde for Aggregation {
+    fn to_serde(&self) -> Value {
+        json!({
+            "op": self.op,
+            "grouping": self.grouping,
+            "labels": self.labels,
+            "param": self.param,
+            "start": self.start,
+            "end": self.end,
+        })
+    }
+}
+
+/// Every aggregation of `expr`, parsed from `query`, outer ones first.
+pub fn aggregations(query: &str, expr: &Expr) -> Vec<Aggregation> {
+    let mut found = vec![];
+    walk_spanned(expr, spans(query, expr).as_ref(), &mut |expr, span| {
+        if let Expr::Aggregate(AggregateExpr { op, param, modifier, .. }) = expr {
+            let (grouping, labels) = match modifier {
+                Some(LabelModifier::Include(by)) => (Some("by"), by.labels.clone()),
+                Some(LabelModifier::Exclude(without)) => (Some("without"), without.labels.clone()),
+                None => (None, vec![]),
+            };
+            found.push(Aggregation {
+                op: op.to_string(),
+                grouping,
+                labels,
+                param: param.as_deref().map(to_promql),
+                start: span.map(|span| span.start),
+                end: span.map(|span| span.end),
+            });
+        }
+    });
+    found
+}
+
+#[test]
+fn check_extract() {
+    let expr = parse(
+        "sum(rate(http_requests_total[5m])) / on () group_left {__name__=\"up\"} \
+         + {__name__=~\"node_(cpu|memory)_total\"} + {__name__=~\"go_.*\", __name__!=\"go_info\"}",
+    )
+    .unwrap();
+    let names = metric_names(&expr);
+    assert_eq!(names.names.into_iter().collect::<Vec<_>>(), vec![
+        "http_requests_total", "node_cpu_total", "node_memory_total", "up",
+    ]);
+    assert_eq!(names.patterns.into_iter().collect::<Vec<_>>(), vec!["__name__=~\"go_.*\""]);
+    let expr = parse(
+        "count_values(\"version\", sum by (job, pod) (up{env=\"prod\"})) \
+         * on (job) group_left (team) label_replace(owner{job!=\"\"}, \"team\", \"$1\", \"owner\", \"(.*)\")",
+    )
+    .unwrap();
+    let labels: Vec<(String, Vec<&str>)> =
+        label_names(&expr).into_iter().map(|(label, contexts)| (label, contexts.into_iter().collect())).collect();
+    assert_eq!(labels, vec![
+        ("env".to_string(), vec!["matcher"]),
+        ("job".to_string(), vec!["by", "matcher", "on"]),
+        ("owner".to_string(), vec!["label_replace"]),
+        ("pod".to_string(), vec!["by"]),
+        ("team".to_string(), vec!["group_left", "label_replace"]),
+        ("version".to_string(), vec!["count_values"]),
+    ]);
+    let query = "rate({__name__=\"x\", a=~\"b\"}[5m] offset 1m) / y @ 10";
+    let found: Vec<Value> = flat_selectors(query, &parse(query).unwrap()).iter().map(ToSerde::to_serde).collect();
+    assert_eq!(found[0]["metric"], "x");
+    assert_eq!(found[0]["matchers"].as_array().map(Vec::len), Some(2));
+    assert_eq!((found[0]["range"].clone(), found[0]["offset"].clone()), (json!(300), json!(60)));
+    assert_eq!((found[1]["start"].clone(), found[1]["end"].clone()), (json!(45), json!(51)));
+    assert_eq!(found[1]["at"], "1970-01-01T00:00:10.000Z");
+    let query = "sum(holt_winters(x[5m], 0.5, 0.5)) / clamp_min(abs(y), 1)";
+    let calls: Vec<(&str, usize, bool, Option<usize>)> = function_calls(query, &parse(query).unwrap())
+        .iter()
+        .map(|call| (call.name, call.args, call.deprecated, call.start))
+        .collect();
+    assert_eq!(calls, vec![("holt_winters", 3, true, Some(4)), ("clamp_min", 2, false, Some(37)), ("abs", 1, false, Some(47))]);
+    let query = "topk(5, sum without (pod) (x)) / ignoring (job) sum(count by (job) (y))";
+    let found: Vec<Value> = aggregations(query, &parse(query).unwrap()).iter().map(ToSerde::to_serde).collect();
+    assert_eq!(found, vec![
+        json!({ "op": "topk", "grouping": null, "labels": [], "param": "5", "start": 0, "end": 30 }),
+        json!({ "op": "sum", "grouping": "without", "labels": ["pod"], "param": null, "start": 8, "end": 29 }),
+        json!({ "op": "sum", "grouping": null, "labels": [], "param": null, "start": 48, "end": 71 }),
+        json!({ "op": "count", "grouping": "by", "labels": ["job"], "param": null, "start": 52, "end": 70 }),
+    ]);
+}
@@ -0,0 +1,337 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::{T_COLON, T_POW};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::cst::cst;
+use crate::lexemes::lex;
+use crate::lint::TextEdit;
+use crate::printer::{bin_modifier, grouping, needs_parens, operand_precedence, precedence, subquery_suffix, to_promql};
+use crate::spans::{spans, Span, SpanTree};
+use crate::ToSerde;
+
+/// Result of formatting part of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormattedRange {
+    /// The node that was reformatted: the smallest one covering the selection.
+    pub span: Span,
+    /// The single edit to apply, `None` if the node is already formatted.
+    pub edit: Option<TextEdit>,
+    /// The query with the edit applied.
+    pub query: String,
+}
+
+impl ToSerde for FormattedRange {
+    fn to_serde(&self) -> Value {
+        json!({
+            "start": self.span.start,
+            "end": self.span.end,
+            "edit": self.edit.as_ref().map(|edit| edit.to_serde()),
+            "query": self.query,
+        })
+    }
+}
+
+/// The innermost node whose span covers `selection`.
+fn covering(tree: &SpanTree, selection: &Span) -> Span {
+    tree.children
+        .iter()
+        .find(|child| child.span.contains(selection))
+        .map(|child| covering(child, selection))
+        .unwrap_or(tree.span)
+}
+
+fn node_at(tree: &SpanTree, expr: &Expr, span: &Span) -> Option<Expr> {
+    if tree.span == *span {
+        return Some(expr.clone());
+    }
+    tree.children
+        .iter()
+        .zip(crate::visit::children(expr))
+        .find(|(child, _)| child.span.contains(span))
+        .and_then(|(child, expr)| node_at(child, expr, span))
+}
+
+/// The smallest edit turning `old` into `new`, at offset `base`.
+fn minimal_edit(base: usize, old: &str, new: &str) -> Option<TextEdit> {
+    if old == new {
+        return None;
+    }
+    let same = |(a, b): &(char, char)| a == b;
+    let prefix: usize = old.chars().zip(new.chars()).take_while(same).map(|(c, _)| c.len_utf8()).sum();
+    let suffix: usize = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(same)
+        .map(|(c, _)| c.len_utf8())
+        .sum();
+    Some(TextEdit {
+        start: base + prefix,
+        end: base + old.len() - suffix,
+        text: new[prefix..new.len() - suffix].to_string(),
+    })
+}
+
+/// Reformats only the node covering the bytes `start..end` of `query`, e.g.
+/// the subtree under the cursor, leaving the rest of the query untouched.
+pub fn format_range(query: &str, start: usize, end: usize) -> Result<FormattedRange, String> {
+    let expr = parse(query)?;
+    let tree = spans(query, &expr).ok_or("could not locate the nodes of the query")?;
+    let selection = Span { start: start.min(end), end: end.max(start) };
+    let span = covering(&tree, &selection);
+    let comments = cst(query)?
+        .tokens
+        .iter()
+        .flat_map(|token| token.leading.iter())
+        .any(|trivia| trivia.kind == "comment" && span.start < trivia.start && trivia.end <= span.end);
+    if comments {
+        return Err("the selected expression contains comments, which formatting would drop".to_string());
+    }
+    let node = node_at(&tree, &expr, &span).ok_or("could not locate the selected node")?;
+    let edit = minimal_edit(span.start, &query[span.start..span.end], &to_promql(&node));
+    let mut formatted = query.to_string();
+    if let Some(edit) = &edit {
+        formatted.replace_range(edit.start..edit.end, &edit.text);
+    }
+    // the edited query must still mean the same
+    if to_promql(&parse(&formatted)?) != to_promql(&expr) {
+        return Err("formatting the selection would change the query".to_string());
+    }
+    Ok(FormattedRange { span, edit, query: formatted })
+}
+
+/// Layout of `format`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct FormatOptions {
+    /// Longest line, indentation included, before an expression is split.
+    pub max_width: usize,
+    /// Spaces per nesting level.
+    pub indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        // as `promtool` does
+        FormatOptions { max_width: 100, indent: 2 }
+    }
+}
+
+struct Pretty<'a> {
+    options: &'a FormatOptions,
+}
+
+impl Pretty<'_> {
+    fn pad(&self, level: usize) -> String {
+        " ".repeat(self.options.indent * level)
+    }
+
+    /// `expr` at nesting `level`, on one line if it fits, otherwise split:
+    /// arguments and aggregated expressions one level deeper than their
+    /// parentheses, binary operands one level deeper than their operator.
+    fn expr(&self, expr: &Expr, level: usize) -> String {
+        let pad = self.pad(level);
+        let single = to_promql(expr);
+        if pad.len() + single.len() <= self.options.max_width {
+            return pad + &single;
+        }
+        match expr {
+            Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => {
+                let mut args: Vec<String> = param.iter().map(|param| self.expr(param, level + 1)).collect();
+                args.push(self.expr(expr, level + 1));
+                format!("{}{}{}(\n{}\n{})", pad, op, grouping(modifier), args.join(",\n"), pad)
+            }
+            Expr::Unary(UnaryExpr { expr }) =>
+                format!("{}-{}", pad, self.operand(expr, precedence(T_POW), level).trim_start()),
+            Expr::Binary(BinaryExpr { lhs, op, rhs, modifier }) => {
+                let (lhs_min, rhs_min) = operand_precedence(op.id());
+                format!(
+                    "{}\n{}{}{}\n{}",
+                    self.operand(lhs, lhs_min, level + 1),
+                    pad,
+                    op,
+                    modifier.as_ref().map(bin_modifier).unwrap_or_default(),
+                    self.operand(rhs, rhs_min, level + 1),
+                )
+            }
+            Expr::Paren(ParenExpr { expr }) => self.parens(expr, level),
+            Expr::Subquery(sq) => format!("{}{}", self.operand(&sq.expr, u8::MAX, level), subquery_suffix(sq)),
+            Expr::Call(Call { func, args }) if !args.args.is_empty() => {
+                let args: Vec<String> = args.args.iter().map(|arg| self.expr(arg, level + 1)).collect();
+                format!("{}{}(\n{}\n{})", pad, func.name, args.join(",\n"), pad)
+            }
+            _ => pad + &single,
+        }
+    }
+
+    fn parens(&self, expr: &Expr, level: usize) -> String {
+        let pad = self.pad(level);
+        let single = to_promql(expr);
+        if pad.len() + single.len() + 2 <= self.options.max_width {
+            format!("{}({})", pad, single)
+        } else {
+            format!("{}(\n{}\n{})", pad, self.expr(expr, level + 1), pad)
+        }
+    }
+
+    fn operand(&self, expr: &Expr, min: u8, level: usize) -> String {
+        if needs_parens(expr, min) {
+            self.parens(expr, level)
+        } else {
+            self.expr(expr, level)
+        }
+    }
+}
+
+/// Reformats `query` canonically: normalized spacing and quoting, with long
+/// expressions split over indented lines.
+pub fn format(query: &str, options: &FormatOptions) -> Result<String, String> {
+    let expr = parse(query)?;
+    let cst = cst(query)?;
+    let comments = cst
+        .tokens
+        .iter()
+        .flat_map(|token| token.leading.iter())
+        .chain(cst.trailing.iter())
+        .any(|trivia| trivia.kind == "comment");
+    if comments {
+        return Err("the query contains comments, which formatting would drop".to_string());
+    }
+    let formatted = Pretty { options }.expr(&expr, 0);
+    // the formatted query must still mean the same
+    if to_promql(&parse(&formatted)?) != to_promql(&expr) {
+        return Err("formatting would change the query".to_string());
+    }
+    Ok(formatted)
+}
+
+/// Removes the parentheses `to_promql` would not need to print `expr` right.
+pub fn unparen(expr: &mut Expr) {
+    while let Expr::Paren(ParenExpr { expr: inner }) = expr {
+        let inner = inner.as_ref().clone();
+        *expr = inner;
+    }
+    match expr {
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => {
+            param.iter_mut().for_each(|param| unparen(param));
+            unparen(expr);
+        }
+        Expr::Unary(UnaryExpr { expr }) => unparen(expr),
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => {
+            unparen(lhs);
+            unparen(rhs);
+        }
+        Expr::Subquery(SubqueryExpr { expr, .. }) => match expr.as_mut() {
+            // `to_promql` would not parenthesize these, e.g. `(x offset 5m)[1h:]`
+            Expr::Paren(ParenExpr { expr: inner })
+                if matches!(
+                    inner.as_ref(),
+                    Expr::VectorSelector(VectorSelector { offset: Some(_), .. })
+                        | Expr::VectorSelector(VectorSelector { at: Some(_), .. })
+                        | Expr::MatrixSelector(_)
+                        | Expr::Subquery(_)
+                ) => unparen(inner),
+            _ => unparen(expr),
+        },
+        Expr::Call(Call { args, .. }) => args.args.iter_mut().for_each(|arg| unparen(arg)),
+        _ => {}
+    }
+}
+
+/// Whether `c` can be part of an identifier, keyword, number or duration, so
+/// that two tokens ending and starting with one must stay apart. Subquery
+/// colons are tokens of their own.
+fn word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == ':' || c == '.'
+}
+
+/// The shortest single-line form of `query`: no comments, no parentheses that
+/// precedence makes redundant and no whitespace that does not separate words.
+pub fn minify(query: &str) -> Result<String, String> {
+    let mut expr = parse(query)?;
+    unparen(&mut expr);
+    let canonical = to_promql(&expr);
+    let mut minified = String::with_capacity(canonical.len());
+    let mut previous = None;
+    for lexeme in lex(&canonical)? {
+        let text = lexeme.text(&canonical);
+        let apart = previous.is_some_and(|id| id != T_COLON) && lexeme.id != T_COLON;
+        if apart && minified.ends_with(word_char) && text.starts_with(word_char) {
+            minified.push(' ');
+        }
+        minified.push_str(text);
+        previous = Some(lexeme.id);
+    }
+    // the minified query must still mean the same
+    if to_promql(&parse(&minified)?) != canonical {
+        return Err("minifying would change the query".to_string());
+    }
+    Ok(minified)
+}
+
+
+#[test]
+fn check_format_range() {
+    let query = "sum by(job)(rate( x{a='b'}[5m] ))  /  count(x)";
+    let at = query.find("x{").unwrap();
+    let formatted = format_range(query, at, at).unwrap();
+    assert_eq!(&query[formatted.span.start..formatted.span.end], "x{a='b'}[5m]");
+    assert_eq!(formatted.query, "sum by(job)(rate( x{a=\"b\"}[5m] ))  /  count(x)");
+    let edit = formatted.edit.unwrap();
+    assert_eq!((edit.end - edit.start, edit.text.as_str()), (3, "\"b\""));
+    let formatted = format_range(query, 0, 5).unwrap();
+    assert_eq!(formatted.query, "sum by (job) (rate(x{a=\"b\"}[5m]))  /  count(x)");
+    assert_eq!(format_range("count(x)", 0, 8).unwrap().edit, None);
+    assert!(format_range("sum(x # a\n)", 0, 1).is_err());
+}
+
+#[test]
+fn check_format() {
+    let options = FormatOptions { max_width: 40, indent: 2 };
+    assert_eq!(format("sum by(job)(rate(x[5m]))", &options).unwrap(), "sum by (job) (rate(x[5m]))");
+    let query = "sum by (job) (rate(http_requests_total{code=~'5..'}[5m])) / sum by (job) (rate(http_requests_total[5m])) > 0.05";
+    assert_eq!(format(query, &options).unwrap(), [
+        "    sum by (job) (",
+        "      rate(",
+        "        http_requests_total{code=~\"5..\"}[5m]",
+        "      )",
+        "    )",
+        "  /",
+        "    sum by (job) (",
+        "      rate(http_requests_total[5m])",
+        "    )",
+        ">",
+        "  0.05",
+    ].join("\n"));
+    let query = "topk(5, max_over_time((some_metric_name + other_metric_name)[1h:]))";
+    assert_eq!(format(query, &options).unwrap(), [
+        "topk(",
+        "  5,",
+        "  max_over_time(",
+        "    (",
+        "        some_metric_name",
+        "      +",
+        "        other_metric_name",
+        "    )[1h:]",
+        "  )",
+        ")",
+    ].join("\n"));
+    assert!(format("x # comment", &options).is_err());
+}
+
+#[test]
+fn check_minify() {
+    let payloads = vec![
+        ("sum by (job) ( rate(x{a = 'b', c!~\"d\"}[5m]) ) # comment", "sum by(job)(rate(x{a=\"b\",c!~\"d\"}[5m]))"),
+        ("((a + b)) * (c) - (d / e)", "(a+b)*c-d/e"),
+        ("a - (b - c)", "a-(b-c)"),
+        (
+            "max_over_time((x offset 5m)[1h:]) + max_over_time((rate(x[5m]))[1h:1m])",
+            "max_over_time((x offset 5m)[1h:])+max_over_time(rate(x[5m])[1h:1m])",
+        ),
+        ("1 - -(2 ^ 3) > bool 1", "1--2^3>bool 1"),
+    ];
+    for (query, expected) in payloads {
+        assert_eq!(minify(query).unwrap(), expected);
+    }
+}
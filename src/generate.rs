@@ -0,0 +1,251 @@
+use serde::Deserialize;
+
+/// Size and feature mix of generated queries.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    /// Maximum nesting of operations around selectors.
+    pub max_depth: usize,
+    pub metrics: Vec<String>,
+    pub labels: Vec<String>,
+    pub values: Vec<String>,
+    pub aggregations: bool,
+    pub binary: bool,
+    pub functions: bool,
+    pub subqueries: bool,
+    pub offsets: bool,
+    pub regex: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect();
+        Profile {
+            max_depth: 3,
+            metrics: strings(&["http_requests_total", "up", "node_cpu_seconds_total", "process_resident_memory_bytes"]),
+            labels: strings(&["job", "instance", "env", "code"]),
+            values: strings(&["api", "node", "prod", "dev", "200", "500"]),
+            aggregations: true,
+            binary: true,
+            functions: true,
+            subqueries: true,
+            offsets: true,
+            regex: true,
+        }
+    }
+}
+
+const RANGES: [&str; 4] = ["1m", "5m", "15m", "1h"];
+const STEPS: [&str; 3] = ["15s", "30s", "1m"];
+const AGGREGATIONS: [&str; 7] = ["sum", "avg", "min", "max", "count", "group", "stddev"];
+const INSTANT_FUNCTIONS: [&str; 6] = ["abs", "ceil", "floor", "sqrt", "ln", "sort"];
+const RANGE_FUNCTIONS: [&str; 8] = [
+    "rate", "irate", "increase", "delta", "avg_over_time", "max_over_time", "sum_over_time", "count_over_time",
+];
+const ARITHMETIC: [&str; 5] = ["+", "-", "*", "/", "%"];
+const COMPARISONS: [&str; 4] = ["==", "!=", ">", "<="];
+const SET_OPERATIONS: [&str; 3] = ["and", "or", "unless"];
+
+/// splitmix64, enough for reproducible test inputs without a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+
+    fn pick<'a, T: AsRef<str>>(&mut self, items: &'a [T]) -> &'a str {
+        items[self.below(items.len())].as_ref()
+    }
+}
+
+/// Generated text, and whether it is a binary expression that must be
+/// parenthesized when used as an operand.
+struct Generated {
+    text: String,
+    binary: bool,
+}
+
+impl Generated {
+    fn new(text: String) -> Generated {
+        Generated { text, binary: false }
+    }
+
+    fn operand(self) -> String {
+        if self.binary {
+            format!("({})", self.text)
+        } else {
+            self.text
+        }
+    }
+}
+
+struct Generator<'a> {
+    rng: Rng,
+    profile: &'a Profile,
+}
+
+impl Generator<'_> {
+    fn matcher(&mut self) -> String {
+        let label = self.rng.pick(&self.profile.labels).to_string();
+        let value = self.rng.pick(&self.profile.values).to_string();
+        match self.rng.below(if self.profile.regex { 4 } else { 2 }) {
+            0 => format!("{}=\"{}\"", label, value),
+            1 => format!("{}!=\"{}\"", label, value),
+            2 => format!("{}=~\"{}|{}\"", label, value, self.rng.pick(&self.profile.values)),
+            _ => format!("{}!~\"{}.*\"", label, value),
+        }
+    }
+
+    fn offset(&mut self) -> String {
+        if self.profile.offsets && self.rng.chance(20) {
+            format!(" offset {}", self.rng.pick(&RANGES))
+        } else {
+            String::new()
+        }
+    }
+
+    fn selector(&mut self) -> String {
+        let metric = self.rng.pick(&self.profile.metrics).to_string();
+        let matchers: Vec<String> = (0..self.rng.below(3)).map(|_| self.matcher()).collect();
+        if matchers.is_empty() {
+            metric
+        } else {
+            format!("{}{{{}}}", metric, matchers.join(", "))
+        }
+    }
+
+    fn scalar(&mut self) -> Generated {
+        match self.rng.below(4) {
+            0 => Generated::new(format!("{}", self.rng.below(100))),
+            1 => Generated::new(format!("{}.{}", self.rng.below(10), self.rng.below(10))),
+            2 => Generated::new("time()".to_string()),
+            _ => Generated::new(format!("{}e{}", 1 + self.rng.below(9), self.rng.below(4))),
+        }
+    }
+
+    fn matrix(&mut self, depth: usize) -> Generated {
+        let range = self.rng.pick(&RANGES);
+        if self.profile.subqueries && depth > 0 && self.rng.chance(15) {
+            let inner = self.vector(depth - 1).operand();
+            return Generated::new(format!("{}[{}:{}]{}", inner, range, self.rng.pick(&STEPS), self.offset()));
+        }
+        let selector = self.selector();
+        Generated::new(format!("{}[{}]{}", selector, range, self.offset()))
+    }
+
+    fn grouping(&mut self) -> String {
+        let mut labels = vec![self.rng.pick(&self.profile.labels)];
+        let second = self.rng.pick(&self.profile.labels);
+        if self.rng.chance(50) && second != labels[0] {
+            labels.push(second);
+        }
+        match self.rng.below(3) {
+            0 => String::new(),
+            1 => format!(" by ({})", labels.join(", ")),
+            _ => format!(" without ({})", labels.join(", ")),
+        }
+    }
+
+    fn binary(&mut self, depth: usize) -> Generated {
+        let lhs = self.vector(depth - 1).operand();
+        let text = match self.rng.below(4) {
+            0 => format!("{} {} {}", lhs, self.rng.pick(&ARITHMETIC), self.scalar().operand()),
+            1 => format!("{} {} {}", lhs, self.rng.pick(&COMPARISONS), self.scalar().operand()),
+            2 => format!("{} {} {}", lhs, self.rng.pick(&SET_OPERATIONS), self.vector(depth - 1).operand()),
+            _ => {
+                let op = self.rng.pick(&ARITHMETIC);
+                let matching = if self.rng.chance(30) {
+                    format!(" on ({})", self.rng.pick(&self.profile.labels))
+                } else {
+                    String::new()
+                };
+                format!("{} {}{} {}", lhs, op, matching, self.vector(depth - 1).operand())
+            }
+        };
+        Generated { text, binary: true }
+    }
+
+    fn vector(&mut self, depth: usize) -> Generated {
+        let mut choices = vec![0];
+        if depth > 0 {
+            if self.profile.aggregations {
+                choices.extend([1, 2]);
+            }
+            if self.profile.functions {
+                choices.extend([3, 4]);
+            }
+            if self.profile.binary {
+                choices.push(5);
+            }
+        }
+        match choices[self.rng.below(choices.len())] {
+            1 => {
+                let op = self.rng.pick(&AGGREGATIONS);
+                let grouping = self.grouping();
+                let inner = self.vector(depth - 1).text;
+                Generated::new(format!("{}{} ({})", op, grouping, inner))
+            }
+            2 => {
+                let (op, param) = match self.rng.below(3) {
+                    0 => ("topk", format!("{}", 1 + self.rng.below(10))),
+                    1 => ("bottomk", format!("{}", 1 + self.rng.below(10))),
+                    _ => ("quantile", format!("0.{}", 1 + self.rng.below(9))),
+                };
+                let grouping = self.grouping();
+                let inner = self.vector(depth - 1).text;
+                Generated::new(format!("{}{} ({}, {})", op, grouping, param, inner))
+            }
+            3 => {
+                let func = self.rng.pick(&INSTANT_FUNCTIONS);
+                Generated::new(format!("{}({})", func, self.vector(depth - 1).text))
+            }
+            4 => {
+                let func = self.rng.pick(&RANGE_FUNCTIONS);
+                Generated::new(format!("{}({})", func, self.matrix(depth - 1).text))
+            }
+            5 => self.binary(depth),
+            _ => {
+                let selector = self.selector();
+                Generated::new(format!("{}{}", selector, self.offset()))
+            }
+        }
+    }
+}
+
+/// A random but valid instant-vector query. The same seed and profile always
+/// give the same query.
+pub fn generate(seed: u64, profile: &Profile) -> Result<String, String> {
+    if profile.metrics.is_empty() || profile.labels.is_empty() || profile.values.is_empty() {
+        return Err("the profile needs at least one metric, label and value".to_string());
+    }
+    let mut generator = Generator { rng: Rng(seed), profile };
+    Ok(generator.vector(profile.max_depth).text)
+}
+
+
+#[test]
+fn check_generate() {
+    let profile = Profile::default();
+    for seed in 0..500 {
+        let query = generate(seed, &profile).unwrap();
+        assert!(promql_parser::parser::parse(&query).is_ok(), "{}", query);
+        assert_eq!(query, generate(seed, &profile).unwrap());
+    }
+    let flat = Profile { max_depth: 0, ..Profile::default() };
+    let selector = promql_parser::parser::parse(&generate(7, &flat).unwrap()).unwrap();
+    assert!(matches!(selector, promql_parser::parser::Expr::VectorSelector(_)));
+    assert!(generate(1, &Profile { metrics: vec![], ..Profile::default() }).is_err());
+}
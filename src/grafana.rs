@@ -0,0 +1,163 @@
+use serde_json::{json, Value};
+use crate::errors::ParseError;
+use crate::options::SerializeOptions;
+use crate::ToSerde;
+
+/// The query of a Prometheus target of a dashboard panel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashboardQuery {
+    pub panel_id: Value,
+    pub panel_title: Option<String>,
+    pub ref_id: Option<String>,
+    /// As the target or, failing that, the panel gives it.
+    pub datasource: Value,
+    pub hidden: bool,
+    pub expr: String,
+    pub ast: Option<Value>,
+    pub error: Option<ParseError>,
+}
+
+impl ToSerde for DashboardQuery {
+    fn to_serde(&self) -> Value {
+        json!({
+            "panel_id": self.panel_id,
+            "panel_title": self.panel_title,
+            "ref_id": self.ref_id,
+            "datasource": self.datasource,
+            "hidden": self.hidden,
+            "expr": self.expr,
+            "ast": self.ast,
+            "error": self.error.as_ref().map(ToSerde::to_serde),
+        })
+    }
+}
+
+/// The panels of `dashboard`, with those of rows, collapsed or of the old
+/// `rows` layout, in dashboard order.
+fn panels(dashboard: &Value) -> Vec<&Value> {
+    fn collect<'a>(panels: &'a Value, out: &mut Vec<&'a Value>) {
+        for panel in panels.as_array().into_iter().flatten() {
+            out.push(panel);
+            collect(&panel["panels"], out);
+        }
+    }
+    let mut out = vec![];
+    collect(&dashboard["panels"], &mut out);
+    for row in dashboard["rows"].as_array().into_iter().flatten() {
+        collect(&row["panels"], &mut out);
+    }
+    out
+}
+
+/// The plugin type of the datasource variable or input `name` of `dashboard`.
+fn variable_type<'a>(dashboard: &'a Value, name: &str) -> Option<&'a str> {
+    let variables = dashboard["templating"]["list"].as_array().into_iter().flatten();
+    let variable = variables
+        .filter(|variable| variable["type"] == "datasource" && variable["name"] == name)
+        .find_map(|variable| variable["query"].as_str());
+    let inputs = dashboard["__inputs"].as_array().into_iter().flatten();
+    variable.or_else(|| inputs.filter(|input| input["name"] == name).find_map(|input| input["pluginId"].as_str()))
+}
+
+/// Whether `datasource` may be Prometheus: its type, or that of the
+/// variable it names, if known, is `prometheus`.
+fn is_prometheus(dashboard: &Value, datasource: &Value) -> bool {
+    let name = match datasource {
+        Value::Object(fields) => match fields.get("type").and_then(Value::as_str) {
+            Some(kind) if !kind.starts_with('$') => return kind == "prometheus",
+            kind => kind.or_else(|| fields.get("uid").and_then(Value::as_str)),
+        },
+        Value::String(name) => Some(name.as_str()),
+        _ => None,
+    };
+    let variable = name.and_then(|name| name.strip_prefix('$')).map(|name| name.trim_start_matches('{').trim_end_matches('}'));
+    match variable.and_then(|variable| variable_type(dashboard, variable)) {
+        Some(kind) => kind == "prometheus",
+        None => true,
+    }
+}
+
+/// The queries of the Prometheus targets of a Grafana dashboard, as
+/// exported or as the HTTP API returns it, `{dashboard, meta}`, parsed
+/// as `options` say with Grafana variables tolerated. Targets of other or
+/// unknown datasources with no `expr` are left out.
+pub fn dashboard_queries(dashboard: &Value, options: &SerializeOptions) -> Vec<DashboardQuery> {
+    let dashboard = match dashboard.get("dashboard") {
+        Some(inner) if inner.is_object() => inner,
+        _ => dashboard,
+    };
+    let options = SerializeOptions { variables: true, ..options.clone() };
+    let mut queries = vec![];
+    for panel in panels(dashboard) {
+        for target in panel["targets"].as_array().into_iter().flatten() {
+            let expr = match target["expr"].as_str() {
+                Some(expr) if !expr.trim().is_empty() => expr,
+                _ => continue,
+            };
+            let datasource = match &target["datasource"] {
+                Value::Null => panel["datasource"].clone(),
+                datasource => datasource.clone(),
+            };
+            if !is_prometheus(dashboard, &datasource) {
+                continue;
+            }
+            let (ast, error) = match crate::parse_serialized(expr, &options) {
+                Ok(ast) => (Some(ast), None),
+                Err(error) => (None, Some(error)),
+            };
+            queries.push(DashboardQuery {
+                panel_id: panel["id"].clone(),
+                panel_title: panel["title"].as_str().map(str::to_string),
+                ref_id: target["refId"].as_str().map(str::to_string),
+                datasource,
+                hidden: target["hide"].as_bool().unwrap_or_default(),
+                expr: expr.to_string(),
+                ast,
+                error,
+            });
+        }
+    }
+    queries
+}
+
+
+#[test]
+fn check_dashboard_queries() {
+    let dashboard = json!({
+        "dashboard": {
+            "templating": { "list": [
+                { "name": "ds", "type": "datasource", "query": "prometheus" },
+                { "name": "logs", "type": "datasource", "query": "loki" },
+            ] },
+            "panels": [
+                {
+                    "id": 1,
+                    "title": "Requests",
+                    "datasource": { "type": "prometheus", "uid": "prom" },
+                    "targets": [
+                        { "refId": "A", "expr": "sum by ($group) (rate(http_requests_total{job=~\"$job\"}[$__rate_interval]))" },
+                        { "refId": "B", "expr": "topk($n, up @ ${__to:date:seconds})", "hide": true },
+                        { "refId": "C", "expr": "" },
+                    ],
+                },
+                { "id": 2, "type": "row", "collapsed": true, "panels": [
+                    { "id": 3, "datasource": "$ds", "targets": [{ "refId": "A", "expr": "rate(x[5m]" }] },
+                    { "id": 4, "datasource": "${logs}", "targets": [{ "refId": "A", "expr": "{app=\"api\"}" }] },
+                ] },
+                { "id": 5, "targets": [{ "refId": "A", "datasource": { "type": "loki" }, "expr": "{app=\"api\"}" }] },
+            ],
+        },
+        "meta": {},
+    });
+    let queries = dashboard_queries(&dashboard, &SerializeOptions::default());
+    let ids: Vec<(&Value, Option<&str>, bool)> = queries.iter().map(|q| (&q.panel_id, q.ref_id.as_deref(), q.hidden)).collect();
+    assert_eq!(ids, [(&json!(1), Some("A"), false), (&json!(1), Some("B"), true), (&json!(3), Some("A"), false)]);
+    let ast = queries[0].ast.as_ref().unwrap();
+    assert_eq!(ast["modifier"], json!({ "include": ["$group"] }));
+    assert_eq!(ast["expr"]["args"][0]["vector"]["matchers"][0]["value"], json!("$job"));
+    let variables: Vec<&Value> = ast["variables"].as_array().unwrap().iter().map(|variable| &variable["context"]).collect();
+    assert_eq!(variables, ["name", "regex", "duration"]);
+    assert_eq!(queries[1].ast.as_ref().unwrap()["param"]["value"], json!(1.0));
+    let error = queries[2].error.as_ref().unwrap();
+    assert_eq!((error.code, &queries[2].datasource), ("unclosed-paren", &json!("$ds")));
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeSet;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::BUCKET_LABEL;
+use serde_json::{json, Value};
+use crate::ToSerde;
+
+/// Labels an enclosing expression still needs from its operand.
+#[derive(Debug, Clone, PartialEq)]
+enum Needed {
+    All,
+    Only(BTreeSet<String>),
+    AllExcept(BTreeSet<String>),
+}
+
+impl Needed {
+    fn only<'a>(labels: impl IntoIterator<Item = &'a String>) -> Needed {
+        Needed::Only(labels.into_iter().cloned().collect())
+    }
+
+    fn union(&self, other: &Needed) -> Needed {
+        match (self, other) {
+            (Needed::All, _) | (_, Needed::All) => Needed::All,
+            (Needed::Only(a), Needed::Only(b)) =>
+                Needed::Only(a.union(b).cloned().collect()),
+            (Needed::AllExcept(e), Needed::Only(s)) | (Needed::Only(s), Needed::AllExcept(e)) =>
+                Needed::AllExcept(e.difference(s).cloned().collect()),
+            (Needed::AllExcept(a), Needed::AllExcept(b)) =>
+                Needed::AllExcept(a.intersection(b).cloned().collect()),
+        }
+    }
+
+    /// Labels of `grouping` that are dropped under this requirement.
+    fn discarded(&self, grouping: &[String]) -> Vec<String> {
+        let mut labels: Vec<String> = grouping
+            .iter()
+            .filter(|label| match self {
+                Needed::All => false,
+                Needed::Only(kept) => !kept.contains(*label),
+                Needed::AllExcept(dropped) => dropped.contains(*label),
+            })
+            .cloned()
+            .collect();
+        labels.sort();
+        labels.dedup();
+        labels
+    }
+}
+
+/// An inner `by()` grouping whose labels are partly thrown away further out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscardedGrouping {
+    pub op: String,
+    pub expr: String,
+    pub grouping: Vec<String>,
+    pub discarded: Vec<String>,
+    pub suggested: Vec<String>,
+    /// Whether tightening the grouping provably leaves the result unchanged.
+    pub safe: bool,
+}
+
+impl DiscardedGrouping {
+    pub fn message(&self) -> String {
+        format!(
+            "labels ({}) kept by {} are dropped by every outer aggregation; consider `{} by ({})`",
+            self.discarded.join(", "),
+            self.op,
+            self.op,
+            self.suggested.join(", "),
+        )
+    }
+}
+
+impl ToSerde for DiscardedGrouping {
+    fn to_serde(&self) -> Value {
+        json!({
+            "op": self.op,
+            "expr": self.expr,
+            "grouping": self.grouping,
+            "discarded": self.discarded,
+            "suggested": self.suggested,
+            "safe": self.safe,
+            "message": self.message(),
+        })
+    }
+}
+
+/// Functions that pass every input label through unchanged (apart from
+/// `__name__`), so label requirements flow straight to their vector argument.
+fn passes_labels_through(name: &str) -> bool {
+    !matches!(
+        name,
+        "label_replace" | "label_join" | "absent" | "absent_over_time" | "scalar" | "vector"
+            | "sort_by_label" | "sort_by_label_desc"
+    )
+}
+
+/// Whether collapsing labels in an `inner` aggregation directly nested in an
+/// `outer` one cannot change the outer result.
+fn is_safe_collapse(outer: TokenId, inner: TokenId) -> bool {
+    matches!(
+        (outer, inner),
+        (T_SUM, T_SUM) | (T_SUM, T_COUNT) | (T_MIN, T_MIN) | (T_MAX, T_MAX) | (T_GROUP, _)
+    )
+}
+
+struct Walker {
+    findings: Vec<DiscardedGrouping>,
+}
+
+impl Walker {
+    fn walk(&mut self, expr: &Expr, needed: &Needed, enclosing: Option<TokenId>) {
+        match expr {
+            Expr::Aggregate(AggregateExpr { op, expr: inner, param, modifier }) => {
+                if let Some(param) = param {
+                    self.walk(param, &Needed::All, None);
+                }
+                let inner_needed = match (op.id(), modifier) {
+                    // topk/bottomk select whole series, so every input label matters
+                    (T_TOPK | T_BOTTOMK, _) => Needed::All,
+                    (_, Some(LabelModifier::Include(labels))) => {
+                        let discarded = needed.discarded(&labels.labels);
+                        if !discarded.is_empty() {
+                            self.findings.push(DiscardedGrouping {
+                                op: op.to_string(),
+                                expr: expr.to_string(),
+                                grouping: labels.labels.clone(),
+                                suggested: labels
+                                    .labels
+                                    .iter()
+                                    .filter(|label| !discarded.contains(label))
+                                    .cloned()
+                                    .collect(),
+                                discarded,
+                                safe: enclosing
+                                    .map(|outer| is_safe_collapse(outer, op.id()))
+                                    .unwrap_or(false),
+                            });
+                        }
+                        Needed::only(&labels.labels)
+                    }
+                    (_, Some(LabelModifier::Exclude(labels))) =>
+                        Needed::AllExcept(labels.labels.iter().cloned().collect()),
+                    (_, None) => Needed::Only(BTreeSet::new()),
+                };
+                self.walk(inner, &inner_needed, Some(op.id()));
+            }
+            Expr::Paren(ParenExpr { expr }) => self.walk(expr, needed, enclosing),
+            Expr::Unary(UnaryExpr { expr }) => self.walk(expr, needed, None),
+            Expr::Subquery(SubqueryExpr { expr, .. }) => self.walk(expr, needed, None),
+            Expr::Binary(BinaryExpr { lhs, rhs, modifier, .. }) => {
+                let side_needed = match (lhs.value_type(), rhs.value_type()) {
+                    (ValueType::Vector, ValueType::Vector) => match modifier {
+                        Some(BinModifier { matching: Some(LabelModifier::Include(on)), card, .. }) => {
+                            let mut on = Needed::only(&on.labels);
+                            if let Some(extra) = card.labels() {
+                                on = on.union(&Needed::only(&extra.labels));
+                            }
+                            needed.union(&on)
+                        }
+                        _ => Needed::All,
+                    },
+                    _ => needed.clone(),
+                };
+                self.walk(lhs, &side_needed, None);
+                self.walk(rhs, &side_needed, None);
+            }
+            Expr::Call(Call { func, args }) => {
+                let arg_needed = if func.name == "histogram_quantile" {
+                    needed.union(&Needed::Only(BTreeSet::from([BUCKET_LABEL.to_string()])))
+                } else if passes_labels_through(func.name) {
+                    needed.clone()
+                } else {
+                    Needed::All
+                };
+                for (i, arg) in args.args.iter().enumerate() {
+                    // variadic functions repeat their last argument type
+                    match func.arg_types.get(i).or_else(|| func.arg_types.last()) {
+                        Some(ValueType::Vector | ValueType::Matrix) => self.walk(arg, &arg_needed, None),
+                        _ => self.walk(arg, &Needed::All, None),
+                    }
+                }
+            }
+            Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::VectorSelector(_)
+            | Expr::MatrixSelector(_)
+            | Expr::Extension(_) => {}
+        }
+    }
+}
+
+/// Finds `by()` labels of inner aggregations that every enclosing
+/// aggregation drops again, i.e. labels that only inflate intermediate
+/// cardinality.
+pub fn discarded_grouping(expr: &Expr) -> Vec<DiscardedGrouping> {
+    let mut walker = Walker { findings: vec![] };
+    walker.walk(expr, &Needed::All, None);
+    walker.findings
+}
+
+
+#[test]
+fn check_discarded_grouping() {
+    type Finding<'a> = (&'a str, Vec<&'a str>, bool);
+    let cases: Vec<(&str, Vec<Finding>)> = vec![
+        ("sum(sum by (a, b) (x))", vec![("sum", vec!["a", "b"], true)]),
+        ("sum by (a) (rate(x[5m]))", vec![]),
+        ("max by (a) (sum by (a, b) (x))", vec![("sum", vec!["b"], false)]),
+        ("sum without (b) ((sum by (a, b) (x)))", vec![("sum", vec!["b"], true)]),
+        ("histogram_quantile(0.9, sum by (le, job) (rate(x[5m])))", vec![]),
+        ("sum by (job) (histogram_quantile(0.9, sum by (le, job, pod) (rate(x[5m]))))",
+            vec![("sum", vec!["pod"], false)]),
+        ("sum(sum by (a) (x) / on(a) sum by (a, b) (y))", vec![("sum", vec!["b"], false)]),
+        ("sum(sum by (a) (x) / sum by (a, b) (y))", vec![]),
+        ("sum(topk by (a) (3, sum by (a, b) (x)))", vec![]),
+    ];
+    for (query, expected) in cases {
+        let found: Vec<(String, Vec<String>, bool)> = discarded_grouping(&parse(query).unwrap())
+            .into_iter()
+            .map(|f| (f.op, f.discarded, f.safe))
+            .collect();
+        let expected: Vec<(String, Vec<String>, bool)> = expected
+            .into_iter()
+            .map(|(op, labels, safe)| (op.to_string(), labels.iter().map(|l| l.to_string()).collect(), safe))
+            .collect();
+        assert_eq!(found, expected, "{}", query);
+    }
+}
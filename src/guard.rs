@@ -0,0 +1,76 @@
+use std::collections::BTreeSet;
+use promql_parser::label::{BUCKET_LABEL, METRIC_NAME};
+use serde::Deserialize;
+
+/// Labels no rewrite may touch without being told to.
+pub const ALWAYS_RESERVED: [&str; 3] = [METRIC_NAME, BUCKET_LABEL, "quantile"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardMode {
+    /// Leave the query alone where a rewrite would touch a reserved label.
+    #[default]
+    Refuse,
+    /// Rewrite anyway and report a warning.
+    Warn,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct GuardConfig {
+    /// Reserved in addition to `ALWAYS_RESERVED`, e.g. `job` and `instance`.
+    #[serde(default)]
+    pub reserved: Vec<String>,
+    #[serde(default)]
+    pub mode: GuardMode,
+}
+
+/// Decides whether transforms may inject, remove or rename a label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelGuard {
+    reserved: BTreeSet<String>,
+    mode: GuardMode,
+}
+
+impl Default for LabelGuard {
+    fn default() -> LabelGuard {
+        LabelGuard::new(&GuardConfig::default())
+    }
+}
+
+impl LabelGuard {
+    pub fn new(config: &GuardConfig) -> LabelGuard {
+        let mut reserved: BTreeSet<String> = ALWAYS_RESERVED.iter().map(|l| l.to_string()).collect();
+        reserved.extend(config.reserved.iter().cloned());
+        LabelGuard { reserved, mode: config.mode }
+    }
+
+    pub fn is_reserved(&self, label: &str) -> bool {
+        self.reserved.contains(label)
+    }
+
+    /// Checks a rewrite that would `action` (e.g. "remove") `labels`.
+    ///
+    /// Returns the warnings to report if the rewrite may go ahead, or why it
+    /// must not.
+    pub fn check<'a>(&self, action: &str, labels: impl IntoIterator<Item = &'a str>) -> Result<Vec<String>, String> {
+        let touched: Vec<&str> = labels.into_iter().filter(|label| self.is_reserved(label)).collect();
+        if touched.is_empty() {
+            return Ok(vec![]);
+        }
+        let message = format!("would {} reserved label(s) {}", action, touched.join(", "));
+        match self.mode {
+            GuardMode::Refuse => Err(message),
+            GuardMode::Warn => Ok(vec![message]),
+        }
+    }
+}
+
+
+#[test]
+fn check_guard() {
+    let guard = LabelGuard::default();
+    assert_eq!(guard.check("remove", ["job", "pod"]), Ok(vec![]));
+    assert!(guard.check("rename", ["le"]).is_err());
+    let guard = LabelGuard::new(&GuardConfig { reserved: vec!["job".to_string()], mode: GuardMode::Warn });
+    assert_eq!(guard.check("inject", ["job", "pod"]).unwrap().len(), 1);
+}
@@ -0,0 +1,96 @@
+use promql_parser::parser::*;
+use serde_json::{json, Value};
+use crate::catalog::{aggregators, functions, Entry};
+use crate::explain::explain;
+use crate::spans::{spans, Span, SpanTree};
+use crate::visit::{children, node_type};
+use crate::ToSerde;
+
+/// The innermost node at an offset of a query, for hover tooltips.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hover {
+    /// `visit::node_type`, the `@type` of the node in the AST.
+    pub node_type: &'static str,
+    pub value_type: ValueType,
+    pub span: Span,
+    pub text: String,
+    /// What the node computes, as `explain` describes it.
+    pub description: String,
+    /// Of a function call or aggregation, as in the Prometheus docs.
+    pub signature: Option<String>,
+    /// The nodes containing it, outermost first, e.g. to grow a selection.
+    pub ancestors: Vec<(&'static str, Span)>,
+}
+
+impl ToSerde for Hover {
+    fn to_serde(&self) -> Value {
+        let ancestors: Vec<Value> = self
+            .ancestors
+            .iter()
+            .map(|(node_type, span)| json!({ "type": node_type, "start": span.start, "end": span.end }))
+            .collect();
+        json!({
+            "type": self.node_type,
+            "value_type": self.value_type.to_serde(),
+            "start": self.span.start,
+            "end": self.span.end,
+            "text": self.text,
+            "description": self.description,
+            "signature": self.signature,
+            "ancestors": ancestors,
+        })
+    }
+}
+
+fn signature(expr: &Expr) -> Option<String> {
+    let (name, entries) = match expr {
+        Expr::Call(Call { func, .. }) => (func.name.to_string(), functions()),
+        Expr::Aggregate(AggregateExpr { op, .. }) => (op.to_string(), aggregators()),
+        _ => return None,
+    };
+    entries.into_iter().find(|entry| entry.name == name).map(|Entry { signature, note, .. }| match note {
+        Some(note) => format!("{} ({})", signature, note),
+        None => signature,
+    })
+}
+
+/// The innermost node of `expr`, parsed from `query`, whose span has byte
+/// `offset` in it; `None` outside of the query's nodes, as in a trailing
+/// comment.
+pub fn hover(query: &str, expr: &Expr, offset: usize) -> Option<Hover> {
+    let inside = |tree: &SpanTree| tree.span.start <= offset && offset < tree.span.end;
+    let root = spans(query, expr).filter(|tree| inside(tree))?;
+    let (mut expr, mut tree, mut ancestors) = (expr, &root, vec![]);
+    while let Some((child, child_tree)) = children(expr).into_iter().zip(&tree.children).find(|(_, tree)| inside(tree)) {
+        ancestors.push((node_type(expr), tree.span));
+        (expr, tree) = (child, child_tree);
+    }
+    Some(Hover {
+        node_type: node_type(expr),
+        value_type: expr.value_type(),
+        span: tree.span,
+        text: query[tree.span.start..tree.span.end].to_string(),
+        description: explain(expr),
+        signature: signature(expr),
+        ancestors,
+    })
+}
+
+
+#[test]
+fn check_hover() {
+    let query = "sum by (job) (rate(http_requests_total{code=\"200\"}[5m])) > 0.95 # slow";
+    let expr = crate::errors::try_parse(query).unwrap();
+    let rate = hover(query, &expr, 15).unwrap();
+    assert_eq!((rate.node_type, rate.value_type, rate.span), ("call", ValueType::Vector, Span { start: 14, end: 55 }));
+    assert_eq!(rate.signature.as_deref(), Some("rate(v range-vector)"));
+    assert_eq!(rate.description, "the 5-minute rate of http_requests_total where code=200");
+    assert_eq!(rate.ancestors, [("binary", Span { start: 0, end: 63 }), ("aggregate", Span { start: 0, end: 56 })]);
+    let selector = hover(query, &expr, 40).unwrap();
+    assert_eq!((selector.node_type, selector.text.as_str()), ("matrix_selector", "http_requests_total{code=\"200\"}[5m]"));
+    let sum = hover(query, &expr, 5).unwrap();
+    assert_eq!(sum.signature.as_deref(), Some("sum(v instant-vector)"));
+    assert_eq!(hover(query, &expr, 57).unwrap().node_type, "binary");
+    assert_eq!(hover(query, &expr, 60).unwrap().text, "0.95");
+    assert_eq!(hover(query, &expr, 65), None);
+}
@@ -0,0 +1,117 @@
+use promql_parser::parser::{Expr, ValueType};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::ToSerde;
+
+/// Where and when to run a query, times in milliseconds since the epoch.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ApiOptions {
+    /// Prometheus base URL, as in `http://localhost:9090`; empty for a
+    /// relative URL.
+    pub base_url: String,
+    pub time: Option<f64>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    /// Resolution of a range query, in milliseconds.
+    pub step: Option<f64>,
+    /// `timeout` parameter, as in `30s`.
+    pub timeout: Option<String>,
+}
+
+/// A request to the Prometheus HTTP API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiRequest {
+    pub endpoint: &'static str,
+    /// Query parameters, in order.
+    pub params: Vec<(&'static str, String)>,
+    pub url: String,
+}
+
+impl ToSerde for ApiRequest {
+    fn to_serde(&self) -> Value {
+        let params: serde_json::Map<String, Value> =
+            self.params.iter().map(|(name, value)| (name.to_string(), json!(value))).collect();
+        json!({
+            "endpoint": self.endpoint,
+            "params": params,
+            "url": self.url,
+        })
+    }
+}
+
+/// `text` percent-encoded for a query string, everything but unreserved
+/// characters escaped.
+pub fn encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Milliseconds as the seconds the API takes, with a fraction if needed.
+fn seconds(millis: f64) -> String {
+    (millis.round() / 1000.0).to_string()
+}
+
+/// The `/api/v1/query` or `/api/v1/query_range` request running `query`.
+/// Range queries need `start`, `end` and `step`; queries returning a range
+/// vector or a string, which `query_range` rejects, run instantly at `time`,
+/// or at `end` if only a range is given.
+pub fn request(query: &str, expr: &Expr, options: &ApiOptions) -> Result<ApiRequest, String> {
+    let instant_only = matches!(expr.value_type(), ValueType::Matrix | ValueType::String);
+    let mut params = vec![("query", query.to_string())];
+    let endpoint = match (options.time, options.start, options.end) {
+        (None, Some(start), Some(end)) if !instant_only => {
+            if start > end {
+                return Err("`start` is after `end`".to_string());
+            }
+            let step = options.step.filter(|step| *step > 0.0).ok_or("a range query needs a positive `step`")?;
+            params.push(("start", seconds(start)));
+            params.push(("end", seconds(end)));
+            params.push(("step", seconds(step)));
+            "/api/v1/query_range"
+        }
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) =>
+            return Err("give either `time`, or `start` and `end`".to_string()),
+        (time, _, end) => {
+            if let Some(time) = time.or(end) {
+                params.push(("time", seconds(time)));
+            }
+            "/api/v1/query"
+        }
+    };
+    if let Some(timeout) = &options.timeout {
+        params.push(("timeout", timeout.clone()));
+    }
+    let pairs: Vec<String> = params.iter().map(|(name, value)| format!("{}={}", name, encode(value))).collect();
+    let url = format!("{}{}?{}", options.base_url.trim_end_matches('/'), endpoint, pairs.join("&"));
+    Ok(ApiRequest { endpoint, params, url })
+}
+
+
+#[test]
+fn check_http_api() {
+    use promql_parser::parser::parse;
+    let url = |query: &str, options: ApiOptions| request(query, &parse(query).unwrap(), &options).map(|r| r.url);
+    let range = ApiOptions {
+        base_url: "http://prom:9090/".to_string(),
+        start: Some(1_700_000_000_000.0),
+        end: Some(1_700_003_600_500.0),
+        step: Some(60_000.0),
+        ..ApiOptions::default()
+    };
+    assert_eq!(
+        url("sum by (job) (rate(x{a=\"b c\"}[5m]))", range.clone()),
+        Ok("http://prom:9090/api/v1/query_range?query=sum%20by%20%28job%29%20%28rate%28x%7Ba%3D%22b%20c%22%7D%5B5m%5D%29%29&start=1700000000&end=1700003600.5&step=60".to_string()),
+    );
+    assert_eq!(url("x[5m]", range.clone()), Ok("http://prom:9090/api/v1/query?query=x%5B5m%5D&time=1700003600.5".to_string()));
+    let instant = ApiOptions { time: Some(1500.0), timeout: Some("30s".to_string()), ..ApiOptions::default() };
+    assert_eq!(url("up", instant), Ok("/api/v1/query?query=up&time=1.5&timeout=30s".to_string()));
+    assert_eq!(url("up", ApiOptions::default()), Ok("/api/v1/query?query=up".to_string()));
+    assert!(url("up", ApiOptions { step: None, ..range }).is_err());
+}
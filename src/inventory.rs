@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use promql_parser::parser;
+use promql_parser::label::*;
+use serde_json::{json, Map, Value};
+use crate::literals::analyze;
+use crate::matchers::{by_label, selectors};
+use crate::ToSerde;
+
+/// Where a single label value is referenced in the corpus.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValueUse {
+    /// Number of matchers referencing the value.
+    pub count: usize,
+    /// Queries referencing the value, in corpus order.
+    pub queries: Vec<String>,
+}
+
+/// A query of the corpus that could not be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusError {
+    pub index: usize,
+    pub query: String,
+    pub message: String,
+}
+
+impl ToSerde for CorpusError {
+    fn to_serde(&self) -> Value {
+        json!({
+            "index": self.index,
+            "query": self.query,
+            "message": self.message,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inventory {
+    pub labels: BTreeMap<String, BTreeMap<String, ValueUse>>,
+    pub errors: Vec<CorpusError>,
+}
+
+impl ToSerde for Inventory {
+    fn to_serde(&self) -> Value {
+        let labels: Map<String, Value> = self
+            .labels
+            .iter()
+            .map(|(label, values)| {
+                let values: Vec<Value> = values
+                    .iter()
+                    .map(|(value, uses)| json!({
+                        "value": value,
+                        "count": uses.count,
+                        "queries": uses.queries,
+                    }))
+                    .collect();
+                (label.clone(), json!(values))
+            })
+            .collect();
+        json!({
+            "labels": labels,
+            "errors": self.errors.to_serde(),
+        })
+    }
+}
+
+/// Literal values a matcher refers to: the value of (in)equality matchers and
+/// the alternatives of regexes that only match a finite set of values.
+fn referenced_values(matcher: &Matcher) -> Vec<String> {
+    let values = match matcher.op {
+        MatchOp::Equal | MatchOp::NotEqual => vec![matcher.value.clone()],
+        MatchOp::Re(_) | MatchOp::NotRe(_) => analyze(&matcher.value)
+            .and_then(|literals| literals.alternatives)
+            .unwrap_or_default(),
+    };
+    // an empty value only asks for the label to be absent
+    values.into_iter().filter(|value| !value.is_empty()).collect()
+}
+
+impl Inventory {
+    fn add(&mut self, query: &str, label: &str, value: String) {
+        let uses = self.labels.entry(label.to_string()).or_default().entry(value).or_default();
+        uses.count += 1;
+        if uses.queries.last().map(String::as_str) != Some(query) {
+            uses.queries.push(query.to_string());
+        }
+    }
+}
+
+/// Collects, per label name, every literal value referenced by the matchers
+/// of `queries`. Queries that fail to parse are reported, not fatal.
+pub fn label_values(queries: &[String]) -> Inventory {
+    let mut inventory = Inventory::default();
+    for (index, query) in queries.iter().enumerate() {
+        let expr = match parser::parse(query) {
+            Ok(expr) => expr,
+            Err(message) => {
+                inventory.errors.push(CorpusError { index, query: query.clone(), message });
+                continue;
+            }
+        };
+        for vs in selectors(&expr) {
+            for (label, matchers) in by_label(&vs) {
+                for matcher in &matchers {
+                    for value in referenced_values(matcher) {
+                        inventory.add(query, &label, value);
+                    }
+                }
+            }
+        }
+    }
+    inventory
+}
+
+
+#[test]
+fn check_label_values() {
+    let queries: Vec<String> = vec![
+        "sum by (namespace) (up{cluster=\"eu-1\", namespace=~\"a|b\"})",
+        "up{cluster=\"eu-1\"} / up{cluster=\"eu-1\", job!=\"\"}",
+        "rate(http_requests_total{cluster=~\"us-.*\"}[5m])",
+        "up{",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let inventory = label_values(&queries);
+    let cluster = &inventory.labels["cluster"];
+    assert_eq!(cluster.len(), 1);
+    assert_eq!(cluster["eu-1"].count, 3);
+    assert_eq!(cluster["eu-1"].queries, queries[..2].to_vec());
+    assert_eq!(inventory.labels["namespace"].keys().collect::<Vec<_>>(), vec!["a", "b"]);
+    assert_eq!(inventory.labels[METRIC_NAME]["up"].count, 3);
+    assert!(!inventory.labels.contains_key("job"));
+    assert_eq!(inventory.errors.len(), 1);
+    assert_eq!(inventory.errors[0].index, 3);
+}
@@ -0,0 +1,101 @@
+use promql_parser::parser::Expr;
+use wasm_bindgen::prelude::*;
+use crate::options::{self, SerializeOptions};
+use crate::serialize::Node;
+use crate::spans::{spans, SpanTree};
+use crate::visit::{children, node_type};
+use crate::{errors, extract, ToSerde};
+
+/// The sub-expression of `expr` at `path`, indices into `visit::children`
+/// from the root down, with its spans.
+pub fn descend<'a>(expr: &'a Expr, tree: Option<&'a SpanTree>, path: &[usize]) -> Option<(&'a Expr, Option<&'a SpanTree>)> {
+    path.iter().try_fold((expr, tree), |(expr, tree), &i| {
+        let child = children(expr).into_iter().nth(i)?;
+        Some((child, tree.and_then(|tree| tree.children.get(i))))
+    })
+}
+
+/// Fails for `options` giving another tree than the native PromQL one a
+/// `PromqlAst` of `query` holds.
+pub fn check_options(query: &str, options: &SerializeOptions) -> Result<(), errors::ParseError> {
+    let native = SerializeOptions { omit_nulls: false, keys: options::KeyCase::Snake, ..options.clone() };
+    if !native.is_direct() {
+        let message = "PromqlAst holds native PromQL trees, without dialect, output, variables or duration_expressions";
+        return Err(errors::located(query, message.to_string(), "invalid-options", 0, 0));
+    }
+    Ok(())
+}
+
+/// A parsed PromQL query held in Rust, of which only the parts asked for are
+/// serialized, for callers that need a few fields of large trees.
+#[wasm_bindgen]
+pub struct PromqlAst {
+    query: String,
+    expr: Expr,
+    tree: Option<SpanTree>,
+    options: SerializeOptions,
+}
+
+#[wasm_bindgen]
+impl PromqlAst {
+    /// Parses `query` with the `promql_parse_with_options` options, except
+    /// those giving another tree: `dialect`, `output`, `variables` and
+    /// `duration_expressions`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(query: String, options: JsValue) -> Result<PromqlAst, JsValue> {
+        let options: SerializeOptions = crate::from_js::<Option<_>>(options)?.unwrap_or_default();
+        check_options(&query, &options).map_err(crate::js_error)?;
+        let expr = crate::parse_extended(&query, &options).map_err(crate::js_error)?;
+        let tree = spans(&query, &expr);
+        Ok(PromqlAst { query, expr, tree, options })
+    }
+
+    /// The `@type` of the root node, e.g. `"aggregate"`.
+    #[wasm_bindgen(js_name = rootKind)]
+    pub fn root_kind(&self) -> String {
+        node_type(&self.expr).to_string()
+    }
+
+    /// Every selector, as `promql_selectors` lists them.
+    pub fn selectors(&self) -> JsValue {
+        let selectors = options::with_options(self.options.clone(), || extract::flat_selectors(&self.query, &self.expr).to_serde());
+        crate::options_to_js(&selectors, &self.options)
+    }
+
+    /// The node at `path`, child indices from the root down as the `expr`,
+    /// `lhs`, `rhs`, `param` and `args` of the tree order them (`[]` for the
+    /// root), serialized as `promql_parse_with_options` would; `null` if there
+    /// is none.
+    #[wasm_bindgen(js_name = childAt)]
+    pub fn child_at(&self, path: Vec<usize>) -> JsValue {
+        let (expr, tree) = match descend(&self.expr, self.tree.as_ref(), &path) {
+            Some(found) => found,
+            None => return JsValue::NULL,
+        };
+        let node = Node::new(&self.query, expr, tree, &self.options);
+        options::with_options(self.options.clone(), || {
+            if self.options.is_direct() {
+                crate::encode(&self.query, &node, &self.options).unwrap_or(JsValue::NULL)
+            } else {
+                let mut value = serde_json::to_value(node).unwrap_or_default();
+                self.options.reshape(&mut value);
+                crate::options_to_js(&value, &self.options)
+            }
+        })
+    }
+}
+
+
+#[test]
+fn check_descend() {
+    let query = "sum by (job) (rate(x[5m])) / on (job) topk(3, y)";
+    let expr = errors::try_parse(query).unwrap();
+    let tree = spans(query, &expr);
+    let text = |path: &[usize]| descend(&expr, tree.as_ref(), path).map(|(e, t)| (node_type(e), &query[t.unwrap().span.start..t.unwrap().span.end]));
+    assert_eq!(text(&[]), Some(("binary", query)));
+    assert_eq!(text(&[0, 0, 0]), Some(("matrix_selector", "x[5m]")));
+    assert_eq!(text(&[1, 0]), Some(("number", "3")));
+    assert_eq!(text(&[1, 1]), Some(("vector_selector", "y")));
+    assert_eq!(text(&[1, 2]), None);
+    assert_eq!(text(&[0, 0, 0, 0]), None);
+}
@@ -0,0 +1,185 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use serde_json::{json, Value};
+use crate::errors::{located, try_parse, ParseError};
+use crate::lexemes::{lex, Lexeme};
+use crate::printer::precedence;
+use crate::spans::{annotate, spans};
+use crate::ToSerde;
+
+/// What could be parsed of a query, and what was wrong with it.
+#[derive(Debug, Clone)]
+pub struct Lenient {
+    pub expr: Option<Expr>,
+    /// The text `expr` was parsed from: the query up to the last token that
+    /// could be kept, plus whatever closes its open strings and brackets.
+    pub text: String,
+    pub diagnostics: Vec<ParseError>,
+}
+
+impl ToSerde for Lenient {
+    fn to_serde(&self) -> Value {
+        let ast = self.expr.as_ref().map(|expr| {
+            let mut ast = expr.to_serde();
+            if let Some(tree) = spans(&self.text, expr) {
+                annotate(&mut ast, &tree);
+            }
+            ast
+        });
+        json!({
+            "ast": ast,
+            "text": self.text,
+            "diagnostics": self.diagnostics.to_serde(),
+        })
+    }
+}
+
+/// The strings, comment and brackets `text` leaves open, outermost first,
+/// with their byte offset.
+fn unclosed(text: &str) -> Vec<(usize, char)> {
+    let mut open = vec![];
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' | '`' => loop {
+                match chars.next() {
+                    Some((_, '\\')) if c != '`' => {
+                        chars.next();
+                    }
+                    Some((_, quote)) if quote == c => break,
+                    Some(_) => {}
+                    None => {
+                        open.push((i, c));
+                        return open;
+                    }
+                }
+            },
+            '#' if !chars.any(|(_, c)| c == '\n') => {
+                open.push((i, c));
+                return open;
+            }
+            '(' | '{' | '[' => open.push((i, c)),
+            ')' | '}' | ']' => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    open
+}
+
+/// What has to be appended to `text` to close its open string, comment and
+/// brackets, innermost first.
+pub fn closing(text: &str) -> String {
+    unclosed(text)
+        .iter()
+        .rev()
+        .map(|(_, c)| match c {
+            '(' => ')',
+            '{' => '}',
+            '[' => ']',
+            '#' => '\n',
+            quote => *quote,
+        })
+        .collect()
+}
+
+/// Whether keeping the query up to lexeme `last` and dropping `dropped`
+/// would change what it means rather than just lose its end: turn a call or
+/// aggregation into a selector of a metric named like it, or drop a binary
+/// operation along with its right operand.
+fn misleading(last: Lexeme, dropped: &[Lexeme]) -> bool {
+    let aggregator = last.id > T_AGGREGATORS_START && last.id < T_AGGREGATORS_END;
+    let call = last.id == T_IDENTIFIER && dropped.first().is_some_and(|next| next.id == T_LEFT_PAREN);
+    aggregator || call || dropped.iter().any(|lexeme| precedence(lexeme.id) > 0)
+}
+
+/// Recovers from the error by dropping tokens from the end of the query, up
+/// to where the error was found, until what is left parses once its strings
+/// and brackets are closed, and keeps the same meaning as far as it goes.
+/// The expression, the text it was parsed from and the length of the part
+/// of `query` kept in it.
+fn recover(query: &str, error: &ParseError) -> Option<(Expr, String, usize)> {
+    // an unterminated string is reported at its opening quote, but can be kept
+    let head = if error.code == "unterminated-string" { query } else { &query[..error.start] };
+    let lexemes = lex(&format!("{}{}", query, closing(query)))
+        .or_else(|_| lex(&format!("{}{}", head, closing(head))))
+        .unwrap_or_default();
+    let mut cuts: Vec<usize> = lexemes.iter().map(|l| l.end).filter(|end| *end < head.len()).collect();
+    cuts.push(head.len());
+    cuts.dedup();
+    cuts.into_iter().rev().find_map(|cut| {
+        let prefix = query[..cut].trim_end();
+        let last = lexemes.iter().rev().find(|l| l.end <= prefix.len());
+        let dropped: Vec<Lexeme> = lexemes.iter().filter(|l| l.start >= prefix.len() && l.start < head.len()).copied().collect();
+        if last.is_some_and(|last| misleading(*last, &dropped)) {
+            return None;
+        }
+        let text = format!("{}{}", prefix, closing(prefix));
+        parse(&text).ok().map(|expr| (expr, text, prefix.len()))
+    })
+}
+
+/// The error of `query`, then one per string and bracket it never closes
+/// that the recovery, keeping its first `kept` bytes, had to.
+fn diagnostics(query: &str, error: ParseError, kept: usize) -> Vec<ParseError> {
+    let mut diagnostics = vec![error];
+    let never_closed = unclosed(query);
+    for (start, c) in unclosed(&query[..kept]).into_iter().filter(|open| never_closed.contains(open)) {
+        let (code, what) = match c {
+            '(' => ("unclosed-paren", "parenthesis"),
+            '{' => ("unclosed-brace", "brace"),
+            '[' => ("unclosed-bracket", "bracket"),
+            '#' => continue,
+            _ => ("unterminated-string", "string"),
+        };
+        if !diagnostics.iter().any(|d| d.code == code && d.start == start) {
+            let message = format!("{} opened here is never closed", what);
+            diagnostics.push(located(query, message, code, start, start + 1));
+        }
+    }
+    diagnostics
+}
+
+/// Parses `query`, and if that fails, the largest part of it that still
+/// parses, reporting the error, and every bracket and string the recovery
+/// had to close, as diagnostics instead of failing.
+pub fn parse_lenient(query: &str) -> Lenient {
+    match try_parse(query) {
+        Ok(expr) => Lenient { expr: Some(expr), text: query.to_string(), diagnostics: vec![] },
+        Err(error) => match recover(query, &error) {
+            Some((expr, text, kept)) => Lenient { expr: Some(expr), text, diagnostics: diagnostics(query, error, kept) },
+            None => Lenient { expr: None, text: String::new(), diagnostics: vec![error] },
+        },
+    }
+}
+
+
+#[test]
+fn check_parse_lenient() {
+    let payloads = vec![
+        ("sum(rate(x[5m]))", Some("sum(rate(x[5m]))"), vec![]),
+        ("sum(rate(x[5m])", Some("sum(rate(x[5m]))"), vec!["unclosed-paren", "unclosed-paren"]),
+        ("sum(rate(x[5m]", Some("sum(rate(x[5m]))"), vec!["unclosed-paren", "unclosed-paren", "unclosed-paren"]),
+        ("sum(x{a=\"b", Some("sum(x{a=\"b\"})"), vec!["unterminated-string", "unclosed-paren", "unclosed-brace"]),
+        ("sum by (a) (x) + * y", None, vec!["invalid-syntax"]),
+        ("1 + foo(x)", None, vec!["unknown-function"]),
+        ("x offset", Some("x"), vec!["unexpected-end"]),
+        ("x{a=1}", Some("x{}"), vec!["unexpected-character"]),
+        ("(x # comment", Some("(x # comment\n)"), vec!["unclosed-paren", "unclosed-paren"]),
+        ("sum(", None, vec!["unclosed-paren"]),
+        ("rate(x[5m]) > on(", None, vec!["unclosed-paren"]),
+        ("rate(x[5m]) offset", Some("rate(x[5m])"), vec!["unexpected-end"]),
+        ("(", None, vec!["unclosed-paren"]),
+    ];
+    for (query, text, codes) in payloads {
+        let lenient = parse_lenient(query);
+        assert_eq!(lenient.expr.is_some(), text.is_some(), "{}", query);
+        assert_eq!(Some(lenient.text.as_str()).filter(|_| lenient.expr.is_some()), text, "{}", query);
+        assert_eq!(lenient.diagnostics.iter().map(|d| d.code).collect::<Vec<_>>(), codes, "{}", query);
+    }
+    let starts: Vec<usize> = parse_lenient("sum(x{a=\"b").diagnostics.iter().map(|d| d.start).collect();
+    assert_eq!(starts, vec![8, 3, 5]);
+    let ast = parse_lenient("sum(rate(x[5m])").to_serde()["ast"].clone();
+    assert_eq!((&ast["@type"], &ast["expr"]["end"]), (&json!("aggregate"), &json!(15)));
+}
@@ -0,0 +1,146 @@
+use lrpar::{Lexeme as _, Lexer as _};
+use promql_parser::parser::lexer;
+use promql_parser::parser::token::*;
+
+/// A token of a query and its byte range in the query text, quotes included
+/// for strings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lexeme {
+    pub id: TokenId,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Lexeme {
+    pub fn text<'a>(&self, query: &'a str) -> &'a str {
+        &query[self.start..self.end]
+    }
+}
+
+/// Coarse classification of a token, e.g. for highlighting.
+pub fn kind(id: TokenId) -> &'static str {
+    match id {
+        T_IDENTIFIER | T_METRIC_IDENTIFIER => "identifier",
+        T_NUMBER => "number",
+        T_DURATION => "duration",
+        T_STRING => "string",
+        _ if id > T_OPERATORS_START && id < T_OPERATORS_END => "operator",
+        _ if id > T_AGGREGATORS_START && id < T_AGGREGATORS_END => "aggregator",
+        _ if id > T_KEYWORDS_START && id < T_KEYWORDS_END => "keyword",
+        _ if id > T_PREPROCESSOR_START && id < T_PREPROCESSOR_END => "keyword",
+        _ => "punctuation",
+    }
+}
+
+/// Name of a token type, the Prometheus item type in lowercase.
+pub fn name(id: TokenId) -> &'static str {
+    match id {
+        T_EQL => "eql",
+        T_BLANK => "blank",
+        T_COLON => "colon",
+        T_COMMA => "comma",
+        T_COMMENT => "comment",
+        T_DURATION => "duration",
+        T_IDENTIFIER => "identifier",
+        T_METRIC_IDENTIFIER => "metric_identifier",
+        T_LEFT_BRACE => "left_brace",
+        T_LEFT_BRACKET => "left_bracket",
+        T_LEFT_PAREN => "left_paren",
+        T_RIGHT_BRACE => "right_brace",
+        T_RIGHT_BRACKET => "right_bracket",
+        T_RIGHT_PAREN => "right_paren",
+        T_NUMBER => "number",
+        T_SEMICOLON => "semicolon",
+        T_SPACE => "space",
+        T_STRING => "string",
+        T_TIMES => "times",
+        T_ADD => "add",
+        T_DIV => "div",
+        T_EQLC => "eqlc",
+        T_EQL_REGEX => "eql_regex",
+        T_GTE => "gte",
+        T_GTR => "gtr",
+        T_LAND => "land",
+        T_LOR => "lor",
+        T_LSS => "lss",
+        T_LTE => "lte",
+        T_LUNLESS => "lunless",
+        T_MOD => "mod",
+        T_MUL => "mul",
+        T_NEQ => "neq",
+        T_NEQ_REGEX => "neq_regex",
+        T_POW => "pow",
+        T_SUB => "sub",
+        T_AT => "at",
+        T_ATAN2 => "atan2",
+        T_AVG => "avg",
+        T_BOTTOMK => "bottomk",
+        T_COUNT => "count",
+        T_COUNT_VALUES => "count_values",
+        T_GROUP => "group",
+        T_MAX => "max",
+        T_MIN => "min",
+        T_QUANTILE => "quantile",
+        T_STDDEV => "stddev",
+        T_STDVAR => "stdvar",
+        T_SUM => "sum",
+        T_TOPK => "topk",
+        T_BOOL => "bool",
+        T_BY => "by",
+        T_GROUP_LEFT => "group_left",
+        T_GROUP_RIGHT => "group_right",
+        T_IGNORING => "ignoring",
+        T_OFFSET => "offset",
+        T_ON => "on",
+        T_WITHOUT => "without",
+        T_START => "start",
+        T_END => "end",
+        _ => "unknown",
+    }
+}
+
+pub fn lex(query: &str) -> Result<Vec<Lexeme>, String> {
+    let lexemes = lexer(query)?;
+    Ok(lexemes
+        .iter()
+        .filter_map(|lexeme| lexeme.ok())
+        .filter(|lexeme| lexeme.tok_id() != T_EOF)
+        .map(|lexeme| {
+            let (start, end) = (lexeme.span().start(), lexeme.span().end());
+            match lexeme.tok_id() {
+                // the lexer leaves the (single byte) quotes out of string spans
+                T_STRING => Lexeme { id: T_STRING, start: start - 1, end: end + 1 },
+                id => Lexeme { id, start, end },
+            }
+        })
+        .collect())
+}
+
+/// Lexemes outside of label matcher braces, where operators are binary
+/// operators and identifiers name metrics and functions.
+pub fn outside_braces(lexemes: &[Lexeme]) -> Vec<Lexeme> {
+    let mut depth = 0usize;
+    let mut outside = vec![];
+    for lexeme in lexemes {
+        match lexeme.id {
+            T_LEFT_BRACE => depth += 1,
+            T_RIGHT_BRACE => depth = depth.saturating_sub(1),
+            _ if depth == 0 => outside.push(*lexeme),
+            _ => {}
+        }
+    }
+    outside
+}
+
+
+#[test]
+fn check_lex() {
+    let query = "sum(rate(foo{bar=~\"b.z\"}[5m])) > 1";
+    let lexemes = lex(query).unwrap();
+    let regex = lexemes.iter().find(|l| l.id == T_EQL_REGEX).unwrap();
+    assert_eq!(regex.text(query), "=~");
+    let string = lexemes.iter().find(|l| l.id == T_STRING).unwrap();
+    assert_eq!(string.text(query), "\"b.z\"");
+    let outside: Vec<&str> = outside_braces(&lexemes).iter().map(|l| l.text(query)).collect();
+    assert_eq!(outside, vec!["sum", "(", "rate", "(", "foo", "[", "5m", "]", ")", ")", ">", "1"]);
+}
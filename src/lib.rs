@@ -1,13 +1,90 @@
 extern crate promql_parser;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+#[cfg(test)]
 use promql_parser::parser;
 use promql_parser::parser::*;
 use promql_parser::label::*;
 use std::time::{Duration, SystemTime};
 use serde_json::{json, Value};
-use iso8601_timestamp::Timestamp;
 use serde::ser::Serialize;
 
+mod ast_path;
+mod builder;
+mod canonical;
+mod cardinality;
+mod catalog;
+mod clickhouse;
+mod completion;
+mod cost;
+mod cst;
+mod dependencies;
+mod dialects;
+mod diagram;
+mod diff;
+mod duration_exprs;
+mod edits;
+mod emptiness;
+mod errors;
+mod events;
+mod explain;
+mod experimental;
+mod extract;
+mod format;
+mod generate;
+mod grafana;
+mod grouping;
+mod guard;
+mod hover;
+mod http_api;
+mod inventory;
+mod lazy;
+mod lenient;
+mod lexemes;
+mod limits;
+mod lint;
+mod literals;
+mod logql;
+mod lookback;
+mod matchers;
+mod metricsql;
+#[cfg(feature = "napi")]
+mod native;
+mod options;
+mod output_labels;
+mod panics;
+mod planning;
+mod printer;
+mod pseudonymize;
+mod quoted;
+mod recording;
+mod regexes;
+mod rewrite;
+mod rules;
+mod rules_yaml;
+mod sarif;
+mod schema;
+mod semantic;
+mod serialize;
+mod sharding;
+mod simplify;
+mod spans;
+mod split;
+mod stats;
+mod substitute;
+mod suggest;
+mod templates;
+mod timestamps;
+mod timing;
+mod tokens;
+mod translate;
+mod typecheck;
+mod typescript;
+mod unparse;
+mod variables;
+mod visit;
+mod visual;
+
 trait ToSerde {
     fn to_serde(&self) -> Value;
 }
@@ -51,6 +128,14 @@ impl ToSerde for bool {
     }
 }
 
+impl ToSerde for f64 {
+    /// JSON has no NaN or infinities, so those are written as the PromQL
+    /// literals `"NaN"`, `"Inf"` and `"-Inf"`.
+    fn to_serde(&self) -> Value {
+        if self.is_finite() { json!(self) } else { json!(printer::number(*self)) }
+    }
+}
+
 impl ToSerde for TokenType {
     fn to_serde(&self) -> Value {
         json!(self.to_string())
@@ -60,21 +145,21 @@ impl ToSerde for TokenType {
 impl ToSerde for Offset {
     fn to_serde(&self) -> Value {
         match self {
-            Offset::Pos(dur) => dur.to_serde(),
-            Offset::Neg(dur) => json!(dur.as_secs() as i32 * -1),
+            Offset::Pos(dur) => options::current().durations.serialize(dur, false),
+            Offset::Neg(dur) => options::current().durations.serialize(dur, true),
         }
     }
 }
 
 impl ToSerde for Duration {
     fn to_serde(&self) -> Value {
-        json!(self.as_secs())
+        options::current().durations.serialize(self, false)
     }
 }
 
 impl ToSerde for SystemTime {
     fn to_serde(&self) -> Value {
-        json!(Timestamp::from(*self))
+        timestamps::to_serde(self, options::current().timestamps)
     }
 }
 
@@ -218,6 +303,8 @@ impl ToSerde for Expr {
                     "op": op.to_serde(),
                     "rhs": rhs.to_serde(),
                     "modifier": modifier.to_serde(),
+                    "precedence": printer::precedence(op.id()),
+                    "is_right_assoc": printer::is_right_assoc(op.id()),
                 }),
             Expr::Paren(ParenExpr { expr }) =>
                 json!({
@@ -236,7 +323,7 @@ impl ToSerde for Expr {
             Expr::NumberLiteral(NumberLiteral { val }) =>
                 json!({
                     "@type": "number",
-                    "value": val,
+                    "value": val.to_serde(),
                 }),
             Expr::StringLiteral(StringLiteral { val }) =>
                 json!({
@@ -257,31 +344,1030 @@ impl ToSerde for Expr {
                     "function": func.to_serde(),
                     "args": args.to_serde(),
                 }),
-            Expr::Extension(_) => json!({ "expr": {} }),
+            Expr::Extension(Extension { expr }) =>
+                json!({
+                    "@type": "extension",
+                    "name": expr.name(),
+                    "value_type": expr.value_type().to_serde(),
+                    "children": expr.children().iter().map(|child| child.to_serde()).collect::<Vec<Value>>(),
+                    "text": format!("{:?}", expr),
+                }),
+        }
+    }
+}
+
+/// Serializes into plain JS object graphs.
+fn js_serializer() -> serde_wasm_bindgen::Serializer {
+    serde_wasm_bindgen::Serializer::new()
+        .serialize_missing_as_null(true)
+        .serialize_maps_as_objects(true)
+}
+
+/// Converts a serialized tree into a plain JS object graph.
+fn to_js(value: &Value) -> JsValue {
+    value.serialize(&js_serializer()).unwrap()
+}
+
+/// Reads a JS value, e.g. an array of queries, into `T`.
+fn from_js<T: serde::de::DeserializeOwned>(value: JsValue) -> Result<T, JsError> {
+    serde_wasm_bindgen::from_value(value).map_err(|err| JsError::new(&err.to_string()))
+}
+
+/// Reads an optional `{reserved, mode}` reserved-label guard configuration.
+fn label_guard(config: JsValue) -> Result<guard::LabelGuard, JsError> {
+    let config: Option<guard::GuardConfig> = from_js(config)?;
+    Ok(guard::LabelGuard::new(&config.unwrap_or_default()))
+}
+
+/// A JS `Error` that also carries the `code`, `start`, `end`, `line`,
+/// `column` and `suggestions` of the problem.
+fn js_error(err: errors::ParseError) -> JsValue {
+    let error = js_sys::Error::new(&err.message);
+    if let Value::Object(fields) = err.to_serde() {
+        for (key, value) in fields.iter().filter(|(key, _)| *key != "message") {
+            let _ = js_sys::Reflect::set(&error, &key.into(), &to_js(value));
+        }
+    }
+    error.into()
+}
+
+/// Parses `query`, failing with a structured `js_error`.
+fn parse_query(query: &str) -> Result<Expr, JsValue> {
+    errors::try_parse(query).map_err(js_error)
+}
+
+/// Serializes `expr` with the `start` and `end` byte offsets of every node in
+/// `query`, from which it was parsed, and their `text` if the options ask.
+fn serialize_ast(query: &str, expr: &Expr) -> Value {
+    let mut ast = expr.to_serde();
+    if let Some(tree) = spans::spans(query, expr) {
+        spans::annotate(&mut ast, &tree);
+        if options::current().text {
+            spans::annotate_text(&mut ast, query);
+        }
+    }
+    ast
+}
+
+/// Parses `query` with the syntax extensions and limits `options` enable.
+fn parse_extended(query: &str, options: &options::SerializeOptions) -> Result<Expr, errors::ParseError> {
+    let names = |query: &str| if options.utf8_names { quoted::try_parse_quoted(query) } else { errors::try_parse(query) };
+    let functions = |query: &str| experimental::parse_gated(query, options.experimental_functions, &names);
+    timing::time("parse", || limits::parse_limited(query, &options.limits, |query| {
+        if options.duration_expressions {
+            duration_exprs::parse_computed(query, functions)
+        } else {
+            functions(query)
+        }
+    }))
+}
+
+/// Parses PromQL `query` into a JSON AST serialized as `options` say.
+fn parse_promql(query: &str, options: &options::SerializeOptions) -> Result<Value, errors::ParseError> {
+    let expr = parse_extended(query, options)?;
+    Ok(timing::time("serialize", || options::with_options(options.clone(), || {
+        if options.output == options::OutputFormat::Prometheus {
+            translate::translate(&expr)
+        } else if options.duration_expressions {
+            duration_exprs::serialize_computed(query, &expr)
+        } else {
+            serialize_ast(query, &expr)
+        }
+    })))
+}
+
+/// Parses `query` in the dialect `options` pick into a JSON AST serialized
+/// as they say, before any `BigInt` conversion; a panic while at it is an
+/// `internal-error`.
+fn parse_serialized(query: &str, options: &options::SerializeOptions) -> Result<Value, errors::ParseError> {
+    panics::guard(query, || {
+        let dialect = dialects::dialect(query, options.dialect.as_deref())?;
+        if options.output == options::OutputFormat::Prometheus && dialect.name != dialects::DEFAULT_DIALECT {
+            let message = format!("the prometheus output is only for PromQL, not {}", dialect.name);
+            return Err(errors::located(query, message, "unsupported-output", 0, 0));
+        }
+        let mut ast = match options.variables {
+            true => variables::parse_variables(query, options.text, |query| (dialect.parse)(query, options))?,
+            false => (dialect.parse)(query, options)?,
+        };
+        options.reshape(&mut ast);
+        Ok(ast)
+    })
+}
+
+/// Converts a serialized tree shaped by `options` to JS.
+fn options_to_js(value: &Value, options: &options::SerializeOptions) -> JsValue {
+    let value = to_js(value);
+    if options.timestamps == timestamps::TimestampFormat::Bigint {
+        bigint_timestamps(&value);
+    }
+    value
+}
+
+/// Parses PromQL `query` straight into JS, without a `Value` tree in between,
+/// for `options` that are `is_direct`.
+fn parse_direct(query: &str, options: &options::SerializeOptions) -> Result<JsValue, errors::ParseError> {
+    panics::guard(query, || {
+        let expr = parse_extended(query, options)?;
+        let tree = spans::spans(query, &expr);
+        let node = serialize::Node::new(query, &expr, tree.as_ref(), options);
+        timing::time("serialize", || options::with_options(options.clone(), || encode(query, &node, options)))
+    })
+}
+
+/// The AST of `query` as JS, or as bytes in the `encoding` of `options`.
+fn encode(query: &str, ast: &impl Serialize, options: &options::SerializeOptions) -> Result<JsValue, errors::ParseError> {
+    match options.encoding.encode(ast) {
+        Some(Ok(bytes)) => Ok(js_sys::Uint8Array::from(&bytes[..]).into()),
+        Some(Err(err)) => Err(errors::located(query, err, "internal-error", 0, 0)),
+        None => {
+            let value = ast.serialize(&js_serializer()).unwrap();
+            if options.timestamps == timestamps::TimestampFormat::Bigint {
+                bigint_timestamps(&value);
+            }
+            Ok(value)
         }
     }
 }
 
+/// Parses `query` into a JSON AST serialized as `options` say.
+fn parse_with_options(query: &str, options: JsValue) -> Result<JsValue, JsValue> {
+    parse_encoded(query, from_js::<Option<_>>(options)?.unwrap_or_default())
+}
+
+/// Parses `query` into its AST, serialized and encoded as `options` say,
+/// and with `{timings}` the time spent in each phase.
+fn parse_encoded(query: &str, options: options::SerializeOptions) -> Result<JsValue, JsValue> {
+    if !options.timings {
+        return parse_untimed(query, &options);
+    }
+    let (ast, timings) = timing::record(|| parse_untimed(query, &options));
+    let result = js_sys::Object::new();
+    js_sys::Reflect::set(&result, &"ast".into(), &ast?)?;
+    js_sys::Reflect::set(&result, &"timings".into(), &to_js(&timings.to_serde()))?;
+    Ok(result.into())
+}
+
+fn parse_untimed(query: &str, options: &options::SerializeOptions) -> Result<JsValue, JsValue> {
+    if options.is_direct() {
+        return parse_direct(query, options).map_err(js_error);
+    }
+    let ast = parse_serialized(query, options).map_err(js_error)?;
+    timing::time("encode", || encode(query, &ast, options)).map_err(js_error)
+}
+
+/// Parses `query` into a JSON AST. `options` may pick the `@` timestamp
+/// format, `{timestamps: "iso" | "seconds" | "millis" | "string" | "bigint"}`, the
+/// duration format, `{durations: "seconds" | "millis" | "string"}`, and limits,
+/// `{max_length, max_depth}`, to reject oversized queries from untrusted users,
+/// and the query language, `{dialect}`, one of `promql_dialects()`.
+#[wasm_bindgen]
+pub fn promql_parse(query: String, options: JsValue) -> Result<typescript::AstNode, JsValue> {
+    Ok(parse_with_options(&query, options)?.unchecked_into())
+}
+
+/// The `[{name, description}]` of the dialects `promql_parse` reads, by its
+/// `dialect` option.
+#[wasm_bindgen]
+pub fn promql_dialects() -> JsValue {
+    to_js(&json!(dialects::DIALECTS.iter().map(|dialect| dialect.to_serde()).collect::<Vec<Value>>()))
+}
+
+/// Parses Loki LogQL `query` into a JSON AST like that of `promql_parse`, with
+/// its options, as with `{dialect: "logql"}`.
+#[wasm_bindgen]
+pub fn logql_parse(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options = options::SerializeOptions {
+        dialect: Some("logql".to_string()),
+        ..from_js::<Option<_>>(options)?.unwrap_or_default()
+    };
+    let ast = parse_serialized(&query, &options).map_err(js_error)?;
+    Ok(options_to_js(&ast, &options))
+}
+
+/// Parses `query` into a JSON AST shaped by `opts`: `{timestamps, durations}`
+/// formats as for `promql_parse`, `{omit_nulls: true}` to leave out null
+/// fields, `{keys: "camel"}` for camelCase keys and `{text: true}` for the
+/// source text of every node; `{utf8_names: true}` accepts the quoted metric and
+/// label names of Prometheus 3, `{"http.requests", "service.name"="api"}`, and
+/// `{duration_expressions: true}` duration arithmetic such as `x[5m + 30s]`;
+/// `{experimental_functions: true}` enables `info()`, `histogram_stddev()`, ...
+/// and `{variables: true}` tolerates Grafana `$name` and `${name}` variables;
+/// `{output: "prometheus"}` gives the tree of Prometheus' own Go parser and
+/// `{encoding: "msgpack" | "cbor" | "json"}` a `Uint8Array` of the encoded AST.
+/// `{timings: true}` returns `{ast, timings}` with the milliseconds spent
+/// parsing, serializing and encoding the query, for performance reports.
+#[wasm_bindgen]
+pub fn promql_parse_with_options(query: String, opts: JsValue) -> Result<JsValue, JsValue> {
+    parse_with_options(&query, opts)
+}
+
+/// Parses `query` into the UTF-8 JSON text of its AST in a `Uint8Array`, to
+/// transfer to a worker or write out without building JS objects first; takes
+/// the `promql_parse_with_options` options.
+#[wasm_bindgen]
+pub fn promql_parse_bytes(query: String, options: JsValue) -> Result<js_sys::Uint8Array, JsValue> {
+    let options = options::SerializeOptions {
+        encoding: options::Encoding::Json,
+        timings: false,
+        ..from_js::<Option<_>>(options)?.unwrap_or_default()
+    };
+    Ok(parse_encoded(&query, options)?.unchecked_into())
+}
+
+/// `{ok: true, ast}` or `{ok: false, error}`, as `query` parses with `options`.
+fn parse_result(query: &str, options: &options::SerializeOptions) -> Value {
+    match parse_serialized(query, options) {
+        Ok(ast) => json!({ "ok": true, "ast": ast }),
+        Err(error) => json!({ "ok": false, "error": error.to_serde() }),
+    }
+}
+
+/// Parses `query` like `promql_parse` without ever throwing, into `{ok: true,
+/// ast}` or `{ok: false, error}`; options that do not deserialize are an
+/// `invalid-options` error.
+#[wasm_bindgen]
+pub fn promql_try_parse(query: String, options: JsValue) -> JsValue {
+    match serde_wasm_bindgen::from_value::<Option<options::SerializeOptions>>(options) {
+        Ok(options) => {
+            let options = options.unwrap_or_default();
+            options_to_js(&parse_result(&query, &options), &options)
+        }
+        Err(err) => {
+            let error = errors::located(&query, err.to_string(), "invalid-options", 0, 0);
+            to_js(&json!({ "ok": false, "error": error.to_serde() }))
+        }
+    }
+}
+
+/// Parses an array of queries in one call, into `{ok: true, ast}` or
+/// `{ok: false, error}` per query, with `promql_parse_with_options` options.
+#[wasm_bindgen]
+pub fn promql_parse_many(queries: JsValue, options: JsValue) -> Result<JsValue, JsValue> {
+    let queries: Vec<String> = from_js(queries)?;
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let results: Vec<Value> = queries.iter().map(|query| parse_result(query, &options)).collect();
+    Ok(options_to_js(&Value::Array(results), &options))
+}
+
+/// Parses the queries of an array or any other iterable, such as a generator
+/// reading a corpus, one at a time, calling `callback(result, index)` with the
+/// `{ok: true, ast}` or `{ok: false, error}` of each instead of collecting
+/// them; `false` from the callback stops. The number of queries parsed.
+#[wasm_bindgen]
+pub fn promql_parse_each(queries: JsValue, callback: &js_sys::Function, options: JsValue) -> Result<u32, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let queries = js_sys::try_iter(&queries)?.ok_or_else(|| JsError::new("queries must be an array or iterable"))?;
+    let mut count = 0;
+    for query in queries {
+        let query = query?.as_string().ok_or_else(|| JsError::new("queries must be strings"))?;
+        let result = options_to_js(&parse_result(&query, &options), &options);
+        let next = callback.call2(&JsValue::NULL, &result, &JsValue::from(count))?;
+        count += 1;
+        if next.as_bool() == Some(false) {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+/// Names of the experimental functions `query` calls, which parse only with
+/// `{experimental_functions: true}`; other `promql_parse_with_options` options apply.
+#[wasm_bindgen]
+pub fn promql_experimental_features(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let options = options::SerializeOptions { experimental_functions: true, ..options };
+    let expr = parse_extended(&query, &options).map_err(js_error)?;
+    Ok(to_js(&json!(experimental::features(&expr))))
+}
+
+/// `{valid, diagnostics}` of semantic checks past the grammar: parse and type
+/// errors plus arguments Prometheus rejects or mis-evaluates at query time.
+#[wasm_bindgen]
+pub fn promql_typecheck(query: String) -> JsValue {
+    to_js(&typecheck::typecheck(&query).to_serde())
+}
+
+/// Turns the millisecond strings of `at` fields into `BigInt`s, in place.
+fn bigint_timestamps(value: &JsValue) {
+    if let Some(array) = value.dyn_ref::<js_sys::Array>() {
+        array.iter().for_each(|item| bigint_timestamps(&item));
+    } else if value.is_object() {
+        for key in js_sys::Object::keys(value.unchecked_ref()).iter() {
+            let field = js_sys::Reflect::get(value, &key).unwrap_or_default();
+            if key == "at" && field.is_string() {
+                if let Ok(ms) = js_sys::BigInt::new(&field) {
+                    let _ = js_sys::Reflect::set(value, &key, &ms);
+                }
+            } else {
+                bigint_timestamps(&field);
+            }
+        }
+    }
+}
+
+/// Renders a JSON AST as produced by `promql_parse`, possibly edited, back
+/// into PromQL. Pass the `{timestamps, durations}` options it was parsed
+/// with, if any.
+#[wasm_bindgen]
+pub fn promql_unparse(ast: typescript::AstNode, options: JsValue) -> Result<String, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    Ok(unparse::unparse(&from_js(ast.into())?, &options).map_err(|err| JsError::new(&err))?)
+}
+
+/// The message of the last panic, which on wasm traps the call with a
+/// `RuntimeError` and leaves the instance unusable; `undefined` if none or
+/// already taken. `safe.js` reads it to throw an `internal-error`.
+#[wasm_bindgen]
+pub fn promql_last_panic() -> Option<String> {
+    panics::take()
+}
+
+/// Records panic messages for `promql_last_panic` as the module loads.
+#[wasm_bindgen(start)]
+fn start() {
+    panics::install();
+}
+
+/// `errors::ERROR_CODES` as `[{ code, description }]`.
+fn error_codes() -> Value {
+    let codes: Vec<Value> = errors::ERROR_CODES
+        .iter()
+        .map(|(code, description)| json!({ "code": code, "description": description }))
+        .collect();
+    json!(codes)
+}
+
+/// The codes parse errors may have, as `[{ code, description }]`.
+#[wasm_bindgen]
+pub fn promql_error_codes() -> JsValue {
+    to_js(&error_codes())
+}
+
+/// Parses as much of `query` as possible, even when it has errors: the partial
+/// AST, or `null` rather than one meaning something else, the `text` it was
+/// recovered from and the parse error plus every bracket left open.
+#[wasm_bindgen]
+pub fn promql_parse_lenient(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&lenient::parse_lenient(&query).to_serde()))
+}
+
+/// Completion candidates at byte `offset` of a partial `query`: functions,
+/// aggregators, operators, keywords, durations and the metric and label
+/// names and values of the optional `metadata`.
+#[wasm_bindgen]
+pub fn promql_complete(query: String, offset: usize, metadata: JsValue) -> Result<JsValue, JsValue> {
+    if !query.is_char_boundary(offset) {
+        return Err(JsError::new("offset is not a character boundary of the query").into());
+    }
+    let metadata: completion::Metadata = from_js::<Option<_>>(metadata)?.unwrap_or_default();
+    Ok(to_js(&completion::complete(&query, offset, &metadata).to_serde()))
+}
+
+/// The innermost node of `query` at byte `offset`, with its span, type, a
+/// short description and its ancestors, or `null` between nodes.
+#[wasm_bindgen]
+pub fn promql_node_at(query: String, offset: usize) -> Result<JsValue, JsValue> {
+    let expr = parse_query(&query)?;
+    Ok(to_js(&hover::hover(&query, &expr, offset).map_or(Value::Null, |hover| hover.to_serde())))
+}
+
+/// Semantic tokens of `query`, possibly incomplete, with a legend, Monaco's
+/// encoded `data` and UTF-16 ranges for CodeMirror decorations.
+#[wasm_bindgen]
+pub fn promql_semantic_tokens(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&semantic::semantic_tokens(&query).map_err(js_error)?.to_serde()))
+}
+
+/// JSON Schema of the AST `promql_parse` returns, with a definition per `@type`.
+#[wasm_bindgen]
+pub fn promql_ast_schema() -> JsValue {
+    to_js(&schema::ast_schema())
+}
+
+/// The `[{path, node}]` of the AST of `query`, as `promql_parse` returns it,
+/// that a path `selector` like `$.binary.lhs..vector_selector` picks.
+#[wasm_bindgen]
+pub fn promql_query_ast(query: String, selector: String) -> Result<JsValue, JsValue> {
+    let ast = serialize_ast(&query, &parse_query(&query)?);
+    Ok(to_js(&ast_path::select_serde(&ast, &selector).map_err(|err| JsError::new(&err))?))
+}
+
+/// Renders a serialized `@` timestamp (ISO text, milliseconds as a number,
+/// string or `BigInt`, `"start"` or `"end"`) as a PromQL `@` modifier.
+/// Numbers are seconds with `{timestamps: "seconds"}`.
+#[wasm_bindgen]
+pub fn promql_at_modifier(value: JsValue, options: JsValue) -> Result<String, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let value: Value = match value.dyn_ref::<js_sys::BigInt>() {
+        Some(ms) => json!(String::from(ms.to_string(10).map_err(|_| JsError::new("invalid BigInt"))?)),
+        None => from_js(value)?,
+    };
+    let at = timestamps::parse_at(&value, options.timestamps).map_err(|err| JsError::new(&err))?;
+    Ok(printer::at(&at))
+}
+
+/// Parses `query` into the AST plus a lossless token stream with all
+/// whitespace and comments, from which the input can be rebuilt byte for byte.
+#[wasm_bindgen]
+pub fn promql_parse_cst(query: String) -> Result<JsValue, JsValue> {
+    let ast = parse_query(&query)?;
+    let cst = cst::cst(&query).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&json!({ "ast": serialize_ast(&query, &ast), "cst": cst.to_serde() })))
+}
+
+/// The tokens and comments of `query` as `{type, kind, text, start, end}`,
+/// without parsing it.
+#[wasm_bindgen]
+pub fn promql_tokenize(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&tokens::tokenize(&query).map_err(js_error)?.to_serde()))
+}
+
+/// Reports inner `by()` labels that every outer aggregation drops again.
+#[wasm_bindgen]
+pub fn promql_discarded_grouping(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&grouping::discarded_grouping(&parse_query(&query)?).to_serde()))
+}
+
+/// Collapses redundant nested aggregations, explaining each step.
+#[wasm_bindgen]
+pub fn promql_simplify_aggregations(query: String, guard: JsValue) -> Result<JsValue, JsValue> {
+    let expr = parse_query(&query)?;
+    let guard = label_guard(guard)?;
+    Ok(to_js(&simplify::collapse_nested_aggregations(&query, &expr, &guard).to_serde()))
+}
+
+/// Evaluates the arithmetic on constants of `query`, as in `(60 * 60) / 2`,
+/// explaining each step.
+#[wasm_bindgen]
+pub fn promql_fold_constants(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&simplify::fold_constants(&query, &parse_query(&query)?).to_serde()))
+}
+
+/// Removes `* 1`, `+ 0`, double negation and repeated `or` legs from `query`,
+/// explaining each step.
+#[wasm_bindgen]
+pub fn promql_simplify_binary(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&simplify::simplify_binary(&query, &parse_query(&query)?).to_serde()))
+}
+
+/// Flags contradictory selectors and operations that can never return data.
+#[wasm_bindgen]
+pub fn promql_always_empty(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&emptiness::always_empty_in(&query, &parse_query(&query)?).to_serde()))
+}
+
+fn parse_selector(selector: &str) -> Result<VectorSelector, JsValue> {
+    match parse_query(selector)? {
+        Expr::VectorSelector(vs) => Ok(vs),
+        _ => Err(JsError::new(&format!("not a vector selector: {}", selector)).into()),
+    }
+}
+
+/// Shards the aggregations of `query` over `{shards, label}` (default
+/// `__query_shard__`) into per-shard queries and a merge query, or says why not.
+#[wasm_bindgen]
+pub fn promql_shard(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: sharding::ShardOptions = from_js(options)?;
+    let plan = sharding::shard(&parse_query(&query)?, &options).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&plan.to_serde()))
+}
+
+/// `{query, warnings}` with the matchers of a `selector` like `{tenant="a"}`
+/// added to every selector, replacing those on the same labels; the optional
+/// `{reserved, mode}` guard may refuse injecting reserved labels.
+#[wasm_bindgen]
+pub fn promql_inject_matchers(query: String, selector: String, guard: JsValue) -> Result<JsValue, JsValue> {
+    let mut expr = parse_query(&query)?;
+    let injected = parse_selector(&selector)?;
+    if let Some(name) = injected.name {
+        return Err(JsError::new(&format!("cannot inject metric name {}", name)).into());
+    }
+    let warnings = rewrite::inject_matchers(&mut expr, &injected.matchers.matchers, &label_guard(guard)?)
+        .map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&json!({ "query": printer::to_promql(&expr), "warnings": warnings })))
+}
+
+/// `query` with every selector that function `name` takes passed to it,
+/// e.g. raw `foo[5m]` as `rate(foo[5m])`.
+#[wasm_bindgen]
+pub fn promql_wrap_selectors(query: String, name: String) -> Result<String, JsValue> {
+    let mut expr = parse_query(&query)?;
+    rewrite::wrap_selectors(&mut expr, &name).map_err(|err| JsError::new(&err))?;
+    Ok(printer::to_promql(&expr))
+}
+
+/// `query` with the ranges of its matrix selectors and subqueries, all or
+/// those of `{from}`, replaced by `{to}` text or scaled by `{factor}`.
+#[wasm_bindgen]
+pub fn promql_rewrite_ranges(query: String, rewrite: JsValue) -> Result<String, JsValue> {
+    let rewrite: rewrite::RangeRewrite = from_js(rewrite)?;
+    Ok(rewrite::rewrite_ranges(&query, &rewrite).map_err(|err| JsError::new(&err))?)
+}
+
+/// `query` with the offsets of its selectors and outermost subqueries
+/// shifted by `{shift}`, e.g. `"1w"` or `"-1h"`, replaced by `{set}` or
+/// dropped with `{remove: true}`.
+#[wasm_bindgen]
+pub fn promql_rewrite_offsets(query: String, rewrite: JsValue) -> Result<String, JsValue> {
+    let mut expr = parse_query(&query)?;
+    let rewrite: rewrite::OffsetRewrite = from_js(rewrite)?;
+    rewrite::rewrite_offsets(&mut expr, &rewrite).map_err(|err| JsError::new(&err))?;
+    Ok(printer::to_promql(&expr))
+}
+
+/// `{query, warnings}` with metric `from` renamed to `to`, in selectors and
+/// `__name__` matchers alike; the optional `{reserved, mode}` guard may refuse
+/// renaming selectors with matchers on reserved labels.
+#[wasm_bindgen]
+pub fn promql_rename_metric(query: String, from: String, to: String, guard: JsValue) -> Result<JsValue, JsValue> {
+    let mut expr = parse_query(&query)?;
+    let warnings = rewrite::rename_metric(&mut expr, &from, &to, &label_guard(guard)?).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&json!({ "query": printer::to_promql(&expr), "warnings": warnings })))
+}
+
+/// `{query, warnings}` with label `from` renamed to `to` in matchers,
+/// grouping and vector matching clauses and label arguments; the optional
+/// `{reserved, mode}` guard may refuse renaming reserved labels.
+#[wasm_bindgen]
+pub fn promql_rename_label(query: String, from: String, to: String, guard: JsValue) -> Result<JsValue, JsValue> {
+    let mut expr = parse_query(&query)?;
+    let warnings = label_guard(guard)?.check("rename", [from.as_str(), to.as_str()]).map_err(|err| JsError::new(&err))?;
+    rewrite::rename_label(&mut expr, &from, &to);
+    Ok(to_js(&json!({ "query": printer::to_promql(&expr), "warnings": warnings })))
+}
+
+/// `query` with the Grafana-style `$name` and `${name}` variables of `vars`
+/// substituted, escaped for where each one is, and checked to parse.
+#[wasm_bindgen]
+pub fn promql_substitute(query: String, vars: JsValue) -> Result<String, JsValue> {
+    let vars: std::collections::BTreeMap<String, Value> = from_js(vars)?;
+    let substituted = substitute::substitute(&query, &vars).map_err(|err| JsError::new(&err))?;
+    errors::try_parse(&substituted).map_err(js_error)?;
+    Ok(substituted)
+}
+
+/// The `match[]` selectors for `/api/v1/series` that look up every series
+/// `query` reads.
+#[wasm_bindgen]
+pub fn promql_series_matchers(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&json!(matchers::series_matchers(&parse_query(&query)?))))
+}
+
+/// The Prometheus HTTP API request running `query`, `/api/v1/query` or
+/// `/api/v1/query_range` as its result type allows, from `{base_url, time,
+/// start, end, step, timeout}` with times in milliseconds.
+#[wasm_bindgen]
+pub fn promql_api_url(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: http_api::ApiOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let request = http_api::request(&query, &parse_query(&query)?, &options).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&request.to_serde()))
+}
+
+/// Experimental: ClickHouse SQL computing `query` over a configurable (qryn by
+/// default) samples and series schema, for a subset of PromQL.
+#[wasm_bindgen]
+pub fn promql_to_clickhouse(query: String, options: JsValue) -> Result<String, JsValue> {
+    let options: clickhouse::SqlOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    Ok(clickhouse::to_sql(&parse_query(&query)?, &options).map_err(|err| JsError::new(&err))?)
+}
+
+/// Compares two selectors: does one imply the other, are they disjoint?
+#[wasm_bindgen]
+pub fn promql_matchers_relation(a: String, b: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&matchers::relation(&parse_selector(&a)?, &parse_selector(&b)?).to_serde()))
+}
+
+/// Checks that every selector of `query` only selects series allowed by `permitted`.
+#[wasm_bindgen]
+pub fn promql_within_selector(query: String, permitted: String) -> Result<JsValue, JsValue> {
+    let expr = parse_query(&query)?;
+    Ok(to_js(&matchers::within(&expr, &parse_selector(&permitted)?).to_serde()))
+}
+
+/// Literal prefixes, suffixes and alternation sets of the regex matchers of `query`.
+#[wasm_bindgen]
+pub fn promql_regex_literals(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&literals::regex_literals(&parse_query(&query)?).to_serde()))
+}
+
+/// Per label name, the literal values referenced across an array of queries.
+#[wasm_bindgen]
+pub fn promql_label_values(queries: JsValue) -> Result<JsValue, JsValue> {
+    Ok(to_js(&inventory::label_values(&from_js::<Vec<String>>(queries)?).to_serde()))
+}
+
+/// Aggregations across an array of queries worth precomputing as recording
+/// rules, named `level:metric:operations`, most shared first.
+#[wasm_bindgen]
+pub fn promql_recording_rules(queries: JsValue) -> Result<JsValue, JsValue> {
+    Ok(to_js(&recording::suggest(&from_js::<Vec<String>>(queries)?).to_serde()))
+}
+
+/// Metric names `query` selects, including those of `__name__` matchers, and
+/// the `__name__` regexes whose names cannot be listed.
+#[wasm_bindgen]
+pub fn promql_metric_names(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&extract::metric_names(&parse_query(&query)?).to_serde()))
+}
+
+/// Label names `query` uses anywhere, each with the contexts it appears in.
+#[wasm_bindgen]
+pub fn promql_label_names(query: String) -> Result<JsValue, JsValue> {
+    let labels: Vec<Value> = extract::label_names(&parse_query(&query)?)
+        .into_iter()
+        .map(|(name, contexts)| json!({ "name": name, "contexts": contexts }))
+        .collect();
+    Ok(to_js(&json!(labels)))
+}
+
+/// Every vector and matrix selector of `query` as a flat list of `{metric,
+/// matchers, range, offset, at, start, end}`, with the `promql_parse_with_options`
+/// options.
+#[wasm_bindgen]
+pub fn promql_selectors(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let expr = parse_extended(&query, &options).map_err(js_error)?;
+    let selectors = options::with_options(options.clone(), || extract::flat_selectors(&query, &expr).to_serde());
+    Ok(options_to_js(&selectors, &options))
+}
+
+/// Every function call of `query` with its argument count, span and whether
+/// the function is deprecated or experimental; options as for
+/// `promql_parse_with_options`.
+#[wasm_bindgen]
+pub fn promql_functions(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let expr = parse_extended(&query, &options).map_err(js_error)?;
+    Ok(to_js(&extract::function_calls(&query, &expr).to_serde()))
+}
+
+/// Every aggregation of `query` with its grouping mode, labels, parameter and
+/// span; options as for `promql_parse_with_options`.
+#[wasm_bindgen]
+pub fn promql_aggregations(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let expr = parse_extended(&query, &options).map_err(js_error)?;
+    Ok(to_js(&extract::aggregations(&query, &expr).to_serde()))
+}
+
+/// A Graphviz DOT graph of the expression tree of `query`, its nodes labeled
+/// with their operator, function, selector or literal.
+#[wasm_bindgen]
+pub fn promql_to_dot(query: String) -> Result<String, JsValue> {
+    Ok(diagram::to_dot(&parse_query(&query)?))
+}
+
+/// A Mermaid flowchart of the expression tree of `query`, labeled as by
+/// `promql_to_dot`, for Markdown that renders Mermaid.
+#[wasm_bindgen]
+pub fn promql_to_mermaid(query: String) -> Result<String, JsValue> {
+    Ok(diagram::to_mermaid(&parse_query(&query)?))
+}
+
+/// A plain-English description of what `query` computes, for people who do
+/// not read PromQL.
+#[wasm_bindgen]
+pub fn promql_explain(query: String) -> Result<String, JsValue> {
+    Ok(explain::explain(&parse_query(&query)?))
+}
+
+/// Converts `query` into a visual query builder model, where representable.
+#[wasm_bindgen]
+pub fn promql_to_builder(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&visual::representation(&parse_query(&query)?).to_serde()))
+}
+
+/// Renders a visual query builder model as PromQL.
+#[wasm_bindgen]
+pub fn promql_from_builder(model: JsValue) -> Result<String, JsValue> {
+    Ok(visual::from_model(&from_js(model)?).map_err(|err| JsError::new(&err))?)
+}
+
+/// Builds validated PromQL from a `{metric, matchers, range, offset, steps}`
+/// description, each step a function, aggregation, binary operation or subquery.
+#[wasm_bindgen]
+pub fn promql_build(spec: JsValue) -> Result<String, JsValue> {
+    Ok(builder::build(&from_js(spec)?).map_err(|err| JsError::new(&err))?)
+}
+
+/// Node counts, depth, selector and matcher counts, range coverage and widest
+/// range of `query`.
+#[wasm_bindgen]
+pub fn promql_stats(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&stats::stats(&parse_query(&query)?).to_serde()))
+}
+
+/// Structural cost score of `query` with its per-feature breakdown, per
+/// optional `{selector, range_hour, regex_matcher, subquery, ...}` weights.
+#[wasm_bindgen]
+pub fn promql_cost(query: String, weights: JsValue) -> Result<JsValue, JsValue> {
+    let weights: Option<cost::Weights> = from_js(weights)?;
+    Ok(to_js(&cost::cost(&parse_query(&query)?, &weights.unwrap_or_default()).to_serde()))
+}
+
+/// History `query` reads before its evaluation time, with the ranges, offsets
+/// and lookback delta it adds up from, per optional `{lookback_delta, retention}` seconds.
+#[wasm_bindgen]
+pub fn promql_lookback(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: lookback::LookbackOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    Ok(to_js(&lookback::lookback_with(&parse_query(&query)?, &options).to_serde()))
+}
+
+/// `{min_time, max_time}` milliseconds of the samples `query` reads when
+/// evaluated at `{time}` or from `{start, end}` milliseconds, with an optional
+/// `lookback_delta` in seconds.
+#[wasm_bindgen]
+pub fn promql_time_bounds(query: String, times: JsValue) -> Result<JsValue, JsValue> {
+    let times: lookback::EvalTimes = from_js::<Option<_>>(times)?.unwrap_or_default();
+    let bounds = lookback::time_bounds(&parse_query(&query)?, &times).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&bounds.to_serde()))
+}
+
+/// Splits a range query over `{start, end, step}` milliseconds into
+/// sub-queries per `interval` (a day by default), for a query frontend to run
+/// in parallel and stitch, with the samples each one reads.
+#[wasm_bindgen]
+pub fn promql_split_by_time(query: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: split::SplitOptions = from_js(options)?;
+    let plan = split::split(&query, &parse_query(&query)?, &options).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&plan.to_serde()))
+}
+
+/// Estimated result series of `query` and of each of its nodes, from
+/// `{series, labels, metrics, max_series}` series and label value counts.
+#[wasm_bindgen]
+pub fn promql_cardinality(query: String, stats: JsValue) -> Result<JsValue, JsValue> {
+    let stats: cardinality::LabelStats = from_js::<Option<_>>(stats)?.unwrap_or_default();
+    Ok(to_js(&cardinality::cardinality(&query, &parse_query(&query)?, &stats).to_serde()))
+}
+
+/// Lints an array of `{query, uri?, line?}` sources, optionally checking them
+/// against a permitted selector, and reports the findings as a SARIF log.
+#[wasm_bindgen]
+pub fn promql_sarif(sources: JsValue, permitted: Option<String>) -> Result<JsValue, JsValue> {
+    let sources: Vec<sarif::Source> = from_js(sources)?;
+    let permitted = permitted.as_deref().map(parse_selector).transpose()?;
+    let checked: Vec<(sarif::Source, Vec<lint::Diagnostic>)> = sources
+        .into_iter()
+        .map(|source| {
+            let diagnostics = lint::check(&source.query, permitted.as_ref());
+            (source, diagnostics)
+        })
+        .collect();
+    Ok(to_js(&sarif::to_sarif(&checked)))
+}
+
+/// Applies the lint fixes of `rule_ids` (all fixable rules if omitted) to
+/// `query`, keeping only those after which it still parses; with
+/// `{experimental_functions: true}` fixes may call experimental functions.
+#[wasm_bindgen]
+pub fn promql_fix(query: String, rule_ids: JsValue, options: JsValue) -> Result<JsValue, JsValue> {
+    let rule_ids: Option<Vec<String>> = from_js(rule_ids)?;
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    let fixed = lint::fix(&query, &rule_ids.unwrap_or_default(), options.experimental_functions).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&fixed.to_serde()))
+}
+
+/// Lint findings of `query` with their spans, per a `{rules, permitted}`
+/// config that turns rules off or overrides their severity.
+#[wasm_bindgen]
+pub fn promql_lint(query: String, config: JsValue) -> Result<JsValue, JsValue> {
+    let config: lint::LintConfig = from_js::<Option<_>>(config)?.unwrap_or_default();
+    let permitted = config.permitted.as_deref().map(parse_selector).transpose()?;
+    let diagnostics = lint::check_configured(&query, &config, permitted.as_ref()).map_err(|err| JsError::new(&err))?;
+    Ok(to_js(&diagnostics.to_serde()))
+}
+
+/// The lint rules `promql_lint` can report, with their default severity.
+#[wasm_bindgen]
+pub fn promql_lint_rules() -> JsValue {
+    to_js(&json!(lint::RULES.iter().map(|rule| rule.to_serde()).collect::<Vec<Value>>()))
+}
+
+/// The queries of the Prometheus targets of a Grafana dashboard, an object or
+/// JSON text, with their panel, `refId`, datasource and AST, parsed with the
+/// `promql_parse_with_options` options and Grafana variables tolerated.
+#[wasm_bindgen]
+pub fn promql_dashboard_queries(dashboard: JsValue, options: JsValue) -> Result<JsValue, JsValue> {
+    let dashboard = match from_js::<Value>(dashboard)? {
+        Value::String(text) => serde_json::from_str(&text).map_err(|err| JsError::new(&format!("invalid dashboard JSON: {}", err)))?,
+        dashboard => dashboard,
+    };
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    Ok(options_to_js(&grafana::dashboard_queries(&dashboard, &options).to_serde(), &options))
+}
+
+/// Parses and checks a Prometheus rules file, as YAML text, into the metadata
+/// and `promql_parse` AST of every rule and its errors with file positions.
+#[wasm_bindgen]
+pub fn rules_parse(yaml: String, options: JsValue) -> Result<JsValue, JsValue> {
+    let options: options::SerializeOptions = from_js::<Option<_>>(options)?.unwrap_or_default();
+    Ok(options_to_js(&rules_yaml::parse_rules(&yaml, &options).to_serde(), &options))
+}
+
+/// Reads a rules file, a `{groups: [...]}` object or YAML text as
+/// `rules_parse` reads it, in which case rules carry their position; only
+/// YAML that does not load at all fails.
+fn rule_file(rules: JsValue) -> Result<rules::RuleFile, JsValue> {
+    match rules.as_string() {
+        Some(yaml) => rules_yaml::rule_file(&yaml).map_err(js_error),
+        None => Ok(from_js(rules)?),
+    }
+}
+
+/// Dependency graph, cycles, missing dependencies and evaluation order of the
+/// rules of a rules file, a `{groups: [...]}` object or YAML text.
 #[wasm_bindgen]
-pub fn promql_parse(query: String) -> Result<JsValue, JsError> {
-    match parser::parse(&query) {
-        Err(err) => Err(JsError::new(&err)),
-        Ok(expr) =>
-            Ok(
-                expr
-                    .to_serde()
-                    .serialize(
-                        &serde_wasm_bindgen::Serializer::new()
-                            .serialize_missing_as_null(true)
-                            .serialize_maps_as_objects(true)
-                    )
-                    .unwrap()
-            ),
+pub fn promql_rule_dependencies(rules: JsValue) -> Result<JsValue, JsValue> {
+    Ok(to_js(&dependencies::dependency_graph(&rule_file(rules)?).to_serde()))
+}
+
+/// Per rule group source metrics, widest lookback and interval checks, for
+/// capacity reviews, of a rules file object or YAML text.
+#[wasm_bindgen]
+pub fn promql_rule_plan(rules: JsValue) -> Result<JsValue, JsValue> {
+    Ok(to_js(&planning::plan(&rule_file(rules)?).to_serde()))
+}
+
+/// Label names the result series of `query` can carry.
+#[wasm_bindgen]
+pub fn promql_output_labels(query: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&output_labels::output_labels(&parse_query(&query)?).to_serde()))
+}
+
+/// Flags `$labels` references in alert templates to labels the alert
+/// expression never produces, in a rules file object or YAML text.
+#[wasm_bindgen]
+pub fn promql_alert_templates(rules: JsValue) -> Result<JsValue, JsValue> {
+    Ok(to_js(&templates::check_templates(&rule_file(rules)?).to_serde()))
+}
+
+/// Reformats `query` canonically, splitting expressions longer than
+/// `{max_width}` (100) over lines indented by `{indent}` (2) spaces per level.
+#[wasm_bindgen]
+pub fn promql_format(query: String, options: JsValue) -> Result<String, JsValue> {
+    let options: Option<format::FormatOptions> = from_js(options)?;
+    Ok(format::format(&query, &options.unwrap_or_default()).map_err(|err| JsError::new(&err))?)
+}
+
+/// The shortest single-line form of `query`, without comments, redundant
+/// parentheses or whitespace, e.g. for URLs.
+#[wasm_bindgen]
+pub fn promql_minify(query: String) -> Result<String, JsValue> {
+    Ok(format::minify(&query).map_err(|err| JsError::new(&err))?)
+}
+
+/// `query` in canonical form: single-line, consistently spaced and quoted,
+/// matchers and grouping labels sorted, redundant parentheses and comments
+/// dropped.
+#[wasm_bindgen]
+pub fn promql_normalize(query: String) -> Result<String, JsValue> {
+    Ok(canonical::canonical(&parse_query(&query)?))
+}
+
+/// Whether queries `a` and `b` have the same structure, regardless of
+/// formatting, matcher and label order and redundant parentheses.
+#[wasm_bindgen]
+pub fn promql_equal(a: String, b: String) -> Result<bool, JsValue> {
+    Ok(canonical::equivalent(&parse_query(&a)?, &parse_query(&b)?))
+}
+
+/// Node by node changes from query `a` to `b`, each `{kind, old, new}` with
+/// `kind` `"added"`, `"removed"` or `"changed"` and the `{path, type, text,
+/// start, end}` of the node on either side.
+#[wasm_bindgen]
+pub fn promql_diff(a: String, b: String) -> Result<JsValue, JsValue> {
+    Ok(to_js(&diff::diff(&a, &parse_query(&a)?, &b, &parse_query(&b)?).to_serde()))
+}
+
+/// A stable hash of `query` as 16 hexadecimal digits, the same for every
+/// spelling that differs only in spacing, matcher and label order or
+/// redundant parentheses.
+#[wasm_bindgen]
+pub fn promql_fingerprint(query: String) -> Result<String, JsValue> {
+    Ok(canonical::fingerprint(&parse_query(&query)?))
+}
+
+/// Reformats only the expression covering the bytes `start..end` of `query`,
+/// returning the minimal text edit.
+#[wasm_bindgen]
+pub fn promql_format_range(query: String, start: usize, end: usize) -> Result<JsValue, JsValue> {
+    Ok(to_js(&format::format_range(&query, start, end).map_err(|err| JsError::new(&err))?.to_serde()))
+}
+
+/// A random but valid query, the same for the same `seed` and optional
+/// `{max_depth, metrics, labels, values, aggregations, binary, ...}` profile.
+#[wasm_bindgen]
+pub fn promql_generate(seed: u32, profile: JsValue) -> Result<String, JsValue> {
+    let profile: Option<generate::Profile> = from_js(profile)?;
+    Ok(generate::generate(seed as u64, &profile.unwrap_or_default()).map_err(|err| JsError::new(&err))?)
+}
+
+/// Replaces metric names and label values across an array of queries with
+/// consistent pseudonyms, optionally `{salt, mapping: true}` to also return
+/// the mapping.
+#[wasm_bindgen]
+pub fn promql_pseudonymize(queries: JsValue, options: JsValue) -> Result<JsValue, JsValue> {
+    let queries: Vec<String> = from_js(queries)?;
+    let options: Option<pseudonymize::PseudonymOptions> = from_js(options)?;
+    Ok(to_js(&pseudonymize::pseudonymize(&queries, &options.unwrap_or_default()).to_serde()))
+}
+
+/// Calls `callback` with an `{event: "enter" | "leave", type, depth, ...}`
+/// object per node instead of building the AST. Returning `false` from the
+/// callback stops the walk.
+#[wasm_bindgen]
+pub fn promql_parse_events(query: String, callback: js_sys::Function) -> Result<(), JsValue> {
+    let expr = parse_query(&query)?;
+    let mut thrown = None;
+    events::events(&expr, &mut |event| {
+        let object = js_sys::Object::new();
+        let set = |key: &str, value: JsValue| js_sys::Reflect::set(&object, &key.into(), &value).map(|_| ());
+        let fields = vec![
+            ("event", JsValue::from(event.phase.as_str())),
+            ("type", JsValue::from(event.node_type)),
+            ("depth", JsValue::from(event.depth)),
+        ];
+        let set_all = fields
+            .into_iter()
+            .chain(event.fields.into_iter().map(|(key, field)| match field {
+                events::Field::Text(text) => (key, JsValue::from(text)),
+                events::Field::Number(number) => (key, JsValue::from(number)),
+            }))
+            .try_for_each(|(key, value)| set(key, value));
+        match set_all.and_then(|_| callback.call1(&JsValue::NULL, &object)) {
+            Ok(result) => result != JsValue::FALSE,
+            Err(err) => {
+                thrown = Some(err);
+                false
+            }
+        }
+    });
+    thrown.map_or(Ok(()), Err)
+}
+
+/// A callback of `callbacks` for nodes of `node_type`: `callbacks[node_type]`
+/// itself or its `enter` or `leave`, with `callbacks.enter` and
+/// `callbacks.leave` for every node.
+fn walk_callbacks(callbacks: &JsValue, node_type: &JsValue, phase: &str) -> Result<Vec<js_sys::Function>, JsValue> {
+    let phase = JsValue::from(phase);
+    let by_type = js_sys::Reflect::get(callbacks, node_type)?;
+    let typed = match by_type.dyn_ref::<js_sys::Function>() {
+        Some(_) if phase == "enter" => by_type.clone(),
+        Some(_) => JsValue::UNDEFINED,
+        None if by_type.is_object() => js_sys::Reflect::get(&by_type, &phase)?,
+        None => JsValue::UNDEFINED,
+    };
+    let all = js_sys::Reflect::get(callbacks, &phase)?;
+    Ok(vec![all, typed].into_iter().filter_map(|callback| callback.dyn_into().ok()).collect())
+}
+
+/// Walks the serialized `node` at `path`, depth first, calling `callbacks`.
+fn walk_js(node: &JsValue, path: &str, callbacks: &JsValue) -> Result<(), JsValue> {
+    let node_type = js_sys::Reflect::get(node, &"@type".into())?;
+    let path_value = JsValue::from(path);
+    let mut skip = false;
+    for callback in walk_callbacks(callbacks, &node_type, "enter")? {
+        skip |= callback.call2(&JsValue::NULL, node, &path_value)? == JsValue::FALSE;
+    }
+    if !skip {
+        let keys: &[&str] = match node_type.as_string().as_deref() {
+            Some("aggregate") => &["param", "expr"],
+            Some("unary") | Some("paren") | Some("subquery") => &["expr"],
+            Some("binary") => &["lhs", "rhs"],
+            _ => &[],
+        };
+        for key in keys {
+            let child = js_sys::Reflect::get(node, &(*key).into())?;
+            if child.is_object() {
+                walk_js(&child, &format!("{}.{}", path, key), callbacks)?;
+            }
+        }
+        if let Ok(args) = js_sys::Reflect::get(node, &"args".into())?.dyn_into::<js_sys::Array>() {
+            for (i, arg) in args.iter().enumerate() {
+                walk_js(&arg, &format!("{}.args[{}]", path, i), callbacks)?;
+            }
+        }
+    }
+    for callback in walk_callbacks(callbacks, &node_type, "leave")? {
+        callback.call2(&JsValue::NULL, node, &path_value)?;
     }
+    Ok(())
 }
 
+/// Walks the AST of `query` as `promql_parse` returns it, calling
+/// `callbacks.enter` and `callbacks.leave` for every node and, per `@type`,
+/// `callbacks[type]` or its `enter` and `leave`, with the node and its path.
+/// Returning `false` on enter skips the node's children.
+#[wasm_bindgen]
+pub fn promql_walk(query: String, callbacks: JsValue) -> Result<(), JsValue> {
+    let ast = to_js(&serialize_ast(&query, &parse_query(&query)?));
+    walk_js(&ast, "$", &callbacks)
+}
 
 #[test]
+#[allow(clippy::bind_instead_of_map, clippy::needless_borrow)]
 fn check_parser() {
     let payloads: Vec<String> = vec![
         "a or b".to_string(),
@@ -304,3 +1390,49 @@ fn check_parser() {
         );
     }
 }
+
+#[test]
+fn check_parse_serialized() {
+    let options = options::SerializeOptions { omit_nulls: true, ..options::SerializeOptions::default() };
+    assert_eq!(parse_serialized("x", &options).unwrap(), json!({ "@type": "vector_selector", "name": "x", "matchers": [], "start": 0, "end": 1 }));
+    assert_eq!(parse_serialized("x[5m", &options).unwrap_err().code, "unexpected-end");
+}
+
+#[test]
+fn check_parse_result() {
+    let options = options::SerializeOptions { omit_nulls: true, ..options::SerializeOptions::default() };
+    assert_eq!(parse_result("x", &options), json!({ "ok": true, "ast": parse_serialized("x", &options).unwrap() }));
+    assert_eq!(parse_result("sum(", &options)["error"]["code"], json!("unclosed-paren"));
+}
+
+#[test]
+fn check_precedence() {
+    let ast = parse("a - b ^ c").unwrap().to_serde();
+    assert_eq!((&ast["precedence"], &ast["is_right_assoc"]), (&json!(4), &json!(false)));
+    assert_eq!((&ast["rhs"]["precedence"], &ast["rhs"]["is_right_assoc"]), (&json!(6), &json!(true)));
+}
+
+#[test]
+fn check_extension() {
+    #[derive(Debug)]
+    struct Dialect(Vec<Expr>);
+    impl promql_parser::parser::ast::ExtensionExpr for Dialect {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn name(&self) -> &str {
+            "dialect"
+        }
+        fn value_type(&self) -> ValueType {
+            ValueType::Vector
+        }
+        fn children(&self) -> &[Expr] {
+            &self.0
+        }
+    }
+    let extension = Expr::Extension(Extension { expr: std::sync::Arc::new(Dialect(vec![parse("x").unwrap()])) });
+    let ast = extension.to_serde();
+    assert_eq!((&ast["@type"], &ast["name"], &ast["value_type"]), (&json!("extension"), &json!("dialect"), &json!("vector")));
+    assert_eq!(ast["children"][0]["name"], json!("x"));
+    assert!(ast["text"].as_str().unwrap().starts_with("Dialect("));
+}
@@ -0,0 +1,100 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use serde::Deserialize;
+use crate::errors::{located, ParseError};
+use crate::lexemes::lex;
+use crate::visit::children;
+
+/// Bounds on the queries accepted for parsing, for untrusted input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Longest accepted query, in bytes.
+    pub max_length: Option<usize>,
+    /// Deepest accepted expression nesting, the root being at depth 1.
+    pub max_depth: Option<usize>,
+}
+
+/// Depth of the deepest node of `expr`, without recursing.
+pub fn depth(expr: &Expr) -> usize {
+    let mut deepest = 0;
+    let mut pending = vec![(expr, 1)];
+    while let Some((expr, depth)) = pending.pop() {
+        deepest = deepest.max(depth);
+        pending.extend(children(expr).into_iter().map(|child| (child, depth + 1)));
+    }
+    deepest
+}
+
+fn too_deep(query: &str, max_depth: usize, start: usize, end: usize) -> ParseError {
+    let message = format!("query is nested deeper than {} expressions", max_depth);
+    located(query, message, "too-deep", start, end)
+}
+
+/// Every bracket nests at least one expression, so brackets open deeper than
+/// `max_depth` are rejected before building the tree.
+fn check_brackets(query: &str, max_depth: usize) -> Result<(), ParseError> {
+    let mut open = 0;
+    for lexeme in lex(query).unwrap_or_default() {
+        match lexeme.id {
+            T_LEFT_PAREN | T_LEFT_BRACE | T_LEFT_BRACKET => open += 1,
+            T_RIGHT_PAREN | T_RIGHT_BRACE | T_RIGHT_BRACKET => open -= 1,
+            _ => {}
+        }
+        if open > max_depth {
+            return Err(too_deep(query, max_depth, lexeme.start, lexeme.end));
+        }
+    }
+    Ok(())
+}
+
+/// Rejects queries over `limits.max_length`.
+pub fn check_length(query: &str, limits: &Limits) -> Result<(), ParseError> {
+    match limits.max_length.filter(|max| query.len() > *max) {
+        Some(max_length) => {
+            let message = format!("query is {} bytes long, the limit is {}", query.len(), max_length);
+            let start = (0..=max_length).rev().find(|i| query.is_char_boundary(*i)).unwrap_or(0);
+            Err(located(query, message, "too-long", start, query.len()))
+        }
+        None => Ok(()),
+    }
+}
+
+/// `parse`s `query`, e.g. with `try_parse`, first rejecting queries over
+/// `limits.max_length`, then queries nested deeper than `limits.max_depth`.
+pub fn parse_limited(
+    query: &str,
+    limits: &Limits,
+    parse: impl Fn(&str) -> Result<Expr, ParseError>,
+) -> Result<Expr, ParseError> {
+    check_length(query, limits)?;
+    match limits.max_depth {
+        None => parse(query),
+        Some(max_depth) => {
+            check_brackets(query, max_depth)?;
+            let expr = parse(query)?;
+            if depth(&expr) > max_depth {
+                return Err(too_deep(query, max_depth, 0, query.len()));
+            }
+            Ok(expr)
+        }
+    }
+}
+
+
+#[test]
+fn check_parse_limited() {
+    use crate::errors::try_parse;
+    let limits = Limits { max_length: Some(20), max_depth: Some(4) };
+    assert!(parse_limited("sum(rate(x[5m]))", &limits, try_parse).is_ok());
+    let error = parse_limited("sum(rate(x{job=\"api\"}[5m]))", &limits, try_parse).unwrap_err();
+    assert_eq!((error.code, error.start, error.column), ("too-long", 20, 21));
+    let error = parse_limited("(((((x)))))", &limits, try_parse).unwrap_err();
+    assert_eq!((error.code, error.start, error.end), ("too-deep", 4, 5));
+    let error = parse_limited("-(-(-x))", &limits, try_parse).unwrap_err();
+    assert_eq!((error.code, error.start, error.end), ("too-deep", 0, 8));
+    assert_ne!(parse_limited("x{", &limits, try_parse).unwrap_err().code, "too-deep");
+    let chain = vec!["x"; 5000].join(" + ");
+    assert_eq!(depth(&parse(&chain).unwrap()), 5000);
+    assert!(parse_limited(&chain, &Limits::default(), try_parse).is_ok());
+}
@@ -0,0 +1,727 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use crate::emptiness::always_empty;
+use crate::errors::try_parse;
+use crate::experimental::parse_gated;
+use crate::grouping::discarded_grouping;
+use crate::guard::LabelGuard;
+use crate::lexemes::{lex, outside_braces, Lexeme};
+use crate::literals::analyze;
+use crate::matchers::{selectors, within};
+use crate::printer::{label_name, quote, to_promql};
+use crate::regexes::{needless, re2_incompatibilities, Needless};
+use crate::simplify::collapse_nested_aggregations;
+use crate::spans::{spans_by_text, Span};
+use crate::visit::{children, walk};
+use crate::ToSerde;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+pub struct Rule {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        id: "invalid-query",
+        severity: Severity::Error,
+        description: "The query does not parse.",
+    },
+    Rule {
+        id: "always-empty",
+        severity: Severity::Error,
+        description: "Contradictory matchers or operations that can never return series.",
+    },
+    Rule {
+        id: "discarded-grouping",
+        severity: Severity::Warning,
+        description: "Grouping labels of an inner aggregation are dropped by every outer aggregation.",
+    },
+    Rule {
+        id: "redundant-aggregation",
+        severity: Severity::Info,
+        description: "Nested aggregations compute the same as a single aggregation.",
+    },
+    Rule {
+        id: "missing-bool",
+        severity: Severity::Warning,
+        description: "An `==` filter feeds arithmetic or an aggregation that only ever sees the compared value.",
+    },
+    Rule {
+        id: "implicit-subquery-step",
+        severity: Severity::Info,
+        description: "A subquery relies on the global evaluation interval as its step.",
+    },
+    Rule {
+        id: "deprecated-function",
+        severity: Severity::Warning,
+        description: "A function that has been renamed or deprecated.",
+    },
+    Rule {
+        id: "literal-regex",
+        severity: Severity::Info,
+        description: "A regex matcher that only matches a single literal value.",
+    },
+    Rule {
+        id: "needless-regex",
+        severity: Severity::Info,
+        description: "A regex matcher with anchors or `.*` that Prometheus' full anchoring makes pointless, or that matches any value.",
+    },
+    Rule {
+        id: "negated-alternation",
+        severity: Severity::Info,
+        description: "A negative regex matcher of literal alternatives, cheaper as one `!=` matcher per value.",
+    },
+    Rule {
+        id: "incompatible-regex",
+        severity: Severity::Error,
+        description: "A regex matcher using syntax that Go's RE2, and so Prometheus, does not support.",
+    },
+    Rule {
+        id: "outside-policy",
+        severity: Severity::Error,
+        description: "A selector may select series outside the permitted selector.",
+    },
+];
+
+pub fn rule(id: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|rule| rule.id == id)
+}
+
+impl ToSerde for Rule {
+    fn to_serde(&self) -> Value {
+        json!({
+            "id": self.id,
+            "severity": self.severity.as_str(),
+            "description": self.description,
+        })
+    }
+}
+
+/// How a rule is reported: not at all, or with a severity of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetting {
+    Off,
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct LintConfig {
+    /// Settings by rule id, the other rules keeping their default severity.
+    #[serde(default)]
+    pub rules: BTreeMap<String, RuleSetting>,
+    /// A selector the selectors of the query must stay within, enabling
+    /// `outside-policy`.
+    #[serde(default)]
+    pub permitted: Option<String>,
+}
+
+/// Replaces the bytes `start..end` of the query with `text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEdit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+impl ToSerde for TextEdit {
+    fn to_serde(&self) -> Value {
+        json!({
+            "start": self.start,
+            "end": self.end,
+            "text": self.text,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    pub description: String,
+    pub edits: Vec<TextEdit>,
+}
+
+impl ToSerde for Fix {
+    fn to_serde(&self) -> Value {
+        json!({
+            "description": self.description,
+            "edits": self.edits.to_serde(),
+        })
+    }
+}
+
+/// A finding of the lint or policy checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// The offending sub-expression, if the finding is not about the whole query.
+    pub expr: Option<String>,
+    /// Where the finding is in the query, if it could be located.
+    pub span: Option<Span>,
+    pub fix: Option<Fix>,
+}
+
+impl Diagnostic {
+    fn new(rule: &'static str, message: String, expr: Option<String>) -> Diagnostic {
+        let severity = self::rule(rule).map(|rule| rule.severity).unwrap_or(Severity::Warning);
+        Diagnostic { rule, severity, message, expr, span: None, fix: None }
+    }
+
+    fn at(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    fn with_fix(mut self, description: String, edits: Vec<TextEdit>) -> Diagnostic {
+        self.fix = Some(Fix { description, edits });
+        self
+    }
+}
+
+impl ToSerde for Diagnostic {
+    fn to_serde(&self) -> Value {
+        json!({
+            "rule": self.rule,
+            "severity": self.severity.as_str(),
+            "message": self.message,
+            "expr": self.expr,
+            "start": self.span.map(|span| span.start),
+            "end": self.span.map(|span| span.end),
+            "fix": self.fix.to_serde(),
+        })
+    }
+}
+
+/// Renamed functions, old name first.
+pub const DEPRECATED_FUNCTIONS: [(&str, &str); 1] = [("holt_winters", "double_exponential_smoothing")];
+
+/// Step written into subqueries that have none, Prometheus' default
+/// evaluation interval.
+const DEFAULT_SUBQUERY_STEP: &str = "1m";
+
+fn strip_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(ParenExpr { expr }) => strip_parens(expr),
+        _ => expr,
+    }
+}
+
+/// Binary expressions in the order of their operators in the query text.
+fn binaries_in_order<'a>(expr: &'a Expr, out: &mut Vec<&'a BinaryExpr>) {
+    if let Expr::Binary(binary) = expr {
+        binaries_in_order(&binary.lhs, out);
+        out.push(binary);
+        binaries_in_order(&binary.rhs, out);
+    } else {
+        for child in children(expr) {
+            binaries_in_order(child, out);
+        }
+    }
+}
+
+/// Subqueries in the order of their `:` in the query text.
+fn subqueries_in_order<'a>(expr: &'a Expr, out: &mut Vec<&'a SubqueryExpr>) {
+    for child in children(expr) {
+        subqueries_in_order(child, out);
+    }
+    if let Expr::Subquery(subquery) = expr {
+        out.push(subquery);
+    }
+}
+
+/// Pairs up nodes with the lexemes they were parsed from, `None` if the
+/// counts differ and the pairing cannot be trusted.
+fn align<T, L>(nodes: Vec<T>, lexemes: Vec<L>) -> Option<Vec<(T, L)>> {
+    (nodes.len() == lexemes.len()).then(|| nodes.into_iter().zip(lexemes).collect())
+}
+
+fn is_comparison(id: TokenId) -> bool {
+    matches!(id, T_EQLC | T_NEQ | T_GTR | T_LSS | T_GTE | T_LTE)
+}
+
+/// Whether `parent` uses the sample values of its operands.
+fn uses_values(parent: &Expr) -> bool {
+    match parent {
+        Expr::Aggregate(AggregateExpr { op, .. }) =>
+            matches!(op.id(), T_SUM | T_AVG | T_MIN | T_MAX | T_STDDEV | T_STDVAR | T_QUANTILE),
+        Expr::Binary(BinaryExpr { op, .. }) => matches!(op.id(), T_ADD | T_SUB | T_MUL | T_DIV | T_MOD | T_POW),
+        _ => false,
+    }
+}
+
+fn missing_bool(expr: &Expr, lexemes: &[Lexeme], diagnostics: &mut Vec<Diagnostic>) {
+    let mut flagged: Vec<(&BinaryExpr, &Expr)> = vec![];
+    walk(expr, &mut |parent| {
+        if !uses_values(parent) {
+            return;
+        }
+        for child in children(parent) {
+            if let Expr::Binary(binary) = strip_parens(child) {
+                let constant = [&binary.lhs, &binary.rhs].iter().any(|side| side.scalar_value().is_some());
+                let return_bool = binary.modifier.as_ref().is_some_and(|m| m.return_bool);
+                if binary.op.id() == T_EQLC && constant && !return_bool && child.value_type() == ValueType::Vector {
+                    flagged.push((binary, parent));
+                }
+            }
+        }
+    });
+    let mut binaries = vec![];
+    binaries_in_order(expr, &mut binaries);
+    let binaries: Vec<&BinaryExpr> = binaries.into_iter().filter(|b| is_comparison(b.op.id())).collect();
+    let operators: Vec<Lexeme> = outside_braces(lexemes).into_iter().filter(|l| is_comparison(l.id)).collect();
+    let aligned = align(binaries, operators).unwrap_or_default();
+    for (binary, parent) in flagged {
+        let comparison = to_promql(&Expr::Binary(binary.clone()));
+        let message = format!(
+            "`{}` only keeps series equal to the constant, so `{}` never sees 0/1 values; use `== bool`",
+            comparison,
+            to_promql(parent),
+        );
+        let diagnostic = Diagnostic::new("missing-bool", message, Some(comparison));
+        let operator = aligned.iter().find(|(b, _)| std::ptr::eq(*b, binary)).map(|(_, l)| *l);
+        diagnostics.push(match operator {
+            Some(operator) => diagnostic.with_fix(
+                "add `bool`".to_string(),
+                vec![TextEdit { start: operator.end, end: operator.end, text: " bool".to_string() }],
+            ),
+            None => diagnostic,
+        });
+    }
+}
+
+fn implicit_subquery_step(expr: &Expr, lexemes: &[Lexeme], diagnostics: &mut Vec<Diagnostic>) {
+    let mut subqueries = vec![];
+    subqueries_in_order(expr, &mut subqueries);
+    let colons: Vec<Lexeme> = outside_braces(lexemes).into_iter().filter(|l| l.id == T_COLON).collect();
+    let aligned = align(subqueries.clone(), colons);
+    for (i, subquery) in subqueries.into_iter().enumerate() {
+        if subquery.step.is_some() {
+            continue;
+        }
+        let text = to_promql(&Expr::Subquery(subquery.clone()));
+        let message = format!("`{}` has no step and depends on the evaluation interval", text);
+        let diagnostic = Diagnostic::new("implicit-subquery-step", message, Some(text));
+        diagnostics.push(match aligned.as_ref().map(|aligned| aligned[i].1) {
+            Some(colon) => diagnostic.with_fix(
+                format!("use an explicit {} step", DEFAULT_SUBQUERY_STEP),
+                vec![TextEdit { start: colon.end, end: colon.end, text: DEFAULT_SUBQUERY_STEP.to_string() }],
+            ),
+            None => diagnostic,
+        });
+    }
+}
+
+fn deprecated_function(query: &str, expr: &Expr, lexemes: &[Lexeme], diagnostics: &mut Vec<Diagnostic>) {
+    let mut calls = vec![];
+    walk(expr, &mut |e| if let Expr::Call(call) = e {
+        calls.push(call);
+    });
+    let outside = outside_braces(lexemes);
+    let names: Vec<Lexeme> = outside
+        .windows(2)
+        .filter(|pair| pair[0].id == T_IDENTIFIER && pair[1].id == T_LEFT_PAREN)
+        .map(|pair| pair[0])
+        .collect();
+    let aligned = align(calls.clone(), names);
+    for (i, call) in calls.into_iter().enumerate() {
+        let replacement = match DEPRECATED_FUNCTIONS.iter().find(|(old, _)| *old == call.func.name) {
+            Some((_, replacement)) => replacement,
+            None => continue,
+        };
+        let message = format!("{}() is deprecated, use {}() instead", call.func.name, replacement);
+        let diagnostic = Diagnostic::new("deprecated-function", message, Some(to_promql(&Expr::Call(call.clone()))));
+        diagnostics.push(match aligned.as_ref().map(|aligned| aligned[i].1) {
+            Some(name) if name.text(query) == call.func.name => diagnostic.with_fix(
+                format!("rename to {}", replacement),
+                vec![TextEdit { start: name.start, end: name.end, text: replacement.to_string() }],
+            ),
+            _ => diagnostic,
+        });
+    }
+}
+
+/// A regex matcher with, when they could be lined up, the lexemes of its
+/// label name, operator and value.
+struct RegexMatcher<'a> {
+    matcher: &'a Matcher,
+    lexemes: Option<[Lexeme; 3]>,
+}
+
+impl RegexMatcher<'_> {
+    fn diagnostic(&self, rule: &'static str, message: String) -> Diagnostic {
+        let diagnostic = Diagnostic::new(rule, message, Some(crate::printer::matcher(self.matcher)));
+        match self.lexemes {
+            Some([name, _, value]) => diagnostic.at(Span { start: name.start, end: value.end }),
+            None => diagnostic,
+        }
+    }
+
+    fn is_negated(&self) -> bool {
+        matches!(self.matcher.op, MatchOp::NotRe(_))
+    }
+}
+
+fn regex_matchers<'a>(vectors: &'a [VectorSelector], lexemes: &[Lexeme]) -> Vec<RegexMatcher<'a>> {
+    let matchers: Vec<&Matcher> = vectors
+        .iter()
+        .flat_map(|vs| vs.matchers.matchers.iter())
+        .filter(|m| matches!(m.op, MatchOp::Re(_) | MatchOp::NotRe(_)))
+        .collect();
+    let operators: Vec<usize> = (0..lexemes.len()).filter(|i| matches!(lexemes[*i].id, T_EQL_REGEX | T_NEQ_REGEX)).collect();
+    let aligned = align(matchers.clone(), operators);
+    matchers
+        .into_iter()
+        .enumerate()
+        .map(|(i, matcher)| {
+            // the matcher runs from the label name before its operator to the value after it
+            let lexemes = aligned.as_ref().and_then(|aligned| {
+                let operator = aligned[i].1;
+                Some([*lexemes.get(operator.checked_sub(1)?)?, lexemes[operator], *lexemes.get(operator + 1)?])
+            });
+            RegexMatcher { matcher, lexemes }
+        })
+        .collect()
+}
+
+fn literal_regex(matchers: &[RegexMatcher], diagnostics: &mut Vec<Diagnostic>) {
+    for regex in matchers {
+        let matcher = regex.matcher;
+        let literal = match analyze(&matcher.value).and_then(|literals| literals.alternatives) {
+            Some(alternatives) if alternatives.len() == 1 => alternatives[0].clone(),
+            _ => continue,
+        };
+        let equality = if regex.is_negated() { "!=" } else { "=" };
+        let message = format!(
+            "`{}{}\"{}\"` only matches one value, `{}` is cheaper",
+            matcher.name, matcher.op, matcher.value, equality,
+        );
+        let diagnostic = regex.diagnostic("literal-regex", message);
+        // only the operator changes, so the value must already read the same unescaped
+        diagnostics.push(match regex.lexemes {
+            Some([_, operator, _]) if literal == matcher.value => diagnostic.with_fix(
+                format!("use `{}`", equality),
+                vec![TextEdit { start: operator.start, end: operator.end, text: equality.to_string() }],
+            ),
+            _ => diagnostic,
+        });
+    }
+}
+
+/// Upstream keeps matcher values escaped, so the regex checks and fixes
+/// below only trust values without escapes.
+fn unescaped(matcher: &Matcher) -> bool {
+    !matcher.value.contains('\\')
+}
+
+fn needless_regex(matchers: &[RegexMatcher], diagnostics: &mut Vec<Diagnostic>) {
+    for regex in matchers.iter().filter(|regex| unescaped(regex.matcher)) {
+        let (name, value) = (&regex.matcher.name, &regex.matcher.value);
+        match needless(value) {
+            // the negated form never matches, which `always-empty` reports
+            Some(Needless::MatchesAll) if !regex.is_negated() => {
+                let message = format!("`{}=~\"{}\"` matches every value, even a missing label, and filters nothing", name, value);
+                diagnostics.push(regex.diagnostic("needless-regex", message));
+            }
+            Some(Needless::NonEmpty) => {
+                let (operator, equality) = if regex.is_negated() { ("=", "=\"\"") } else { ("!=", "!=\"\"") };
+                let message = format!("`{}{}\"{}\"` is `{}{}`, which is cheaper", name, regex.matcher.op, value, name, equality);
+                let diagnostic = regex.diagnostic("needless-regex", message);
+                diagnostics.push(match regex.lexemes {
+                    Some([_, op, value]) => diagnostic.with_fix(format!("use `{}`", equality), vec![
+                        TextEdit { start: op.start, end: op.end, text: operator.to_string() },
+                        TextEdit { start: value.start, end: value.end, text: "\"\"".to_string() },
+                    ]),
+                    None => diagnostic,
+                });
+            }
+            Some(Needless::Redundant { parts, pattern }) => {
+                let message = format!(
+                    "`{}{}\"{}\"` has {}, which change nothing as Prometheus anchors regexes at both ends",
+                    name, regex.matcher.op, value, parts.join(" and "),
+                );
+                let diagnostic = regex.diagnostic("needless-regex", message);
+                diagnostics.push(match regex.lexemes {
+                    Some([_, _, value]) => diagnostic.with_fix(
+                        format!("use `\"{}\"`", pattern),
+                        vec![TextEdit { start: value.start, end: value.end, text: quote(&pattern) }],
+                    ),
+                    None => diagnostic,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+fn negated_alternation(matchers: &[RegexMatcher], diagnostics: &mut Vec<Diagnostic>) {
+    for regex in matchers.iter().filter(|regex| regex.is_negated() && unescaped(regex.matcher)) {
+        let values = match analyze(&regex.matcher.value).and_then(|literals| literals.alternatives) {
+            Some(values) if values.len() > 1 => values,
+            _ => continue,
+        };
+        let name = label_name(&regex.matcher.name);
+        let replacement: Vec<String> = values.iter().map(|value| format!("{}!={}", name, quote(value))).collect();
+        let message = format!(
+            "`{}` only excludes {} values, `{}` is cheaper",
+            crate::printer::matcher(regex.matcher),
+            values.len(),
+            replacement.join(", "),
+        );
+        let diagnostic = regex.diagnostic("negated-alternation", message);
+        diagnostics.push(match regex.lexemes {
+            Some([name, _, value]) => diagnostic.with_fix(
+                "use one `!=` matcher per value".to_string(),
+                vec![TextEdit { start: name.start, end: value.end, text: replacement.join(", ") }],
+            ),
+            None => diagnostic,
+        });
+    }
+}
+
+fn incompatible_regex(matchers: &[RegexMatcher], diagnostics: &mut Vec<Diagnostic>) {
+    for regex in matchers {
+        let problems = re2_incompatibilities(&regex.matcher.value);
+        if !problems.is_empty() {
+            let message = format!("`{}` does not compile in Prometheus: {}", crate::printer::matcher(regex.matcher), problems.join(", "));
+            diagnostics.push(regex.diagnostic("incompatible-regex", message));
+        }
+    }
+}
+
+pub fn lint(query: &str, expr: &Expr) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for finding in always_empty(expr).findings {
+        diagnostics.push(Diagnostic::new("always-empty", finding.reason, Some(finding.expr)));
+    }
+    for grouping in discarded_grouping(expr) {
+        diagnostics.push(Diagnostic::new("discarded-grouping", grouping.message(), Some(grouping.expr)));
+    }
+    for rewrite in collapse_nested_aggregations(query, expr, &LabelGuard::default()).rewrites {
+        let message = format!("can be written as `{}`: {}", rewrite.after, rewrite.reason);
+        diagnostics.push(Diagnostic::new("redundant-aggregation", message, Some(rewrite.before)));
+    }
+    let lexemes = lex(query).unwrap_or_default();
+    missing_bool(expr, &lexemes, &mut diagnostics);
+    implicit_subquery_step(expr, &lexemes, &mut diagnostics);
+    deprecated_function(query, expr, &lexemes, &mut diagnostics);
+    let vectors = selectors(expr);
+    let regexes = regex_matchers(&vectors, &lexemes);
+    literal_regex(&regexes, &mut diagnostics);
+    needless_regex(&regexes, &mut diagnostics);
+    negated_alternation(&regexes, &mut diagnostics);
+    incompatible_regex(&regexes, &mut diagnostics);
+    locate(query, expr, &mut diagnostics);
+    diagnostics
+}
+
+/// Gives a span to the diagnostics without one: that of the first node
+/// reading like their `expr`, or else that of their fix.
+fn locate(query: &str, expr: &Expr, diagnostics: &mut [Diagnostic]) {
+    let nodes = spans_by_text(query, expr);
+    for diagnostic in diagnostics.iter_mut().filter(|d| d.span.is_none()) {
+        let node = diagnostic.expr.as_ref().and_then(|text| nodes.iter().find(|(node, _)| node == text));
+        let fix = diagnostic.fix.as_ref().and_then(|fix| {
+            let start = fix.edits.iter().map(|e| e.start).min()?;
+            let end = fix.edits.iter().map(|e| e.end).max()?;
+            Some(Span { start, end })
+        });
+        diagnostic.span = node.map(|(_, span)| *span).or(fix);
+    }
+}
+
+pub fn check_policy(expr: &Expr, permitted: &VectorSelector) -> Vec<Diagnostic> {
+    within(expr, permitted)
+        .selectors
+        .into_iter()
+        .filter(|(_, allowed)| *allowed != Some(true))
+        .map(|(selector, allowed)| {
+            let message = match allowed {
+                Some(_) => format!("`{}` selects series outside the permitted selector", selector),
+                None => format!("`{}` could not be proven to stay within the permitted selector", selector),
+            };
+            Diagnostic::new("outside-policy", message, Some(selector))
+        })
+        .collect()
+}
+
+/// Lints `query` and, given a permitted selector, checks it against that
+/// policy. Parse errors are reported as diagnostics too.
+pub fn check(query: &str, permitted: Option<&VectorSelector>) -> Vec<Diagnostic> {
+    let expr = match try_parse(query) {
+        Ok(expr) => expr,
+        Err(err) => {
+            let span = Span { start: err.start, end: err.end };
+            return vec![Diagnostic::new("invalid-query", err.message, None).at(span)];
+        }
+    };
+    let mut diagnostics = lint(query, &expr);
+    if let Some(permitted) = permitted {
+        let mut outside = check_policy(&expr, permitted);
+        locate(query, &expr, &mut outside);
+        diagnostics.extend(outside);
+    }
+    diagnostics
+}
+
+/// `check`, with the rules `config` turns off left out and the severities
+/// it sets in place of the defaults.
+pub fn check_configured(query: &str, config: &LintConfig, permitted: Option<&VectorSelector>) -> Result<Vec<Diagnostic>, String> {
+    if let Some(id) = config.rules.keys().find(|id| rule(id).is_none()) {
+        return Err(format!("unknown lint rule '{}'", id));
+    }
+    let diagnostics = check(query, permitted)
+        .into_iter()
+        .filter_map(|mut diagnostic| {
+            diagnostic.severity = match config.rules.get(diagnostic.rule) {
+                Some(RuleSetting::Off) => return None,
+                Some(RuleSetting::Info) => Severity::Info,
+                Some(RuleSetting::Warning) => Severity::Warning,
+                Some(RuleSetting::Error) => Severity::Error,
+                None => diagnostic.severity,
+            };
+            Some(diagnostic)
+        })
+        .collect();
+    Ok(diagnostics)
+}
+
+/// A query with lint fixes applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixed {
+    pub query: String,
+    pub applied: Vec<Diagnostic>,
+}
+
+impl ToSerde for Fixed {
+    fn to_serde(&self) -> Value {
+        json!({
+            "query": self.query,
+            "applied": self.applied.to_serde(),
+        })
+    }
+}
+
+/// `query` with `edits` applied, which must not overlap.
+fn apply_edits(query: &str, edits: &[&TextEdit]) -> String {
+    let mut edits = edits.to_vec();
+    edits.sort_by_key(|e| std::cmp::Reverse(e.start));
+    let mut fixed = query.to_string();
+    for edit in edits {
+        fixed.replace_range(edit.start..edit.end, &edit.text);
+    }
+    fixed
+}
+
+/// Applies the fixes of the given rules, or of every fixable rule if
+/// `rule_ids` is empty. Fixes overlapping an earlier one are skipped, as are
+/// those after which the query no longer parses, such as renames to
+/// functions that need `experimental_functions`.
+pub fn fix(query: &str, rule_ids: &[String], experimental_functions: bool) -> Result<Fixed, String> {
+    let parse = |query: &str| parse_gated(query, experimental_functions, &try_parse);
+    let expr = parse(query).map_err(|err| err.message)?;
+    let mut fixable: Vec<Diagnostic> = lint(query, &expr)
+        .into_iter()
+        .filter(|d| d.fix.is_some() && (rule_ids.is_empty() || rule_ids.iter().any(|id| id == d.rule)))
+        .collect();
+    fixable.sort_by_key(|d| d.fix.as_ref().map(|fix| fix.edits.iter().map(|e| e.start).min()));
+    let mut applied: Vec<Diagnostic> = vec![];
+    let mut edits: Vec<&TextEdit> = vec![];
+    for diagnostic in &fixable {
+        let fix = diagnostic.fix.as_ref().unwrap();
+        let overlaps = fix.edits.iter().any(|e| {
+            edits.iter().any(|o| (e.start < o.end && o.start < e.end) || e.start == o.start)
+        });
+        if overlaps {
+            continue;
+        }
+        let tried: Vec<&TextEdit> = edits.iter().copied().chain(&fix.edits).collect();
+        if parse(&apply_edits(query, &tried)).is_ok() {
+            edits = tried;
+            applied.push(diagnostic.clone());
+        }
+    }
+    Ok(Fixed { query: apply_edits(query, &edits), applied })
+}
+
+#[test]
+fn check_lint() {
+    let permitted = match parse("{env=\"prod\"}").unwrap() {
+        Expr::VectorSelector(vs) => vs,
+        _ => unreachable!(),
+    };
+    let payloads = vec![
+        ("sum(rate(x{env=\"prod\"}[5m]))", vec![]),
+        ("sum(sum by (a) (x{env=\"prod\"}))", vec!["discarded-grouping", "redundant-aggregation"]),
+        ("up{job=\"a\", job=\"b\"}", vec!["always-empty"]),
+        ("up{job=\"a\"}", vec!["outside-policy"]),
+        ("sum(", vec!["invalid-query"]),
+        ("up{env=\"prod\", job=~\"(?x) a\", a=~\"foo|.*\"}", vec!["literal-regex", "needless-regex", "incompatible-regex"]),
+    ];
+    for (query, rules) in payloads {
+        let found: Vec<&str> = check(query, Some(&permitted)).iter().map(|d| d.rule).collect();
+        assert_eq!(found, rules, "{}", query);
+    }
+    let query = "sum(rate(x{a=~\"b\"}[5m])) + up{job=\"a\", job=\"c\"} + (y == 1) * 2";
+    let located: Vec<(&str, &str)> =
+        check(query, None).iter().map(|d| (d.rule, d.span.map_or("", |s| &query[s.start..s.end]))).collect();
+    assert_eq!(located, vec![
+        ("always-empty", "up{job=\"a\", job=\"c\"}"),
+        ("missing-bool", "y == 1"),
+        ("literal-regex", "a=~\"b\""),
+    ]);
+    assert_eq!(check("sum(x", None)[0].span, Some(Span { start: 5, end: 5 }));
+    let config: LintConfig = serde_json::from_value(json!({
+        "rules": { "literal-regex": "off", "missing-bool": "error" },
+    })).unwrap();
+    let configured: Vec<(&str, Severity)> =
+        check_configured(query, &config, None).unwrap().iter().map(|d| (d.rule, d.severity)).collect();
+    assert_eq!(configured, vec![("always-empty", Severity::Error), ("missing-bool", Severity::Error)]);
+    let config: LintConfig = serde_json::from_value(json!({ "rules": { "no-such-rule": "off" } })).unwrap();
+    assert!(check_configured(query, &config, None).is_err());
+}
+
+#[test]
+fn check_fix() {
+    let payloads = vec![
+        ("avg(up{job=\"a\"} == 1)", "avg(up{job=\"a\"} == bool 1)"),
+        ("max_over_time(rate(x[5m])[1h:])", "max_over_time(rate(x[5m])[1h:1m])"),
+        ("x{a=~\"foo\", b!~\"bar\", c=~\"ba.\"}", "x{a=\"foo\", b!=\"bar\", c=~\"ba.\"}"),
+        ("count(up == 1) * (max_over_time(x[1h:]) == 1)", "count(up == 1) * (max_over_time(x[1h:1m]) == bool 1)"),
+        ("x{a=~\".+\", b=~\"^foo.*.*$\", c!~\"(.+)\"}", "x{a!=\"\", b=~\"foo.*\", c=\"\"}"),
+        ("x{a!~\"b|c\", d!~\"e\"}", "x{a!=\"b\", a!=\"c\", d!=\"e\"}"),
+    ];
+    for (query, expected) in payloads {
+        assert_eq!(fix(query, &[], false).unwrap().query, expected, "{}", query);
+    }
+    let only = fix("avg(x == 1) + max_over_time(y[5m:])", &["implicit-subquery-step".to_string()], false).unwrap();
+    assert_eq!(only.query, "avg(x == 1) + max_over_time(y[5m:1m])");
+    let renamed = fix("holt_winters(x[10m], 0.5, 0.5)", &[], false).unwrap();
+    assert_eq!((renamed.query.as_str(), renamed.applied.len()), ("holt_winters(x[10m], 0.5, 0.5)", 0));
+    let renamed = fix("holt_winters(x[10m], 0.5, 0.5)", &[], true).unwrap();
+    assert_eq!(renamed.query, "double_exponential_smoothing(x[10m], 0.5, 0.5)");
+}
@@ -0,0 +1,131 @@
+use promql_parser::parser::Expr;
+use promql_parser::label::*;
+use regex_syntax::hir::literal::{ExtractKind, Extractor, Seq};
+use serde_json::{json, Value};
+use crate::matchers::selectors;
+use crate::printer::to_promql;
+use crate::ToSerde;
+
+/// Alternation sets larger than this are not worth enumerating.
+const MAX_ALTERNATIVES: usize = 64;
+
+/// Literal facts about the values a regex matcher can accept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegexLiterals {
+    /// Every accepted value starts with this.
+    pub prefix: String,
+    /// Every accepted value ends with this.
+    pub suffix: String,
+    /// The complete, finite set of accepted values, if there is one.
+    pub alternatives: Option<Vec<String>>,
+}
+
+impl ToSerde for RegexLiterals {
+    fn to_serde(&self) -> Value {
+        json!({
+            "prefix": self.prefix,
+            "suffix": self.suffix,
+            "alternatives": self.alternatives,
+        })
+    }
+}
+
+fn extract(hir: &regex_syntax::hir::Hir, kind: ExtractKind) -> Seq {
+    let mut extractor = Extractor::new();
+    extractor.kind(kind).limit_total(MAX_ALTERNATIVES);
+    extractor.extract(hir)
+}
+
+fn utf8(bytes: Option<&[u8]>) -> String {
+    bytes
+        .and_then(|bytes| std::str::from_utf8(bytes).ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Analyzes `pattern` with Prometheus' anchoring, `None` if it does not compile.
+pub fn analyze(pattern: &str) -> Option<RegexLiterals> {
+    let hir = regex_syntax::Parser::new().parse(&format!("(?s:{})", pattern)).ok()?;
+    let prefixes = extract(&hir, ExtractKind::Prefix);
+    let suffixes = extract(&hir, ExtractKind::Suffix);
+    let alternatives = match prefixes.literals() {
+        Some(literals) if prefixes.is_exact() => literals
+            .iter()
+            .map(|literal| std::str::from_utf8(literal.as_bytes()).ok().map(str::to_string))
+            .collect::<Option<Vec<String>>>()
+            .map(|mut values| {
+                values.sort();
+                values.dedup();
+                values
+            }),
+        _ => None,
+    };
+    Some(RegexLiterals {
+        prefix: utf8(prefixes.longest_common_prefix()),
+        suffix: utf8(suffixes.longest_common_suffix()),
+        alternatives,
+    })
+}
+
+/// A regex matcher of a query with its literal analysis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatcherLiterals {
+    pub selector: String,
+    pub matcher: Matcher,
+    pub literals: Option<RegexLiterals>,
+}
+
+impl ToSerde for MatcherLiterals {
+    fn to_serde(&self) -> Value {
+        let mut value = json!({
+            "selector": self.selector,
+            "label": self.matcher.name,
+            "op": self.matcher.op.to_string(),
+            "regex": self.matcher.value,
+        });
+        if let (Some(literals), Value::Object(map)) = (&self.literals, &mut value) {
+            if let Value::Object(extra) = literals.to_serde() {
+                map.extend(extra);
+            }
+        }
+        value
+    }
+}
+
+/// Literal prefixes, suffixes and alternation sets of every regex matcher.
+pub fn regex_literals(expr: &Expr) -> Vec<MatcherLiterals> {
+    let mut found = vec![];
+    for vs in selectors(expr) {
+        let selector = to_promql(&Expr::VectorSelector(vs.clone()));
+        for matcher in &vs.matchers.matchers {
+            if let MatchOp::Re(_) | MatchOp::NotRe(_) = matcher.op {
+                found.push(MatcherLiterals {
+                    selector: selector.clone(),
+                    matcher: matcher.clone(),
+                    literals: analyze(&matcher.value),
+                });
+            }
+        }
+    }
+    found
+}
+
+
+#[test]
+fn check_analyze() {
+    let strings = |values: &[&str]| Some(values.iter().map(|v| v.to_string()).collect::<Vec<_>>());
+    let payloads = vec![
+        ("api-.*", "api-", "", None),
+        (".*-prod", "", "-prod", None),
+        ("prod|staging|dev", "", "", strings(&["dev", "prod", "staging"])),
+        ("eu-(west|east)-1", "eu-", "st-1", strings(&["eu-east-1", "eu-west-1"])),
+        ("foo", "foo", "foo", strings(&["foo"])),
+        ("[a-z]+", "", "", None),
+    ];
+    for (pattern, prefix, suffix, alternatives) in payloads {
+        let literals = analyze(pattern).unwrap();
+        assert_eq!(literals.prefix, prefix, "{}", pattern);
+        assert_eq!(literals.suffix, suffix, "{}", pattern);
+        assert_eq!(literals.alternatives, alternatives, "{}", pattern);
+    }
+}
@@ -0,0 +1,853 @@
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::errors::{located, ParseError};
+use crate::options::{self, SerializeOptions};
+use crate::spans::annotate_text;
+use crate::limits;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Identifier,
+    /// Quoted with `"` or `` ` ``, quotes included.
+    String,
+    Number,
+    Duration,
+    /// A size, as `20KB` or `1.5MiB`.
+    Bytes,
+    /// A parser flag, as `--strict`.
+    Flag,
+    Symbol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Token {
+    kind: Kind,
+    start: usize,
+    end: usize,
+}
+
+/// Symbols, longest first.
+const SYMBOLS: [&str; 26] = [
+    "|=", "|~", "|>", "!=", "!~", "!>", "=~", "==", ">=", "<=",
+    "|", "=", ">", "<", "+", "-", "*", "/", "%", "^", "{", "}", "(", ")", "[", "]",
+];
+
+/// Bytes per unit of a size, case-insensitively, as Loki reads them.
+fn unit(name: &str) -> Option<f64> {
+    let power = |base: f64, exp: i32| Some(base.powi(exp));
+    match name.to_ascii_lowercase().as_str() {
+        "b" => Some(1.0),
+        "k" | "kb" => power(1000.0, 1),
+        "m" | "mb" => power(1000.0, 2),
+        "g" | "gb" => power(1000.0, 3),
+        "t" | "tb" => power(1000.0, 4),
+        "p" | "pb" => power(1000.0, 5),
+        "e" | "eb" => power(1000.0, 6),
+        "ki" | "kib" => power(1024.0, 1),
+        "mi" | "mib" => power(1024.0, 2),
+        "gi" | "gib" => power(1024.0, 3),
+        "ti" | "tib" => power(1024.0, 4),
+        "pi" | "pib" => power(1024.0, 5),
+        "ei" | "eib" => power(1024.0, 6),
+        _ => None,
+    }
+}
+
+/// The duration `text` stands for, as Prometheus reads ranges, `1h30m`, or
+/// as Go does label values, `1.5s` or `250us`.
+fn duration(text: &str) -> Option<Duration> {
+    if let Ok(duration) = promql_parser::util::parse_duration(text) {
+        return Some(duration);
+    }
+    let mut rest = text;
+    let mut total = 0.0;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let value: f64 = rest[..split].parse().ok()?;
+        let len = rest[split..].find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(rest.len() - split);
+        let seconds = match &rest[split..split + len] {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += value * seconds;
+        rest = &rest[split + len..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+/// The number of bytes `text`, as `20KB`, stands for.
+fn bytes(text: &str) -> Option<f64> {
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let value: f64 = text[..split].parse().ok()?;
+    Some(value * unit(&text[split..])?)
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while let Some(c) = query[pos..].chars().next() {
+        let rest = &query[pos..];
+        let start = pos;
+        let (kind, len) = match c {
+            _ if c.is_whitespace() => {
+                pos += c.len_utf8();
+                continue;
+            }
+            '#' => {
+                pos += rest.find('\n').unwrap_or(rest.len());
+                continue;
+            }
+            '"' | '`' => {
+                let mut escaped = false;
+                let close = rest.char_indices().skip(1).find(|(_, next)| match next {
+                    '\\' if c == '"' && !escaped => {
+                        escaped = true;
+                        false
+                    }
+                    _ if *next == c && !escaped => true,
+                    _ => {
+                        escaped = false;
+                        false
+                    }
+                });
+                match close {
+                    Some((close, _)) => (Kind::String, close + 1),
+                    None => return Err(located(query, "unterminated quoted string".to_string(), "unterminated-string", start, query.len())),
+                }
+            }
+            ',' => (Kind::Symbol, 1),
+            '-' if rest.starts_with("--") && rest[2..].starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                let len = 2 + rest[2..].find(|c: char| !c.is_ascii_alphanumeric() && c != '-').unwrap_or(rest.len() - 2);
+                (Kind::Flag, len)
+            }
+            _ if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) => {
+                let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '.').unwrap_or(rest.len());
+                let word = &rest[..len];
+                let kind = if word.parse::<f64>().is_ok() {
+                    Kind::Number
+                } else if duration(word).is_some() {
+                    Kind::Duration
+                } else if bytes(word).is_some() {
+                    Kind::Bytes
+                } else {
+                    let message = format!("bad number, duration or size: {}", word);
+                    return Err(located(query, message, "invalid-syntax", start, start + len));
+                };
+                (kind, len)
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                (Kind::Identifier, rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len()))
+            }
+            _ => match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                Some(symbol) => (Kind::Symbol, symbol.len()),
+                None => {
+                    let message = format!("unexpected character: '{}'", c);
+                    return Err(located(query, message, "unexpected-character", start, start + c.len_utf8()));
+                }
+            },
+        };
+        pos += len;
+        tokens.push(Token { kind, start, end: pos });
+    }
+    Ok(tokens)
+}
+
+/// Binding strength and right associativity of a binary operator, as in PromQL.
+fn binary_op(op: &str) -> Option<(u8, bool)> {
+    match op {
+        "or" => Some((1, false)),
+        "and" | "unless" => Some((2, false)),
+        "==" | "!=" | ">" | ">=" | "<" | "<=" => Some((3, false)),
+        "+" | "-" => Some((4, false)),
+        "*" | "/" | "%" => Some((5, false)),
+        "^" => Some((6, true)),
+        _ => None,
+    }
+}
+
+const AGGREGATIONS: [&str; 11] =
+    ["sum", "avg", "min", "max", "stddev", "stdvar", "count", "topk", "bottomk", "sort", "sort_desc"];
+
+/// Range aggregations of log lines, then of unwrapped label values.
+const LOG_RANGE_AGGREGATIONS: [&str; 6] =
+    ["rate", "count_over_time", "bytes_rate", "bytes_over_time", "absent_over_time", "rate_counter"];
+const UNWRAPPED_RANGE_AGGREGATIONS: [&str; 9] = [
+    "sum_over_time", "avg_over_time", "max_over_time", "min_over_time", "stdvar_over_time",
+    "stddev_over_time", "quantile_over_time", "first_over_time", "last_over_time",
+];
+
+const LINE_FILTERS: [&str; 6] = ["|=", "!=", "|~", "!~", "|>", "!>"];
+const LABEL_OPS: [&str; 9] = ["=", "!=", "=~", "!~", "==", ">", ">=", "<", "<="];
+
+fn span(node: &Value) -> (usize, usize) {
+    let offset = |key: &str| node[key].as_u64().unwrap_or_default() as usize;
+    (offset("start"), offset("end"))
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    options: &'a SerializeOptions,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn text(&self, token: Token) -> &str {
+        &self.query[token.start..token.end]
+    }
+
+    fn peek_text(&self) -> Option<&str> {
+        self.peek().map(|token| self.text(token))
+    }
+
+    /// Whether the next token is the symbol or identifier `text`.
+    fn is(&self, text: &str) -> bool {
+        self.peek().is_some_and(|token| matches!(token.kind, Kind::Symbol | Kind::Identifier) && self.text(token) == text)
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        let found = self.is(text);
+        self.pos += usize::from(found);
+        found
+    }
+
+    /// End of the last token taken.
+    fn end(&self) -> usize {
+        self.pos.checked_sub(1).and_then(|last| self.tokens.get(last)).map_or(0, |token| token.end)
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.peek() {
+            Some(token) => {
+                let message = format!("unexpected `{}`, expected {}", self.text(token), expected);
+                located(self.query, message, "invalid-syntax", token.start, token.end)
+            }
+            None => {
+                let message = format!("unexpected end of input, expected {}", expected);
+                located(self.query, message, "unexpected-end", self.query.len(), self.query.len())
+            }
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, ParseError> {
+        match self.peek() {
+            Some(token) if self.is(text) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            _ => Err(self.unexpected(&format!("`{}`", text))),
+        }
+    }
+
+    fn take(&mut self, kind: Kind, expected: &str) -> Result<Token, ParseError> {
+        match self.peek() {
+            Some(token) if token.kind == kind => {
+                self.pos += 1;
+                Ok(token)
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, ParseError> {
+        let token = self.take(Kind::Identifier, "a label name")?;
+        Ok(self.text(token).to_string())
+    }
+
+    /// A string, as written between its quotes.
+    fn string(&mut self) -> Result<String, ParseError> {
+        let token = self.take(Kind::String, "a string")?;
+        Ok(self.query[token.start + 1..token.end - 1].to_string())
+    }
+
+    fn number(&mut self) -> Result<f64, ParseError> {
+        let token = self.take(Kind::Number, "a number")?;
+        Ok(self.text(token).parse().unwrap_or_default())
+    }
+
+    fn duration(&mut self) -> Result<Value, ParseError> {
+        let token = self.take(Kind::Duration, "a duration")?;
+        Ok(serialize_duration(&duration(self.text(token)).unwrap_or_default(), false))
+    }
+
+    /// Comma separated items `item` parses, up to the closing `close`.
+    fn list<T>(&mut self, close: &str, mut item: impl FnMut(&mut Self) -> Result<T, ParseError>) -> Result<Vec<T>, ParseError> {
+        let mut items = vec![];
+        while !self.eat(close) {
+            items.push(item(self)?);
+            if !self.eat(",") {
+                self.expect(close)?;
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    fn labels(&mut self) -> Result<Vec<String>, ParseError> {
+        self.expect("(")?;
+        self.list(")", Self::identifier)
+    }
+
+    fn grouping(&mut self) -> Result<Value, ParseError> {
+        if self.eat("by") {
+            Ok(json!({ "include": self.labels()? }))
+        } else if self.eat("without") {
+            Ok(json!({ "exclude": self.labels()? }))
+        } else {
+            Ok(Value::Null)
+        }
+    }
+
+    fn bin_modifier(&mut self, op: &str) -> Result<Value, ParseError> {
+        let comparison = binary_op(op).is_some_and(|(precedence, _)| precedence == 3);
+        let return_bool = comparison && self.eat("bool");
+        let matching = if self.eat("on") {
+            json!({ "include": self.labels()? })
+        } else if self.eat("ignoring") {
+            json!({ "exclude": self.labels()? })
+        } else {
+            Value::Null
+        };
+        let include = |parser: &mut Self| if parser.is("(") { parser.labels() } else { Ok(vec![]) };
+        let card = if self.eat("group_left") {
+            json!({ "@type": "many-to-one", "labels": include(self)? })
+        } else if self.eat("group_right") {
+            json!({ "@type": "one-to-many", "labels": include(self)? })
+        } else if matches!(op, "and" | "or" | "unless") {
+            json!({ "@type": "many-to-many" })
+        } else {
+            json!({ "@type": "one-to-one" })
+        };
+        if !return_bool && matching.is_null() && card["labels"].is_null() {
+            return Ok(Value::Null);
+        }
+        Ok(json!({ "card": card, "matching": matching, "return_bool": return_bool }))
+    }
+
+    /// Fails for a log query where a metric query is needed.
+    fn metric(&self, node: &Value, context: &str) -> Result<(), ParseError> {
+        if node["@type"] != "log_query" {
+            return Ok(());
+        }
+        let (start, end) = span(node);
+        let message = format!("a log query cannot be an operand of {}, only a metric query", context);
+        Err(located(self.query, message, "type-mismatch", start, end))
+    }
+
+    fn expr(&mut self, min: u8) -> Result<Value, ParseError> {
+        self.depth += 1;
+        if let Some(max_depth) = self.options.limits.max_depth.filter(|max| self.depth > *max) {
+            let start = self.peek().map_or(self.query.len(), |token| token.start);
+            let message = format!("query is nested deeper than {} expressions", max_depth);
+            return Err(located(self.query, message, "too-deep", start, self.query.len()));
+        }
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_text().map(str::to_string) {
+            let (precedence, right_assoc) = match binary_op(&op) {
+                Some((precedence, right_assoc)) if precedence >= min => (precedence, right_assoc),
+                _ => break,
+            };
+            self.pos += 1;
+            let modifier = self.bin_modifier(&op)?;
+            let rhs = self.expr(if right_assoc { precedence } else { precedence + 1 })?;
+            let context = format!("`{}`", op);
+            self.metric(&lhs, &context)?;
+            self.metric(&rhs, &context)?;
+            let (start, end) = (span(&lhs).0, span(&rhs).1);
+            lhs = json!({
+                "@type": "binary",
+                "lhs": lhs,
+                "op": op,
+                "rhs": rhs,
+                "modifier": modifier,
+                "precedence": precedence,
+                "is_right_assoc": right_assoc,
+                "start": start,
+                "end": end,
+            });
+        }
+        self.depth -= 1;
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Value, ParseError> {
+        let start = self.peek().map_or(0, |token| token.start);
+        if self.eat("+") {
+            return self.unary();
+        }
+        if !self.eat("-") {
+            return self.primary();
+        }
+        let expr = self.unary()?;
+        self.metric(&expr, "`-`")?;
+        let end = span(&expr).1;
+        Ok(match expr["value"].as_f64() {
+            Some(val) if expr["@type"] == "number" => json!({ "@type": "number", "value": -val, "start": start, "end": end }),
+            _ => json!({ "@type": "unary", "expr": expr, "start": start, "end": end }),
+        })
+    }
+
+    fn primary(&mut self) -> Result<Value, ParseError> {
+        let token = self.peek().ok_or_else(|| self.unexpected("a query"))?;
+        let start = token.start;
+        match token.kind {
+            Kind::Number => {
+                let value = self.number()?;
+                Ok(json!({ "@type": "number", "value": value, "start": start, "end": self.end() }))
+            }
+            Kind::Symbol if self.is("{") => self.log_query(),
+            Kind::Symbol if self.is("(") => {
+                self.pos += 1;
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                Ok(json!({ "@type": "paren", "expr": expr, "start": start, "end": self.end() }))
+            }
+            Kind::Identifier => {
+                let name = self.text(token).to_string();
+                self.pos += 1;
+                match name.as_str() {
+                    _ if AGGREGATIONS.contains(&name.as_str()) => self.aggregation(name, start),
+                    _ if LOG_RANGE_AGGREGATIONS.contains(&name.as_str()) || UNWRAPPED_RANGE_AGGREGATIONS.contains(&name.as_str()) =>
+                        self.range_aggregation(name, start),
+                    "vector" | "label_replace" => self.call(name, start),
+                    _ => {
+                        let message = format!("unknown function or aggregation `{}`", name);
+                        Err(located(self.query, message, "unknown-function", token.start, token.end))
+                    }
+                }
+            }
+            _ => Err(self.unexpected("a query")),
+        }
+    }
+
+    fn aggregation(&mut self, op: String, start: usize) -> Result<Value, ParseError> {
+        let mut modifier = self.grouping()?;
+        self.expect("(")?;
+        let param = match op.as_str() {
+            "topk" | "bottomk" => {
+                let param = self.expr(0)?;
+                self.expect(",")?;
+                param
+            }
+            _ => Value::Null,
+        };
+        let expr = self.expr(0)?;
+        self.metric(&expr, &format!("`{}`", op))?;
+        self.expect(")")?;
+        if modifier.is_null() {
+            modifier = self.grouping()?;
+        }
+        Ok(json!({
+            "@type": "aggregate",
+            "op": op,
+            "expr": expr,
+            "param": param,
+            "modifier": modifier,
+            "start": start,
+            "end": self.end(),
+        }))
+    }
+
+    fn range_aggregation(&mut self, op: String, start: usize) -> Result<Value, ParseError> {
+        self.expect("(")?;
+        let param = match op.as_str() {
+            "quantile_over_time" => {
+                let param = self.expr(0)?;
+                self.expect(",")?;
+                param
+            }
+            _ => Value::Null,
+        };
+        let range = self.log_range()?;
+        let unwrapped = range["expr"]["pipeline"].as_array().is_some_and(|stages| stages.iter().any(|stage| stage["@type"] == "unwrap"));
+        if unwrapped != UNWRAPPED_RANGE_AGGREGATIONS.contains(&op.as_str()) {
+            let (start, end) = span(&range);
+            let message = match unwrapped {
+                true => format!("`{}` cannot aggregate unwrapped label values", op),
+                false => format!("`{}` needs a range with an `unwrap` stage", op),
+            };
+            return Err(located(self.query, message, "type-mismatch", start, end));
+        }
+        self.expect(")")?;
+        let modifier = self.grouping()?;
+        Ok(json!({
+            "@type": "range_aggregation",
+            "op": op,
+            "param": param,
+            "expr": range,
+            "modifier": modifier,
+            "start": start,
+            "end": self.end(),
+        }))
+    }
+
+    fn call(&mut self, name: String, start: usize) -> Result<Value, ParseError> {
+        self.expect("(")?;
+        let args = self.list(")", |parser| match parser.peek() {
+            Some(token) if token.kind == Kind::String => {
+                let value = parser.string()?;
+                Ok(json!({ "@type": "string", "value": value, "start": token.start, "end": token.end }))
+            }
+            _ => parser.expr(0),
+        })?;
+        let expected = if name == "vector" { 1 } else { 5 };
+        if args.len() != expected {
+            let message = format!("expected {} argument(s) in call to '{}', got {}", expected, name, args.len());
+            return Err(located(self.query, message, "wrong-argument-count", start, self.end()));
+        }
+        Ok(json!({
+            "@type": "call",
+            "function": { "name": name },
+            "args": args,
+            "start": start,
+            "end": self.end(),
+        }))
+    }
+
+    /// A log query with a range, as in `{app="api"} |= "error" [5m]`, the
+    /// range possibly right after the selector or after parentheses.
+    fn log_range(&mut self) -> Result<Value, ParseError> {
+        let start = self.peek().map_or(0, |token| token.start);
+        let (expr, range) = if self.eat("(") {
+            let expr = self.log_query()?;
+            self.expect(")")?;
+            (expr, self.range()?)
+        } else {
+            let selector_start = self.pos;
+            self.selector()?;
+            match self.is("[") {
+                true => {
+                    let range = self.range()?;
+                    let after_range = self.pos;
+                    self.pos = selector_start;
+                    (self.log_query_skipping(after_range)?, range)
+                }
+                false => {
+                    self.pos = selector_start;
+                    let expr = self.log_query()?;
+                    (expr, self.range()?)
+                }
+            }
+        };
+        let offset = match self.eat("offset") {
+            true => self.duration()?,
+            false => Value::Null,
+        };
+        Ok(json!({
+            "@type": "log_range",
+            "expr": expr,
+            "range": range,
+            "offset": offset,
+            "start": start,
+            "end": self.end(),
+        }))
+    }
+
+    fn range(&mut self) -> Result<Value, ParseError> {
+        self.expect("[")?;
+        let range = self.duration()?;
+        self.expect("]")?;
+        Ok(range)
+    }
+
+    /// A log query whose selector is followed by a range, at `after_range`,
+    /// before its pipeline.
+    fn log_query_skipping(&mut self, after_range: usize) -> Result<Value, ParseError> {
+        let start = self.peek().map_or(0, |token| token.start);
+        let matchers = self.selector()?;
+        self.pos = after_range;
+        let pipeline = self.pipeline()?;
+        Ok(json!({ "@type": "log_query", "matchers": matchers, "pipeline": pipeline, "start": start, "end": self.end() }))
+    }
+
+    fn log_query(&mut self) -> Result<Value, ParseError> {
+        let start = self.peek().map_or(0, |token| token.start);
+        let matchers = self.selector()?;
+        let pipeline = self.pipeline()?;
+        Ok(json!({ "@type": "log_query", "matchers": matchers, "pipeline": pipeline, "start": start, "end": self.end() }))
+    }
+
+    /// The matchers of a stream selector, at least one.
+    fn selector(&mut self) -> Result<Vec<Value>, ParseError> {
+        let open = self.expect("{")?;
+        let matchers = self.list("}", |parser| {
+            let name = parser.identifier()?;
+            let op = match parser.peek_text() {
+                Some(op @ ("=" | "!=" | "=~" | "!~")) => op.to_string(),
+                _ => return Err(parser.unexpected("one of `=`, `!=`, `=~` or `!~`")),
+            };
+            parser.pos += 1;
+            let value = parser.string()?;
+            Ok(json!({ "name": name, "op": op, "value": value }))
+        })?;
+        if matchers.is_empty() {
+            let message = "stream selector must contain at least one matcher".to_string();
+            return Err(located(self.query, message, "empty-selector", open.start, self.end()));
+        }
+        Ok(matchers)
+    }
+
+    fn pipeline(&mut self) -> Result<Vec<Value>, ParseError> {
+        let mut stages = vec![];
+        loop {
+            let start = self.peek().map_or(0, |token| token.start);
+            let filter = self.peek_text().filter(|op| LINE_FILTERS.contains(op)).map(str::to_string);
+            let next = self.tokens.get(self.pos + 1).map(|token| token.kind);
+            let stage = match filter {
+                Some(op) if next == Some(Kind::String) => {
+                    self.pos += 1;
+                    let mut values = vec![self.string()?];
+                    while self.is("or") && self.tokens.get(self.pos + 1).map(|token| token.kind) == Some(Kind::String) {
+                        self.pos += 1;
+                        values.push(self.string()?);
+                    }
+                    json!({ "@type": "line_filter", "op": op, "values": values })
+                }
+                _ if self.is("|") => {
+                    self.pos += 1;
+                    self.stage()?
+                }
+                _ => return Ok(stages),
+            };
+            let mut stage = stage;
+            stage["start"] = json!(start);
+            stage["end"] = json!(self.end());
+            stages.push(stage);
+        }
+    }
+
+    /// A stage after `|`.
+    fn stage(&mut self) -> Result<Value, ParseError> {
+        let keyword = self.peek().filter(|token| token.kind == Kind::Identifier).map(|token| self.text(token).to_string());
+        let next = self.tokens.get(self.pos + 1).map(|token| self.text(*token));
+        // a label may be named like a stage, as in `| json | json="x"`
+        if keyword.is_none() || next.is_some_and(|next| LABEL_OPS.contains(&next)) {
+            return Ok(json!({ "@type": "label_filter", "expr": self.label_filter(0)? }));
+        }
+        let keyword = keyword.unwrap_or_default();
+        match keyword.as_str() {
+            "json" | "logfmt" => {
+                self.pos += 1;
+                let mut flags = vec![];
+                while let Some(token) = self.peek().filter(|token| token.kind == Kind::Flag) {
+                    flags.push(self.text(token).to_string());
+                    self.pos += 1;
+                }
+                let mut params = vec![];
+                while !params.is_empty() || self.starts_param() {
+                    let label = self.identifier()?;
+                    let expression = match self.eat("=") {
+                        true => Value::from(self.string()?),
+                        false => Value::Null,
+                    };
+                    params.push(json!({ "label": label, "expression": expression }));
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                Ok(json!({ "@type": "label_parser", "parser": keyword, "flags": flags, "param": null, "params": params }))
+            }
+            "regexp" | "pattern" => {
+                self.pos += 1;
+                let param = self.string()?;
+                Ok(json!({ "@type": "label_parser", "parser": keyword, "flags": [], "param": param, "params": [] }))
+            }
+            "unpack" => {
+                self.pos += 1;
+                Ok(json!({ "@type": "label_parser", "parser": keyword, "flags": [], "param": null, "params": [] }))
+            }
+            "line_format" => {
+                self.pos += 1;
+                Ok(json!({ "@type": "line_format", "template": self.string()? }))
+            }
+            "label_format" => {
+                self.pos += 1;
+                let mut labels = vec![];
+                loop {
+                    let name = self.identifier()?;
+                    self.expect("=")?;
+                    let (value, template) = match self.peek().map(|token| token.kind) {
+                        Some(Kind::String) => (self.string()?, true),
+                        _ => (self.identifier()?, false),
+                    };
+                    labels.push(json!({ "name": name, "value": value, "template": template }));
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                Ok(json!({ "@type": "label_format", "labels": labels }))
+            }
+            "drop" | "keep" => {
+                self.pos += 1;
+                let mut labels = vec![];
+                loop {
+                    let name = self.identifier()?;
+                    let matcher = match self.peek_text() {
+                        Some(op @ ("=" | "!=" | "=~" | "!~")) => {
+                            let op = op.to_string();
+                            self.pos += 1;
+                            json!({ "op": op, "value": self.string()? })
+                        }
+                        _ => Value::Null,
+                    };
+                    labels.push(json!({ "name": name, "matcher": matcher }));
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                Ok(json!({ "@type": keyword, "labels": labels }))
+            }
+            "decolorize" => {
+                self.pos += 1;
+                Ok(json!({ "@type": "decolorize" }))
+            }
+            "unwrap" => {
+                self.pos += 1;
+                let name = self.identifier()?;
+                let (label, conversion) = match name.as_str() {
+                    "duration" | "duration_seconds" | "bytes" if self.eat("(") => {
+                        let label = self.identifier()?;
+                        self.expect(")")?;
+                        (label, Value::from(name))
+                    }
+                    _ => (name, Value::Null),
+                };
+                Ok(json!({ "@type": "unwrap", "label": label, "conversion": conversion }))
+            }
+            _ => Ok(json!({ "@type": "label_filter", "expr": self.label_filter(0)? })),
+        }
+    }
+
+    /// Whether a `json` or `logfmt` parameter, `label` or `label="..."`, follows.
+    fn starts_param(&self) -> bool {
+        let token = |i: usize| self.tokens.get(self.pos + i).copied();
+        match (token(0), token(1).map(|second| self.text(second))) {
+            (Some(first), Some("=")) if first.kind == Kind::Identifier => token(2).is_some_and(|third| third.kind == Kind::String),
+            (Some(first), second) => first.kind == Kind::Identifier && !second.is_some_and(|second| LABEL_OPS.contains(&second)),
+            _ => false,
+        }
+    }
+
+    /// Label filters joined by `and`, `,` or `or`, `and` binding tighter.
+    fn label_filter(&mut self, min: u8) -> Result<Value, ParseError> {
+        let mut lhs = self.label_comparison()?;
+        loop {
+            let (op, precedence) = match self.peek_text() {
+                Some("or") => ("or", 1),
+                Some("and" | ",") => ("and", 2),
+                _ => break,
+            };
+            if precedence < min {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.label_filter(precedence + 1)?;
+            let (start, end) = (span(&lhs).0, span(&rhs).1);
+            lhs = json!({ "@type": "label_filter_binary", "op": op, "lhs": lhs, "rhs": rhs, "start": start, "end": end });
+        }
+        Ok(lhs)
+    }
+
+    fn label_comparison(&mut self) -> Result<Value, ParseError> {
+        let start = self.peek().map_or(0, |token| token.start);
+        if self.eat("(") {
+            let expr = self.label_filter(0)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        let name = self.identifier()?;
+        let op = match self.peek_text() {
+            Some(op) if LABEL_OPS.contains(&op) => op.to_string(),
+            _ => return Err(self.unexpected("a label filter operator")),
+        };
+        self.pos += 1;
+        let token = self.peek().ok_or_else(|| self.unexpected("a value"))?;
+        let text = self.text(token);
+        let (value, value_type) = match token.kind {
+            Kind::String if matches!(op.as_str(), "=" | "!=" | "=~" | "!~") => {
+                (Value::from(&self.query[token.start + 1..token.end - 1]), "string")
+            }
+            Kind::Number => (json!(text.parse::<f64>().unwrap_or_default()), "number"),
+            Kind::Duration => (serialize_duration(&duration(text).unwrap_or_default(), false), "duration"),
+            Kind::Bytes => (json!(bytes(text).unwrap_or_default()), "bytes"),
+            _ => return Err(self.unexpected("a string, number, duration or size")),
+        };
+        self.pos += 1;
+        Ok(json!({
+            "@type": "label_comparison",
+            "name": name,
+            "op": op,
+            "value": value,
+            "value_type": value_type,
+            "start": start,
+            "end": self.end(),
+        }))
+    }
+}
+
+fn serialize_duration(dur: &Duration, negative: bool) -> Value {
+    options::current().durations.serialize(dur, negative)
+}
+
+/// Parses Loki LogQL `query` into a JSON AST like that of PromQL, as
+/// `options` say: `log_query` nodes for stream selectors and their pipeline
+/// stages, `log_range` for them over a range, `range_aggregation` for the
+/// functions of log ranges and `aggregate`, `binary`, `number` and `paren`
+/// nodes as in PromQL. Strings are kept as written between their quotes.
+pub fn parse(query: &str, options: &SerializeOptions) -> Result<Value, ParseError> {
+    limits::check_length(query, &options.limits)?;
+    let tokens = tokenize(query)?;
+    if tokens.is_empty() {
+        return Err(located(query, "no expression found in input".to_string(), "empty-query", 0, query.len()));
+    }
+    let mut parser = Parser { query, tokens, pos: 0, options, depth: 0 };
+    let mut ast = options::with_options(options.clone(), || parser.expr(0))?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected("the end of the query"));
+    }
+    if options.text {
+        annotate_text(&mut ast, query);
+    }
+    Ok(ast)
+}
+
+
+#[test]
+fn check_logql() {
+    let options = SerializeOptions::default();
+    let query = "sum by (level) (count_over_time({app=\"api\", env=~\"prod|stg\"} |= \"error\" or \"fatal\" != `debug` | json | level=\"err\" and duration > 1.5s, size >= 20KB [5m] offset 1h)) / 2";
+    let ast = parse(query, &options).unwrap();
+    assert_eq!((&ast["@type"], &ast["op"], &ast["precedence"]), (&json!("binary"), &json!("/"), &json!(5)));
+    let range = &ast["lhs"]["expr"]["expr"];
+    assert_eq!((&range["@type"], &range["range"], &range["offset"]), (&json!("log_range"), &json!(300), &json!(3600)));
+    let log = &range["expr"];
+    assert_eq!(log["matchers"][1], json!({ "name": "env", "op": "=~", "value": "prod|stg" }));
+    let stages = log["pipeline"].as_array().unwrap();
+    assert_eq!((&stages[0]["values"], &stages[1]["values"]), (&json!(["error", "fatal"]), &json!(["debug"])));
+    assert_eq!((&stages[2]["parser"], &stages[3]["@type"]), (&json!("json"), &json!("label_filter")));
+    let filter = &stages[3]["expr"];
+    assert_eq!((&filter["op"], &filter["lhs"]["op"]), (&json!("and"), &json!("and")));
+    assert_eq!((&filter["lhs"]["rhs"]["value"], &filter["rhs"]["value"]), (&json!(1.5), &json!(20000.0)));
+    assert_eq!((log["start"].as_u64(), log["end"].as_u64()), (Some(32), Some(148)));
+    let unwrapped = parse("quantile_over_time(0.99, {app=\"api\"}[1m] | logfmt --strict status, rt=\"resp.time\" | unwrap duration(rt) | __error__=\"\") by (status)", &options).unwrap();
+    assert_eq!((&unwrapped["param"]["value"], &unwrapped["modifier"]), (&json!(0.99), &json!({ "include": ["status"] })));
+    let stages = &unwrapped["expr"]["expr"]["pipeline"];
+    assert_eq!(stages[0]["flags"], json!(["--strict"]));
+    assert_eq!(stages[0]["params"][1], json!({ "label": "rt", "expression": "resp.time" }));
+    assert_eq!((&stages[1]["label"], &stages[1]["conversion"]), (&json!("rt"), &json!("duration")));
+    let error = parse("sum_over_time({app=\"api\"}[5m])", &options).unwrap_err();
+    assert_eq!((error.code, error.message.as_str()), ("type-mismatch", "`sum_over_time` needs a range with an `unwrap` stage"));
+    assert_eq!(parse("{app=\"api\"} + 1", &options).unwrap_err().code, "type-mismatch");
+    assert_eq!(parse("{}", &options).unwrap_err().code, "empty-selector");
+    assert_eq!(parse("rate({app=\"api\"}[5m]", &options).unwrap_err().code, "unexpected-end");
+}
@@ -0,0 +1,225 @@
+use std::time::Duration;
+use promql_parser::parser::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::timestamps::millis;
+use crate::visit::children;
+use crate::ToSerde;
+
+/// How far back Prometheus looks for a sample of an instant vector selector.
+pub const LOOKBACK_DELTA: Duration = Duration::from_secs(5 * 60);
+
+/// The lookback delta the server runs with, and its retention to check against.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LookbackOptions {
+    /// `--query.lookback-delta`, in seconds.
+    pub lookback_delta: f64,
+    /// `--storage.tsdb.retention.time`, in seconds.
+    pub retention: Option<f64>,
+}
+
+impl Default for LookbackOptions {
+    fn default() -> Self {
+        LookbackOptions { lookback_delta: LOOKBACK_DELTA.as_secs_f64(), retention: None }
+    }
+}
+
+/// The history the query reads furthest back, split into what adds up to it
+/// along that path, all in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Lookback {
+    /// Matrix selector and subquery ranges.
+    pub ranges: i128,
+    pub offsets: i128,
+    /// Lookback delta of an instant vector selector at the end of the path.
+    pub delta: i128,
+    /// Retention, if it was given.
+    pub retention: Option<i128>,
+}
+
+impl Lookback {
+    pub fn total(&self) -> i128 {
+        (self.ranges + self.offsets + self.delta).max(0)
+    }
+}
+
+impl ToSerde for Lookback {
+    fn to_serde(&self) -> Value {
+        let seconds = |millis: i128| millis as f64 / 1000.0;
+        json!({
+            "seconds": seconds(self.total()),
+            "ranges_seconds": seconds(self.ranges),
+            "offsets_seconds": seconds(self.offsets),
+            "lookback_delta_seconds": seconds(self.delta),
+            "exceeds_retention": self.retention.map(|retention| self.total() > retention),
+        })
+    }
+}
+
+/// Shift into the past of an offset, in milliseconds (negative for `offset -1m`).
+pub fn offset_millis(offset: &Option<Offset>) -> i128 {
+    match offset {
+        Some(Offset::Pos(dur)) => dur.as_millis() as i128,
+        Some(Offset::Neg(dur)) => -(dur.as_millis() as i128),
+        None => 0,
+    }
+}
+
+fn lookback_parts(expr: &Expr, delta: i128) -> Lookback {
+    match expr {
+        Expr::VectorSelector(vs) => Lookback { offsets: offset_millis(&vs.offset), delta, ..Lookback::default() },
+        Expr::MatrixSelector(MatrixSelector { vs, range }) =>
+            Lookback { ranges: range.as_millis() as i128, offsets: offset_millis(&vs.offset), ..Lookback::default() },
+        Expr::Subquery(SubqueryExpr { expr, range, offset, .. }) => {
+            let inner = lookback_parts(expr, delta);
+            Lookback {
+                ranges: inner.ranges + range.as_millis() as i128,
+                offsets: inner.offsets + offset_millis(offset),
+                ..inner
+            }
+        }
+        _ => children(expr)
+            .into_iter()
+            .map(|child| lookback_parts(child, delta))
+            .max_by_key(|parts| parts.ranges + parts.offsets + parts.delta)
+            .unwrap_or_default(),
+    }
+}
+
+/// How much history before the evaluation time `expr` reads, including the
+/// lookback delta of instant selectors. `@` modifiers are not taken into account.
+pub fn lookback(expr: &Expr) -> Duration {
+    Duration::from_millis(lookback_parts(expr, LOOKBACK_DELTA.as_millis() as i128).total() as u64)
+}
+
+/// The history `expr` reads furthest back, with the ranges, offsets and
+/// lookback delta it is made of, and whether it exceeds the retention.
+pub fn lookback_with(expr: &Expr, options: &LookbackOptions) -> Lookback {
+    let millis = |seconds: f64| (seconds * 1000.0).round() as i128;
+    Lookback { retention: options.retention.map(millis), ..lookback_parts(expr, millis(options.lookback_delta)) }
+}
+
+
+/// When a query is evaluated: at `time`, or every step from `start` to `end`,
+/// in milliseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct EvalTimes {
+    pub time: Option<f64>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    /// `--query.lookback-delta`, in seconds.
+    pub lookback_delta: f64,
+}
+
+impl Default for EvalTimes {
+    fn default() -> Self {
+        EvalTimes { time: None, start: None, end: None, lookback_delta: LOOKBACK_DELTA.as_secs_f64() }
+    }
+}
+
+/// The samples a query reads, from `min` to `max` milliseconds since the
+/// epoch, both included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBounds {
+    pub min: i128,
+    pub max: i128,
+}
+
+impl TimeBounds {
+    fn union(self, other: TimeBounds) -> TimeBounds {
+        TimeBounds { min: self.min.min(other.min), max: self.max.max(other.max) }
+    }
+}
+
+impl ToSerde for Option<TimeBounds> {
+    fn to_serde(&self) -> Value {
+        json!({
+            "min_time": self.map(|bounds| bounds.min as f64),
+            "max_time": self.map(|bounds| bounds.max as f64),
+        })
+    }
+}
+
+struct Resolver {
+    /// Start and end of the query, which `@ start()` and `@ end()` pin to.
+    query: TimeBounds,
+    delta: i128,
+}
+
+impl Resolver {
+    /// The evaluation times `eval` pinned by `at` and shifted by `offset`.
+    fn shift(&self, eval: TimeBounds, at: &Option<AtModifier>, offset: &Option<Offset>) -> TimeBounds {
+        let eval = match at {
+            Some(AtModifier::Start) => TimeBounds { min: self.query.min, max: self.query.min },
+            Some(AtModifier::End) => TimeBounds { min: self.query.max, max: self.query.max },
+            Some(AtModifier::At(time)) => TimeBounds { min: millis(time), max: millis(time) },
+            None => eval,
+        };
+        let offset = offset_millis(offset);
+        TimeBounds { min: eval.min - offset, max: eval.max - offset }
+    }
+
+    /// What `expr` reads when evaluated from `eval.min` to `eval.max`.
+    fn bounds(&self, expr: &Expr, eval: TimeBounds) -> Option<TimeBounds> {
+        let back = |eval: TimeBounds, range: i128| TimeBounds { min: eval.min - range, max: eval.max };
+        match expr {
+            Expr::VectorSelector(vs) => Some(back(self.shift(eval, &vs.at, &vs.offset), self.delta)),
+            Expr::MatrixSelector(MatrixSelector { vs, range }) =>
+                Some(back(self.shift(eval, &vs.at, &vs.offset), range.as_millis() as i128)),
+            Expr::Subquery(SubqueryExpr { expr, range, offset, at, .. }) =>
+                self.bounds(expr, back(self.shift(eval, at, offset), range.as_millis() as i128)),
+            _ => children(expr)
+                .into_iter()
+                .filter_map(|child| self.bounds(child, eval))
+                .reduce(TimeBounds::union),
+        }
+    }
+}
+
+/// The interval of samples `expr` reads when evaluated at `times`, with its
+/// offsets, `@` modifiers, ranges and lookback deltas resolved; `None` if it
+/// has no selectors. Subquery steps are not aligned, so the interval may start
+/// up to a step earlier than Prometheus reads.
+pub fn time_bounds(expr: &Expr, times: &EvalTimes) -> Result<Option<TimeBounds>, String> {
+    let query = match (times.time, times.start, times.end) {
+        (Some(time), None, None) => TimeBounds { min: time.round() as i128, max: time.round() as i128 },
+        (None, Some(start), Some(end)) if start <= end => TimeBounds { min: start.round() as i128, max: end.round() as i128 },
+        (None, Some(_), Some(_)) => return Err("`start` is after `end`".to_string()),
+        _ => return Err("time bounds need either `time`, or `start` and `end`".to_string()),
+    };
+    let resolver = Resolver { query, delta: (times.lookback_delta * 1000.0).round() as i128 };
+    Ok(resolver.bounds(expr, query))
+}
+
+#[test]
+fn check_lookback() {
+    let payloads = vec![
+        ("up", 300),
+        ("rate(x[10m] offset 1h)", 4200),
+        ("max_over_time(rate(x[5m])[1h:1m])", 3900),
+        ("up offset -10m", 0),
+        ("1 + 2", 0),
+    ];
+    for (query, seconds) in payloads {
+        assert_eq!(lookback(&parse(query).unwrap()).as_secs(), seconds, "{}", query);
+    }
+    let options = LookbackOptions { lookback_delta: 60.0, retention: Some(3600.0) };
+    let found = lookback_with(&parse("max_over_time(x[1h:] offset 5m) / y").unwrap(), &options);
+    assert_eq!(found, Lookback { ranges: 3600000, offsets: 300000, delta: 60000, retention: Some(3600000) });
+    assert_eq!(found.to_serde()["exceeds_retention"], json!(true));
+    assert_eq!(lookback_with(&parse("y").unwrap(), &LookbackOptions::default()).to_serde()["exceeds_retention"], json!(null));
+    let bounds = |query: &str, times: EvalTimes| {
+        time_bounds(&parse(query).unwrap(), &times).unwrap().map(|bounds| (bounds.min, bounds.max))
+    };
+    let at = |time: f64| EvalTimes { time: Some(time), ..EvalTimes::default() };
+    let range = |start: f64, end: f64| EvalTimes { start: Some(start), end: Some(end), ..EvalTimes::default() };
+    assert_eq!(bounds("up", at(1000000.0)), Some((700000, 1000000)));
+    assert_eq!(bounds("rate(x[1m] offset 1m) + y @ 100", at(1000000.0)), Some((-200000, 940000)));
+    assert_eq!(bounds("x[1m] @ end()", range(0.0, 3600000.0)), Some((3540000, 3600000)));
+    assert_eq!(bounds("max_over_time(x[5m:1m] offset 1h)", range(7200000.0, 10800000.0)), Some((3000000, 7200000)));
+    assert_eq!(bounds("max_over_time(x[5m:1m] @ start())", range(7200000.0, 10800000.0)), Some((6600000, 7200000)));
+    assert_eq!(bounds("1 + 2", at(0.0)), None);
+    assert!(time_bounds(&parse("up").unwrap(), &EvalTimes::default()).is_err());
+}
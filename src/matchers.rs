@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use promql_parser::label::*;
+use promql_parser::parser::{Expr, MatrixSelector, VectorSelector};
+use serde_json::{json, Value};
+use crate::canonical::canonical;
+use crate::printer::{to_promql, unescape};
+use crate::visit::walk;
+use crate::ToSerde;
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::{Anchored, Input};
+
+/// Upper bound on product states explored before giving up on a proof.
+const MAX_STATES: usize = 10_000;
+const DFA_SIZE_LIMIT: usize = 1 << 20;
+
+/// Outcome of asking whether some label value satisfies a set of matchers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Satisfiability {
+    /// Satisfiable, with an example value.
+    Witness(String),
+    Unsat,
+    /// The matchers were too complex to decide.
+    Unknown,
+}
+
+impl Satisfiability {
+    pub fn is_unsat(&self) -> bool {
+        matches!(self, Satisfiability::Unsat)
+    }
+}
+
+/// Compiles `pattern` with Prometheus semantics: fully anchored, `.` matching
+/// newlines.
+fn compile(pattern: &str) -> Option<dense::DFA<Vec<u32>>> {
+    dense::Builder::new()
+        .configure(
+            dense::Config::new()
+                .start_kind(StartKind::Anchored)
+                .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+                .determinize_size_limit(Some(DFA_SIZE_LIMIT)),
+        )
+        .build(&format!("^(?s:{})$", pattern))
+        .ok()
+}
+
+/// The label value or regex of `matcher`, without the Go escapes upstream
+/// keeps in it: `"a\\.b"` is the regex `a\.b`.
+fn value(matcher: &Matcher) -> String {
+    unescape(&matcher.value)
+}
+
+/// A matcher as a regular language over label values, possibly negated.
+struct Constraint {
+    dfa: dense::DFA<Vec<u32>>,
+    negated: bool,
+}
+
+impl Constraint {
+    fn new(matcher: &Matcher) -> Option<Constraint> {
+        let (pattern, negated) = match &matcher.op {
+            MatchOp::Equal => (regex_syntax::escape(&value(matcher)), false),
+            MatchOp::NotEqual => (regex_syntax::escape(&value(matcher)), true),
+            MatchOp::Re(_) => (value(matcher), false),
+            MatchOp::NotRe(_) => (value(matcher), true),
+        };
+        Some(Constraint { dfa: compile(&pattern)?, negated })
+    }
+
+    /// Only valid UTF-8 strings are label values.
+    fn any_value() -> Option<Constraint> {
+        Some(Constraint { dfa: compile(".*")?, negated: false })
+    }
+
+    fn start(&self) -> Option<StateID> {
+        self.dfa
+            .start_state_forward(&Input::new("").anchored(Anchored::Yes))
+            .ok()
+    }
+
+    fn accepts(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state)) != self.negated
+    }
+
+    /// Whether no continuation from `state` can ever be accepted.
+    fn is_dead(&self, state: StateID) -> bool {
+        !self.negated && self.dfa.is_dead_state(state)
+    }
+}
+
+/// Decides whether a single label value can satisfy every matcher in
+/// `matchers` by exploring the product of their automata.
+pub fn satisfiable(matchers: &[&Matcher]) -> Satisfiability {
+    // equality pins the only candidate value, no automata needed
+    if let Some(eq) = matchers.iter().find(|m| m.op == MatchOp::Equal) {
+        let pinned = value(eq);
+        return match matchers.iter().all(|m| matches_value(m, &pinned)) {
+            true => Satisfiability::Witness(pinned),
+            false => Satisfiability::Unsat,
+        };
+    }
+    let mut constraints = vec![];
+    for matcher in matchers.iter().copied() {
+        match Constraint::new(matcher) {
+            Some(constraint) => constraints.push(constraint),
+            None => return Satisfiability::Unknown,
+        }
+    }
+    match Constraint::any_value() {
+        Some(constraint) => constraints.push(constraint),
+        None => return Satisfiability::Unknown,
+    }
+    let start: Option<Vec<StateID>> = constraints.iter().map(|c| c.start()).collect();
+    let start = match start {
+        Some(start) => start,
+        None => return Satisfiability::Unknown,
+    };
+    let mut seen: HashSet<Vec<StateID>> = HashSet::new();
+    let mut queue: VecDeque<(Vec<StateID>, Vec<u8>)> = VecDeque::new();
+    seen.insert(start.clone());
+    queue.push_back((start, vec![]));
+    while let Some((states, value)) = queue.pop_front() {
+        if constraints.iter().zip(&states).all(|(c, s)| c.accepts(*s)) {
+            return match String::from_utf8(value) {
+                Ok(value) => Satisfiability::Witness(value),
+                Err(_) => Satisfiability::Unknown,
+            };
+        }
+        for byte in 0..=255u8 {
+            let next: Vec<StateID> = constraints
+                .iter()
+                .zip(&states)
+                .map(|(c, s)| c.dfa.next_state(*s, byte))
+                .collect();
+            if constraints.iter().zip(&next).any(|(c, s)| c.is_dead(*s)) || seen.contains(&next) {
+                continue;
+            }
+            if seen.len() >= MAX_STATES {
+                return Satisfiability::Unknown;
+            }
+            seen.insert(next.clone());
+            let mut value = value.clone();
+            value.push(byte);
+            queue.push_back((next, value));
+        }
+    }
+    Satisfiability::Unsat
+}
+
+/// Evaluates `matcher` against a concrete, unescaped label value using
+/// Prometheus' fully anchored regex semantics.
+pub fn matches_value(matcher: &Matcher, value: &str) -> bool {
+    match &matcher.op {
+        MatchOp::Equal => self::value(matcher) == value,
+        MatchOp::NotEqual => self::value(matcher) != value,
+        MatchOp::Re(_) | MatchOp::NotRe(_) => {
+            let matched = regex::Regex::new(&format!("^(?s:{})$", self::value(matcher)))
+                .map(|re| re.is_match(value))
+                .unwrap_or(false);
+            matched == matches!(matcher.op, MatchOp::Re(_))
+        }
+    }
+}
+
+/// All matchers of a selector grouped by label name, with the metric name
+/// turned into a `__name__` matcher.
+pub fn by_label(vs: &VectorSelector) -> BTreeMap<String, Vec<Matcher>> {
+    let mut labels: BTreeMap<String, Vec<Matcher>> = BTreeMap::new();
+    if let Some(name) = &vs.name {
+        labels
+            .entry(METRIC_NAME.to_string())
+            .or_default()
+            .push(Matcher::new(MatchOp::Equal, METRIC_NAME, name));
+    }
+    for matcher in &vs.matchers.matchers {
+        labels.entry(matcher.name.clone()).or_default().push(matcher.clone());
+    }
+    labels
+}
+
+/// The metric name `vs` selects, whether written as a name or as an
+/// equality matcher on `__name__`.
+pub fn metric_name(vs: &VectorSelector) -> Option<String> {
+    vs.name.clone().or_else(|| {
+        vs.matchers
+            .matchers
+            .iter()
+            .find(|m| m.name == METRIC_NAME && m.op == MatchOp::Equal)
+            .map(|m| m.value.clone())
+    })
+}
+
+/// Labels of `vs` whose matchers can never be satisfied together.
+pub fn contradictions(vs: &VectorSelector) -> Vec<(String, Vec<Matcher>)> {
+    by_label(vs)
+        .into_iter()
+        .filter(|(_, matchers)| satisfiable(&matchers.iter().collect::<Vec<_>>()).is_unsat())
+        .collect()
+}
+
+/// The matcher accepting exactly the values `matcher` rejects.
+pub fn negate(matcher: &Matcher) -> Matcher {
+    let op = match &matcher.op {
+        MatchOp::Equal => MatchOp::NotEqual,
+        MatchOp::NotEqual => MatchOp::Equal,
+        MatchOp::Re(re) => MatchOp::NotRe(re.clone()),
+        MatchOp::NotRe(re) => MatchOp::Re(re.clone()),
+    };
+    Matcher::new(op, &matcher.name, &matcher.value)
+}
+
+/// Whether no series at all can match `labels`, `None` if undecidable.
+fn is_empty(labels: &BTreeMap<String, Vec<Matcher>>) -> Option<bool> {
+    let mut known = true;
+    for matchers in labels.values() {
+        match satisfiable(&matchers.iter().collect::<Vec<_>>()) {
+            Satisfiability::Unsat => return Some(true),
+            Satisfiability::Unknown => known = false,
+            Satisfiability::Witness(_) => {}
+        }
+    }
+    known.then_some(false)
+}
+
+/// Whether every series selected by `a` is also selected by `b`, `None` if
+/// the matchers are too complex to decide.
+pub fn implies(a: &VectorSelector, b: &VectorSelector) -> Option<bool> {
+    let a = by_label(a);
+    let a_empty = is_empty(&a);
+    if a_empty == Some(true) {
+        return Some(true);
+    }
+    let mut known = true;
+    for (label, b_matchers) in by_label(b) {
+        let a_matchers = a.get(&label).cloned().unwrap_or_default();
+        for b_matcher in &b_matchers {
+            // a implies b_matcher iff a together with its negation is unsatisfiable
+            let negated = negate(b_matcher);
+            let both: Vec<&Matcher> = a_matchers.iter().chain(std::iter::once(&negated)).collect();
+            match satisfiable(&both) {
+                Satisfiability::Unsat => {}
+                Satisfiability::Witness(_) if a_empty == Some(false) => return Some(false),
+                _ => known = false,
+            }
+        }
+    }
+    known.then_some(true)
+}
+
+/// Whether no series can be selected by both `a` and `b`, `None` if the
+/// matchers are too complex to decide.
+pub fn disjoint(a: &VectorSelector, b: &VectorSelector) -> Option<bool> {
+    let mut both = by_label(a);
+    for (label, matchers) in by_label(b) {
+        both.entry(label).or_default().extend(matchers);
+    }
+    is_empty(&both)
+}
+
+/// How two selectors relate as sets of series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub a_implies_b: Option<bool>,
+    pub b_implies_a: Option<bool>,
+    pub disjoint: Option<bool>,
+}
+
+impl ToSerde for Relation {
+    fn to_serde(&self) -> Value {
+        json!({
+            "a_implies_b": self.a_implies_b,
+            "b_implies_a": self.b_implies_a,
+            "equivalent": match (self.a_implies_b, self.b_implies_a) {
+                (Some(ab), Some(ba)) => Some(ab && ba),
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                _ => None,
+            },
+            "disjoint": self.disjoint,
+        })
+    }
+}
+
+pub fn relation(a: &VectorSelector, b: &VectorSelector) -> Relation {
+    Relation {
+        a_implies_b: implies(a, b),
+        b_implies_a: implies(b, a),
+        disjoint: disjoint(a, b),
+    }
+}
+
+/// Every vector selector of `expr`, including those of range selectors.
+pub fn selectors(expr: &Expr) -> Vec<VectorSelector> {
+    let mut selectors = vec![];
+    walk(expr, &mut |expr| match expr {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) =>
+            selectors.push(vs.clone()),
+        _ => {}
+    });
+    selectors
+}
+
+/// Whether every selector of a query stays within a permitted selector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Containment {
+    pub allowed: Option<bool>,
+    pub selectors: Vec<(String, Option<bool>)>,
+}
+
+impl ToSerde for Containment {
+    fn to_serde(&self) -> Value {
+        json!({
+            "allowed": self.allowed,
+            "selectors": self.selectors
+                .iter()
+                .map(|(selector, allowed)| json!({ "selector": selector, "allowed": allowed }))
+                .collect::<Vec<Value>>(),
+        })
+    }
+}
+
+pub fn within(expr: &Expr, permitted: &VectorSelector) -> Containment {
+    let selectors: Vec<(String, Option<bool>)> = selectors(expr)
+        .into_iter()
+        .map(|vs| {
+            let allowed = implies(&vs, permitted);
+            (to_promql(&Expr::VectorSelector(vs)), allowed)
+        })
+        .collect();
+    let allowed = if selectors.iter().any(|(_, allowed)| *allowed == Some(false)) {
+        Some(false)
+    } else if selectors.iter().all(|(_, allowed)| *allowed == Some(true)) {
+        Some(true)
+    } else {
+        None
+    };
+    Containment { allowed, selectors }
+}
+
+
+/// The `match[]` selectors of `/api/v1/series` that find every series `expr`
+/// reads: its selectors without ranges, offsets or `@`, canonical, leaving
+/// out those another one provably covers.
+pub fn series_matchers(expr: &Expr) -> Vec<String> {
+    let mut kept: Vec<VectorSelector> = vec![];
+    for mut vs in selectors(expr) {
+        vs.offset = None;
+        vs.at = None;
+        if kept.iter().any(|other| implies(&vs, other) == Some(true)) {
+            continue;
+        }
+        kept.retain(|other| implies(other, &vs) != Some(true));
+        kept.push(vs);
+    }
+    kept.into_iter().map(|vs| canonical(&Expr::VectorSelector(vs))).collect()
+}
+
+
+#[test]
+fn check_satisfiable() {
+    let payloads = vec![
+        ("{job=\"a\", job=\"b\"}", true),
+        ("{job=~\"foo\", job!=\"foo\"}", true),
+        ("{job=~\"a.*\", job=~\"b.*\"}", true),
+        ("{job!~\".*\"}", true),
+        ("{job=~\"a|b\", job!~\"a\"}", false),
+        ("{job=~\"a.*\", job!=\"a\"}", false),
+        ("{job!=\"a\", job!=\"b\", x=\"1\"}", false),
+        ("{job=~\"fo+\", job=\"fooo\"}", false),
+        ("{__name__=\"foo\", __name__=~\"ba.*\"}", true),
+        ("{a=~\"\\\\d+\", a=\"5\"}", false),
+        ("{a=~\"a\\\\.b\", a=\"a.b\"}", false),
+        ("{a=~\"a\\\\.b\", a=\"axb\"}", true),
+        ("{a=\"\\x41\", a=\"A\"}", false),
+        ("{a=\"\\101\", a!=\"A\"}", true),
+        ("{a=\"x\\\"y\", a='x\"y'}", false),
+    ];
+    for (selector, contradictory) in payloads {
+        let vs = match promql_parser::parser::parse(selector).unwrap() {
+            promql_parser::parser::Expr::VectorSelector(vs) => vs,
+            _ => unreachable!(),
+        };
+        assert_eq!(!contradictions(&vs).is_empty(), contradictory, "{}", selector);
+    }
+}
+
+#[test]
+fn check_relation() {
+    let selector = |s: &str| match promql_parser::parser::parse(s).unwrap() {
+        Expr::VectorSelector(vs) => vs,
+        _ => unreachable!(),
+    };
+    let payloads = vec![
+        ("{job=\"a\", env=\"prod\"}", "{job=\"a\"}", Some(true), Some(false), Some(false)),
+        ("{job=~\"a|b\"}", "{job=~\"[ab]\"}", Some(true), Some(true), Some(false)),
+        ("{job=\"a\"}", "{job=~\"b.*\"}", Some(false), Some(false), Some(true)),
+        ("up{tenant=\"t1\"}", "{tenant=~\"t[0-9]\"}", Some(true), Some(false), Some(false)),
+        ("{job=~\"api-.*\"}", "{job=~\".+\", job!~\"db-.*\"}", Some(true), Some(false), Some(false)),
+    ];
+    for (a, b, ab, ba, dis) in payloads {
+        let rel = relation(&selector(a), &selector(b));
+        assert_eq!((rel.a_implies_b, rel.b_implies_a, rel.disjoint), (ab, ba, dis), "{} vs {}", a, b);
+    }
+    let query = promql_parser::parser::parse("sum(rate(x{tenant=\"t1\"}[5m])) / sum(y{tenant=~\"t1|t2\"})").unwrap();
+    let report = within(&query, &selector("{tenant=\"t1\"}"));
+    assert_eq!(report.allowed, Some(false));
+    assert_eq!(report.selectors[0].1, Some(true));
+}
+
+#[test]
+fn check_series_matchers() {
+    let query = "sum(rate(x{job=\"a\", env=\"prod\"}[5m] offset 1h)) / sum(x{job=\"a\"} @ end()) + on () {__name__=\"y\", a=~\"b|c\"} or x{job=\"a\"}";
+    let expr = promql_parser::parser::parse(query).unwrap();
+    assert_eq!(series_matchers(&expr), vec!["x{job=\"a\"}", "y{a=~\"b|c\"}"]);
+}
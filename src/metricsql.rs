@@ -0,0 +1,558 @@
+use promql_parser::parser::token::*;
+use promql_parser::parser::{Expr, ValueType};
+use serde_json::{json, Value};
+use crate::edits::{apply, original_error, original_spans, Edit};
+use crate::errors::{located, relocated, try_parse, ParseError};
+use crate::experimental::{parse_functions, Experimental};
+use crate::lexemes::{lex, Lexeme};
+use crate::options::{self, SerializeOptions};
+use crate::spans::{annotate, annotate_text, spans};
+use crate::{limits, quoted, ToSerde};
+
+const MATRIX: &[ValueType] = &[ValueType::Matrix];
+/// A range and a threshold, as in `count_gt_over_time(x[5m], 10)`.
+const THRESHOLD: &[ValueType] = &[ValueType::Matrix, ValueType::Scalar];
+/// A range and an optional `"min"`, `"max"` or `"avg"`, as in `rollup_rate(x[5m], "max")`.
+const ROLLUP: &[ValueType] = &[ValueType::Matrix, ValueType::String];
+const BOUND: &[ValueType] = &[ValueType::Scalar, ValueType::Matrix];
+
+/// The MetricsQL rollup functions PromQL lacks.
+pub const FUNCTIONS: &[Experimental] = &[
+    Experimental { name: "ascent_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "changes_prometheus", arg_types: MATRIX, variadic: false },
+    Experimental { name: "count_eq_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "count_gt_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "count_le_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "count_ne_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "decreases_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "default_rollup", arg_types: MATRIX, variadic: false },
+    Experimental { name: "delta_prometheus", arg_types: MATRIX, variadic: false },
+    Experimental { name: "deriv_fast", arg_types: MATRIX, variadic: false },
+    Experimental { name: "descent_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "distinct_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "duration_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "geomean_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "histogram_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "hoeffding_bound_lower", arg_types: BOUND, variadic: false },
+    Experimental { name: "hoeffding_bound_upper", arg_types: BOUND, variadic: false },
+    Experimental { name: "ideriv", arg_types: MATRIX, variadic: false },
+    Experimental { name: "increase_prometheus", arg_types: MATRIX, variadic: false },
+    Experimental { name: "increase_pure", arg_types: MATRIX, variadic: false },
+    Experimental { name: "increases_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "integrate", arg_types: MATRIX, variadic: false },
+    Experimental { name: "lag", arg_types: MATRIX, variadic: false },
+    Experimental { name: "lifetime", arg_types: MATRIX, variadic: false },
+    Experimental { name: "mad_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "median_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "mode_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "outlier_iqr_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "range_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "rate_over_sum", arg_types: MATRIX, variadic: false },
+    Experimental { name: "rollup", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "rollup_candlestick", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "rollup_delta", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "rollup_deriv", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "rollup_increase", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "rollup_rate", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "rollup_scrape_interval", arg_types: ROLLUP, variadic: true },
+    Experimental { name: "scrape_interval", arg_types: MATRIX, variadic: false },
+    Experimental { name: "share_eq_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "share_gt_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "share_le_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "stale_samples_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "sum2_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "sum_eq_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "sum_gt_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "sum_le_over_time", arg_types: THRESHOLD, variadic: false },
+    Experimental { name: "tfirst_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "tlast_change_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "tlast_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "tmax_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "tmin_over_time", arg_types: MATRIX, variadic: false },
+    Experimental { name: "zscore_over_time", arg_types: MATRIX, variadic: false },
+];
+
+pub fn function(name: &str) -> Option<&'static Experimental> {
+    FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+/// Tokens before a parenthesized list of label names.
+const GROUPING: [TokenId; 6] = [T_BY, T_WITHOUT, T_ON, T_IGNORING, T_GROUP_LEFT, T_GROUP_RIGHT];
+
+/// The MetricsQL binary operators upstream lacks, with their precedence,
+/// below the 1 of `or`.
+fn operator(name: &str) -> Option<i64> {
+    match name.to_ascii_lowercase().as_str() {
+        "default" => Some(-1),
+        "if" | "ifnot" => Some(0),
+        _ => None,
+    }
+}
+
+fn nesting(id: TokenId) -> i32 {
+    match id {
+        T_LEFT_PAREN | T_LEFT_BRACE | T_LEFT_BRACKET => 1,
+        T_RIGHT_PAREN | T_RIGHT_BRACE | T_RIGHT_BRACKET => -1,
+        _ => 0,
+    }
+}
+
+/// Index of the lexeme closing the bracket opened at `open`, before `to`.
+fn closing(lexemes: &[Lexeme], open: usize, to: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, lexeme) in lexemes.iter().enumerate().take(to).skip(open) {
+        depth += nesting(lexeme.id);
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// The comma separated parts of lexemes `from..to`, as index ranges.
+fn parts(lexemes: &[Lexeme], from: usize, to: usize) -> Vec<(usize, usize)> {
+    let mut parts = vec![];
+    let (mut depth, mut start) = (0, from);
+    for (i, lexeme) in lexemes.iter().enumerate().take(to).skip(from) {
+        depth += nesting(lexeme.id);
+        if depth == 0 && lexeme.id == T_COMMA {
+            parts.push((start, i));
+            start = i + 1;
+        }
+    }
+    parts.push((start, to));
+    parts
+}
+
+/// The lexemes of `text`, which may have template parameters in ranges, as
+/// in `x[w]`, that upstream does not lex: the insides of brackets are
+/// lexed apart, as durations, identifiers and colons.
+fn tokens(text: &str) -> Result<Vec<Lexeme>, String> {
+    let mut masked = text.as_bytes().to_vec();
+    let mut inside = vec![];
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' | '`' => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    match next {
+                        '\\' if c != '`' && !escaped => escaped = true,
+                        _ if next == c && !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+            }
+            '#' => while chars.next().is_some_and(|(_, c)| c != '\n') {},
+            '[' => {
+                let end = text[i..].find(']').map_or(text.len(), |end| i + end);
+                let mut start = None;
+                for (j, c) in text[i + 1..end].char_indices().map(|(j, c)| (i + 1 + j, c)).chain(Some((end, ' '))) {
+                    match (start, c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                        (None, true) => start = Some(j),
+                        (Some(from), false) => {
+                            let word = &text[from..j];
+                            let id = if word.starts_with(|c: char| c.is_ascii_digit()) { T_DURATION } else { T_IDENTIFIER };
+                            inside.push(Lexeme { id, start: from, end: j });
+                            start = None;
+                        }
+                        _ => {}
+                    }
+                    if c == ':' {
+                        inside.push(Lexeme { id: T_COLON, start: j, end: j + 1 });
+                    }
+                }
+                masked[i + 1..end].iter_mut().for_each(|byte| *byte = b' ');
+                while chars.clone().next().is_some_and(|(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            _ => {}
+        }
+    }
+    let masked = String::from_utf8(masked).map_err(|err| err.to_string())?;
+    let mut lexemes = lex(&masked)?;
+    lexemes.extend(inside);
+    lexemes.sort_by_key(|lexeme| lexeme.start);
+    Ok(lexemes)
+}
+
+/// A `WITH` template, `name = body` or `name(params) = body`, with the
+/// templates its body uses expanded.
+#[derive(Debug, Clone)]
+struct Template {
+    name: String,
+    params: Vec<String>,
+    body: String,
+}
+
+/// Whether `body` reads as one operand where a template is used, or needs
+/// parentheses: a name, number, duration or string, then matchers, call arguments and
+/// a range, each optional, or a parenthesized expression.
+fn is_operand(body: &str) -> bool {
+    let lexemes = tokens(body).unwrap_or_default();
+    let named = matches!(lexemes.first().map(|l| l.id), Some(T_IDENTIFIER | T_METRIC_IDENTIFIER | T_NUMBER | T_DURATION | T_STRING));
+    let mut i = usize::from(named);
+    for open in [T_LEFT_BRACE, T_LEFT_PAREN, T_LEFT_BRACKET] {
+        if lexemes.get(i).map(|l| l.id) == Some(open) {
+            match closing(&lexemes, i, lexemes.len()) {
+                Some(close) => i = close + 1,
+                None => return false,
+            }
+        }
+    }
+    i > 0 && i == lexemes.len()
+}
+
+fn operand(body: &str) -> String {
+    if is_operand(body) {
+        body.to_string()
+    } else {
+        format!("({})", body)
+    }
+}
+
+/// The matchers inside the braces of `body`, if it is a selector without a
+/// name, as in `WITH (common = {job="api"}) x{common}`.
+fn filters(body: &str) -> Option<&str> {
+    let lexemes = tokens(body).unwrap_or_default();
+    match lexemes.as_slice() {
+        [open, .., close] if open.id == T_LEFT_BRACE && closing(&lexemes, 0, lexemes.len()) == Some(lexemes.len() - 1) =>
+            Some(body[open.end..close.start].trim()),
+        _ => None,
+    }
+}
+
+/// Template `name`, a selector, with the matchers `extra` added.
+fn with_filters(template: &Template, extra: &str) -> Result<String, String> {
+    let body = &template.body;
+    let lexemes = tokens(body).unwrap_or_default();
+    let not_selector = || format!("template `{}` is not a selector and cannot take label filters", template.name);
+    match lexemes.as_slice() {
+        [name] if matches!(name.id, T_IDENTIFIER | T_METRIC_IDENTIFIER) => Ok(format!("{}{{{}}}", body, extra)),
+        [.., close] if close.id == T_RIGHT_BRACE && is_operand(body) => {
+            let open = lexemes.iter().position(|l| l.id == T_LEFT_BRACE).ok_or_else(not_selector)?;
+            let existing = body[lexemes[open].end..close.start].trim();
+            let separator = if existing.is_empty() || extra.trim().is_empty() { "" } else { ", " };
+            Ok(format!("{}{}{}}}", &body[..close.start], separator, extra))
+        }
+        _ => Err(not_selector()),
+    }
+}
+
+/// `text` with the uses of `templates` replaced by their bodies, later
+/// templates shadowing earlier ones of the same name.
+fn substitute(text: &str, templates: &[Template]) -> Result<String, String> {
+    let lexemes = tokens(text)?;
+    let find = |name: &str, called: bool| templates.iter().rev().find(|t| t.name == name && t.params.is_empty() != called);
+    let mut edits = vec![];
+    let mut i = 0;
+    while i < lexemes.len() {
+        let lexeme = lexemes[i];
+        let next = lexemes.get(i + 1).map(|l| l.id);
+        i = match lexeme.id {
+            T_LEFT_BRACE => {
+                let close = closing(&lexemes, i, lexemes.len()).unwrap_or(lexemes.len() - 1);
+                // templates of matchers stand alone between commas
+                for (from, to) in parts(&lexemes, i + 1, close) {
+                    let body = match &lexemes[from..to] {
+                        [name] => find(name.text(text), false).and_then(|t| filters(&t.body)),
+                        _ => None,
+                    };
+                    if let Some(body) = body {
+                        edits.push(Edit { start: lexemes[from].start, end: lexemes[from].end, text: body.to_string() });
+                    }
+                }
+                close + 1
+            }
+            id if GROUPING.contains(&id) && next == Some(T_LEFT_PAREN) =>
+                closing(&lexemes, i + 1, lexemes.len()).map_or(lexemes.len(), |close| close + 1),
+            T_IDENTIFIER | T_METRIC_IDENTIFIER => match find(lexeme.text(text), next == Some(T_LEFT_PAREN)) {
+                Some(template) if next == Some(T_LEFT_PAREN) => {
+                    let close = closing(&lexemes, i + 1, lexemes.len())
+                        .ok_or_else(|| format!("unclosed call of template `{}`", template.name))?;
+                    let args = parts(&lexemes, i + 2, close);
+                    if args.len() != template.params.len() || args.iter().any(|(from, to)| from == to) {
+                        return Err(format!(
+                            "template `{}` takes {} argument(s), got {}",
+                            template.name,
+                            template.params.len(),
+                            args.iter().filter(|(from, to)| from < to).count(),
+                        ));
+                    }
+                    let args = template
+                        .params
+                        .iter()
+                        .zip(args)
+                        .map(|(param, (from, to))| {
+                            let arg = substitute(&text[lexemes[from].start..lexemes[to - 1].end], templates)?;
+                            Ok(Template { name: param.clone(), params: vec![], body: arg })
+                        })
+                        .collect::<Result<Vec<Template>, String>>()?;
+                    let body = substitute(&template.body, &args)?;
+                    edits.push(Edit { start: lexeme.start, end: lexemes[close].end, text: operand(&body) });
+                    close + 1
+                }
+                Some(template) if next == Some(T_LEFT_BRACE) => {
+                    let close = closing(&lexemes, i + 1, lexemes.len())
+                        .ok_or_else(|| format!("unclosed label filters of template `{}`", template.name))?;
+                    let extra = substitute(&text[lexemes[i + 1].end..lexemes[close].start], templates)?;
+                    edits.push(Edit { start: lexeme.start, end: lexemes[close].end, text: with_filters(template, extra.trim())? });
+                    close + 1
+                }
+                Some(template) => {
+                    edits.push(Edit { start: lexeme.start, end: lexeme.end, text: operand(&template.body) });
+                    i + 1
+                }
+                None => i + 1,
+            },
+            _ => i + 1,
+        };
+    }
+    Ok(apply(text, &edits))
+}
+
+/// `query` with its leading `WITH (...)` templates expanded, if it has any.
+fn expand(query: &str) -> Result<Option<String>, ParseError> {
+    let lexemes = tokens(query).unwrap_or_default();
+    match lexemes.as_slice() {
+        [with, open, ..] if with.text(query).eq_ignore_ascii_case("with") && open.id == T_LEFT_PAREN => {}
+        _ => return Ok(None),
+    }
+    let invalid = |message: String, end: usize| located(query, message, "invalid-template", lexemes[0].start, end);
+    let close = closing(&lexemes, 1, lexemes.len())
+        .ok_or_else(|| invalid("unclosed left parenthesis of WITH templates".to_string(), query.len()))?;
+    let invalid = |message: String| invalid(message, lexemes[close].end);
+    let mut templates: Vec<Template> = vec![];
+    for (from, to) in parts(&lexemes, 2, close) {
+        let definition = &lexemes[from..to];
+        let (name, params, body) = match definition {
+            [name, eq, ..] if eq.id == T_EQL => (name, vec![], from + 2),
+            [name, open, ..] if open.id == T_LEFT_PAREN => {
+                let params_end = closing(&lexemes, from + 1, to).filter(|end| lexemes.get(end + 1).map(|l| l.id) == Some(T_EQL));
+                let params_end = params_end.ok_or_else(|| invalid(format!("expected `=` after the parameters of `{}`", name.text(query))))?;
+                let params = parts(&lexemes, from + 2, params_end)
+                    .into_iter()
+                    .filter(|(from, to)| from < to)
+                    .map(|(from, to)| match &lexemes[from..to] {
+                        [param] if matches!(param.id, T_IDENTIFIER | T_METRIC_IDENTIFIER) => Ok(param.text(query).to_string()),
+                        _ => Err(invalid(format!("invalid parameters of template `{}`", name.text(query)))),
+                    })
+                    .collect::<Result<Vec<String>, ParseError>>()?;
+                (name, params, params_end + 2)
+            }
+            _ => {
+                let text = definition.first().map_or("", |first| &query[first.start..definition[definition.len() - 1].end]);
+                return Err(invalid(format!("expected `name = expression` in WITH templates, got `{}`", text)));
+            }
+        };
+        if !matches!(name.id, T_IDENTIFIER | T_METRIC_IDENTIFIER) || body >= to {
+            return Err(invalid(format!("invalid template `{}`", &query[name.start..lexemes[to - 1].end])));
+        }
+        let visible: Vec<Template> = templates.iter().filter(|t| !params.contains(&t.name)).cloned().collect();
+        let body = substitute(&query[lexemes[body].start..lexemes[to - 1].end], &visible).map_err(invalid)?;
+        templates.push(Template { name: name.text(query).to_string(), params, body });
+    }
+    if close + 1 == lexemes.len() {
+        return Err(invalid("WITH templates must be followed by a query".to_string()));
+    }
+    let expanded = substitute(query[lexemes[close].end..].trim(), &templates).map_err(invalid)?;
+    Ok(Some(expand(&expanded)?.unwrap_or(expanded)))
+}
+
+/// Adds `by` to the `start` and `end` offsets of a serialized tree.
+fn shift(value: &mut Value, by: usize) {
+    match value {
+        Value::Object(object) => {
+            for key in ["start", "end"] {
+                if let Some(offset) = object.get(key).and_then(Value::as_u64) {
+                    object.insert(key.to_string(), (offset as usize + by).into());
+                }
+            }
+            object.values_mut().for_each(|field| shift(field, by));
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| shift(item, by)),
+        _ => {}
+    }
+}
+
+/// Parses the parts of a query: operands of the MetricsQL operators are
+/// parsed separately, in place of placeholder selectors.
+struct Parser<'a> {
+    text: &'a str,
+    lexemes: Vec<Lexeme>,
+    options: &'a SerializeOptions,
+    prefix: String,
+}
+
+impl Parser<'_> {
+    /// Byte range of lexemes `from..to`, the whole text if there are none.
+    fn bounds(&self, from: usize, to: usize) -> (usize, usize) {
+        match (self.lexemes.get(from), to.checked_sub(1).and_then(|last| self.lexemes.get(last))) {
+            (Some(first), Some(last)) if from < to => (first.start, last.end),
+            _ => (0, self.text.len()),
+        }
+    }
+
+    /// The MetricsQL operators of lexemes `from..to` outside brackets.
+    fn operators(&self, from: usize, to: usize) -> Vec<(usize, i64)> {
+        let mut depth = 0;
+        let mut found = vec![];
+        for (i, lexeme) in self.lexemes.iter().enumerate().take(to).skip(from) {
+            depth += nesting(lexeme.id);
+            // an operator has operands on both sides
+            if depth == 0 && lexeme.id == T_IDENTIFIER && i > from && i + 1 < to {
+                found.extend(operator(lexeme.text(self.text)).map(|precedence| (i, precedence)));
+            }
+        }
+        found
+    }
+
+    /// The parts of the parenthesized groups of lexemes `from..to` that use
+    /// MetricsQL operators, outermost only.
+    fn groups(&self, from: usize, to: usize, found: &mut Vec<(usize, usize)>) {
+        let mut i = from;
+        while i < to {
+            let close = match self.lexemes[i].id {
+                T_LEFT_PAREN => closing(&self.lexemes, i, to),
+                _ => None,
+            };
+            match close {
+                Some(close) => {
+                    for (start, end) in parts(&self.lexemes, i + 1, close) {
+                        if self.operators(start, end).is_empty() {
+                            self.groups(start, end, found);
+                        } else {
+                            found.push((start, end));
+                        }
+                    }
+                    i = close + 1;
+                }
+                None => i += 1,
+            }
+        }
+    }
+
+    fn parse(&self, text: &str) -> Result<Expr, ParseError> {
+        let names = |text: &str| if self.options.utf8_names { quoted::try_parse_quoted(text) } else { try_parse(text) };
+        limits::parse_limited(text, &self.options.limits, |text| parse_functions(text, function, &names))
+    }
+
+    /// Puts the serialized parts in place of their placeholder selectors.
+    fn substitute(&self, value: &mut Value, parts: &mut [Option<Value>]) {
+        match value {
+            Value::Object(object) => {
+                let index = match (object.get("@type").and_then(Value::as_str), object.get("name").and_then(Value::as_str)) {
+                    (Some("vector_selector"), Some(name)) => name.strip_prefix(&self.prefix).and_then(|n| n.parse::<usize>().ok()),
+                    _ => None,
+                };
+                match index.and_then(|index| parts.get_mut(index)).and_then(Option::take) {
+                    Some(part) => *value = part,
+                    None => object.values_mut().for_each(|field| self.substitute(field, parts)),
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.substitute(item, parts)),
+            _ => {}
+        }
+    }
+
+    /// The serialized tree of lexemes `from..to`, offsets in the whole text.
+    fn serialize(&self, from: usize, to: usize) -> Result<Value, ParseError> {
+        let (start, end) = self.bounds(from, to);
+        // the rightmost of the loosest operators is the root
+        if let Some(&(split, precedence)) = self.operators(from, to).iter().rev().min_by_key(|(_, precedence)| *precedence) {
+            return Ok(json!({
+                "@type": "binary",
+                "lhs": self.serialize(from, split)?,
+                "op": self.lexemes[split].text(self.text).to_ascii_lowercase(),
+                "rhs": self.serialize(split + 1, to)?,
+                "modifier": null,
+                "precedence": precedence,
+                "is_right_assoc": false,
+                "start": start,
+                "end": end,
+            }));
+        }
+        let text = &self.text[start..end];
+        let mut groups = vec![];
+        self.groups(from, to, &mut groups);
+        let edits: Vec<Edit> = groups
+            .iter()
+            .enumerate()
+            .map(|(n, (from, to))| {
+                let (part_start, part_end) = self.bounds(*from, *to);
+                Edit { start: part_start - start, end: part_end - start, text: format!("{}{}", self.prefix, n) }
+            })
+            .collect();
+        let edited = apply(text, &edits);
+        let expr = self.parse(&edited).map_err(|error| {
+            let error = original_error(text, &edits, error);
+            let (from, to) = (start + error.start, start + error.end);
+            relocated(self.text, error, from, to)
+        })?;
+        let mut ast = options::with_options(self.options.clone(), || expr.to_serde());
+        if let Some(tree) = spans(&edited, &expr) {
+            annotate(&mut ast, &tree);
+            original_spans(&mut ast, &edits);
+            shift(&mut ast, start);
+        }
+        let mut parts = groups
+            .iter()
+            .map(|(from, to)| self.serialize(*from, *to).map(Some))
+            .collect::<Result<Vec<Option<Value>>, ParseError>>()?;
+        self.substitute(&mut ast, &mut parts);
+        Ok(ast)
+    }
+}
+
+/// Parses VictoriaMetrics MetricsQL `query` into the JSON AST of PromQL, as
+/// `options` say: leading `WITH` templates are expanded, the text they expand
+/// to added as `expanded` and the offsets of nodes refer to it; the rollup
+/// functions MetricsQL adds are calls, and `default`, `if` and `ifnot` binary
+/// nodes looser than `or`.
+pub fn parse(query: &str, options: &SerializeOptions) -> Result<Value, ParseError> {
+    limits::check_length(query, &options.limits)?;
+    let expanded = expand(query)?;
+    let text = expanded.as_deref().unwrap_or(query);
+    let mut prefix = "__metricsql_".to_string();
+    while text.contains(&prefix) {
+        prefix.push('_');
+    }
+    let parser = Parser { text, lexemes: lex(text).unwrap_or_default(), options, prefix };
+    let mut ast = parser.serialize(0, parser.lexemes.len()).map_err(|error| match expanded {
+        // offsets into the expansion would mislead
+        Some(_) => relocated(query, error, 0, query.len()),
+        None => error,
+    })?;
+    if options.text {
+        annotate_text(&mut ast, text);
+    }
+    if let Some(expanded) = expanded {
+        ast["expanded"] = json!(expanded);
+    }
+    Ok(ast)
+}
+
+
+#[test]
+fn check_metricsql() {
+    let options = SerializeOptions::default();
+    let ast = parse("sum(rate(x[5m])) by (job) default 0 if y > 1 or z", &options).unwrap();
+    assert_eq!((&ast["op"], &ast["precedence"], &ast["lhs"]["@type"]), (&json!("default"), &json!(-1), &json!("aggregate")));
+    assert_eq!((&ast["rhs"]["op"], &ast["rhs"]["lhs"]["@type"]), (&json!("if"), &json!("number")));
+    let or = &ast["rhs"]["rhs"];
+    assert_eq!((&or["op"], &or["start"], &or["end"]), (&json!("or"), &json!(39), &json!(49)));
+    let ast = parse("max(rollup_rate(x{if=\"a\"}[5m], \"max\") default 1) by (default)", &options).unwrap();
+    let inner = &ast["expr"];
+    assert_eq!((&inner["op"], &inner["start"], &inner["end"]), (&json!("default"), &json!(4), &json!(47)));
+    assert_eq!(inner["lhs"]["function"]["name"], json!("rollup_rate"));
+    assert_eq!(ast["modifier"], json!({ "include": ["default"] }));
+    let query = "WITH (f(m, w) = increase_pure(m[w]), common = {env=\"prod\"}, q = f(x{common, job=\"a\"}, 5m) / 60) q ifnot (common) # no filters";
+    let ast = parse(query, &options).unwrap();
+    assert_eq!(ast["expanded"], json!("(increase_pure(x{env=\"prod\", job=\"a\"}[5m]) / 60) ifnot ({env=\"prod\"}) # no filters"));
+    assert_eq!(ast["lhs"]["expr"]["lhs"]["function"]["name"], json!("increase_pure"));
+    assert_eq!(parse("WITH (f(a) = a) f(1, 2)", &options).unwrap_err().message, "template `f` takes 1 argument(s), got 2");
+    let error = parse("x default sum(", &options).unwrap_err();
+    assert_eq!((error.code, error.start), ("unclosed-paren", 14));
+    assert!(parse("WITH (a = 1)", &options).is_err());
+}
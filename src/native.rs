@@ -0,0 +1,859 @@
+// napi-derive registers the functions outside of tests only.
+#![cfg_attr(test, allow(dead_code))]
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use napi::bindgen_prelude::Buffer;
+use napi::{Env, JsFunction, JsObject, JsUnknown, Status, ValueType};
+use napi_derive::napi;
+use promql_parser::parser::{Expr, VectorSelector};
+use crate::spans::SpanTree;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use crate::errors::{located, ParseError};
+use crate::options::{Encoding, SerializeOptions};
+use crate::*;
+
+/// Why a call failed: a structured parse error, thrown as `js_error` throws
+/// in wasm, or a plain message.
+enum Failure {
+    Parse(ParseError),
+    Message(String),
+}
+
+impl From<ParseError> for Failure {
+    fn from(err: ParseError) -> Failure {
+        Failure::Parse(err)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure::Message(message)
+    }
+}
+
+impl From<napi::Error> for Failure {
+    fn from(err: napi::Error) -> Failure {
+        Failure::Message(err.reason)
+    }
+}
+
+/// Throws an `Error` with the fields of `err`, as `js_error` does in wasm.
+fn throw<T>(env: Env, err: ParseError) -> napi::Result<T> {
+    let mut error = env.create_error(napi::Error::from_reason(err.message.clone()))?;
+    if let Value::Object(fields) = err.to_serde() {
+        for (key, value) in fields.iter().filter(|(key, _)| *key != "message") {
+            error.set_named_property(key, env.to_js_value(value)?)?;
+        }
+    }
+    env.throw(error)?;
+    Err(napi::Error::from_status(Status::PendingException))
+}
+
+/// The result of `f`, throwing its failure.
+fn run<T>(env: Env, f: impl FnOnce() -> Result<T, Failure>) -> napi::Result<T> {
+    match f() {
+        Ok(value) => Ok(value),
+        Err(Failure::Parse(err)) => throw(env, err),
+        Err(Failure::Message(message)) => Err(napi::Error::from_reason(message)),
+    }
+}
+
+/// Reads a JS value, e.g. a rewrite, into `T`.
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Failure> {
+    serde_json::from_value(value).map_err(|err| Failure::Message(err.to_string()))
+}
+
+/// Reads optional arguments, e.g. options, into `T`, its default if omitted.
+fn args<T: DeserializeOwned + Default>(value: Option<Value>) -> Result<T, Failure> {
+    match value {
+        Some(Value::Null) | None => Ok(T::default()),
+        Some(value) => from_value(value),
+    }
+}
+
+fn parse(query: &str) -> Result<Expr, Failure> {
+    Ok(errors::try_parse(query)?)
+}
+
+fn selector(selector: &str) -> Result<VectorSelector, Failure> {
+    match parse(selector)? {
+        Expr::VectorSelector(vs) => Ok(vs),
+        _ => Err(format!("not a vector selector: {}", selector).into()),
+    }
+}
+
+fn label_guard(config: Option<Value>) -> Result<guard::LabelGuard, Failure> {
+    Ok(guard::LabelGuard::new(&args(config)?))
+}
+
+/// A rules file, a `{groups: [...]}` object or YAML text, as `rule_file` reads it.
+fn rule_file(rules: Value) -> Result<rules::RuleFile, Failure> {
+    match rules {
+        Value::String(yaml) => Ok(rules_yaml::rule_file(&yaml)?),
+        rules => from_value(rules),
+    }
+}
+
+/// `value` as JS, with the millisecond strings of its `at` fields as `BigInt`s.
+fn bigint_timestamps(env: Env, value: &Value) -> napi::Result<JsUnknown> {
+    match value {
+        Value::Array(items) => {
+            let mut array = env.create_array_with_length(items.len())?;
+            for (i, item) in items.iter().enumerate() {
+                array.set_element(i as u32, bigint_timestamps(env, item)?)?;
+            }
+            Ok(array.into_unknown())
+        }
+        Value::Object(fields) => {
+            let mut object = env.create_object()?;
+            for (key, field) in fields {
+                let field = match field.as_str().map(str::parse::<i64>) {
+                    Some(Ok(ms)) if key == "at" => env.create_bigint_from_i64(ms)?.into_unknown()?,
+                    _ => bigint_timestamps(env, field)?,
+                };
+                object.set_named_property(key, field)?;
+            }
+            Ok(object.into_unknown())
+        }
+        value => env.to_js_value(value),
+    }
+}
+
+/// Converts a serialized tree shaped by `options` to JS, as `options_to_js`
+/// does in wasm.
+fn options_to_js(env: Env, value: &Value, options: &SerializeOptions) -> Result<JsUnknown, Failure> {
+    if options.timestamps == timestamps::TimestampFormat::Bigint {
+        return Ok(bigint_timestamps(env, value)?);
+    }
+    Ok(env.to_js_value(value)?)
+}
+
+/// The AST of `query` as JS, or as a `Buffer` in the `encoding` of `options`.
+fn encode(env: Env, query: &str, ast: &Value, options: &SerializeOptions) -> Result<JsUnknown, Failure> {
+    match options.encoding.encode(ast) {
+        Some(Ok(bytes)) => Ok(env.create_buffer_with_data(bytes)?.into_raw().into_unknown()),
+        Some(Err(err)) => Err(located(query, err, "internal-error", 0, 0).into()),
+        None => options_to_js(env, ast, options),
+    }
+}
+
+/// Calls `f` with the items of an array or any other iterable, one at a
+/// time, until it returns `false`.
+fn each(env: Env, iterable: JsUnknown, mut f: impl FnMut(JsUnknown) -> napi::Result<bool>) -> napi::Result<()> {
+    let not_iterable = || napi::Error::from_reason("queries must be an array or iterable");
+    let iterable = JsObject::try_from(iterable).map_err(|_| not_iterable())?;
+    let symbol: JsUnknown = env.get_global()?.get_named_property::<JsFunction>("Symbol")?.coerce_to_object()?.get_named_property("iterator")?;
+    let method = JsFunction::try_from(iterable.get_property::<_, JsUnknown>(symbol)?).map_err(|_| not_iterable())?;
+    let iterator = JsObject::try_from(method.call_without_args(Some(&iterable))?).map_err(|_| not_iterable())?;
+    let next: JsFunction = iterator.get_named_property("next")?;
+    loop {
+        let step = JsObject::try_from(next.call_without_args(Some(&iterator))?).map_err(|_| not_iterable())?;
+        if step.get_named_property::<JsUnknown>("done")?.coerce_to_bool()?.get_value()? || !f(step.get_named_property("value")?)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses `query` as `parse_encoded` does in wasm, with `{ast, timings}` if
+/// the options ask for timings.
+fn parse_encoded(env: Env, query: &str, options: &SerializeOptions) -> Result<JsUnknown, Failure> {
+    let untimed = || {
+        let ast = parse_serialized(query, options)?;
+        timing::time("encode", || encode(env, query, &ast, options))
+    };
+    if !options.timings {
+        return untimed();
+    }
+    let (ast, timings) = timing::record(untimed);
+    let mut result = env.create_object()?;
+    result.set_named_property("ast", ast?)?;
+    result.set_named_property("timings", env.to_js_value(&timings.to_serde())?)?;
+    Ok(result.into_unknown())
+}
+
+/// `promql_parse`.
+#[napi(js_name = "promql_parse")]
+pub fn promql_parse(env: Env, query: String, options: Option<Value>) -> napi::Result<JsUnknown> {
+    promql_parse_with_options(env, query, options)
+}
+
+/// `promql_dialects`.
+#[napi(js_name = "promql_dialects")]
+pub fn promql_dialects() -> Value {
+    json!(dialects::DIALECTS.iter().map(|dialect| dialect.to_serde()).collect::<Vec<Value>>())
+}
+
+/// `logql_parse`.
+#[napi(js_name = "logql_parse")]
+pub fn logql_parse(env: Env, query: String, options: Option<Value>) -> napi::Result<JsUnknown> {
+    run(env, || {
+        let options = SerializeOptions { dialect: Some("logql".to_string()), ..args(options)? };
+        options_to_js(env, &parse_serialized(&query, &options)?, &options)
+    })
+}
+
+/// `promql_parse_with_options`.
+#[napi(js_name = "promql_parse_with_options")]
+pub fn promql_parse_with_options(env: Env, query: String, opts: Option<Value>) -> napi::Result<JsUnknown> {
+    run(env, || parse_encoded(env, &query, &args(opts)?))
+}
+
+/// `promql_parse_bytes`, into a `Buffer`.
+#[napi(js_name = "promql_parse_bytes")]
+pub fn promql_parse_bytes(env: Env, query: String, options: Option<Value>) -> napi::Result<Buffer> {
+    run(env, || {
+        let options = SerializeOptions { encoding: Encoding::Json, timings: false, ..args(options)? };
+        let ast = parse_serialized(&query, &options)?;
+        Ok(serde_json::to_vec(&ast).map_err(|err| err.to_string())?.into())
+    })
+}
+
+/// `promql_try_parse`.
+#[napi(js_name = "promql_try_parse")]
+pub fn promql_try_parse(env: Env, query: String, options: Option<Value>) -> napi::Result<JsUnknown> {
+    let result = match args(options) {
+        Ok(options) => return run(env, || options_to_js(env, &parse_result(&query, &options), &options)),
+        Err(Failure::Parse(err)) => json!({ "ok": false, "error": err.to_serde() }),
+        Err(Failure::Message(message)) =>
+            json!({ "ok": false, "error": located(&query, message, "invalid-options", 0, 0).to_serde() }),
+    };
+    env.to_js_value(&result)
+}
+
+/// `promql_parse_many`.
+#[napi(js_name = "promql_parse_many")]
+pub fn promql_parse_many(env: Env, queries: Vec<String>, options: Option<Value>) -> napi::Result<JsUnknown> {
+    run(env, || {
+        let options = args(options)?;
+        let results = Value::Array(queries.iter().map(|query| parse_result(query, &options)).collect());
+        options_to_js(env, &results, &options)
+    })
+}
+
+/// `promql_parse_each`.
+#[napi(js_name = "promql_parse_each")]
+pub fn promql_parse_each(env: Env, queries: JsUnknown, callback: JsFunction, options: Option<Value>) -> napi::Result<u32> {
+    let options: SerializeOptions = run(env, || args(options))?;
+    let mut count = 0;
+    each(env, queries, |query| {
+        if query.get_type()? != ValueType::String {
+            return Err(napi::Error::from_reason("queries must be strings"));
+        }
+        let query = query.coerce_to_string()?.into_utf8()?.into_owned()?;
+        let result = run(env, || options_to_js(env, &parse_result(&query, &options), &options))?;
+        let next: JsUnknown = callback.call(None, &[result, env.create_uint32(count)?.into_unknown()])?;
+        count += 1;
+        Ok(next.get_type()? != ValueType::Boolean || next.coerce_to_bool()?.get_value()?)
+    })?;
+    Ok(count)
+}
+
+/// `PromqlAst`, which the garbage collector frees.
+#[napi(js_name = "PromqlAst")]
+pub struct PromqlAst {
+    query: String,
+    expr: Expr,
+    tree: Option<SpanTree>,
+    options: SerializeOptions,
+}
+
+#[napi]
+impl PromqlAst {
+    #[napi(constructor)]
+    pub fn new(env: Env, query: String, options: Option<Value>) -> napi::Result<Self> {
+        run(env, || {
+            let options: SerializeOptions = args(options)?;
+            lazy::check_options(&query, &options)?;
+            let expr = parse_extended(&query, &options)?;
+            let tree = spans::spans(&query, &expr);
+            Ok(PromqlAst { query, expr, tree, options })
+        })
+    }
+
+    #[napi(js_name = "rootKind")]
+    pub fn root_kind(&self) -> String {
+        visit::node_type(&self.expr).to_string()
+    }
+
+    #[napi]
+    pub fn selectors(&self, env: Env) -> napi::Result<JsUnknown> {
+        run(env, || {
+            let selectors = options::with_options(self.options.clone(), || extract::flat_selectors(&self.query, &self.expr).to_serde());
+            options_to_js(env, &selectors, &self.options)
+        })
+    }
+
+    #[napi(js_name = "childAt")]
+    pub fn child_at(&self, env: Env, path: Vec<u32>) -> napi::Result<JsUnknown> {
+        let path: Vec<usize> = path.into_iter().map(|i| i as usize).collect();
+        let (expr, tree) = match lazy::descend(&self.expr, self.tree.as_ref(), &path) {
+            Some(found) => found,
+            None => return Ok(env.get_null()?.into_unknown()),
+        };
+        let node = serialize::Node::new(&self.query, expr, tree, &self.options);
+        run(env, || {
+            let mut value = options::with_options(self.options.clone(), || serde_json::to_value(node)).map_err(|err| err.to_string())?;
+            self.options.reshape(&mut value);
+            encode(env, &self.query, &value, &self.options)
+        })
+    }
+
+    /// Does nothing, for code written against the wasm class.
+    #[napi]
+    pub fn free(&self) {}
+}
+
+/// `promql_experimental_features`.
+#[napi(js_name = "promql_experimental_features")]
+pub fn promql_experimental_features(env: Env, query: String, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let options = SerializeOptions { experimental_functions: true, ..args(options)? };
+        Ok(json!(experimental::features(&parse_extended(&query, &options)?)))
+    })
+}
+
+/// `promql_typecheck`.
+#[napi(js_name = "promql_typecheck")]
+pub fn promql_typecheck(query: String) -> Value {
+    typecheck::typecheck(&query).to_serde()
+}
+
+/// `promql_unparse`.
+#[napi(js_name = "promql_unparse")]
+pub fn promql_unparse(env: Env, ast: Value, options: Option<Value>) -> napi::Result<String> {
+    run(env, || Ok(unparse::unparse(&from_value(ast)?, &args(options)?)?))
+}
+
+/// `promql_error_codes`.
+#[napi(js_name = "promql_error_codes")]
+pub fn promql_error_codes() -> Value {
+    error_codes()
+}
+
+/// `promql_parse_lenient`.
+#[napi(js_name = "promql_parse_lenient")]
+pub fn promql_parse_lenient(query: String) -> Value {
+    lenient::parse_lenient(&query).to_serde()
+}
+
+/// `promql_complete`.
+#[napi(js_name = "promql_complete")]
+pub fn promql_complete(env: Env, query: String, offset: u32, metadata: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let offset = offset as usize;
+        if !query.is_char_boundary(offset) {
+            return Err("offset is not a character boundary of the query".to_string().into());
+        }
+        Ok(completion::complete(&query, offset, &args(metadata)?).to_serde())
+    })
+}
+
+/// `promql_node_at`.
+#[napi(js_name = "promql_node_at")]
+pub fn promql_node_at(env: Env, query: String, offset: u32) -> napi::Result<Value> {
+    run(env, || {
+        let expr = parse(&query)?;
+        Ok(hover::hover(&query, &expr, offset as usize).map_or(Value::Null, |hover| hover.to_serde()))
+    })
+}
+
+/// `promql_semantic_tokens`.
+#[napi(js_name = "promql_semantic_tokens")]
+pub fn promql_semantic_tokens(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(semantic::semantic_tokens(&query)?.to_serde()))
+}
+
+/// `promql_ast_schema`.
+#[napi(js_name = "promql_ast_schema")]
+pub fn promql_ast_schema() -> Value {
+    schema::ast_schema()
+}
+
+/// `promql_query_ast`.
+#[napi(js_name = "promql_query_ast")]
+pub fn promql_query_ast(env: Env, query: String, selector: String) -> napi::Result<Value> {
+    run(env, || {
+        let ast = serialize_ast(&query, &parse(&query)?);
+        Ok(ast_path::select_serde(&ast, &selector)?)
+    })
+}
+
+/// `promql_at_modifier`.
+#[napi(js_name = "promql_at_modifier")]
+pub fn promql_at_modifier(env: Env, value: JsUnknown, options: Option<Value>) -> napi::Result<String> {
+    run(env, || {
+        let options: SerializeOptions = args(options)?;
+        let value: Value = match value.get_type()? {
+            ValueType::BigInt => json!(value.coerce_to_string()?.into_utf8()?.into_owned()?),
+            _ => env.from_js_value(value)?,
+        };
+        Ok(printer::at(&timestamps::parse_at(&value, options.timestamps)?))
+    })
+}
+
+/// `promql_parse_cst`.
+#[napi(js_name = "promql_parse_cst")]
+pub fn promql_parse_cst(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || {
+        let ast = parse(&query)?;
+        let cst = cst::cst(&query)?;
+        Ok(json!({ "ast": serialize_ast(&query, &ast), "cst": cst.to_serde() }))
+    })
+}
+
+/// `promql_tokenize`.
+#[napi(js_name = "promql_tokenize")]
+pub fn promql_tokenize(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(tokens::tokenize(&query)?.to_serde()))
+}
+
+/// `promql_discarded_grouping`.
+#[napi(js_name = "promql_discarded_grouping")]
+pub fn promql_discarded_grouping(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(grouping::discarded_grouping(&parse(&query)?).to_serde()))
+}
+
+/// `promql_simplify_aggregations`.
+#[napi(js_name = "promql_simplify_aggregations")]
+pub fn promql_simplify_aggregations(env: Env, query: String, guard: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let expr = parse(&query)?;
+        Ok(simplify::collapse_nested_aggregations(&query, &expr, &label_guard(guard)?).to_serde())
+    })
+}
+
+/// `promql_fold_constants`.
+#[napi(js_name = "promql_fold_constants")]
+pub fn promql_fold_constants(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(simplify::fold_constants(&query, &parse(&query)?).to_serde()))
+}
+
+/// `promql_simplify_binary`.
+#[napi(js_name = "promql_simplify_binary")]
+pub fn promql_simplify_binary(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(simplify::simplify_binary(&query, &parse(&query)?).to_serde()))
+}
+
+/// `promql_always_empty`.
+#[napi(js_name = "promql_always_empty")]
+pub fn promql_always_empty(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(emptiness::always_empty_in(&query, &parse(&query)?).to_serde()))
+}
+
+/// `promql_shard`.
+#[napi(js_name = "promql_shard")]
+pub fn promql_shard(env: Env, query: String, options: Value) -> napi::Result<Value> {
+    run(env, || Ok(sharding::shard(&parse(&query)?, &from_value(options)?)?.to_serde()))
+}
+
+/// `promql_inject_matchers`.
+#[napi(js_name = "promql_inject_matchers")]
+pub fn promql_inject_matchers(env: Env, query: String, selector: String, guard: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let mut expr = parse(&query)?;
+        let injected = self::selector(&selector)?;
+        if let Some(name) = injected.name {
+            return Err(format!("cannot inject metric name {}", name).into());
+        }
+        let warnings = rewrite::inject_matchers(&mut expr, &injected.matchers.matchers, &label_guard(guard)?)?;
+        Ok(json!({ "query": printer::to_promql(&expr), "warnings": warnings }))
+    })
+}
+
+/// `promql_wrap_selectors`.
+#[napi(js_name = "promql_wrap_selectors")]
+pub fn promql_wrap_selectors(env: Env, query: String, name: String) -> napi::Result<String> {
+    run(env, || {
+        let mut expr = parse(&query)?;
+        rewrite::wrap_selectors(&mut expr, &name)?;
+        Ok(printer::to_promql(&expr))
+    })
+}
+
+/// `promql_rewrite_ranges`.
+#[napi(js_name = "promql_rewrite_ranges")]
+pub fn promql_rewrite_ranges(env: Env, query: String, rewrite: Value) -> napi::Result<String> {
+    run(env, || Ok(rewrite::rewrite_ranges(&query, &from_value(rewrite)?)?))
+}
+
+/// `promql_rewrite_offsets`.
+#[napi(js_name = "promql_rewrite_offsets")]
+pub fn promql_rewrite_offsets(env: Env, query: String, rewrite: Value) -> napi::Result<String> {
+    run(env, || {
+        let mut expr = parse(&query)?;
+        rewrite::rewrite_offsets(&mut expr, &from_value(rewrite)?)?;
+        Ok(printer::to_promql(&expr))
+    })
+}
+
+/// `promql_rename_metric`.
+#[napi(js_name = "promql_rename_metric")]
+pub fn promql_rename_metric(env: Env, query: String, from: String, to: String, guard: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let mut expr = parse(&query)?;
+        let warnings = rewrite::rename_metric(&mut expr, &from, &to, &label_guard(guard)?)?;
+        Ok(json!({ "query": printer::to_promql(&expr), "warnings": warnings }))
+    })
+}
+
+/// `promql_rename_label`.
+#[napi(js_name = "promql_rename_label")]
+pub fn promql_rename_label(env: Env, query: String, from: String, to: String, guard: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let mut expr = parse(&query)?;
+        let warnings = label_guard(guard)?.check("rename", [from.as_str(), to.as_str()])?;
+        rewrite::rename_label(&mut expr, &from, &to);
+        Ok(json!({ "query": printer::to_promql(&expr), "warnings": warnings }))
+    })
+}
+
+/// `promql_substitute`.
+#[napi(js_name = "promql_substitute")]
+pub fn promql_substitute(env: Env, query: String, vars: Value) -> napi::Result<String> {
+    run(env, || {
+        let vars: BTreeMap<String, Value> = from_value(vars)?;
+        let substituted = substitute::substitute(&query, &vars)?;
+        errors::try_parse(&substituted)?;
+        Ok(substituted)
+    })
+}
+
+/// `promql_series_matchers`.
+#[napi(js_name = "promql_series_matchers")]
+pub fn promql_series_matchers(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(json!(matchers::series_matchers(&parse(&query)?))))
+}
+
+/// `promql_api_url`.
+#[napi(js_name = "promql_api_url")]
+pub fn promql_api_url(env: Env, query: String, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(http_api::request(&query, &parse(&query)?, &args(options)?)?.to_serde()))
+}
+
+/// `promql_to_clickhouse`.
+#[napi(js_name = "promql_to_clickhouse")]
+pub fn promql_to_clickhouse(env: Env, query: String, options: Option<Value>) -> napi::Result<String> {
+    run(env, || Ok(clickhouse::to_sql(&parse(&query)?, &args(options)?)?))
+}
+
+/// `promql_matchers_relation`.
+#[napi(js_name = "promql_matchers_relation")]
+pub fn promql_matchers_relation(env: Env, a: String, b: String) -> napi::Result<Value> {
+    run(env, || Ok(matchers::relation(&selector(&a)?, &selector(&b)?).to_serde()))
+}
+
+/// `promql_within_selector`.
+#[napi(js_name = "promql_within_selector")]
+pub fn promql_within_selector(env: Env, query: String, permitted: String) -> napi::Result<Value> {
+    run(env, || {
+        let expr = parse(&query)?;
+        Ok(matchers::within(&expr, &selector(&permitted)?).to_serde())
+    })
+}
+
+/// `promql_regex_literals`.
+#[napi(js_name = "promql_regex_literals")]
+pub fn promql_regex_literals(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(literals::regex_literals(&parse(&query)?).to_serde()))
+}
+
+/// `promql_label_values`.
+#[napi(js_name = "promql_label_values")]
+pub fn promql_label_values(queries: Vec<String>) -> Value {
+    inventory::label_values(&queries).to_serde()
+}
+
+/// `promql_recording_rules`.
+#[napi(js_name = "promql_recording_rules")]
+pub fn promql_recording_rules(queries: Vec<String>) -> Value {
+    recording::suggest(&queries).to_serde()
+}
+
+/// `promql_metric_names`.
+#[napi(js_name = "promql_metric_names")]
+pub fn promql_metric_names(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(extract::metric_names(&parse(&query)?).to_serde()))
+}
+
+/// `promql_label_names`.
+#[napi(js_name = "promql_label_names")]
+pub fn promql_label_names(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || {
+        let labels: Vec<Value> = extract::label_names(&parse(&query)?)
+            .into_iter()
+            .map(|(name, contexts)| json!({ "name": name, "contexts": contexts }))
+            .collect();
+        Ok(json!(labels))
+    })
+}
+
+/// `promql_selectors`.
+#[napi(js_name = "promql_selectors")]
+pub fn promql_selectors(env: Env, query: String, options: Option<Value>) -> napi::Result<JsUnknown> {
+    run(env, || {
+        let options: SerializeOptions = args(options)?;
+        let expr = parse_extended(&query, &options)?;
+        let selectors = options::with_options(options.clone(), || extract::flat_selectors(&query, &expr).to_serde());
+        options_to_js(env, &selectors, &options)
+    })
+}
+
+/// `promql_functions`.
+#[napi(js_name = "promql_functions")]
+pub fn promql_functions(env: Env, query: String, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(extract::function_calls(&query, &parse_extended(&query, &args(options)?)?).to_serde()))
+}
+
+/// `promql_aggregations`.
+#[napi(js_name = "promql_aggregations")]
+pub fn promql_aggregations(env: Env, query: String, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(extract::aggregations(&query, &parse_extended(&query, &args(options)?)?).to_serde()))
+}
+
+/// `promql_to_dot`.
+#[napi(js_name = "promql_to_dot")]
+pub fn promql_to_dot(env: Env, query: String) -> napi::Result<String> {
+    run(env, || Ok(diagram::to_dot(&parse(&query)?)))
+}
+
+/// `promql_to_mermaid`.
+#[napi(js_name = "promql_to_mermaid")]
+pub fn promql_to_mermaid(env: Env, query: String) -> napi::Result<String> {
+    run(env, || Ok(diagram::to_mermaid(&parse(&query)?)))
+}
+
+/// `promql_explain`.
+#[napi(js_name = "promql_explain")]
+pub fn promql_explain(env: Env, query: String) -> napi::Result<String> {
+    run(env, || Ok(explain::explain(&parse(&query)?)))
+}
+
+/// `promql_to_builder`.
+#[napi(js_name = "promql_to_builder")]
+pub fn promql_to_builder(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(visual::representation(&parse(&query)?).to_serde()))
+}
+
+/// `promql_from_builder`.
+#[napi(js_name = "promql_from_builder")]
+pub fn promql_from_builder(env: Env, model: Value) -> napi::Result<String> {
+    run(env, || Ok(visual::from_model(&from_value(model)?)?))
+}
+
+/// `promql_build`.
+#[napi(js_name = "promql_build")]
+pub fn promql_build(env: Env, spec: Value) -> napi::Result<String> {
+    run(env, || Ok(builder::build(&from_value(spec)?)?))
+}
+
+/// `promql_stats`.
+#[napi(js_name = "promql_stats")]
+pub fn promql_stats(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(stats::stats(&parse(&query)?).to_serde()))
+}
+
+/// `promql_cost`.
+#[napi(js_name = "promql_cost")]
+pub fn promql_cost(env: Env, query: String, weights: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(cost::cost(&parse(&query)?, &args(weights)?).to_serde()))
+}
+
+/// `promql_lookback`.
+#[napi(js_name = "promql_lookback")]
+pub fn promql_lookback(env: Env, query: String, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(lookback::lookback_with(&parse(&query)?, &args(options)?).to_serde()))
+}
+
+/// `promql_time_bounds`.
+#[napi(js_name = "promql_time_bounds")]
+pub fn promql_time_bounds(env: Env, query: String, times: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(lookback::time_bounds(&parse(&query)?, &args(times)?)?.to_serde()))
+}
+
+/// `promql_split_by_time`.
+#[napi(js_name = "promql_split_by_time")]
+pub fn promql_split_by_time(env: Env, query: String, options: Value) -> napi::Result<Value> {
+    run(env, || Ok(split::split(&query, &parse(&query)?, &from_value(options)?)?.to_serde()))
+}
+
+/// `promql_cardinality`.
+#[napi(js_name = "promql_cardinality")]
+pub fn promql_cardinality(env: Env, query: String, stats: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(cardinality::cardinality(&query, &parse(&query)?, &args(stats)?).to_serde()))
+}
+
+/// `promql_sarif`.
+#[napi(js_name = "promql_sarif")]
+pub fn promql_sarif(env: Env, sources: Value, permitted: Option<String>) -> napi::Result<Value> {
+    run(env, || {
+        let sources: Vec<sarif::Source> = from_value(sources)?;
+        let permitted = permitted.as_deref().map(selector).transpose()?;
+        let checked: Vec<(sarif::Source, Vec<lint::Diagnostic>)> = sources
+            .into_iter()
+            .map(|source| {
+                let diagnostics = lint::check(&source.query, permitted.as_ref());
+                (source, diagnostics)
+            })
+            .collect();
+        Ok(sarif::to_sarif(&checked))
+    })
+}
+
+/// `promql_fix`.
+#[napi(js_name = "promql_fix")]
+pub fn promql_fix(env: Env, query: String, rule_ids: Option<Vec<String>>, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let options: SerializeOptions = args(options)?;
+        Ok(lint::fix(&query, &rule_ids.unwrap_or_default(), options.experimental_functions)?.to_serde())
+    })
+}
+
+/// `promql_lint`.
+#[napi(js_name = "promql_lint")]
+pub fn promql_lint(env: Env, query: String, config: Option<Value>) -> napi::Result<Value> {
+    run(env, || {
+        let config: lint::LintConfig = args(config)?;
+        let permitted = config.permitted.as_deref().map(selector).transpose()?;
+        Ok(lint::check_configured(&query, &config, permitted.as_ref())?.to_serde())
+    })
+}
+
+/// `promql_lint_rules`.
+#[napi(js_name = "promql_lint_rules")]
+pub fn promql_lint_rules() -> Value {
+    json!(lint::RULES.iter().map(|rule| rule.to_serde()).collect::<Vec<Value>>())
+}
+
+/// `promql_dashboard_queries`.
+#[napi(js_name = "promql_dashboard_queries")]
+pub fn promql_dashboard_queries(env: Env, dashboard: Value, options: Option<Value>) -> napi::Result<JsUnknown> {
+    run(env, || {
+        let dashboard = match dashboard {
+            Value::String(text) => serde_json::from_str(&text).map_err(|err| format!("invalid dashboard JSON: {}", err))?,
+            dashboard => dashboard,
+        };
+        let options = args(options)?;
+        options_to_js(env, &grafana::dashboard_queries(&dashboard, &options).to_serde(), &options)
+    })
+}
+
+/// `rules_parse`.
+#[napi(js_name = "rules_parse")]
+pub fn rules_parse(env: Env, yaml: String, options: Option<Value>) -> napi::Result<JsUnknown> {
+    run(env, || {
+        let options = args(options)?;
+        options_to_js(env, &rules_yaml::parse_rules(&yaml, &options).to_serde(), &options)
+    })
+}
+
+/// `promql_rule_dependencies`.
+#[napi(js_name = "promql_rule_dependencies")]
+pub fn promql_rule_dependencies(env: Env, rules: Value) -> napi::Result<Value> {
+    run(env, || Ok(dependencies::dependency_graph(&rule_file(rules)?).to_serde()))
+}
+
+/// `promql_rule_plan`.
+#[napi(js_name = "promql_rule_plan")]
+pub fn promql_rule_plan(env: Env, rules: Value) -> napi::Result<Value> {
+    run(env, || Ok(planning::plan(&rule_file(rules)?).to_serde()))
+}
+
+/// `promql_output_labels`.
+#[napi(js_name = "promql_output_labels")]
+pub fn promql_output_labels(env: Env, query: String) -> napi::Result<Value> {
+    run(env, || Ok(output_labels::output_labels(&parse(&query)?).to_serde()))
+}
+
+/// `promql_alert_templates`.
+#[napi(js_name = "promql_alert_templates")]
+pub fn promql_alert_templates(env: Env, rules: Value) -> napi::Result<Value> {
+    run(env, || Ok(templates::check_templates(&rule_file(rules)?).to_serde()))
+}
+
+/// `promql_format`.
+#[napi(js_name = "promql_format")]
+pub fn promql_format(env: Env, query: String, options: Option<Value>) -> napi::Result<String> {
+    run(env, || Ok(format::format(&query, &args(options)?)?))
+}
+
+/// `promql_minify`.
+#[napi(js_name = "promql_minify")]
+pub fn promql_minify(env: Env, query: String) -> napi::Result<String> {
+    run(env, || Ok(format::minify(&query)?))
+}
+
+/// `promql_normalize`.
+#[napi(js_name = "promql_normalize")]
+pub fn promql_normalize(env: Env, query: String) -> napi::Result<String> {
+    run(env, || Ok(canonical::canonical(&parse(&query)?)))
+}
+
+/// `promql_equal`.
+#[napi(js_name = "promql_equal")]
+pub fn promql_equal(env: Env, a: String, b: String) -> napi::Result<bool> {
+    run(env, || Ok(canonical::equivalent(&parse(&a)?, &parse(&b)?)))
+}
+
+/// `promql_diff`.
+#[napi(js_name = "promql_diff")]
+pub fn promql_diff(env: Env, a: String, b: String) -> napi::Result<Value> {
+    run(env, || Ok(diff::diff(&a, &parse(&a)?, &b, &parse(&b)?).to_serde()))
+}
+
+/// `promql_fingerprint`.
+#[napi(js_name = "promql_fingerprint")]
+pub fn promql_fingerprint(env: Env, query: String) -> napi::Result<String> {
+    run(env, || Ok(canonical::fingerprint(&parse(&query)?)))
+}
+
+/// `promql_format_range`.
+#[napi(js_name = "promql_format_range")]
+pub fn promql_format_range(env: Env, query: String, start: u32, end: u32) -> napi::Result<Value> {
+    run(env, || Ok(format::format_range(&query, start as usize, end as usize)?.to_serde()))
+}
+
+/// `promql_generate`.
+#[napi(js_name = "promql_generate")]
+pub fn promql_generate(env: Env, seed: u32, profile: Option<Value>) -> napi::Result<String> {
+    run(env, || Ok(generate::generate(seed as u64, &args(profile)?)?))
+}
+
+/// `promql_pseudonymize`.
+#[napi(js_name = "promql_pseudonymize")]
+pub fn promql_pseudonymize(env: Env, queries: Vec<String>, options: Option<Value>) -> napi::Result<Value> {
+    run(env, || Ok(pseudonymize::pseudonymize(&queries, &args(options)?).to_serde()))
+}
+
+/// `promql_parse_events`.
+#[napi(js_name = "promql_parse_events")]
+pub fn promql_parse_events(env: Env, query: String, callback: JsFunction) -> napi::Result<()> {
+    let expr = run(env, || parse(&query))?;
+    let mut thrown = None;
+    events::events(&expr, &mut |event| {
+        let mut object = json!({
+            "event": event.phase.as_str(),
+            "type": event.node_type,
+            "depth": event.depth,
+        });
+        for (key, field) in event.fields {
+            object[key] = match field {
+                events::Field::Text(text) => json!(text),
+                events::Field::Number(number) => number.to_serde(),
+            };
+        }
+        let next = env
+            .to_js_value(&object)
+            .and_then(|object| callback.call::<JsUnknown>(None, &[object]))
+            .and_then(|next| Ok(next.get_type()? != ValueType::Boolean || next.coerce_to_bool()?.get_value()?));
+        match next {
+            Ok(next) => next,
+            Err(err) => {
+                thrown = Some(err);
+                false
+            }
+        }
+    });
+    thrown.map_or(Ok(()), Err)
+}
@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::time::Duration;
+use promql_parser::util::parse_duration;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::limits::Limits;
+use crate::printer;
+use crate::timestamps::TimestampFormat;
+
+/// Serialized form of ranges, steps and offsets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationFormat {
+    /// Seconds, with a fraction for sub-second durations.
+    #[default]
+    Seconds,
+    /// Whole milliseconds.
+    Millis,
+    /// Prometheus duration text, e.g. `"1h30m"` or `"1s500ms"`.
+    String,
+}
+
+impl DurationFormat {
+    /// Serializes `dur`, negated for negative offsets, without losing milliseconds.
+    pub fn serialize(self, dur: &Duration, negative: bool) -> Value {
+        let sign = if negative { -1 } else { 1 };
+        let millis = sign * dur.as_millis() as i64;
+        match self {
+            DurationFormat::Seconds if millis % 1000 == 0 => json!(millis / 1000),
+            DurationFormat::Seconds => json!(millis as f64 / 1000.0),
+            DurationFormat::Millis => json!(millis),
+            DurationFormat::String if negative => json!(format!("-{}", printer::duration(dur))),
+            DurationFormat::String => json!(printer::duration(dur)),
+        }
+    }
+
+    /// Reads a duration serialized in this format back, and whether it was
+    /// negative. Duration strings are accepted in any format.
+    pub fn parse(self, value: &Value) -> Result<(Duration, bool), String> {
+        let invalid = || format!("invalid duration: {}", value);
+        let millis = match value {
+            Value::String(s) => {
+                let (negative, text) = match s.strip_prefix('-') {
+                    Some(text) => (true, text),
+                    None => (false, s.as_str()),
+                };
+                return parse_duration(text).map(|dur| (dur, negative)).map_err(|_| invalid());
+            }
+            Value::Number(n) if self == DurationFormat::Millis => n.as_f64().ok_or_else(invalid)?,
+            Value::Number(n) => n.as_f64().ok_or_else(invalid)? * 1000.0,
+            _ => return Err(invalid()),
+        };
+        if !millis.is_finite() || millis.abs() > u64::MAX as f64 {
+            return Err(invalid());
+        }
+        Ok((Duration::from_millis(millis.abs().round() as u64), millis < 0.0))
+    }
+}
+
+/// Naming of the keys of serialized nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyCase {
+    /// `return_bool`, as upstream names the fields.
+    #[default]
+    Snake,
+    /// `returnBool`.
+    Camel,
+}
+
+/// Shape of serialized trees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// This package's nodes, with their spans.
+    #[default]
+    Native,
+    /// The nodes of Prometheus' Go parser, as its `/api/v1/parse_query`
+    /// returns them.
+    Prometheus,
+}
+
+/// Encoding of the parsed AST.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// JS objects.
+    #[default]
+    Js,
+    /// MessagePack bytes in a `Uint8Array`, with string keys.
+    Msgpack,
+    /// CBOR bytes in a `Uint8Array`.
+    Cbor,
+    /// UTF-8 JSON text in a `Uint8Array`.
+    Json,
+}
+
+impl Encoding {
+    /// Encodes `value` as bytes, `None` for `Js`.
+    pub fn encode(self, value: &impl serde::Serialize) -> Option<Result<Vec<u8>, String>> {
+        match self {
+            Encoding::Js => None,
+            Encoding::Msgpack => Some(rmp_serde::to_vec_named(value).map_err(|err| err.to_string())),
+            Encoding::Cbor => {
+                let mut bytes = vec![];
+                Some(ciborium::ser::into_writer(value, &mut bytes).map(|_| bytes).map_err(|err| err.to_string()))
+            }
+            Encoding::Json => Some(serde_json::to_vec(value).map_err(|err| err.to_string())),
+        }
+    }
+}
+
+fn camel_case(key: &str) -> String {
+    let mut parts = key.split('_');
+    let mut camel = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        camel.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+        camel.push_str(chars.as_str());
+    }
+    camel
+}
+
+/// How `promql_parse` serializes the AST.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SerializeOptions {
+    pub timestamps: TimestampFormat,
+    pub durations: DurationFormat,
+    /// Leave out fields that are `null`, e.g. an absent `offset`.
+    pub omit_nulls: bool,
+    /// Add the `text` each node was parsed from.
+    pub text: bool,
+    pub keys: KeyCase,
+    pub output: OutputFormat,
+    /// Accept the quoted UTF-8 metric and label names of Prometheus 3.
+    pub utf8_names: bool,
+    /// Accept the experimental duration arithmetic of newer PromQL, e.g.
+    /// `[5m + 30s]`, and add the expressions the durations were computed from.
+    pub duration_expressions: bool,
+    /// Accept the functions Prometheus gates behind its experimental
+    /// functions flag, e.g. `info()`.
+    pub experimental_functions: bool,
+    /// Tolerate the Grafana-style `$name` and `${name}` variables of
+    /// dashboard queries.
+    pub variables: bool,
+    /// JS objects, or bytes to move ASTs to workers in bulk.
+    pub encoding: Encoding,
+    /// Query language, one of `dialects::DIALECTS`; PromQL by default.
+    pub dialect: Option<String>,
+    /// Time the phases of the parse, returning `{ast, timings}`.
+    pub timings: bool,
+    /// Query length and nesting limits, checked before serializing.
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+impl SerializeOptions {
+    /// Whether the AST of a query is exactly the PromQL `serialize_ast` tree,
+    /// with nothing reshaping it as a whole, so it can be written without
+    /// building it first.
+    pub fn is_direct(&self) -> bool {
+        !self.omit_nulls
+            && self.keys == KeyCase::Snake
+            && self.output == OutputFormat::Native
+            && !self.duration_expressions
+            && !self.variables
+            && self.dialect.as_deref().is_none_or(|dialect| dialect == crate::dialects::DEFAULT_DIALECT)
+    }
+
+    /// Applies the options that reshape a serialized tree as a whole: null
+    /// field omission and key naming.
+    pub fn reshape(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                if self.omit_nulls {
+                    object.retain(|_, field| !field.is_null());
+                }
+                if self.keys == KeyCase::Camel && object.keys().any(|key| key.contains('_')) {
+                    *object = std::mem::take(object).into_iter().map(|(key, field)| (camel_case(&key), field)).collect();
+                }
+                object.values_mut().for_each(|field| self.reshape(field));
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.reshape(item)),
+            _ => {}
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<SerializeOptions> = RefCell::new(SerializeOptions::default());
+}
+
+/// The options `ToSerde` implementations should follow.
+pub fn current() -> SerializeOptions {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Runs `f` with `options` in effect, e.g. around a `to_serde()` call.
+pub fn with_options<T>(options: SerializeOptions, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT.with(|current| current.replace(options));
+    let result = f();
+    CURRENT.with(|current| current.replace(previous));
+    result
+}
+
+
+#[test]
+fn check_with_options() {
+    let options = SerializeOptions {
+        timestamps: TimestampFormat::Millis,
+        durations: DurationFormat::String,
+        ..SerializeOptions::default()
+    };
+    assert_eq!(with_options(options.clone(), current), options);
+    assert_eq!(current(), SerializeOptions::default());
+    let expr = promql_parser::parser::parse("max_over_time(x[1h30m] offset -5m)[1d:90s]").unwrap();
+    let ast = with_options(options, || crate::ToSerde::to_serde(&expr));
+    assert_eq!((&ast["range"], &ast["step"]), (&json!("1d"), &json!("1m30s")));
+    let matrix = &ast["expr"]["args"][0];
+    assert_eq!((&matrix["range"], &matrix["vector"]["offset"]), (&json!("1h30m"), &json!("-5m")));
+    assert_eq!(crate::ToSerde::to_serde(&expr)["step"], json!(90));
+    let expr = promql_parser::parser::parse("x[1500ms] offset -1s500ms").unwrap();
+    for (durations, range, offset) in [
+        (DurationFormat::Seconds, json!(1.5), json!(-1.5)),
+        (DurationFormat::Millis, json!(1500), json!(-1500)),
+        (DurationFormat::String, json!("1s500ms"), json!("-1s500ms")),
+    ] {
+        let options = SerializeOptions { durations, ..SerializeOptions::default() };
+        let ast = with_options(options, || crate::ToSerde::to_serde(&expr));
+        assert_eq!((ast["range"].clone(), ast["vector"]["offset"].clone()), (range, offset));
+    }
+    let options = SerializeOptions { omit_nulls: true, keys: KeyCase::Camel, ..SerializeOptions::default() };
+    let mut ast = crate::ToSerde::to_serde(&promql_parser::parser::parse("rate(x[5m]) > bool 1").unwrap());
+    options.reshape(&mut ast);
+    assert_eq!(ast["modifier"], json!({ "card": { "@type": "one-to-one" }, "returnBool": true }));
+    assert_eq!(ast["lhs"]["function"]["returnType"], json!("vector"));
+    assert!(ast["lhs"]["args"][0]["vector"].get("offset").is_none());
+    let msgpack = Encoding::Msgpack.encode(&ast).unwrap().unwrap();
+    assert_eq!(rmp_serde::from_slice::<Value>(&msgpack).unwrap(), ast);
+    let cbor = Encoding::Cbor.encode(&ast).unwrap().unwrap();
+    assert_eq!(ciborium::de::from_reader::<Value, _>(&cbor[..]).unwrap(), ast);
+    assert_eq!(Encoding::Json.encode(&ast).unwrap().unwrap(), ast.to_string().into_bytes());
+    assert!(Encoding::Js.encode(&ast).is_none());
+}
@@ -0,0 +1,204 @@
+use std::collections::BTreeSet;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde_json::{json, Value};
+use crate::ToSerde;
+
+/// Label names the series returned by an expression can carry.
+///
+/// `__name__` is left out: almost every operation drops it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputLabels {
+    /// No labels other than these.
+    Only(BTreeSet<String>),
+    /// Whatever labels the selected series have, except these.
+    AllExcept(BTreeSet<String>),
+}
+
+impl ToSerde for OutputLabels {
+    fn to_serde(&self) -> Value {
+        match self {
+            OutputLabels::Only(labels) => json!({ "only": labels }),
+            OutputLabels::AllExcept(labels) => json!({ "all_except": labels }),
+        }
+    }
+}
+
+fn set<'a>(labels: impl IntoIterator<Item = &'a String>) -> BTreeSet<String> {
+    labels.into_iter().filter(|l| *l != METRIC_NAME).cloned().collect()
+}
+
+impl OutputLabels {
+    fn none() -> OutputLabels {
+        OutputLabels::Only(BTreeSet::new())
+    }
+
+    fn any() -> OutputLabels {
+        OutputLabels::AllExcept(BTreeSet::new())
+    }
+
+    pub fn can_have(&self, label: &str) -> bool {
+        match self {
+            OutputLabels::Only(labels) => labels.contains(label),
+            OutputLabels::AllExcept(labels) => !labels.contains(label),
+        }
+    }
+
+    fn keep(&self, kept: &BTreeSet<String>) -> OutputLabels {
+        OutputLabels::Only(kept.iter().filter(|l| self.can_have(l)).cloned().collect())
+    }
+
+    fn drop(&self, dropped: &BTreeSet<String>) -> OutputLabels {
+        match self {
+            OutputLabels::Only(labels) => OutputLabels::Only(labels.difference(dropped).cloned().collect()),
+            OutputLabels::AllExcept(labels) => OutputLabels::AllExcept(labels.union(dropped).cloned().collect()),
+        }
+    }
+
+    fn add(&self, added: &BTreeSet<String>) -> OutputLabels {
+        match self {
+            OutputLabels::Only(labels) => OutputLabels::Only(labels.union(added).cloned().collect()),
+            OutputLabels::AllExcept(labels) => OutputLabels::AllExcept(labels.difference(added).cloned().collect()),
+        }
+    }
+
+    fn union(&self, other: &OutputLabels) -> OutputLabels {
+        match (self, other) {
+            (OutputLabels::Only(a), OutputLabels::Only(b)) => OutputLabels::Only(a.union(b).cloned().collect()),
+            (OutputLabels::AllExcept(e), OutputLabels::Only(s)) | (OutputLabels::Only(s), OutputLabels::AllExcept(e)) =>
+                OutputLabels::AllExcept(e.difference(s).cloned().collect()),
+            (OutputLabels::AllExcept(a), OutputLabels::AllExcept(b)) =>
+                OutputLabels::AllExcept(a.intersection(b).cloned().collect()),
+        }
+    }
+}
+
+fn selector(vs: &VectorSelector) -> OutputLabels {
+    // `foo=""` only matches series without `foo`
+    let absent = vs
+        .matchers
+        .matchers
+        .iter()
+        .filter(|m| m.op == MatchOp::Equal && m.value.is_empty())
+        .map(|m| &m.name);
+    OutputLabels::AllExcept(set(absent))
+}
+
+fn string_arg(args: &FunctionArgs, i: usize) -> Option<String> {
+    match args.args.get(i).map(|arg| arg.as_ref()) {
+        Some(Expr::StringLiteral(StringLiteral { val })) => Some(val.clone()),
+        _ => None,
+    }
+}
+
+fn call(func: &Function, args: &FunctionArgs) -> OutputLabels {
+    let vector = args
+        .args
+        .iter()
+        .find(|arg| matches!(arg.value_type(), ValueType::Vector | ValueType::Matrix))
+        .map(|arg| output_labels(arg));
+    let input = vector.unwrap_or_else(OutputLabels::none);
+    match func.name {
+        "label_replace" | "label_join" => match string_arg(args, 1) {
+            Some(dst) => input.add(&set([&dst])),
+            None => OutputLabels::any(),
+        },
+        "absent" | "absent_over_time" => {
+            // labels come from the equality matchers of the argument, if it is a selector
+            let equal = match args.args.first().map(|arg| arg.as_ref()) {
+                Some(Expr::VectorSelector(vs)) | Some(Expr::MatrixSelector(MatrixSelector { vs, .. })) => set(vs
+                    .matchers
+                    .matchers
+                    .iter()
+                    .filter(|m| m.op == MatchOp::Equal && !m.value.is_empty())
+                    .map(|m| &m.name)),
+                _ => BTreeSet::new(),
+            };
+            OutputLabels::Only(equal)
+        }
+        "histogram_quantile" => input.drop(&set([&BUCKET_LABEL.to_string()])),
+        _ => input,
+    }
+}
+
+fn aggregate(agg: &AggregateExpr) -> OutputLabels {
+    let input = output_labels(&agg.expr);
+    let grouped = match &agg.modifier {
+        Some(LabelModifier::Include(by)) => input.keep(&set(&by.labels)),
+        Some(LabelModifier::Exclude(without)) => input.drop(&set(&without.labels)),
+        None => OutputLabels::none(),
+    };
+    match agg.op.id() {
+        T_TOPK | T_BOTTOMK => input,
+        T_COUNT_VALUES => match agg.param.as_deref() {
+            Some(Expr::StringLiteral(StringLiteral { val })) => grouped.add(&set([val])),
+            _ => OutputLabels::any(),
+        },
+        _ => grouped,
+    }
+}
+
+fn binary(binary: &BinaryExpr) -> OutputLabels {
+    let BinaryExpr { lhs, op, rhs, modifier } = binary;
+    let (left, right) = (output_labels(lhs), output_labels(rhs));
+    match (lhs.value_type(), rhs.value_type()) {
+        (ValueType::Vector, ValueType::Vector) => {}
+        (ValueType::Vector, _) => return left,
+        (_, ValueType::Vector) => return right,
+        _ => return OutputLabels::none(),
+    }
+    match op.id() {
+        T_LAND | T_LUNLESS => return left,
+        T_LOR => return left.union(&right),
+        _ => {}
+    }
+    let matching = modifier.as_ref().and_then(|m| m.matching.as_ref());
+    match modifier.as_ref().map(|m| &m.card) {
+        Some(VectorMatchCardinality::ManyToOne(include)) => left.add(&set(&include.labels)),
+        Some(VectorMatchCardinality::OneToMany(include)) => right.add(&set(&include.labels)),
+        _ => match matching {
+            Some(LabelModifier::Include(on)) => left.keep(&set(&on.labels)),
+            Some(LabelModifier::Exclude(ignoring)) => left.drop(&set(&ignoring.labels)),
+            None => left,
+        },
+    }
+}
+
+/// The labels `expr` can produce, following Prometheus' rules for how each
+/// operation keeps, drops and adds labels.
+pub fn output_labels(expr: &Expr) -> OutputLabels {
+    match expr {
+        Expr::VectorSelector(vs) => selector(vs),
+        Expr::MatrixSelector(MatrixSelector { vs, .. }) => selector(vs),
+        Expr::Paren(ParenExpr { expr }) => output_labels(expr),
+        Expr::Unary(UnaryExpr { expr }) => output_labels(expr),
+        Expr::Subquery(SubqueryExpr { expr, .. }) => output_labels(expr),
+        Expr::Aggregate(agg) => aggregate(agg),
+        Expr::Binary(b) => binary(b),
+        Expr::Call(Call { func, args }) => call(func, args),
+        Expr::NumberLiteral(_) | Expr::StringLiteral(_) => OutputLabels::none(),
+        Expr::Extension(_) => OutputLabels::any(),
+    }
+}
+
+
+#[test]
+fn check_output_labels() {
+    let only = |labels: &[&str]| OutputLabels::Only(labels.iter().map(|l| l.to_string()).collect());
+    let payloads = vec![
+        ("sum by (job, instance) (rate(x[5m]))", only(&["instance", "job"])),
+        ("sum by (job) (x) / on (job) sum by (job, env) (y)", only(&["job"])),
+        ("label_replace(sum by (job) (x), \"team\", \"$1\", \"job\", \"(.*)\")", only(&["job", "team"])),
+        ("sum by (job) (x) * on (job) group_left (owner) info", only(&["job", "owner"])),
+        ("absent(up{job=\"a\", env!=\"b\"})", only(&["job"])),
+        ("count_values(\"version\", build_info)", only(&["version"])),
+        ("sum by (a) (x) or sum by (b) (y)", only(&["a", "b"])),
+    ];
+    for (query, expected) in payloads {
+        assert_eq!(output_labels(&parse(query).unwrap()), expected, "{}", query);
+    }
+    let open = output_labels(&parse("sum without (pod) (x{node=\"\"})").unwrap());
+    assert!(open.can_have("job"));
+    assert!(!open.can_have("pod") && !open.can_have("node"));
+}
@@ -0,0 +1,48 @@
+use std::panic::{catch_unwind, set_hook, take_hook, AssertUnwindSafe};
+use std::sync::{Mutex, Once};
+use crate::errors::{located, ParseError};
+
+static HOOK: Once = Once::new();
+static LAST: Mutex<Option<String>> = Mutex::new(None);
+
+/// Records the message of every panic for `take`, before the previous hook
+/// runs. On wasm a panic aborts, trapping the call, and the message is all
+/// the JS side can still learn of it.
+pub fn install() {
+    HOOK.call_once(|| {
+        let previous = take_hook();
+        set_hook(Box::new(move |info| {
+            if let Ok(mut last) = LAST.lock() {
+                *last = Some(info.to_string());
+            }
+            previous(info)
+        }));
+    });
+}
+
+/// The message of the last panic, if not taken yet.
+pub fn take() -> Option<String> {
+    LAST.lock().ok().and_then(|mut last| last.take())
+}
+
+/// Runs `f` on `query`, turning a panic, where it unwinds, into an
+/// `internal-error` about the whole query rather than a crash.
+pub fn guard<T>(query: &str, f: impl FnOnce() -> Result<T, ParseError>) -> Result<T, ParseError> {
+    install();
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = take()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(located(query, format!("internal error: {}", message), "internal-error", 0, query.len()))
+    })
+}
+
+
+#[test]
+fn check_guard() {
+    let error = guard("x", || -> Result<(), ParseError> { panic!("boom") }).unwrap_err();
+    assert_eq!((error.code, error.start, error.end), ("internal-error", 0, 1));
+    assert!(error.message.starts_with("internal error: ") && error.message.contains("boom"), "{}", error.message);
+    assert_eq!((take(), guard("x", || Ok(1))), (None, Ok(1)));
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+use promql_parser::parser::*;
+use serde_json::{json, Value};
+use crate::lookback::lookback;
+use crate::matchers::{metric_name, selectors};
+use crate::printer::{duration, to_promql};
+use crate::rules::{errors, ParsedRule, RuleError, RuleFile, RuleGroup};
+use crate::visit::walk;
+use crate::ToSerde;
+
+/// A range that is shorter than the interval its rule is evaluated at, so
+/// some samples are never looked at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntervalFinding {
+    pub rule: usize,
+    pub expr: String,
+    pub range: Duration,
+    pub message: String,
+}
+
+impl ToSerde for IntervalFinding {
+    fn to_serde(&self) -> Value {
+        json!({
+            "rule": self.rule,
+            "expr": self.expr,
+            "range": duration(&self.range),
+            "message": self.message,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupPlan {
+    pub name: String,
+    /// `Err` holds an unparsable interval.
+    pub interval: Result<Duration, String>,
+    pub rules: usize,
+    /// Metrics read by the group that no rule of the file records.
+    pub source_metrics: BTreeSet<String>,
+    /// Metrics read by the group that rules of the file record.
+    pub recorded_metrics: BTreeSet<String>,
+    pub widest_lookback: Duration,
+    pub findings: Vec<IntervalFinding>,
+}
+
+impl ToSerde for GroupPlan {
+    fn to_serde(&self) -> Value {
+        json!({
+            "name": self.name,
+            "interval": self.interval.as_ref().map(duration).ok(),
+            "interval_error": self.interval.as_ref().err(),
+            "rules": self.rules,
+            "source_metrics": self.source_metrics,
+            "recorded_metrics": self.recorded_metrics,
+            "widest_lookback": duration(&self.widest_lookback),
+            "widest_lookback_seconds": self.widest_lookback.as_secs_f64(),
+            "findings": self.findings.to_serde(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanningReport {
+    pub groups: Vec<GroupPlan>,
+    pub errors: Vec<RuleError>,
+}
+
+impl ToSerde for PlanningReport {
+    fn to_serde(&self) -> Value {
+        json!({
+            "groups": self.groups.to_serde(),
+            "errors": self.errors.to_serde(),
+        })
+    }
+}
+
+fn short_ranges(rule: usize, expr: &Expr, interval: Duration, findings: &mut Vec<IntervalFinding>) {
+    walk(expr, &mut |e| {
+        let range = match e {
+            Expr::MatrixSelector(MatrixSelector { range, .. }) => *range,
+            Expr::Subquery(SubqueryExpr { range, .. }) => *range,
+            _ => return,
+        };
+        if range < interval {
+            findings.push(IntervalFinding {
+                rule,
+                expr: to_promql(e),
+                range,
+                message: format!(
+                    "range {} is shorter than the group interval {}, samples between evaluations are skipped",
+                    duration(&range),
+                    duration(&interval),
+                ),
+            });
+        }
+    });
+}
+
+fn plan_group(group: &RuleGroup, rules: &[&ParsedRule], recorded: &BTreeSet<&str>) -> GroupPlan {
+    let interval = group.interval();
+    let mut plan = GroupPlan {
+        name: group.name.clone(),
+        interval: interval.clone(),
+        rules: rules.len(),
+        source_metrics: BTreeSet::new(),
+        recorded_metrics: BTreeSet::new(),
+        widest_lookback: Duration::ZERO,
+        findings: vec![],
+    };
+    for r in rules {
+        let expr = match &r.expr {
+            Ok(expr) => expr,
+            Err(_) => continue,
+        };
+        for metric in selectors(expr).iter().filter_map(metric_name) {
+            if recorded.contains(metric.as_str()) {
+                plan.recorded_metrics.insert(metric);
+            } else {
+                plan.source_metrics.insert(metric);
+            }
+        }
+        plan.widest_lookback = plan.widest_lookback.max(lookback(expr));
+        if let Ok(interval) = interval {
+            short_ranges(r.id, expr, interval, &mut plan.findings);
+        }
+    }
+    plan
+}
+
+/// Summarizes per rule group which metrics it reads, how much history it
+/// needs and which ranges are too short for its evaluation interval.
+pub fn plan(file: &RuleFile) -> PlanningReport {
+    let rules = file.parsed();
+    let recorded: BTreeSet<&str> = rules.iter().filter_map(|r| r.rule.record.as_deref()).collect();
+    let groups = file
+        .groups
+        .iter()
+        .map(|group| {
+            let members: Vec<&ParsedRule> = rules.iter().filter(|r| std::ptr::eq(r.group, group)).collect();
+            plan_group(group, &members, &recorded)
+        })
+        .collect();
+    PlanningReport { groups, errors: errors(&rules) }
+}
+
+
+#[test]
+fn check_plan() {
+    let file: RuleFile = serde_json::from_value(json!({
+        "groups": [
+            {
+                "name": "fast",
+                "interval": "2m",
+                "rules": [
+                    { "record": "job:requests:rate1m", "expr": "sum by (job) (rate(requests_total[1m]))" },
+                    { "alert": "Down", "expr": "up == 0", "for": "5m" },
+                ],
+            },
+            {
+                "name": "slow",
+                "rules": [
+                    { "record": "job:requests:max1h", "expr": "max_over_time(job:requests:rate1m[1h] offset 5m)" },
+                ],
+            },
+        ],
+    }))
+    .unwrap();
+    let report = plan(&file);
+    let fast = &report.groups[0];
+    assert_eq!(fast.source_metrics.iter().collect::<Vec<_>>(), vec!["requests_total", "up"]);
+    assert_eq!(fast.widest_lookback, Duration::from_secs(300));
+    assert_eq!(fast.findings.len(), 1);
+    let slow = &report.groups[1];
+    assert_eq!(slow.interval, Ok(Duration::from_secs(60)));
+    assert_eq!(slow.recorded_metrics.iter().collect::<Vec<_>>(), vec!["job:requests:rate1m"]);
+    assert_eq!(slow.widest_lookback, Duration::from_secs(3900));
+    assert!(slow.findings.is_empty());
+}
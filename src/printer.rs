@@ -0,0 +1,345 @@
+use std::time::{Duration, SystemTime};
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use promql_parser::util::display_duration;
+
+/// Binding strength of a binary operator, higher binds tighter.
+pub fn precedence(op: TokenId) -> u8 {
+    match op {
+        T_LOR => 1,
+        T_LAND | T_LUNLESS => 2,
+        T_EQLC | T_NEQ | T_LTE | T_LSS | T_GTE | T_GTR => 3,
+        T_ADD | T_SUB => 4,
+        T_MUL | T_DIV | T_MOD | T_ATAN2 => 5,
+        T_POW => 6,
+        _ => 0,
+    }
+}
+
+pub fn is_right_assoc(op: TokenId) -> bool {
+    op == T_POW
+}
+
+/// Quotes a raw (still escaped) string value as a double-quoted literal.
+///
+/// Escapes a double-quoted string also takes are kept as written, `\'` of
+/// single-quoted strings is decoded and any other backslash is escaped.
+pub fn quote(raw: &str) -> String {
+    let mut s = String::with_capacity(raw.len() + 2);
+    s.push('"');
+    let mut chars = raw.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.peek() {
+                Some('\'') => {
+                    s.push('\'');
+                    chars.next();
+                }
+                Some(next @ ('a' | 'b' | 'f' | 'n' | 'r' | 't' | 'v' | '\\' | '"' | 'x' | 'u' | 'U' | '0'..='7')) => {
+                    s.push('\\');
+                    s.push(*next);
+                    chars.next();
+                }
+                _ => s.push_str("\\\\"),
+            },
+            '"' => s.push_str("\\\""),
+            '\n' => s.push_str("\\n"),
+            _ => s.push(ch),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// A string value without the Go escapes upstream keeps in it.
+pub fn unescape(raw: &str) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            value.push(ch);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('a') => '\u{7}',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('v') => '\u{b}',
+            Some(code @ ('x' | 'u' | 'U' | '0'..='7')) => {
+                let (radix, digits) = match code {
+                    'x' => (16, chars.by_ref().take(2).collect()),
+                    'u' => (16, chars.by_ref().take(4).collect()),
+                    'U' => (16, chars.by_ref().take(8).collect()),
+                    _ => (8, std::iter::once(code).chain(chars.by_ref().take(2)).collect::<String>()),
+                };
+                match u32::from_str_radix(&digits, radix).ok().and_then(char::from_u32) {
+                    Some(decoded) => decoded,
+                    None => {
+                        value.push('\\');
+                        if radix == 16 {
+                            value.push(code);
+                        }
+                        value.push_str(&digits);
+                        continue;
+                    }
+                }
+            }
+            Some(other) => other,
+            None => '\\',
+        };
+        value.push(escaped);
+    }
+    value
+}
+
+pub fn duration(dur: &Duration) -> String {
+    display_duration(dur)
+}
+
+pub fn number(val: f64) -> String {
+    if val == f64::INFINITY {
+        "Inf".to_string()
+    } else if val == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if val.is_nan() {
+        "NaN".to_string()
+    } else {
+        val.to_string()
+    }
+}
+
+/// Whether `name` can be written unquoted, as a metric name if `metric`
+/// (colons allowed) or as a label name.
+pub fn plain_name(name: &str, metric: bool) -> bool {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_' || (metric && c == ':');
+    name.chars().next().is_some_and(|c| word(c) && !c.is_ascii_digit()) && name.chars().all(word)
+}
+
+/// A label name, quoted if it is not a plain identifier (Prometheus 3 UTF-8 names).
+pub fn label_name(name: &str) -> String {
+    if plain_name(name, false) {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
+pub fn labels(labels: &Labels) -> String {
+    let names: Vec<String> = labels.labels.iter().map(|name| label_name(name)).collect();
+    format!("({})", names.join(", "))
+}
+
+pub fn matcher(matcher: &Matcher) -> String {
+    format!("{}{}{}", label_name(&matcher.name), matcher.op, quote(&matcher.value))
+}
+
+/// A selector of metric `name` with rendered `matchers`. Names that are not
+/// plain identifiers go quoted inside the braces, as in `{"http.requests"}`.
+pub fn selector_text(name: Option<&str>, mut matchers: Vec<String>) -> String {
+    let mut s = String::new();
+    match name {
+        Some(name) if plain_name(name, true) => s.push_str(name),
+        Some(name) => matchers.insert(0, quote(name)),
+        None => {}
+    }
+    if !matchers.is_empty() || s.is_empty() {
+        s.push('{');
+        s.push_str(&matchers.join(", "));
+        s.push('}');
+    }
+    s
+}
+
+pub fn offset(offset: &Offset) -> String {
+    match offset {
+        Offset::Pos(dur) => format!("offset {}", duration(dur)),
+        Offset::Neg(dur) => format!("offset -{}", duration(dur)),
+    }
+}
+
+pub fn at(at: &AtModifier) -> String {
+    match at {
+        AtModifier::Start => "@ start()".to_string(),
+        AtModifier::End => "@ end()".to_string(),
+        AtModifier::At(time) => {
+            let millis = match time.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(dur) => dur.as_millis() as i128,
+                Err(err) => -(err.duration().as_millis() as i128),
+            };
+            format!("@ {}{}.{:03}", if millis < 0 { "-" } else { "" }, millis.abs() / 1000, millis.abs() % 1000)
+        }
+    }
+}
+
+fn selector_suffix(s: &mut String, vs: &VectorSelector) {
+    if let Some(at) = &vs.at {
+        s.push(' ');
+        s.push_str(&self::at(at));
+    }
+    if let Some(offset) = &vs.offset {
+        s.push(' ');
+        s.push_str(&self::offset(offset));
+    }
+}
+
+fn selector_body(vs: &VectorSelector) -> String {
+    selector_text(vs.name.as_deref(), vs.matchers.matchers.iter().map(matcher).collect())
+}
+
+pub fn bin_modifier(modifier: &BinModifier) -> String {
+    let mut s = String::new();
+    if modifier.return_bool {
+        s.push_str(" bool");
+    }
+    let grouped = !matches!(
+        modifier.card,
+        VectorMatchCardinality::OneToOne | VectorMatchCardinality::ManyToMany
+    );
+    match &modifier.matching {
+        Some(LabelModifier::Include(ls)) => s.push_str(&format!(" on {}", labels(ls))),
+        Some(LabelModifier::Exclude(ls)) if !ls.is_empty() || grouped =>
+            s.push_str(&format!(" ignoring {}", labels(ls))),
+        _ if grouped => s.push_str(" ignoring ()"),
+        _ => {}
+    }
+    match &modifier.card {
+        VectorMatchCardinality::ManyToOne(ls) => s.push_str(&format!(" group_left {}", labels(ls))),
+        VectorMatchCardinality::OneToMany(ls) => s.push_str(&format!(" group_right {}", labels(ls))),
+        _ => {}
+    }
+    s
+}
+
+/// Whether `expr` needs parentheses as an operand that must bind at least as
+/// tight as `min`.
+pub fn needs_parens(expr: &Expr, min: u8) -> bool {
+    let binds = match expr {
+        Expr::Binary(BinaryExpr { op, .. }) => precedence(op.id()),
+        // unary minus binds like multiplication
+        Expr::Unary(_) => precedence(T_MUL),
+        Expr::NumberLiteral(NumberLiteral { val }) if val.is_sign_negative() && !val.is_nan() =>
+            precedence(T_MUL),
+        _ => u8::MAX,
+    };
+    binds < min
+}
+
+/// Wraps `expr` in parentheses unless it binds at least as tight as `min`.
+fn operand(expr: &Expr, min: u8) -> String {
+    if needs_parens(expr, min) {
+        format!("({})", to_promql(expr))
+    } else {
+        to_promql(expr)
+    }
+}
+
+/// The `by`/`without` clause of an aggregation, padded, or nothing.
+pub fn grouping(modifier: &Option<LabelModifier>) -> String {
+    match modifier {
+        Some(LabelModifier::Include(ls)) if !ls.is_empty() => format!(" by {} ", labels(ls)),
+        Some(LabelModifier::Exclude(ls)) => format!(" without {} ", labels(ls)),
+        _ => String::new(),
+    }
+}
+
+/// Minimum precedence of the left and right operands of `op`.
+pub fn operand_precedence(op: TokenId) -> (u8, u8) {
+    let prec = precedence(op);
+    if is_right_assoc(op) {
+        (prec + 1, prec)
+    } else {
+        (prec, prec + 1)
+    }
+}
+
+/// What follows the expression of a subquery: range, step and modifiers.
+pub fn subquery_suffix(sq: &SubqueryExpr) -> String {
+    let mut s = format!("[{}:{}]", duration(&sq.range), sq.step.as_ref().map(duration).unwrap_or_default());
+    if let Some(at) = &sq.at {
+        s.push(' ');
+        s.push_str(&self::at(at));
+    }
+    if let Some(offset) = &sq.offset {
+        s.push(' ');
+        s.push_str(&self::offset(offset));
+    }
+    s
+}
+
+/// Renders `expr` as a single-line PromQL query.
+pub fn to_promql(expr: &Expr) -> String {
+    match expr {
+        Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => {
+            let mut s = format!("{}{}(", op, grouping(modifier));
+            if let Some(param) = param {
+                s.push_str(&to_promql(param));
+                s.push_str(", ");
+            }
+            s.push_str(&to_promql(expr));
+            s.push(')');
+            s
+        }
+        Expr::Unary(UnaryExpr { expr }) => format!("-{}", operand(expr, precedence(T_POW))),
+        Expr::Binary(BinaryExpr { lhs, op, rhs, modifier }) => {
+            let (lhs_min, rhs_min) = operand_precedence(op.id());
+            format!(
+                "{} {}{} {}",
+                operand(lhs, lhs_min),
+                op,
+                modifier.as_ref().map(bin_modifier).unwrap_or_default(),
+                operand(rhs, rhs_min),
+            )
+        }
+        Expr::Paren(ParenExpr { expr }) => format!("({})", to_promql(expr)),
+        Expr::Subquery(sq) => format!("{}{}", operand(&sq.expr, u8::MAX), subquery_suffix(sq)),
+        Expr::NumberLiteral(NumberLiteral { val }) => number(*val),
+        Expr::StringLiteral(StringLiteral { val }) => quote(val),
+        Expr::VectorSelector(vs) => {
+            let mut s = selector_body(vs);
+            selector_suffix(&mut s, vs);
+            s
+        }
+        Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+            let mut s = selector_body(vs);
+            s.push_str(&format!("[{}]", duration(range)));
+            selector_suffix(&mut s, vs);
+            s
+        }
+        Expr::Call(Call { func, args }) => {
+            let args: Vec<String> = args.args.iter().map(|arg| to_promql(arg)).collect();
+            format!("{}({})", func.name, args.join(", "))
+        }
+        Expr::Extension(ext) => format!("{:?}", ext),
+    }
+}
+
+
+#[test]
+fn check_printer() {
+    let payloads = vec![
+        ("sum by (a, b) (rate(foo{bar=\"baz\"}[5m]))", "sum by (a, b) (rate(foo{bar=\"baz\"}[5m]))"),
+        ("foo{a='x\"y'} offset -1m", "foo{a=\"x\\\"y\"} offset -1m"),
+        ("x{a='\\''} + x{a='\\\\'}", "x{a=\"'\"} + x{a=\"\\\\\"}"),
+        ("a / on(x) group_left b", "a / on (x) group_left () b"),
+        ("a * ignoring() group_right(c) b", "a * ignoring () group_right (c) b"),
+        ("(a + b)[5m:1m] @ 1.5", "(a + b)[5m:1m] @ 1.500"),
+        ("2 ^ 3 ^ 4 - -1", "2 ^ 3 ^ 4 - -1"),
+        ("topk(5, x) > bool 3", "topk(5, x) > bool 3"),
+    ];
+    for (query, expected) in payloads {
+        let printed = to_promql(&parse(query).unwrap());
+        assert_eq!(printed, expected);
+        assert_eq!(to_promql(&parse(&printed).unwrap()), printed);
+    }
+    let value = |query: &str| match parse(query).unwrap() {
+        Expr::VectorSelector(vs) => unescape(&vs.matchers.matchers[0].value),
+        _ => unreachable!(),
+    };
+    for query in ["x{a='\\''}", "x{a='\\\\'}", "x{a='\\x41\\n\"'}"] {
+        assert_eq!(value(&to_promql(&parse(query).unwrap())), value(query));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use promql_parser::parser;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::inventory::CorpusError;
+use crate::literals::analyze;
+use crate::printer::{to_promql, unescape};
+use crate::visit::selectors_mut;
+use crate::ToSerde;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PseudonymOptions {
+    /// Mixed into every pseudonym, so they cannot be reversed by hashing
+    /// guessed names. Keep it to get the same pseudonyms in a later batch.
+    pub salt: String,
+    /// Whether to return the original → pseudonym mapping.
+    pub mapping: bool,
+}
+
+/// Original names and values with their pseudonyms.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mapping {
+    pub metrics: BTreeMap<String, String>,
+    pub values: BTreeMap<String, String>,
+}
+
+impl ToSerde for Mapping {
+    fn to_serde(&self) -> Value {
+        json!({
+            "metrics": self.metrics,
+            "values": self.values,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pseudonymized {
+    /// The rewritten queries, `None` where a query could not be parsed.
+    pub queries: Vec<Option<String>>,
+    pub mapping: Option<Mapping>,
+    pub errors: Vec<CorpusError>,
+}
+
+impl ToSerde for Pseudonymized {
+    fn to_serde(&self) -> Value {
+        let mut value = json!({
+            "queries": self.queries,
+            "errors": self.errors.to_serde(),
+        });
+        if let Some(mapping) = &self.mapping {
+            value["mapping"] = mapping.to_serde();
+        }
+        value
+    }
+}
+
+/// 64-bit FNV-1a, stable across platforms and releases unlike `DefaultHasher`.
+pub fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+struct Pseudonymizer<'a> {
+    options: &'a PseudonymOptions,
+    mapping: Mapping,
+}
+
+impl Pseudonymizer<'_> {
+    fn metric(&mut self, name: &str) -> String {
+        let pseudonym = format!("metric_{:012x}", fnv1a(&[&self.options.salt, "metric", name]) >> 16);
+        self.mapping.metrics.entry(name.to_string()).or_insert(pseudonym).clone()
+    }
+
+    fn value(&mut self, value: &str) -> String {
+        // an empty value selects series without the label, keep that meaning
+        if value.is_empty() {
+            return String::new();
+        }
+        let pseudonym = format!("value_{:012x}", fnv1a(&[&self.options.salt, "value", value]) >> 16);
+        self.mapping.values.entry(value.to_string()).or_insert(pseudonym).clone()
+    }
+
+    /// A finite alternation keeps its shape with each alternative replaced,
+    /// any other pattern becomes a single pseudonym.
+    fn pattern(&mut self, pattern: &str, metric: bool) -> String {
+        let alternatives = analyze(pattern).and_then(|literals| literals.alternatives);
+        let mut replace = |s: &str| if metric { self.metric(s) } else { self.value(s) };
+        match alternatives {
+            Some(alternatives) => alternatives.iter().map(|a| replace(a)).collect::<Vec<String>>().join("|"),
+            None => replace(pattern),
+        }
+    }
+
+    fn matcher(&mut self, matcher: &Matcher) -> Matcher {
+        let metric = matcher.name == METRIC_NAME;
+        // the same value may be spelled with different escapes and quotes
+        let raw = unescape(&matcher.value);
+        let (id, value) = match &matcher.op {
+            MatchOp::Equal | MatchOp::NotEqual if metric => (None, self.metric(&raw)),
+            MatchOp::Equal | MatchOp::NotEqual => (None, self.value(&raw)),
+            MatchOp::Re(_) => (Some(T_EQL_REGEX), self.pattern(&raw, metric)),
+            MatchOp::NotRe(_) => (Some(T_NEQ_REGEX), self.pattern(&raw, metric)),
+        };
+        match id {
+            // pseudonyms are plain words, so the pattern always compiles
+            Some(id) => Matcher::new_matcher(id, matcher.name.clone(), value).unwrap(),
+            None => Matcher::new(matcher.op.clone(), &matcher.name, &value),
+        }
+    }
+
+    fn selector(&mut self, vs: &mut parser::VectorSelector) {
+        vs.name = vs.name.as_deref().map(|name| self.metric(name));
+        vs.matchers.matchers = vs.matchers.matchers.iter().map(|m| self.matcher(m)).collect();
+    }
+}
+
+/// Replaces the metric names and label values of every query of a corpus
+/// with pseudonyms. The same name or value always gets the same pseudonym,
+/// so joins and repeated selectors keep working. Label names, function names
+/// and string arguments are left as they are.
+pub fn pseudonymize(queries: &[String], options: &PseudonymOptions) -> Pseudonymized {
+    let mut pseudonymizer = Pseudonymizer { options, mapping: Mapping::default() };
+    let mut errors = vec![];
+    let queries = queries
+        .iter()
+        .enumerate()
+        .map(|(index, query)| match parser::parse(query) {
+            Ok(mut expr) => {
+                selectors_mut(&mut expr, &mut |vs| pseudonymizer.selector(vs));
+                Some(to_promql(&expr))
+            }
+            Err(message) => {
+                errors.push(CorpusError { index, query: query.clone(), message });
+                None
+            }
+        })
+        .collect();
+    let mapping = options.mapping.then_some(pseudonymizer.mapping);
+    Pseudonymized { queries, mapping, errors }
+}
+
+
+#[test]
+fn check_pseudonymize() {
+    let corpus: Vec<String> = [
+        "sum by (job) (rate(http_requests_total{job=\"api\", code=~\"5..\"}[5m]))",
+        "up{job=~\"api|db\", env=\"\"} / on (job) http_requests_total{job=\"api\"}",
+        "sum(",
+    ]
+    .iter()
+    .map(|q| q.to_string())
+    .collect();
+    let options = PseudonymOptions { salt: "s".to_string(), mapping: true };
+    let result = pseudonymize(&corpus, &options);
+    let mapping = result.mapping.as_ref().unwrap();
+    let (metric, api) = (&mapping.metrics["http_requests_total"], &mapping.values["api"]);
+    let first = result.queries[0].as_ref().unwrap();
+    let second = result.queries[1].as_ref().unwrap();
+    assert!(first.contains(metric.as_str()) && first.contains(api.as_str()) && first.contains("by (job)"));
+    assert!(second.contains(&format!("{}|{}", api, mapping.values["db"])));
+    assert!(second.contains("env=\"\"") && second.contains(metric.as_str()));
+    assert!(!first.contains("5..") && !second.contains("http_requests_total"));
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(result.queries[2], None);
+    assert_eq!(pseudonymize(&corpus, &options), result);
+    let other = pseudonymize(&corpus, &PseudonymOptions::default());
+    assert!(other.mapping.is_none());
+    assert_ne!(other.queries[0], result.queries[0]);
+    let quoted: Vec<String> = vec!["x{a=\"x\\\"y\"}".to_string(), "x{a='x\"y'}".to_string()];
+    let quoted = pseudonymize(&quoted, &options).queries;
+    assert_eq!(quoted[0], quoted[1]);
+}
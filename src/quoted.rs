@@ -0,0 +1,192 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use crate::edits::{apply, original_error, Edit};
+use crate::errors::{located, try_parse, ParseError};
+use crate::lexemes::lex;
+
+/// Tokens before a parenthesized list of label names.
+const GROUPING: [TokenId; 6] = [T_BY, T_WITHOUT, T_ON, T_IGNORING, T_GROUP_LEFT, T_GROUP_RIGHT];
+const MATCH_OPS: [TokenId; 4] = [T_EQL, T_NEQ, T_EQL_REGEX, T_NEQ_REGEX];
+
+/// A query with its quoted names replaced by placeholder identifiers
+/// upstream parses.
+struct Rewrite {
+    prefix: String,
+    text: String,
+    edits: Vec<Edit>,
+    /// Per placeholder, the unquoted label name, or `None` for a metric name,
+    /// which is kept as the value of the placeholder matcher.
+    labels: Vec<Option<String>>,
+}
+
+/// The unescaped value of a string literal, as upstream keeps string values.
+fn unquote(literal: &str) -> Option<String> {
+    match parse(literal) {
+        Ok(Expr::StringLiteral(StringLiteral { val })) => Some(val),
+        _ => None,
+    }
+}
+
+impl Rewrite {
+    fn new(query: &str) -> Rewrite {
+        let mut prefix = "__utf8_".to_string();
+        while query.contains(&prefix) {
+            prefix.push('_');
+        }
+        let lexemes = lex(query).unwrap_or_default();
+        let mut edits = vec![];
+        let mut labels = vec![];
+        let mut in_braces = false;
+        let mut label_lists = vec![];
+        for (i, lexeme) in lexemes.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|i| lexemes[i].id);
+            let next = lexemes.get(i + 1).map(|l| l.id);
+            let placeholder = format!("{}{}", prefix, labels.len());
+            let literal = lexeme.text(query);
+            let replacement = match lexeme.id {
+                T_LEFT_BRACE | T_RIGHT_BRACE => {
+                    in_braces = lexeme.id == T_LEFT_BRACE;
+                    None
+                }
+                T_LEFT_PAREN => {
+                    label_lists.push(prev.is_some_and(|prev| GROUPING.contains(&prev)));
+                    None
+                }
+                T_RIGHT_PAREN => {
+                    label_lists.pop();
+                    None
+                }
+                T_STRING if in_braces && next.is_some_and(|next| MATCH_OPS.contains(&next)) =>
+                    unquote(literal).map(|name| (placeholder, Some(name))),
+                T_STRING if in_braces
+                    && matches!(prev, Some(T_LEFT_BRACE | T_COMMA))
+                    && matches!(next, Some(T_RIGHT_BRACE | T_COMMA)) =>
+                    Some((format!("{}={}", placeholder, literal), None)),
+                T_STRING if !in_braces && label_lists.last() == Some(&true) =>
+                    unquote(literal).map(|name| (placeholder, Some(name))),
+                _ => None,
+            };
+            if let Some((text, label)) = replacement {
+                edits.push(Edit { start: lexeme.start, end: lexeme.end, text });
+                labels.push(label);
+            }
+        }
+        Rewrite { prefix, text: apply(query, &edits), edits, labels }
+    }
+
+    /// What the placeholder `name` stands for, if it is one.
+    fn replacement(&self, name: &str) -> Option<&Option<String>> {
+        let index: usize = name.strip_prefix(&self.prefix)?.parse().ok()?;
+        self.labels.get(index)
+    }
+
+    fn labels(&self, labels: &mut Labels) {
+        for name in labels.labels.iter_mut() {
+            if let Some(label) = self.replacement(name).cloned().flatten() {
+                *name = label;
+            }
+        }
+    }
+
+    fn label_modifier(&self, modifier: &mut Option<LabelModifier>) {
+        if let Some(LabelModifier::Include(labels) | LabelModifier::Exclude(labels)) = modifier {
+            self.labels(labels);
+        }
+    }
+
+    fn selector(&self, vs: &mut VectorSelector) -> Result<(), String> {
+        let mut matchers = vec![];
+        for mut matcher in std::mem::take(&mut vs.matchers.matchers) {
+            match self.replacement(&matcher.name) {
+                Some(Some(label)) => {
+                    matcher.name = label.clone();
+                    matchers.push(matcher);
+                }
+                Some(None) => match &vs.name {
+                    Some(name) => {
+                        return Err(format!("metric name must not be set twice: '{}' or '{}'", name, matcher.value))
+                    }
+                    None => vs.name = Some(matcher.value),
+                },
+                None => matchers.push(matcher),
+            }
+        }
+        vs.matchers.matchers = matchers;
+        Ok(())
+    }
+
+    /// Puts the quoted names back in place of their placeholders.
+    fn restore(&self, expr: &mut Expr) -> Result<(), String> {
+        match expr {
+            Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => self.selector(vs)?,
+            Expr::Aggregate(AggregateExpr { expr, param, modifier, .. }) => {
+                self.label_modifier(modifier);
+                if let Some(param) = param {
+                    self.restore(param)?;
+                }
+                self.restore(expr)?;
+            }
+            Expr::Binary(BinaryExpr { lhs, rhs, modifier, .. }) => {
+                if let Some(modifier) = modifier {
+                    self.label_modifier(&mut modifier.matching);
+                    if let VectorMatchCardinality::ManyToOne(labels) | VectorMatchCardinality::OneToMany(labels) =
+                        &mut modifier.card
+                    {
+                        self.labels(labels);
+                    }
+                }
+                self.restore(lhs)?;
+                self.restore(rhs)?;
+            }
+            Expr::Unary(UnaryExpr { expr })
+            | Expr::Paren(ParenExpr { expr })
+            | Expr::Subquery(SubqueryExpr { expr, .. }) => self.restore(expr)?,
+            Expr::Call(Call { args, .. }) => {
+                for arg in args.args.iter_mut() {
+                    self.restore(arg)?;
+                }
+            }
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) | Expr::Extension(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// `try_parse`, also accepting the quoted UTF-8 metric and label names of
+/// Prometheus 3: `{"http.requests", "service name"="api"}` and
+/// `sum by ("service name") (...)`.
+pub fn try_parse_quoted(query: &str) -> Result<Expr, ParseError> {
+    let rewrite = Rewrite::new(query);
+    if rewrite.edits.is_empty() {
+        return try_parse(query);
+    }
+    let mut expr = try_parse(&rewrite.text).map_err(|error| original_error(query, &rewrite.edits, error))?;
+    rewrite
+        .restore(&mut expr)
+        .map_err(|message| located(query, message, "invalid-query", 0, query.len()))?;
+    Ok(expr)
+}
+
+
+#[test]
+fn check_try_parse_quoted() {
+    use crate::printer::to_promql;
+    let payloads = vec![
+        ("{\"http.requests\"}", "{\"http.requests\"}"),
+        ("{\"http.requests\", \"service name\"=~\"a.*\"}[5m]", "{\"http.requests\", \"service name\"=~\"a.*\"}[5m]"),
+        ("sum by (\"service.name\", job) (rate(x{\"a-b\"!=\"\"}[1m]))", "sum by (\"service.name\", job) (rate(x{\"a-b\"!=\"\"}[1m]))"),
+        ("a / on (\"k.8\") group_left (\"b c\") b", "a / on (\"k.8\") group_left (\"b c\") b"),
+        ("{\"plain\"} + label_replace(y, \"dst\", \"$1\", \"src\", \"(.*)\")", "plain + label_replace(y, \"dst\", \"$1\", \"src\", \"(.*)\")"),
+    ];
+    for (query, expected) in payloads {
+        let expr = try_parse_quoted(query).unwrap_or_else(|err| panic!("{}: {}", query, err.message));
+        assert_eq!(to_promql(&expr), expected);
+        assert_eq!(try_parse_quoted(&to_promql(&expr)).unwrap(), expr);
+        assert_eq!(crate::spans::spans(query, &expr).map(|tree| tree.span.end), Some(query.len()));
+    }
+    assert!(try_parse("{\"http.requests\"}").is_err());
+    assert!(try_parse_quoted("x{\"y\"}").unwrap_err().message.contains("twice"));
+    let error = try_parse_quoted("{\"a.b\"=\"c\"} + * y").unwrap_err();
+    assert_eq!((error.code, error.start, error.column), ("invalid-syntax", 14, 15));
+}
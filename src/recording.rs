@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use promql_parser::parser;
+use promql_parser::parser::*;
+use serde_json::{json, Value};
+use crate::canonical::canonical;
+use crate::cost::{cost, Weights};
+use crate::inventory::CorpusError;
+use crate::output_labels::{output_labels, OutputLabels};
+use crate::printer::duration;
+use crate::visit::walk;
+use crate::ToSerde;
+
+/// A subexpression worth precomputing as a recording rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    /// Rule name, as `level:metric:operations`.
+    pub record: String,
+    /// The subexpression, canonical.
+    pub expr: String,
+    /// How many times it appears across the queries.
+    pub occurrences: usize,
+    /// Indices of the queries it appears in.
+    pub queries: Vec<usize>,
+    /// `cost` score of the subexpression, saved on every evaluation.
+    pub cost: f64,
+}
+
+impl ToSerde for Suggestion {
+    fn to_serde(&self) -> Value {
+        json!({
+            "record": self.record,
+            "expr": self.expr,
+            "occurrences": self.occurrences,
+            "queries": self.queries,
+            "cost": self.cost,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Suggestions {
+    pub suggestions: Vec<Suggestion>,
+    pub errors: Vec<CorpusError>,
+}
+
+impl ToSerde for Suggestions {
+    fn to_serde(&self) -> Value {
+        json!({
+            "suggestions": self.suggestions.to_serde(),
+            "errors": self.errors.to_serde(),
+        })
+    }
+}
+
+/// The metric and operations, newest first, of an expression that can be
+/// named by the `level:metric:operations` convention.
+struct Name {
+    metric: String,
+    operations: Vec<String>,
+}
+
+fn strip<'a>(metric: &'a str, suffix: &str) -> &'a str {
+    metric.strip_suffix(suffix).filter(|base| !base.is_empty()).unwrap_or(metric)
+}
+
+/// `histogram_quantile(0.99, ...)` as `p99`, `0.999` as `p99_9`.
+fn percentile(phi: &Expr) -> String {
+    match phi {
+        Expr::NumberLiteral(NumberLiteral { val }) => {
+            format!("p{}", ((val * 100_000.0).round() / 1000.0).to_string().replace('.', "_"))
+        }
+        _ => "quantile".to_string(),
+    }
+}
+
+fn call(func: &Function, args: &FunctionArgs) -> Option<Name> {
+    let args: Vec<&Expr> = args.args.iter().map(|arg| arg.as_ref()).collect();
+    if let Some(MatrixSelector { vs, range }) = args.iter().find_map(|arg| match arg {
+        Expr::MatrixSelector(ms) => Some(ms),
+        _ => None,
+    }) {
+        let metric = vs.name.as_deref().filter(|_| vs.at.is_none())?;
+        let metric = match func.name {
+            "rate" | "irate" | "increase" => strip(metric, "_total"),
+            _ => metric,
+        };
+        return Some(Name { metric: metric.to_string(), operations: vec![format!("{}{}", func.name, duration(range))] });
+    }
+    let inner = args.iter().find(|arg| arg.value_type() == ValueType::Vector)?;
+    let mut name = describe(inner)?;
+    let operation = match func.name {
+        "histogram_quantile" => {
+            name.metric = strip(&name.metric, "_bucket").to_string();
+            percentile(args[0])
+        }
+        name => name.to_string(),
+    };
+    name.operations.insert(0, operation);
+    Some(name)
+}
+
+/// `a / b` as `a_per_b:ratio_...`, and rates of `x_sum` over `x_count` as
+/// `x:mean...`.
+fn ratio(lhs: &Expr, rhs: &Expr) -> Option<Name> {
+    let (lhs, rhs) = (describe(lhs)?, describe(rhs)?);
+    if lhs.operations != rhs.operations {
+        return None;
+    }
+    let (sum, count) = (lhs.metric.strip_suffix("_sum"), rhs.metric.strip_suffix("_count"));
+    match (sum, count, lhs.operations.as_slice()) {
+        (Some(sum), Some(count), [rate]) if sum == count && rate.starts_with("rate") =>
+            Some(Name { metric: sum.to_string(), operations: vec![rate.replacen("rate", "mean", 1)] }),
+        _ => {
+            let mut operations = lhs.operations;
+            operations.insert(0, "ratio".to_string());
+            Some(Name { metric: format!("{}_per_{}", lhs.metric, rhs.metric), operations })
+        }
+    }
+}
+
+fn describe(expr: &Expr) -> Option<Name> {
+    match expr {
+        Expr::VectorSelector(vs) if vs.at.is_none() =>
+            vs.name.clone().map(|metric| Name { metric, operations: vec![] }),
+        Expr::Paren(ParenExpr { expr }) => describe(expr),
+        Expr::Call(Call { func, args }) => call(func, args),
+        Expr::Aggregate(AggregateExpr { op, expr, .. }) => {
+            let mut name = describe(expr)?;
+            // sum is what aggregating implies
+            if op.id() != token::T_SUM {
+                name.operations.insert(0, op.to_string());
+            }
+            Some(name)
+        }
+        Expr::Binary(BinaryExpr { lhs, op, rhs, .. })
+            if op.id() == token::T_DIV && lhs.value_type() == ValueType::Vector && rhs.value_type() == ValueType::Vector =>
+            ratio(lhs, rhs),
+        _ => None,
+    }
+}
+
+/// The recording rule name of `expr`, if it is an aggregation of named
+/// series, possibly with functions and ratios on top.
+fn record_name(expr: &Expr) -> Option<String> {
+    let mut aggregated = false;
+    walk(expr, &mut |node| aggregated |= matches!(node, Expr::Aggregate(_)));
+    if !aggregated || matches!(expr, Expr::Paren(_)) {
+        return None;
+    }
+    let name = describe(expr)?;
+    let level = match output_labels(expr) {
+        OutputLabels::Only(labels) => labels.into_iter().collect::<Vec<_>>().join("_"),
+        OutputLabels::AllExcept(labels) => {
+            let mut level = vec!["without".to_string()];
+            level.extend(labels);
+            level.join("_")
+        }
+    };
+    Some(format!("{}:{}:{}", level, name.metric, name.operations.join("_")))
+}
+
+/// Suggests the aggregations `queries` compute, with whatever functions and
+/// ratios are applied on top, as recording rules named `level:metric:operations`:
+/// the labels kept, the metric without `_total` for rates or `_bucket` for
+/// quantiles, and the operations newest first, `sum` implied. Subexpressions
+/// shared by more queries, then costlier ones, come first. Queries that fail
+/// to parse are reported, not fatal.
+pub fn suggest(queries: &[String]) -> Suggestions {
+    let mut result = Suggestions::default();
+    let mut found: BTreeMap<String, Suggestion> = BTreeMap::new();
+    for (index, query) in queries.iter().enumerate() {
+        let expr = match parser::parse(query) {
+            Ok(expr) => expr,
+            Err(message) => {
+                result.errors.push(CorpusError { index, query: query.clone(), message });
+                continue;
+            }
+        };
+        walk(&expr, &mut |node| {
+            let record = match record_name(node) {
+                Some(record) => record,
+                None => return,
+            };
+            let text = canonical(node);
+            let suggestion = found.entry(text.clone()).or_insert_with(|| Suggestion {
+                record,
+                expr: text,
+                occurrences: 0,
+                queries: vec![],
+                cost: cost(node, &Weights::default()).score(),
+            });
+            suggestion.occurrences += 1;
+            if suggestion.queries.last() != Some(&index) {
+                suggestion.queries.push(index);
+            }
+        });
+    }
+    let mut suggestions: Vec<Suggestion> = found.into_values().collect();
+    suggestions.sort_by(|a, b| {
+        b.occurrences.cmp(&a.occurrences).then(b.cost.total_cmp(&a.cost)).then(a.record.cmp(&b.record))
+    });
+    // different expressions, e.g. with other matchers, can share a name
+    let mut taken: BTreeMap<String, usize> = BTreeMap::new();
+    for suggestion in &mut suggestions {
+        let count = taken.entry(suggestion.record.clone()).or_default();
+        *count += 1;
+        if *count > 1 {
+            suggestion.record = format!("{}_{}", suggestion.record, count);
+        }
+    }
+    result.suggestions = suggestions;
+    result
+}
+
+
+#[test]
+fn check_recording() {
+    let queries: Vec<String> = vec![
+        "sum by (job) (rate(http_requests_total{code=~\"5..\"}[5m])) / sum by (job) (rate(http_requests_total[5m]))",
+        "sum by (job)(rate(http_requests_total[5m]))",
+        "histogram_quantile(0.99, sum by (le, job) (rate(request_duration_seconds_bucket[5m])))",
+        "sum(rate(request_duration_seconds_sum[5m])) / sum(rate(request_duration_seconds_count[5m]))",
+        "max without (instance) (up) > 0",
+        "rate(x[5m])",
+        "sum(",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let result = suggest(&queries);
+    let records: Vec<(&str, &str, usize)> = result
+        .suggestions
+        .iter()
+        .map(|s| (s.record.as_str(), s.expr.as_str(), s.occurrences))
+        .collect();
+    assert_eq!(records, vec![
+        ("job:http_requests:rate5m", "sum by (job) (rate(http_requests_total[5m]))", 2),
+        ("job:http_requests_per_http_requests:ratio_rate5m", "sum by (job) (rate(http_requests_total{code=~\"5..\"}[5m])) / sum by (job) (rate(http_requests_total[5m]))", 1),
+        (":request_duration_seconds:mean5m", "sum(rate(request_duration_seconds_sum[5m])) / sum(rate(request_duration_seconds_count[5m]))", 1),
+        ("job:http_requests:rate5m_2", "sum by (job) (rate(http_requests_total{code=~\"5..\"}[5m]))", 1),
+        (":request_duration_seconds_count:rate5m", "sum(rate(request_duration_seconds_count[5m]))", 1),
+        (":request_duration_seconds_sum:rate5m", "sum(rate(request_duration_seconds_sum[5m]))", 1),
+        ("job:request_duration_seconds:p99_rate5m", "histogram_quantile(0.99, sum by (job, le) (rate(request_duration_seconds_bucket[5m])))", 1),
+        ("job_le:request_duration_seconds_bucket:rate5m", "sum by (job, le) (rate(request_duration_seconds_bucket[5m]))", 1),
+        ("without_instance:up:max", "max without (instance) (up)", 1),
+    ]);
+    assert_eq!(result.suggestions[0].queries, vec![0, 1]);
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(result.errors[0].index, 6);
+}
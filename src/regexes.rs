@@ -0,0 +1,181 @@
+use regex_syntax::ast::parse::Parser;
+use regex_syntax::ast::*;
+
+/// Largest repetition count of Go's RE2 `regexp` package, which Prometheus
+/// compiles regex matchers with.
+const MAX_REPEAT: u32 = 1000;
+
+/// Collects what the `regex` crate accepts but Go's `regexp` does not.
+struct Re2 {
+    problems: Vec<String>,
+}
+
+impl Re2 {
+    fn flags(&mut self, flags: &Flags) {
+        for item in &flags.items {
+            let flag = match item.kind {
+                FlagsItemKind::Flag(Flag::IgnoreWhitespace) => 'x',
+                FlagsItemKind::Flag(Flag::Unicode) => 'u',
+                FlagsItemKind::Flag(Flag::CRLF) => 'R',
+                _ => continue,
+            };
+            self.problems.push(format!("flag `{}` is not supported", flag));
+        }
+    }
+}
+
+impl Visitor for Re2 {
+    type Output = Vec<String>;
+    type Err = ();
+
+    fn finish(self) -> Result<Vec<String>, ()> {
+        Ok(self.problems)
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<(), ()> {
+        match ast {
+            Ast::Flags(SetFlags { flags, .. }) | Ast::Group(Group { kind: GroupKind::NonCapturing(flags), .. }) =>
+                self.flags(flags),
+            Ast::Repetition(Repetition { op: RepetitionOp { kind: RepetitionKind::Range(range), .. }, .. }) => {
+                let count = match *range {
+                    RepetitionRange::Exactly(n) | RepetitionRange::AtLeast(n) => n,
+                    RepetitionRange::Bounded(_, n) => n,
+                };
+                if count > MAX_REPEAT {
+                    self.problems.push(format!("repetition count {} is above {}", count, MAX_REPEAT));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn visit_class_set_item_pre(&mut self, item: &ClassSetItem) -> Result<(), ()> {
+        if let ClassSetItem::Bracketed(_) = item {
+            self.problems.push("nested character classes are not supported".to_string());
+        }
+        Ok(())
+    }
+
+    fn visit_class_set_binary_op_pre(&mut self, op: &ClassSetBinaryOp) -> Result<(), ()> {
+        let op = match op.kind {
+            ClassSetBinaryOpKind::Intersection => "&&",
+            ClassSetBinaryOpKind::Difference => "--",
+            ClassSetBinaryOpKind::SymmetricDifference => "~~",
+        };
+        self.problems.push(format!("character class operator `{}` is not supported", op));
+        Ok(())
+    }
+}
+
+/// Why `pattern`, which parses here, would not compile in Prometheus; empty
+/// if it would, or if it does not parse at all.
+pub fn re2_incompatibilities(pattern: &str) -> Vec<String> {
+    match Parser::new().parse(pattern) {
+        Ok(ast) => {
+            let mut problems = visit(&ast, Re2 { problems: vec![] }).unwrap_or_default();
+            problems.dedup();
+            problems
+        }
+        Err(_) => vec![],
+    }
+}
+
+/// Parts of a regex Prometheus' full anchoring makes pointless.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Needless {
+    /// `.*`, or an alternation with it, which matches any value, even none.
+    MatchesAll,
+    /// `.+`, which is `!=""` spelled as a regex.
+    NonEmpty,
+    /// Anchors and repeated `.*`, described, and the pattern without them.
+    Redundant { parts: Vec<&'static str>, pattern: String },
+}
+
+/// Looks through groups that only capture or set no flags.
+fn unwrap_groups(ast: &Ast) -> &Ast {
+    match ast {
+        Ast::Group(Group { kind: GroupKind::NonCapturing(flags), ast, .. }) if flags.items.is_empty() =>
+            unwrap_groups(ast),
+        Ast::Group(Group { kind: GroupKind::CaptureIndex(_), ast, .. }) => unwrap_groups(ast),
+        _ => ast,
+    }
+}
+
+fn is_dot_repetition(ast: &Ast, kind: RepetitionKind) -> bool {
+    match unwrap_groups(ast) {
+        Ast::Repetition(Repetition { op, ast, .. }) => op.kind == kind && matches!(**ast, Ast::Dot(_)),
+        _ => false,
+    }
+}
+
+/// What of `pattern` changes nothing, `None` if it is all needed or does
+/// not parse.
+pub fn needless(pattern: &str) -> Option<Needless> {
+    let ast = Parser::new().parse(pattern).ok()?;
+    let alternatives = match unwrap_groups(&ast) {
+        Ast::Alternation(Alternation { asts, .. }) => asts.iter().collect(),
+        ast => vec![ast],
+    };
+    if alternatives.iter().any(|ast| is_dot_repetition(ast, RepetitionKind::ZeroOrMore)) {
+        return Some(Needless::MatchesAll);
+    }
+    if is_dot_repetition(&ast, RepetitionKind::OneOrMore) {
+        return Some(Needless::NonEmpty);
+    }
+    let items: Vec<&Ast> = match &ast {
+        Ast::Concat(Concat { asts, .. }) => asts.iter().collect(),
+        _ => return None,
+    };
+    let mut parts = vec![];
+    let mut removed: Vec<&Span> = vec![];
+    if let Some(Ast::Assertion(Assertion { kind: AssertionKind::StartLine | AssertionKind::StartText, span })) = items.first() {
+        parts.push("a leading `^`");
+        removed.push(span);
+    }
+    if let Some(Ast::Assertion(Assertion { kind: AssertionKind::EndLine | AssertionKind::EndText, span })) = items.last() {
+        parts.push("a trailing `$`");
+        removed.push(span);
+    }
+    let repeated: Vec<&Span> = items
+        .windows(2)
+        .filter(|pair| pair.iter().all(|ast| is_dot_repetition(ast, RepetitionKind::ZeroOrMore)))
+        .map(|pair| pair[1].span())
+        .collect();
+    if !repeated.is_empty() {
+        parts.push("a repeated `.*`");
+        removed.extend(repeated);
+    }
+    if removed.is_empty() {
+        return None;
+    }
+    removed.sort_by_key(|span| span.start.offset);
+    let mut kept = String::new();
+    let mut last = 0;
+    for span in removed {
+        kept.push_str(&pattern[last..span.start.offset]);
+        last = span.end.offset;
+    }
+    kept.push_str(&pattern[last..]);
+    Some(Needless::Redundant { parts, pattern: kept })
+}
+
+
+#[test]
+fn check_regexes() {
+    assert_eq!(re2_incompatibilities("(?x) a b"), vec!["flag `x` is not supported"]);
+    assert_eq!(re2_incompatibilities("[a-z&&[^aeiou]]"), vec![
+        "character class operator `&&` is not supported",
+        "nested character classes are not supported",
+    ]);
+    assert_eq!(re2_incompatibilities("a{1001}"), vec!["repetition count 1001 is above 1000"]);
+    assert!(re2_incompatibilities("(?i)[[:alpha:]]+\\d{2,1000}").is_empty());
+    assert_eq!(needless("foo|.*"), Some(Needless::MatchesAll));
+    assert_eq!(needless("(.+)"), Some(Needless::NonEmpty));
+    assert_eq!(needless("^foo.*.*$"), Some(Needless::Redundant {
+        parts: vec!["a leading `^`", "a trailing `$`", "a repeated `.*`"],
+        pattern: "foo.*".to_string(),
+    }));
+    assert_eq!(needless(".*foo.*"), None);
+    assert_eq!(needless("foo\\$"), None);
+}
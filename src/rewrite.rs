@@ -0,0 +1,383 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use promql_parser::util::parse_duration;
+use serde::Deserialize;
+use crate::edits::{apply, Edit};
+use crate::guard::LabelGuard;
+use crate::lexemes::lex;
+use crate::literals::analyze;
+use crate::lookback::offset_millis;
+use crate::printer::{duration, plain_name};
+use crate::visit::{children_mut, selectors_mut};
+
+/// Adds `injected` to every selector of `expr`, replacing the matchers it
+/// has on the same labels, as prom-label-proxy enforces a tenant's labels.
+/// Returns the warnings of `guard`, which may refuse injecting reserved labels.
+pub fn inject_matchers(expr: &mut Expr, injected: &[Matcher], guard: &LabelGuard) -> Result<Vec<String>, String> {
+    if injected.iter().any(|matcher| matcher.name == METRIC_NAME) {
+        return Err("cannot inject a metric name matcher".to_string());
+    }
+    let warnings = guard.check("inject", injected.iter().map(|matcher| matcher.name.as_str()))?;
+    selectors_mut(expr, &mut |vs| {
+        vs.matchers.matchers.retain(|matcher| !injected.iter().any(|injected| injected.name == matcher.name));
+        vs.matchers.matchers.extend(injected.iter().cloned());
+    });
+    Ok(warnings)
+}
+
+
+/// Function `name`, if it can be called with a single vector or matrix.
+fn single_argument(name: &str) -> Option<Function> {
+    ["x", "x[1m]"].iter().find_map(|arg| match parse(&format!("{}({})", name, arg)) {
+        Ok(Expr::Call(Call { func, .. })) => Some(func),
+        _ => None,
+    })
+}
+
+fn wrap(expr: &mut Expr, func: &Function) {
+    let wrapped = match expr {
+        Expr::VectorSelector(_) => func.arg_types[0] == ValueType::Vector,
+        Expr::MatrixSelector(_) => func.arg_types[0] == ValueType::Matrix,
+        _ => false,
+    };
+    if wrapped {
+        let arg = std::mem::replace(expr, Expr::NumberLiteral(NumberLiteral { val: 0.0 }));
+        *expr = Expr::Call(Call { func: func.clone(), args: FunctionArgs { args: vec![Box::new(arg)] } });
+        return;
+    }
+    if let Expr::Call(call) = expr {
+        // matrix selectors are already consumed by the function they are passed to
+        let same = call.func.name == func.name;
+        for arg in call.args.args.iter_mut() {
+            match arg.as_ref() {
+                Expr::MatrixSelector(_) => {}
+                Expr::VectorSelector(_) if same => {}
+                _ => wrap(arg, func),
+            }
+        }
+        return;
+    }
+    for child in children_mut(expr) {
+        wrap(child, func);
+    }
+}
+
+/// Passes every selector of `expr` the function `name` takes, vector or
+/// matrix, to it, as in `foo[5m]` to `rate(foo[5m])`. Matrix selectors that
+/// are already arguments, and vector selectors already passed to `name`, are
+/// left alone.
+pub fn wrap_selectors(expr: &mut Expr, name: &str) -> Result<(), String> {
+    let func = single_argument(name)
+        .ok_or_else(|| format!("{}() does not take a single vector or matrix argument", name))?;
+    wrap(expr, &func);
+    Ok(())
+}
+
+/// How `rewrite_ranges` changes the ranges of a query.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RangeRewrite {
+    /// Only the ranges of this duration, however spelled: `5m` matches `300s`.
+    pub from: Option<String>,
+    /// Text to write instead, e.g. Grafana's `$__rate_interval`.
+    pub to: Option<String>,
+    /// What to multiply the ranges by, unless `to` is given.
+    pub factor: Option<f64>,
+}
+
+/// Rewrites the range of every matrix selector and subquery of `query` as
+/// `rewrite` says, leaving the rest of its text as it is.
+pub fn rewrite_ranges(query: &str, rewrite: &RangeRewrite) -> Result<String, String> {
+    parse(query)?;
+    let from = rewrite.from.as_deref().map(parse_duration).transpose()?;
+    let replace = |range: Duration| -> Result<String, String> {
+        match (&rewrite.to, rewrite.factor) {
+            (Some(to), None) => Ok(to.clone()),
+            (None, Some(factor)) if factor.is_finite() && factor > 0.0 => {
+                let millis = (range.as_millis() as f64 * factor).round();
+                if millis < 1.0 {
+                    return Err(format!("scaling {} by {} leaves no range", duration(&range), factor));
+                }
+                Ok(duration(&Duration::from_millis(millis as u64)))
+            }
+            (None, Some(factor)) => Err(format!("invalid factor {}", factor)),
+            _ => Err("give either `to` or `factor`".to_string()),
+        }
+    };
+    let lexemes = lex(query)?;
+    let mut edits = vec![];
+    // a range is the duration right after `[`, a subquery step follows `:`
+    for pair in lexemes.windows(2) {
+        if let [bracket, range] = pair {
+            if bracket.id != T_LEFT_BRACKET || range.id != T_DURATION {
+                continue;
+            }
+            let value = parse_duration(range.text(query))?;
+            if from.is_none_or(|from| from == value) {
+                edits.push(Edit { start: range.start, end: range.end, text: replace(value)? });
+            }
+        }
+    }
+    Ok(apply(query, &edits))
+}
+
+/// How `rewrite_offsets` changes offsets: `shift` adds to them, as in `1w`
+/// or `-1h`, `set` replaces them and `remove` drops them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct OffsetRewrite {
+    pub shift: Option<String>,
+    pub set: Option<String>,
+    pub remove: bool,
+}
+
+/// Milliseconds of a duration with an optional leading `-`.
+fn signed_millis(text: &str) -> Result<i128, String> {
+    match text.trim().strip_prefix('-') {
+        Some(text) => Ok(-(parse_duration(text)?.as_millis() as i128)),
+        None => Ok(parse_duration(text.trim())?.as_millis() as i128),
+    }
+}
+
+fn to_offset(millis: i128) -> Option<Offset> {
+    let dur = Duration::from_millis(millis.unsigned_abs() as u64);
+    match millis {
+        0 => None,
+        millis if millis < 0 => Some(Offset::Neg(dur)),
+        _ => Some(Offset::Pos(dur)),
+    }
+}
+
+/// Calls `f` on the offsets of the selectors and subqueries of `expr` that
+/// are not inside a subquery, which shift everything inside them.
+fn outer_offsets(expr: &mut Expr, f: &mut impl FnMut(&mut Option<Offset>)) {
+    match expr {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => f(&mut vs.offset),
+        Expr::Subquery(SubqueryExpr { offset, .. }) => f(offset),
+        _ => children_mut(expr).into_iter().for_each(|child| outer_offsets(child, f)),
+    }
+}
+
+/// Changes the offsets of `expr` as `rewrite` says, e.g. to compare a query
+/// with the same one a week earlier. Selectors inside subqueries are left
+/// alone, as they are evaluated relative to the subquery.
+pub fn rewrite_offsets(expr: &mut Expr, rewrite: &OffsetRewrite) -> Result<(), String> {
+    let change: Box<dyn Fn(i128) -> i128> = match (&rewrite.shift, &rewrite.set, rewrite.remove) {
+        (Some(shift), None, false) => {
+            let shift = signed_millis(shift)?;
+            Box::new(move |millis| millis + shift)
+        }
+        (None, Some(set), false) => {
+            let set = signed_millis(set)?;
+            Box::new(move |_| set)
+        }
+        (None, None, true) => Box::new(|_| 0),
+        _ => return Err("give one of `shift`, `set` or `remove`".to_string()),
+    };
+    outer_offsets(expr, &mut |offset| *offset = to_offset(change(offset_millis(offset))));
+    Ok(())
+}
+
+/// `matcher` of the metric name with `from` as `to`, if it names it.
+/// Regexes are only rewritten when they list plain names, as in `a|b`.
+fn rename_matcher(matcher: &Matcher, from: &str, to: &str) -> Option<Matcher> {
+    match matcher.op {
+        MatchOp::Equal | MatchOp::NotEqual if matcher.value == from => Some(Matcher { value: to.to_string(), ..matcher.clone() }),
+        MatchOp::Re(_) | MatchOp::NotRe(_) => {
+            let alternatives = analyze(&matcher.value)?.alternatives?;
+            let plain = plain_name(to, true) && alternatives.iter().all(|name| plain_name(name, true));
+            if !plain || !alternatives.iter().any(|name| name == from) {
+                return None;
+            }
+            let renamed: Vec<&str> = alternatives.iter().map(|name| if name == from { to } else { name.as_str() }).collect();
+            let id = if let MatchOp::Re(_) = matcher.op { T_EQL_REGEX } else { T_NEQ_REGEX };
+            Matcher::new_matcher(id, matcher.name.clone(), renamed.join("|")).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Renames metric `from` to `to` in every selector of `expr`, by name or
+/// by `__name__` matcher.
+///
+/// Matchers on reserved labels other than `__name__`, such as `le`, select
+/// series of the old metric; `guard` may refuse moving them to the new one.
+pub fn rename_metric(expr: &mut Expr, from: &str, to: &str, guard: &LabelGuard) -> Result<Vec<String>, String> {
+    let mut renamed = expr.clone();
+    let mut labels = BTreeSet::new();
+    selectors_mut(&mut renamed, &mut |vs| {
+        let mut touched = false;
+        if vs.name.as_deref() == Some(from) {
+            vs.name = Some(to.to_string());
+            touched = true;
+        }
+        for matcher in vs.matchers.matchers.iter_mut().filter(|matcher| matcher.name == METRIC_NAME) {
+            if let Some(renamed) = rename_matcher(matcher, from, to) {
+                *matcher = renamed;
+                touched = true;
+            }
+        }
+        if touched {
+            labels.extend(vs.matchers.matchers.iter().map(|matcher| matcher.name.clone()).filter(|name| name != METRIC_NAME));
+        }
+    });
+    let warnings = guard.check("rename the metric selected by", labels.iter().map(String::as_str))?;
+    *expr = renamed;
+    Ok(warnings)
+}
+
+fn rename_in(labels: &mut Labels, from: &str, to: &str) {
+    for label in labels.labels.iter_mut().filter(|label| *label == from) {
+        *label = to.to_string();
+    }
+}
+
+fn rename_in_modifier(modifier: &mut Option<LabelModifier>, from: &str, to: &str) {
+    if let Some(LabelModifier::Include(labels) | LabelModifier::Exclude(labels)) = modifier {
+        rename_in(labels, from, to);
+    }
+}
+
+/// Indexes of the arguments of `function` that name labels.
+fn label_arguments(function: &str, count: usize) -> Vec<usize> {
+    match function {
+        "label_replace" => vec![1, 3],
+        "label_join" => std::iter::once(1).chain(3..count).collect(),
+        "sort_by_label" | "sort_by_label_desc" => (1..count).collect(),
+        _ => vec![],
+    }
+}
+
+fn rename_string(expr: &mut Expr, from: &str, to: &str) {
+    if let Expr::StringLiteral(StringLiteral { val }) = expr {
+        if val == from {
+            *val = to.to_string();
+        }
+    }
+}
+
+/// Renames label `from` to `to` throughout `expr`: in matchers, grouping and
+/// vector matching clauses, `count_values` and the label arguments of
+/// `label_replace` and `label_join`.
+pub fn rename_label(expr: &mut Expr, from: &str, to: &str) {
+    match expr {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => {
+            for matcher in vs.matchers.matchers.iter_mut().filter(|matcher| matcher.name == from) {
+                matcher.name = to.to_string();
+            }
+        }
+        Expr::Aggregate(AggregateExpr { op, param, modifier, .. }) => {
+            rename_in_modifier(modifier, from, to);
+            if let (T_COUNT_VALUES, Some(param)) = (op.id(), param) {
+                rename_string(param, from, to);
+            }
+        }
+        Expr::Binary(BinaryExpr { modifier: Some(modifier), .. }) => {
+            rename_in_modifier(&mut modifier.matching, from, to);
+            if let VectorMatchCardinality::ManyToOne(labels) | VectorMatchCardinality::OneToMany(labels) = &mut modifier.card {
+                rename_in(labels, from, to);
+            }
+        }
+        Expr::Call(Call { func, args }) => {
+            for i in label_arguments(func.name, args.args.len()) {
+                if let Some(arg) = args.args.get_mut(i) {
+                    rename_string(arg, from, to);
+                }
+            }
+        }
+        _ => {}
+    }
+    for child in children_mut(expr) {
+        rename_label(child, from, to);
+    }
+}
+
+#[test]
+fn check_rewrite() {
+    use crate::guard::{GuardConfig, GuardMode};
+    use crate::printer::to_promql;
+    let injected = |selector: &str| match parse(selector).unwrap() {
+        Expr::VectorSelector(vs) => vs.matchers.matchers,
+        _ => unreachable!(),
+    };
+    let payloads = vec![
+        ("up", "up{tenant=\"a\"}"),
+        ("sum(rate(x{tenant=~\".*\", b=\"c\"}[5m])) / y offset 1h", "sum(rate(x{b=\"c\", tenant=\"a\"}[5m])) / y{tenant=\"a\"} offset 1h"),
+        ("vector(1)", "vector(1)"),
+    ];
+    for (query, expected) in payloads {
+        let mut expr = parse(query).unwrap();
+        inject_matchers(&mut expr, &injected("{tenant=\"a\"}"), &LabelGuard::default()).unwrap();
+        assert_eq!(to_promql(&expr), expected);
+    }
+    let mut expr = parse("{a=\"b\"}").unwrap();
+    inject_matchers(&mut expr, &injected("{a!=\"c\", d=~\"e|f\"}"), &LabelGuard::default()).unwrap();
+    assert_eq!(to_promql(&expr), "{a!=\"c\", d=~\"e|f\"}");
+    assert!(inject_matchers(&mut expr, &injected("{__name__=\"x\"}"), &LabelGuard::default()).is_err());
+    assert!(inject_matchers(&mut expr, &injected("{le=\"1\"}"), &LabelGuard::default()).is_err());
+    assert_eq!(to_promql(&expr), "{a!=\"c\", d=~\"e|f\"}");
+    let warn = LabelGuard::new(&GuardConfig { reserved: vec!["job".to_string()], mode: GuardMode::Warn });
+    assert_eq!(inject_matchers(&mut expr, &injected("{job=\"x\"}"), &warn).map(|warnings| warnings.len()), Ok(1));
+    assert!(inject_matchers(&mut expr, &injected("{__name__=\"x\"}"), &warn).is_err());
+    let wrapped = |query: &str, name: &str| {
+        let mut expr = parse(query).unwrap();
+        wrap_selectors(&mut expr, name).map(|_| to_promql(&expr))
+    };
+    assert_eq!(wrapped("foo[5m]", "rate"), Ok("rate(foo[5m])".to_string()));
+    assert_eq!(wrapped("sum(irate(a[5m])) / b", "rate"), Ok("sum(irate(a[5m])) / b".to_string()));
+    assert_eq!(wrapped("abs(a) + b offset 1m + sum(c)", "abs"), Ok("abs(a) + abs(b offset 1m) + sum(abs(c))".to_string()));
+    assert_eq!(wrapped("round(a)", "abs"), Ok("round(abs(a))".to_string()));
+    assert!(wrapped("a", "clamp_max").is_err());
+    assert!(wrapped("a", "nope").is_err());
+    let interval = RangeRewrite { from: Some("300s".to_string()), to: Some("$__rate_interval".to_string()), factor: None };
+    assert_eq!(
+        rewrite_ranges("rate(a[5m])  /  rate(b[1h]) + max_over_time(c[5m:1m] offset 5m)", &interval),
+        Ok("rate(a[$__rate_interval])  /  rate(b[1h]) + max_over_time(c[$__rate_interval:1m] offset 5m)".to_string()),
+    );
+    let doubled = RangeRewrite { factor: Some(2.0), ..RangeRewrite::default() };
+    assert_eq!(rewrite_ranges("rate(a[90s]) + max_over_time(d[1h:])", &doubled), Ok("rate(a[3m]) + max_over_time(d[2h:])".to_string()));
+    assert!(rewrite_ranges("a[5m]", &RangeRewrite::default()).is_err());
+    assert!(rewrite_ranges("a[1ms]", &RangeRewrite { factor: Some(0.1), ..RangeRewrite::default() }).is_err());
+    let offsets = |query: &str, rewrite: OffsetRewrite| {
+        let mut expr = parse(query).unwrap();
+        rewrite_offsets(&mut expr, &rewrite).map(|_| to_promql(&expr))
+    };
+    let week_ago = OffsetRewrite { shift: Some("1w".to_string()), ..OffsetRewrite::default() };
+    assert_eq!(
+        offsets("rate(a[5m]) / b offset 1d + max_over_time(c offset 1h[1h:])", week_ago),
+        Ok("rate(a[5m] offset 1w) / b offset 8d + max_over_time(c offset 1h[1h:] offset 1w)".to_string()),
+    );
+    let back = OffsetRewrite { shift: Some("-1h".to_string()), ..OffsetRewrite::default() };
+    assert_eq!(offsets("a offset 1h + b", back), Ok("a + b offset -1h".to_string()));
+    let set = OffsetRewrite { set: Some("5m".to_string()), ..OffsetRewrite::default() };
+    assert_eq!(offsets("a offset 1h + b", set), Ok("a offset 5m + b offset 5m".to_string()));
+    let removed = OffsetRewrite { remove: true, ..OffsetRewrite::default() };
+    assert_eq!(offsets("a offset -1h", removed), Ok("a".to_string()));
+    assert!(offsets("a", OffsetRewrite::default()).is_err());
+    let renamed = |query: &str| {
+        let mut expr = parse(query).unwrap();
+        rename_metric(&mut expr, "old_total", "new_total", &LabelGuard::default()).map(|_| to_promql(&expr))
+    };
+    assert_eq!(renamed("sum(rate(old_total{a=\"b\"}[5m])) / old"), Ok("sum(rate(new_total{a=\"b\"}[5m])) / old".to_string()));
+    assert_eq!(renamed("{__name__=\"old_total\"} or {__name__!=\"old_total\", c=\"d\"}"), Ok("{__name__=\"new_total\"} or {__name__!=\"new_total\", c=\"d\"}".to_string()));
+    assert_eq!(renamed("{__name__=~\"old_total|other\"}"), Ok("{__name__=~\"new_total|other\"}".to_string()));
+    assert_eq!(renamed("{__name__=~\"old_.*\"}"), Ok("{__name__=~\"old_.*\"}".to_string()));
+    assert_eq!(renamed("old_total{le=\"1\"} / other_total{le=\"1\"}"), Err("would rename the metric selected by reserved label(s) le".to_string()));
+    assert_eq!(renamed("other_total{le=\"1\"}"), Ok("other_total{le=\"1\"}".to_string()));
+    let relabeled = |query: &str| {
+        let mut expr = parse(query).unwrap();
+        rename_label(&mut expr, "pod", "pod_name");
+        to_promql(&expr)
+    };
+    assert_eq!(
+        relabeled("sum by (pod, job) (x{pod=~\"a.*\"}) * on (pod) count without (pod) (y) / ignoring (node) group_left (pod) z"),
+        "sum by (pod_name, job) (x{pod_name=~\"a.*\"}) * on (pod_name) count without (pod_name) (y) / ignoring (node) group_left (pod_name) z",
+    );
+    assert_eq!(
+        relabeled("label_replace(count_values(\"pod\", x), \"pod\", \"$1\", \"node\", \"(.*)\")"),
+        "label_replace(count_values(\"pod_name\", x), \"pod_name\", \"$1\", \"node\", \"(.*)\")",
+    );
+}
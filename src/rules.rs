@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use promql_parser::parser;
+use promql_parser::parser::Expr;
+use promql_parser::util::parse_duration;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::ToSerde;
+
+/// A Prometheus rules file, as `{ groups: [...] }` or loaded from YAML by
+/// `rules_yaml::parse_rules`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RuleFile {
+    #[serde(default)]
+    pub groups: Vec<RuleGroup>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RuleGroup {
+    pub name: String,
+    #[serde(default)]
+    pub interval: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Line and column it starts at in its YAML file, from 1.
+    #[serde(skip)]
+    pub position: Option<(usize, usize)>,
+}
+
+/// Prometheus' default `evaluation_interval`, used by groups without an interval.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+impl RuleGroup {
+    pub fn interval(&self) -> Result<Duration, String> {
+        match &self.interval {
+            Some(interval) => parse_duration(interval),
+            None => Ok(DEFAULT_INTERVAL),
+        }
+    }
+}
+
+/// A recording (`record`) or alerting (`alert`) rule.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub record: Option<String>,
+    #[serde(default)]
+    pub alert: Option<String>,
+    pub expr: String,
+    #[serde(default, rename = "for")]
+    pub for_: Option<String>,
+    #[serde(default)]
+    pub keep_firing_for: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// Line and column it starts at in its YAML file, from 1.
+    #[serde(skip)]
+    pub position: Option<(usize, usize)>,
+}
+
+impl Rule {
+    pub fn kind(&self) -> &'static str {
+        if self.record.is_some() { "record" } else { "alert" }
+    }
+
+    pub fn name(&self) -> &str {
+        self.record.as_deref().or(self.alert.as_deref()).unwrap_or_default()
+    }
+
+    /// Identifies the rule, numbered `id`, of `group` in reports, with its
+    /// position in its YAML file if known.
+    pub fn summary(&self, id: usize, group: &RuleGroup) -> Value {
+        let mut summary = json!({
+            "id": id,
+            "group": group.name,
+            "name": self.name(),
+            "kind": self.kind(),
+        });
+        if let Some((line, column)) = self.position {
+            summary["line"] = json!(line);
+            summary["column"] = json!(column);
+        }
+        summary
+    }
+}
+
+/// A rule of a file, numbered in file order, with its parsed expression.
+pub struct ParsedRule<'a> {
+    pub id: usize,
+    pub group: &'a RuleGroup,
+    pub rule: &'a Rule,
+    pub expr: Result<Expr, String>,
+}
+
+impl ParsedRule<'_> {
+    /// Identifies the rule in reports.
+    pub fn summary(&self) -> Value {
+        self.rule.summary(self.id, self.group)
+    }
+}
+
+/// A rule expression that does not parse.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleError {
+    pub rule: usize,
+    pub message: String,
+}
+
+impl ToSerde for RuleError {
+    fn to_serde(&self) -> Value {
+        json!({
+            "rule": self.rule,
+            "message": self.message,
+        })
+    }
+}
+
+impl RuleFile {
+    /// The rules of every group, in file order, with their group.
+    pub fn rules(&self) -> impl Iterator<Item = (&RuleGroup, &Rule)> {
+        self.groups.iter().flat_map(|group| group.rules.iter().map(move |rule| (group, rule)))
+    }
+
+    pub fn parsed(&self) -> Vec<ParsedRule<'_>> {
+        self.rules()
+            .enumerate()
+            .map(|(id, (group, rule))| ParsedRule { id, group, rule, expr: parser::parse(&rule.expr) })
+            .collect()
+    }
+}
+
+pub fn errors(rules: &[ParsedRule]) -> Vec<RuleError> {
+    rules
+        .iter()
+        .filter_map(|r| r.expr.as_ref().err().map(|message| RuleError { rule: r.id, message: message.clone() }))
+        .collect()
+}
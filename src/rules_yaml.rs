@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use promql_parser::util::parse_duration;
+use serde_json::{json, Value};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle};
+use crate::errors::{line_column, located, relocated, ParseError};
+use crate::options::SerializeOptions;
+use crate::rules::{Rule, RuleFile, RuleGroup};
+use crate::ToSerde;
+
+/// A YAML node with the byte offset it starts at.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    /// `None` for null, `~` or an empty value.
+    Scalar(Option<String>, usize),
+    Sequence(Vec<Node>, usize),
+    Mapping(Vec<(Node, Node)>, usize),
+}
+
+impl Node {
+    fn start(&self) -> usize {
+        match self {
+            Node::Scalar(_, start) | Node::Sequence(_, start) | Node::Mapping(_, start) => *start,
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Node::Scalar(text, _) => text.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Node::Scalar(None, _) => "null",
+            Node::Scalar(..) => "string",
+            Node::Sequence(..) => "list",
+            Node::Mapping(..) => "map",
+        }
+    }
+}
+
+/// Builds `Node`s from parser events, resolving aliases.
+struct Builder<'a> {
+    yaml: &'a str,
+    /// Nodes being filled in, with the anchor they are to be stored under.
+    stack: Vec<(Node, usize)>,
+    anchors: HashMap<usize, Node>,
+    documents: Vec<Node>,
+}
+
+impl Builder<'_> {
+    /// Byte offset of `mark`, which counts characters.
+    fn offset(&self, mark: Marker) -> usize {
+        self.yaml.char_indices().nth(mark.index()).map_or(self.yaml.len(), |(offset, _)| offset)
+    }
+
+    fn push(&mut self, node: Node, anchor: usize) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, node.clone());
+        }
+        match self.stack.last_mut() {
+            Some((Node::Sequence(items, _), _)) => items.push(node),
+            Some((Node::Mapping(entries, _), _)) => match entries.last_mut() {
+                Some((_, value @ Node::Scalar(None, usize::MAX))) => *value = node,
+                _ => entries.push((node, Node::Scalar(None, usize::MAX))),
+            },
+            _ => self.documents.push(node),
+        }
+    }
+}
+
+impl MarkedEventReceiver for Builder<'_> {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let start = self.offset(mark);
+        match event {
+            Event::Scalar(value, style, anchor, _) => {
+                let null = style == TScalarStyle::Plain && matches!(value.as_str(), "~" | "null" | "Null" | "NULL" | "");
+                self.push(Node::Scalar(Some(value).filter(|_| !null), start), anchor);
+            }
+            Event::SequenceStart(anchor) => self.stack.push((Node::Sequence(vec![], start), anchor)),
+            Event::MappingStart(anchor) => self.stack.push((Node::Mapping(vec![], start), anchor)),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((node, anchor)) = self.stack.pop() {
+                    self.push(node, anchor);
+                }
+            }
+            Event::Alias(anchor) => {
+                let node = self.anchors.get(&anchor).cloned().unwrap_or(Node::Scalar(None, start));
+                self.push(node, 0);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses `yaml` into its first document, `None` if it is empty.
+fn load(yaml: &str) -> Result<Option<Node>, ParseError> {
+    let mut builder = Builder { yaml, stack: vec![], anchors: HashMap::new(), documents: vec![] };
+    Parser::new(yaml.chars()).load(&mut builder, false).map_err(|err| {
+        let start = builder.offset(*err.marker());
+        located(yaml, format!("invalid YAML: {}", err), "invalid-yaml", start, start)
+    })?;
+    Ok(builder.documents.into_iter().next())
+}
+
+/// The byte offset in `yaml` of byte `offset` of `text`, the value of a
+/// scalar starting at `start`, found by the occurrence of the word around it.
+fn locate(yaml: &str, start: usize, text: &str, offset: usize) -> usize {
+    let offset = text[..offset].trim_end().len();
+    let word_start = text[..offset].rfind(char::is_whitespace).map_or(0, |space| space + 1);
+    let word_end = text[word_start..].find(char::is_whitespace).map_or(text.len(), |space| word_start + space);
+    let word = &text[word_start..word_end];
+    if word.is_empty() {
+        return start;
+    }
+    let occurrence = text[..word_start].match_indices(word).count();
+    yaml[start..]
+        .match_indices(word)
+        .nth(occurrence)
+        .map_or(start, |(found, _)| start + found + offset - word_start)
+}
+
+/// A problem of a rules file, located in it, of the rule `rule` if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileError {
+    pub rule: Option<usize>,
+    pub error: ParseError,
+}
+
+impl ToSerde for FileError {
+    fn to_serde(&self) -> Value {
+        let mut error = self.error.to_serde();
+        error["rule"] = json!(self.rule);
+        error
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RulesReport {
+    pub file: RuleFile,
+    /// The AST of the expression of every rule in file order, `None` if it
+    /// has none, or the error of the expression, located in it.
+    pub asts: Vec<Result<Option<Value>, ParseError>>,
+    pub errors: Vec<FileError>,
+}
+
+impl ToSerde for RulesReport {
+    fn to_serde(&self) -> Value {
+        let groups: Vec<Value> = self
+            .file
+            .groups
+            .iter()
+            .map(|group| {
+                json!({
+                    "name": group.name,
+                    "interval": group.interval,
+                    "line": group.position.map(|(line, _)| line),
+                    "column": group.position.map(|(_, column)| column),
+                })
+            })
+            .collect();
+        let rules: Vec<Value> = self
+            .file
+            .rules()
+            .zip(&self.asts)
+            .enumerate()
+            .map(|(id, ((group, rule), ast))| {
+                let mut summary = rule.summary(id, group);
+                summary["expr"] = json!(rule.expr);
+                summary["for"] = json!(rule.for_);
+                summary["keep_firing_for"] = json!(rule.keep_firing_for);
+                summary["labels"] = json!(rule.labels);
+                summary["annotations"] = json!(rule.annotations);
+                summary["ast"] = json!(ast.as_ref().ok());
+                summary["error"] = ast.as_ref().err().map_or(Value::Null, ToSerde::to_serde);
+                summary
+            })
+            .collect();
+        json!({
+            "valid": self.errors.is_empty(),
+            "groups": groups,
+            "rules": rules,
+            "errors": self.errors.to_serde(),
+        })
+    }
+}
+
+const GROUP_FIELDS: [&str; 6] = ["name", "interval", "limit", "query_offset", "rules", "labels"];
+const RULE_FIELDS: [&str; 7] = ["record", "alert", "expr", "for", "keep_firing_for", "labels", "annotations"];
+
+struct Checker<'a> {
+    yaml: &'a str,
+    options: &'a SerializeOptions,
+    report: RulesReport,
+}
+
+impl Checker<'_> {
+    /// Line and column of byte `offset` of the file, from 1.
+    fn position(&self, offset: usize) -> (usize, usize) {
+        line_column(self.yaml, offset)
+    }
+
+    fn error(&mut self, rule: Option<usize>, message: String, code: &'static str, start: usize) {
+        let error = located(self.yaml, message, code, start, start);
+        self.report.errors.push(FileError { rule, error });
+    }
+
+    /// The fields of mapping `node`, reporting unknown and repeated ones.
+    fn fields<'n>(&mut self, node: &'n Node, what: &str, known: &[&str], rule: Option<usize>) -> BTreeMap<&'n str, &'n Node> {
+        let mut fields = BTreeMap::new();
+        let entries = match node {
+            Node::Mapping(entries, _) => entries,
+            _ => {
+                self.error(rule, format!("{} must be a map, not a {}", what, node.kind()), "invalid-rules", node.start());
+                return fields;
+            }
+        };
+        for (key, value) in entries {
+            match key.text() {
+                Some(name) if !known.contains(&name) => {
+                    self.error(rule, format!("unknown field `{}` in {}", name, what), "invalid-rules", key.start())
+                }
+                Some(name) if fields.insert(name, value).is_some() => {
+                    self.error(rule, format!("field `{}` repeated in {}", name, what), "invalid-rules", key.start())
+                }
+                Some(_) => {}
+                None => self.error(rule, format!("{} keys must be strings", what), "invalid-rules", key.start()),
+            }
+        }
+        fields
+    }
+
+    fn string(&mut self, node: Option<&&Node>, field: &str, rule: Option<usize>) -> Option<String> {
+        let node = node?;
+        match node {
+            Node::Scalar(text, _) => text.clone(),
+            _ => {
+                self.error(rule, format!("`{}` must be a string, not a {}", field, node.kind()), "invalid-rules", node.start());
+                None
+            }
+        }
+    }
+
+    fn duration(&mut self, node: Option<&&Node>, field: &str, rule: Option<usize>) -> Option<String> {
+        let text = self.string(node, field, rule)?;
+        if let Err(err) = parse_duration(&text) {
+            let start = node.map_or(0, |node| node.start());
+            self.error(rule, format!("invalid `{}` duration `{}`: {}", field, text, err), "invalid-duration", start);
+        }
+        Some(text)
+    }
+
+    fn map(&mut self, node: Option<&&Node>, field: &str, rule: Option<usize>) -> BTreeMap<String, String> {
+        let mut map = BTreeMap::new();
+        let entries = match node {
+            Some(Node::Mapping(entries, _)) => entries,
+            Some(Node::Scalar(None, _)) | None => return map,
+            Some(node) => {
+                self.error(rule, format!("`{}` must be a map, not a {}", field, node.kind()), "invalid-rules", node.start());
+                return map;
+            }
+        };
+        for (key, value) in entries {
+            match (key.text(), value) {
+                (Some(key), Node::Scalar(text, _)) => {
+                    map.insert(key.to_string(), text.clone().unwrap_or_default());
+                }
+                _ => self.error(rule, format!("`{}` must map strings to strings", field), "invalid-rules", value.start()),
+            }
+        }
+        map
+    }
+
+    fn group(&mut self, node: &Node, names: &mut Vec<String>) {
+        let fields = self.fields(node, "rule group", &GROUP_FIELDS, None);
+        let name = self.string(fields.get("name"), "name", None).unwrap_or_default();
+        if name.is_empty() {
+            self.error(None, "rule group has no name".to_string(), "invalid-rules", node.start());
+        } else if names.contains(&name) {
+            self.error(None, format!("rule group `{}` is repeated in the file", name), "invalid-rules", node.start());
+        }
+        names.push(name.clone());
+        let interval = self.string(fields.get("interval"), "interval", None);
+        self.duration(fields.get("query_offset"), "query_offset", None);
+        let position = Some(self.position(node.start()));
+        let group = RuleGroup { name, interval, rules: vec![], position };
+        if let (Err(err), Some(interval)) = (group.interval(), fields.get("interval")) {
+            let message = format!("invalid `interval` duration `{}`: {}", group.interval.as_deref().unwrap_or_default(), err);
+            self.error(None, message, "invalid-duration", interval.start());
+        }
+        self.report.file.groups.push(group);
+        match fields.get("rules") {
+            Some(Node::Sequence(rules, _)) => rules.iter().for_each(|rule| self.rule(rule)),
+            Some(Node::Scalar(None, _)) | None => {}
+            Some(rules) => {
+                let message = format!("`rules` must be a list, not a {}", rules.kind());
+                self.error(None, message, "invalid-rules", rules.start());
+            }
+        }
+    }
+
+    /// Adds the rule `node` to the last group.
+    fn rule(&mut self, node: &Node) {
+        let id = Some(self.report.asts.len());
+        let fields = self.fields(node, "rule", &RULE_FIELDS, id);
+        let record = self.string(fields.get("record"), "record", id);
+        let alert = self.string(fields.get("alert"), "alert", id);
+        if record.is_some() == alert.is_some() {
+            let message = match record.is_some() {
+                true => "rule has both `record` and `alert`",
+                false => "rule has neither `record` nor `alert`",
+            };
+            self.error(id, message.to_string(), "invalid-rules", node.start());
+        }
+        if let Some(name) = record.as_ref().filter(|name| !name.is_empty() && !is_metric_name(name)) {
+            let start = fields.get("record").map_or(node.start(), |record| record.start());
+            self.error(id, format!("invalid recording rule name `{}`", name), "invalid-rules", start);
+        }
+        let for_ = self.duration(fields.get("for"), "for", id);
+        let keep_firing_for = self.duration(fields.get("keep_firing_for"), "keep_firing_for", id);
+        let labels = self.map(fields.get("labels"), "labels", id);
+        let annotations = self.map(fields.get("annotations"), "annotations", id);
+        let expr = self.string(fields.get("expr"), "expr", id).unwrap_or_default();
+        let position = Some(self.position(node.start()));
+        let rule = Rule { record, alert, expr, for_, keep_firing_for, labels, annotations, position };
+        if rule.kind() == "record" {
+            for field in ["for", "keep_firing_for", "annotations"].iter().filter(|field| fields.contains_key(*field)) {
+                let message = format!("recording rule `{}` cannot have `{}`", rule.name(), field);
+                self.error(id, message, "invalid-rules", fields[*field].start());
+            }
+        }
+        let ast = match rule.expr.trim() {
+            "" => {
+                self.error(id, "rule has no `expr`".to_string(), "invalid-rules", node.start());
+                Ok(None)
+            }
+            _ => match crate::parse_serialized(&rule.expr, self.options) {
+                Ok(ast) => Ok(Some(ast)),
+                Err(error) => {
+                    let scalar = fields["expr"].start();
+                    let start = locate(self.yaml, scalar, &rule.expr, error.start);
+                    let end = locate(self.yaml, scalar, &rule.expr, error.end).max(start);
+                    let located = relocated(self.yaml, error.clone(), start, end);
+                    self.report.errors.push(FileError { rule: id, error: located });
+                    Err(error)
+                }
+            },
+        };
+        self.report.asts.push(ast);
+        if let Some(group) = self.report.file.groups.last_mut() {
+            group.rules.push(rule);
+        }
+    }
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Parses and checks a Prometheus rules file, `yaml`, as Prometheus loads
+/// it, into a `RuleFile` with positions, parsing every `expr` into an AST as
+/// `options` say. Errors of the file, including those of expressions, are
+/// located in it.
+pub fn parse_rules(yaml: &str, options: &SerializeOptions) -> RulesReport {
+    let mut checker = Checker { yaml, options, report: RulesReport::default() };
+    match load(yaml) {
+        Ok(Some(root)) => {
+            let fields = checker.fields(&root, "rules file", &["groups"], None);
+            match fields.get("groups") {
+                Some(Node::Sequence(groups, _)) => {
+                    let mut names = vec![];
+                    groups.iter().for_each(|group| checker.group(group, &mut names));
+                }
+                Some(Node::Scalar(None, _)) | None => {}
+                Some(groups) => {
+                    let message = format!("`groups` must be a list, not a {}", groups.kind());
+                    checker.error(None, message, "invalid-rules", groups.start());
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(error) => checker.report.errors.push(FileError { rule: None, error }),
+    }
+    checker.report
+}
+
+/// The rules of `yaml` with their positions, for analyses that tolerate
+/// invalid rules; only YAML that does not load at all fails.
+pub fn rule_file(yaml: &str) -> Result<RuleFile, ParseError> {
+    let report = parse_rules(yaml, &SerializeOptions::default());
+    match report.errors.into_iter().find(|error| error.error.code == "invalid-yaml") {
+        Some(error) => Err(error.error),
+        None => Ok(report.file),
+    }
+}
+
+#[test]
+fn check_parse_rules() {
+    let yaml = "groups:\n  - name: api\n    interval: 30s\n    rules:\n      - record: job:http_requests:rate5m\n        expr: sum by (job) (rate(http_requests_total[5m]))\n      - alert: HighErrors\n        expr: |\n          sum(rate(errors_total[5m]))\n            > on() group_left sum(rate(http_requests_total[5m])\n        for: 10m\n        labels:\n          severity: page\n        annotations:\n          summary: \"{{ $value }} errors\"\n      - record: bad name\n        expr: 'up == '\n        for: 5m\n";
+    let report = parse_rules(yaml, &SerializeOptions::default());
+    assert_eq!(report.file.groups[0].interval.as_deref(), Some("30s"));
+    let rules = &report.file.groups[0].rules;
+    assert_eq!((rules[0].kind(), rules[0].name(), rules[0].position), ("record", "job:http_requests:rate5m", Some((5, 15))));
+    assert_eq!(report.asts[0].as_ref().unwrap().as_ref().unwrap()["@type"], json!("aggregate"));
+    assert_eq!((rules[1].for_.as_deref(), rules[1].labels["severity"].as_str()), (Some("10m"), "page"));
+    assert_eq!(report.asts[1].as_ref().unwrap_err().code, "unclosed-paren");
+    let serialized = report.to_serde();
+    assert_eq!((&serialized["rules"][1]["line"], &serialized["rules"][1]["error"]["code"]), (&json!(7), &json!("unclosed-paren")));
+    let graph = crate::dependencies::dependency_graph(&report.file);
+    assert_eq!((&graph.rules[1]["name"], &graph.rules[1]["line"], graph.errors.len()), (&json!("HighErrors"), &json!(7), 2));
+    let errors: Vec<(Option<usize>, &str, usize, usize)> =
+        report.errors.iter().map(|e| (e.rule, e.error.code, e.error.line, e.error.column)).collect();
+    assert_eq!(errors[0], (Some(1), "unclosed-paren", 10, 64));
+    assert_eq!(errors[1..], [(Some(2), "invalid-rules", 16, 17), (Some(2), "invalid-rules", 18, 14), (Some(2), "invalid-syntax", 17, 21)]);
+    let report = parse_rules("groups:\n  - name: [", &SerializeOptions::default());
+    assert_eq!((report.errors[0].error.code, report.errors[0].error.line), ("invalid-yaml", 2));
+}
@@ -0,0 +1,122 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::errors::line_column;
+use crate::lint::{Diagnostic, Severity, RULES};
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const INFORMATION_URI: &str = "https://github.com/lmangani/rust-promql-parser-js";
+/// Artifact of a query found nowhere in particular, i.e. the query itself.
+const DEFAULT_URI: &str = "query.promql";
+
+/// A query together with where it was found, e.g. a rule file or dashboard.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Source {
+    pub query: String,
+    #[serde(default)]
+    pub uri: Option<String>,
+    /// 1-based line of the query in `uri`.
+    #[serde(default)]
+    pub line: Option<u32>,
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Info => "note",
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    }
+}
+
+fn result(source: &Source, diagnostic: &Diagnostic) -> Value {
+    let mut result = json!({
+        "ruleId": diagnostic.rule,
+        "level": level(diagnostic.severity),
+        "message": { "text": diagnostic.message },
+        "properties": {
+            "query": source.query,
+            "expr": diagnostic.expr,
+        },
+    });
+    if let Some(index) = RULES.iter().position(|rule| rule.id == diagnostic.rule) {
+        result["ruleIndex"] = json!(index);
+    }
+    result["locations"] = json!([{ "physicalLocation": {
+        "artifactLocation": { "uri": source.uri.as_deref().unwrap_or(DEFAULT_URI) },
+    } }]);
+    if let Some(region) = region(source, diagnostic) {
+        result["locations"][0]["physicalLocation"]["region"] = region;
+    }
+    result
+}
+
+/// Where `diagnostic` is in the artifact of `source`: its exact range when
+/// the artifact is the query itself, or its line when the query sits at a
+/// known line of `uri`.
+fn region(source: &Source, diagnostic: &Diagnostic) -> Option<Value> {
+    let (start, end) = diagnostic.span.map_or((0, 0), |span| (span.start, span.end));
+    let (start_line, start_column) = line_column(&source.query, start);
+    match (&source.uri, source.line) {
+        (None, _) => {
+            let mut region = json!({ "startLine": start_line, "startColumn": start_column });
+            if diagnostic.span.is_some() {
+                let (end_line, end_column) = line_column(&source.query, end);
+                region["endLine"] = json!(end_line);
+                region["endColumn"] = json!(end_column);
+            }
+            Some(region)
+        }
+        (Some(_), Some(line)) => Some(json!({ "startLine": line as usize + start_line - 1 })),
+        (Some(_), None) => None,
+    }
+}
+
+/// Serializes diagnostics as a single-run SARIF 2.1.0 log.
+pub fn to_sarif(checked: &[(Source, Vec<Diagnostic>)]) -> Value {
+    let rules: Vec<Value> = RULES
+        .iter()
+        .map(|rule| json!({
+            "id": rule.id,
+            "shortDescription": { "text": rule.description },
+            "defaultConfiguration": { "level": level(rule.severity) },
+        }))
+        .collect();
+    let results: Vec<Value> = checked
+        .iter()
+        .flat_map(|(source, diagnostics)| diagnostics.iter().map(move |d| result(source, d)))
+        .collect();
+    json!({
+        "$schema": SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": INFORMATION_URI,
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+
+#[test]
+fn check_sarif() {
+    let source = Source { query: "up{job=\"a\", job=\"b\"}".to_string(), uri: Some("rules.yml".to_string()), line: Some(7) };
+    let diagnostics = crate::lint::check(&source.query, None);
+    let log = to_sarif(&[(source, diagnostics)]);
+    let result = &log["runs"][0]["results"][0];
+    assert_eq!(result["ruleId"], "always-empty");
+    assert_eq!(result["level"], "error");
+    assert_eq!(log["runs"][0]["tool"]["driver"]["rules"][result["ruleIndex"].as_u64().unwrap() as usize]["id"], "always-empty");
+    assert_eq!(result["locations"][0]["physicalLocation"]["region"]["startLine"], 7);
+
+    let source = Source { query: "sum(\n  up{job=\"a\", job=\"b\"}\n)".to_string(), uri: None, line: None };
+    let diagnostics = crate::lint::check(&source.query, None);
+    let log = to_sarif(&[(source, diagnostics)]);
+    let location = &log["runs"][0]["results"][0]["locations"][0]["physicalLocation"];
+    assert_eq!(location["artifactLocation"]["uri"], "query.promql");
+    assert_eq!(location["region"], json!({ "startLine": 2, "startColumn": 3, "endLine": 2, "endColumn": 23 }));
+}
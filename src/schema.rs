@@ -0,0 +1,249 @@
+use serde_json::{json, Value};
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/$defs/{}", name) })
+}
+
+fn nullable(name: &str) -> Value {
+    json!({ "anyOf": [reference(name), { "type": "null" }] })
+}
+
+/// An object tagged `@type: node_type` with `fields`, all of them required.
+fn tagged(node_type: &str, fields: Value) -> Value {
+    let mut properties = json!({ "@type": { "const": node_type } });
+    let mut required = vec![json!("@type")];
+    for (key, schema) in fields.as_object().into_iter().flatten() {
+        properties[key] = schema.clone();
+        required.push(json!(key));
+    }
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// An AST node: `tagged`, plus its optional byte offsets and source text.
+fn node(node_type: &str, fields: Value) -> Value {
+    let mut node = tagged(node_type, fields);
+    node["properties"]["start"] = json!({ "type": "integer", "minimum": 0, "description": "Byte offset of the node in the query." });
+    node["properties"]["end"] = json!({ "type": "integer", "minimum": 0, "description": "Byte offset just past the node." });
+    if node["properties"].get("text").is_none() {
+        node["properties"]["text"] = json!({ "type": "string", "description": "Source text of the node, with the `text` option." });
+    }
+    node
+}
+
+/// Adds optional `fields` to an object schema.
+fn optional(mut object: Value, fields: Value) -> Value {
+    for (key, schema) in fields.as_object().into_iter().flatten() {
+        object["properties"][key] = schema.clone();
+    }
+    object
+}
+
+/// Node types, in the order of the `@type` union.
+const NODES: [&str; 11] = [
+    "aggregate", "unary", "binary", "paren", "subquery", "number", "string", "vector_selector", "matrix_selector", "call",
+    "extension",
+];
+
+/// JSON Schema (draft 2020-12) of the AST `promql_parse` returns, in any of
+/// its timestamp and duration formats.
+pub fn ast_schema() -> Value {
+    let labels = json!({ "type": "array", "items": { "type": "string" } });
+    let value_type = json!({ "enum": ["vector", "scalar", "matrix", "string"] });
+    let grouped = |card: &str| tagged(card, json!({ "labels": labels }));
+    let defs = json!({
+        "expr": {
+            "oneOf": NODES.iter().map(|name| reference(name)).collect::<Vec<Value>>(),
+        },
+        "duration": {
+            "description": "Seconds (the default), milliseconds or a Prometheus duration string, per the `durations` option.",
+            "oneOf": [{ "type": "number", "minimum": 0 }, { "type": "string" }],
+        },
+        "offset": {
+            "description": "Like `duration`, negative for negative offsets.",
+            "oneOf": [{ "type": "number" }, { "type": "string" }],
+        },
+        "at": {
+            "description": "`start`, `end`, or a timestamp: ISO 8601 text, seconds since the epoch, or milliseconds as a number or string, per the `timestamps` option.",
+            "oneOf": [{ "type": "string" }, { "type": "number" }],
+        },
+        "labels": labels,
+        "label_modifier": {
+            "oneOf": [
+                { "type": "object", "properties": { "include": labels }, "required": ["include"], "additionalProperties": false },
+                { "type": "object", "properties": { "exclude": labels }, "required": ["exclude"], "additionalProperties": false },
+            ],
+        },
+        "matcher": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "op": { "enum": ["=", "!=", "=~", "!~"] },
+                "value": { "type": "string" },
+            },
+            "required": ["name", "op", "value"],
+            "additionalProperties": false,
+        },
+        "card": {
+            "oneOf": [
+                tagged("one-to-one", json!({})),
+                grouped("many-to-one"),
+                grouped("one-to-many"),
+                tagged("many-to-many", json!({})),
+            ],
+        },
+        "bin_modifier": {
+            "type": "object",
+            "properties": {
+                "card": reference("card"),
+                "matching": nullable("label_modifier"),
+                "return_bool": { "type": "boolean" },
+            },
+            "required": ["card", "matching", "return_bool"],
+            "additionalProperties": false,
+        },
+        "function": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "arg_types": { "type": "array", "items": value_type },
+                "variadic": { "type": "boolean" },
+                "return_type": value_type,
+            },
+            "required": ["name", "arg_types", "variadic", "return_type"],
+            "additionalProperties": false,
+        },
+        "duration_expr": {
+            "description": "Duration arithmetic a range, step or offset was computed from, with the `duration_expressions` option.",
+            "oneOf": [
+                reference("duration_literal"),
+                reference("duration_number"),
+                reference("duration_negation"),
+                reference("duration_binary"),
+            ],
+        },
+        "duration_literal": tagged("duration_literal", json!({ "value": reference("duration") })),
+        "duration_number": tagged("duration_number", json!({ "value": { "type": "number", "description": "Seconds." } })),
+        "duration_negation": tagged("duration_negation", json!({ "expr": reference("duration_expr") })),
+        "duration_binary": tagged("duration_binary", json!({
+            "op": { "enum": ["+", "-", "*", "/", "%", "^"] },
+            "lhs": reference("duration_expr"),
+            "rhs": reference("duration_expr"),
+        })),
+        "aggregate": node("aggregate", json!({
+            "op": { "type": "string" },
+            "expr": reference("expr"),
+            "param": nullable("expr"),
+            "modifier": nullable("label_modifier"),
+        })),
+        "unary": node("unary", json!({ "expr": reference("expr") })),
+        "binary": node("binary", json!({
+            "lhs": reference("expr"),
+            "op": { "type": "string" },
+            "rhs": reference("expr"),
+            "modifier": nullable("bin_modifier"),
+            "precedence": { "type": "integer", "minimum": 1, "description": "Binding strength of `op`, higher binds tighter." },
+            "is_right_assoc": { "type": "boolean" },
+        })),
+        "paren": node("paren", json!({ "expr": reference("expr") })),
+        "subquery": optional(node("subquery", json!({
+            "expr": reference("expr"),
+            "offset": nullable("offset"),
+            "at": nullable("at"),
+            "range": reference("duration"),
+            "step": nullable("duration"),
+        })), json!({
+            "range_expr": reference("duration_expr"),
+            "step_expr": reference("duration_expr"),
+            "offset_expr": reference("duration_expr"),
+        })),
+        "number": node("number", json!({
+            "value": {
+                "oneOf": [{ "type": "number" }, { "enum": ["NaN", "Inf", "-Inf"] }],
+                "description": "`\"NaN\"`, `\"Inf\"` and `\"-Inf\"` for NaN and infinities, which JSON lacks.",
+            },
+        })),
+        "string": node("string", json!({ "value": { "type": "string" } })),
+        "vector_selector": optional(node("vector_selector", json!({
+            "name": { "type": ["string", "null"] },
+            "matchers": { "type": "array", "items": reference("matcher") },
+            "offset": nullable("offset"),
+            "at": nullable("at"),
+        })), json!({ "offset_expr": reference("duration_expr") })),
+        "matrix_selector": optional(node("matrix_selector", json!({
+            "vector": reference("vector_selector"),
+            "range": reference("duration"),
+        })), json!({ "range_expr": reference("duration_expr") })),
+        "call": node("call", json!({
+            "function": reference("function"),
+            "args": { "type": "array", "items": reference("expr") },
+        })),
+        "extension": node("extension", json!({
+            "name": { "type": "string" },
+            "value_type": value_type,
+            "children": { "type": "array", "items": reference("expr") },
+            "text": { "type": "string", "description": "Debug representation of the extension." },
+        })),
+    });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "PromQL AST",
+        "$ref": "#/$defs/expr",
+        "$defs": defs,
+    })
+}
+
+
+#[test]
+fn check_ast_schema() {
+    use crate::options::{with_options, DurationFormat, SerializeOptions};
+    use crate::timestamps::TimestampFormat;
+    let schema = ast_schema();
+    let defs = &schema["$defs"];
+    // every `@type` object must have all and only the fields of its definition
+    fn check(value: &Value, defs: &Value) {
+        match value {
+            Value::Object(object) => {
+                if let Some(node_type) = object.get("@type").and_then(Value::as_str) {
+                    let def = match &defs[node_type] {
+                        Value::Null => defs["card"]["oneOf"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .find(|card| card["properties"]["@type"]["const"] == node_type)
+                            .unwrap_or_else(|| panic!("no definition for {}", node_type)),
+                        def => def,
+                    };
+                    for key in object.keys() {
+                        assert!(def["properties"].get(key).is_some(), "{}.{}", node_type, key);
+                    }
+                    for key in def["required"].as_array().unwrap() {
+                        assert!(object.contains_key(key.as_str().unwrap()), "{}.{}", node_type, key);
+                    }
+                }
+                object.values().for_each(|value| check(value, defs));
+            }
+            Value::Array(items) => items.iter().for_each(|item| check(item, defs)),
+            _ => {}
+        }
+    }
+    let query = "sum by (a) (rate(x{b=~\"c\"}[5m] offset -1m @ 10)) / on (a) group_left (d) -max_over_time(topk(2, y)[1h:]) \
+        + (label_replace(z, \"e\", \"f\", \"g\", \"h\") > bool NaN)";
+    let expr = promql_parser::parser::parse(query).unwrap();
+    for (timestamps, durations) in [(TimestampFormat::Iso, DurationFormat::Seconds), (TimestampFormat::String, DurationFormat::String)] {
+        let options = SerializeOptions { timestamps, durations, text: true, ..SerializeOptions::default() };
+        check(&with_options(options, || crate::serialize_ast(query, &expr)), defs);
+    }
+    let query = "rate(x[5m + 30s] offset -(1h * 2)) > max_over_time(max_over_time(y[1h:1m])[2 ^ 3 * 1m:-(-30)])";
+    let expr = crate::duration_exprs::parse_computed(query, crate::errors::try_parse).unwrap();
+    let ast = crate::duration_exprs::serialize_computed(query, &expr);
+    assert_eq!(ast["rhs"]["args"][0]["step_expr"]["@type"], "duration_negation");
+    check(&ast, defs);
+    for name in NODES {
+        assert_eq!(defs[name]["properties"]["@type"]["const"], name);
+    }
+}
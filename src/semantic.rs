@@ -0,0 +1,188 @@
+use serde_json::{json, Value};
+use crate::errors::ParseError;
+use crate::experimental;
+use crate::lint::DEPRECATED_FUNCTIONS;
+use crate::tokens::{tokenize, Token};
+use crate::ToSerde;
+
+/// The token types of the legend, in order.
+pub const TOKEN_TYPES: [&str; 9] = ["metric", "label", "function", "keyword", "operator", "string", "number", "duration", "comment"];
+/// The token modifiers of the legend, bit `i` for the `i`th.
+pub const TOKEN_MODIFIERS: [&str; 4] = ["aggregation", "regex", "deprecated", "experimental"];
+
+/// A classified token, with its byte span and its position in UTF-16 code
+/// units, as JS strings and editors count.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticToken {
+    pub token_type: &'static str,
+    pub modifiers: Vec<&'static str>,
+    pub start: usize,
+    pub end: usize,
+    pub from: usize,
+    pub to: usize,
+    /// Zero-based, of `from`.
+    pub line: usize,
+    pub character: usize,
+}
+
+/// The tokens of a query with the legend they are classified by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticTokens(pub Vec<SemanticToken>);
+
+impl SemanticTokens {
+    /// Monaco's relative encoding: per token, the line and start character
+    /// deltas, the length, the token type and the modifier bits.
+    pub fn data(&self) -> Vec<u32> {
+        let (mut line, mut character) = (0, 0);
+        let mut data = vec![];
+        for token in &self.0 {
+            let delta = token.line - line;
+            let start = if delta == 0 { token.character - character } else { token.character };
+            let token_type = TOKEN_TYPES.iter().position(|t| *t == token.token_type).unwrap_or_default();
+            let modifiers = token
+                .modifiers
+                .iter()
+                .filter_map(|m| TOKEN_MODIFIERS.iter().position(|known| known == m))
+                .fold(0, |bits, i| bits | 1 << i);
+            data.extend([delta, start, token.to - token.from, token_type, modifiers].map(|n| n as u32));
+            (line, character) = (token.line, token.character);
+        }
+        data
+    }
+}
+
+impl ToSerde for SemanticTokens {
+    fn to_serde(&self) -> Value {
+        let tokens: Vec<Value> = self
+            .0
+            .iter()
+            .map(|token| {
+                json!({
+                    "type": token.token_type,
+                    "modifiers": token.modifiers,
+                    "start": token.start,
+                    "end": token.end,
+                    "from": token.from,
+                    "to": token.to,
+                })
+            })
+            .collect();
+        json!({
+            "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": TOKEN_MODIFIERS },
+            "data": self.data(),
+            "tokens": tokens,
+        })
+    }
+}
+
+fn is_matcher_op(token: Option<&Token>) -> bool {
+    token.is_some_and(|token| matches!(token.token_type, "eql" | "neq" | "eql_regex" | "neq_regex"))
+}
+
+/// The type and modifiers of `tokens[i]`, given whether it is inside label
+/// matcher braces and inside the label list of a grouping or matching
+/// modifier; `None` for punctuation.
+fn classify(tokens: &[Token], i: usize, braces: bool, label_list: bool) -> Option<(&'static str, Vec<&'static str>)> {
+    let token = &tokens[i];
+    let (prev, next) = (i.checked_sub(1).map(|j| &tokens[j]), tokens.get(i + 1));
+    let function = |name: &str| {
+        let deprecated = DEPRECATED_FUNCTIONS.iter().any(|(old, _)| *old == name);
+        let modifier = match experimental::function(name) {
+            Some(_) => vec!["experimental"],
+            None if deprecated => vec!["deprecated"],
+            None => vec![],
+        };
+        ("function", modifier)
+    };
+    Some(match token.kind {
+        "comment" => ("comment", vec![]),
+        "number" => ("number", vec![]),
+        "duration" => ("duration", vec![]),
+        "string" if braces && is_matcher_op(next) => ("label", vec![]),
+        "string" if braces && !is_matcher_op(prev) => ("metric", vec![]),
+        "string" if label_list => ("label", vec![]),
+        "string" if prev.is_some_and(|prev| matches!(prev.token_type, "eql_regex" | "neq_regex")) => ("string", vec!["regex"]),
+        "string" => ("string", vec![]),
+        "identifier" if braces || label_list => ("label", vec![]),
+        "identifier" if next.is_some_and(|next| next.token_type == "left_paren") => function(&token.text),
+        "identifier" => ("metric", vec![]),
+        "aggregator" if braces || label_list => ("label", vec![]),
+        "aggregator" => ("function", vec!["aggregation"]),
+        "keyword" if braces || label_list => ("label", vec![]),
+        "keyword" => ("keyword", vec![]),
+        "operator" => ("operator", vec![]),
+        // the `=` of label matchers is not among the operator tokens
+        _ if token.token_type == "eql" => ("operator", vec![]),
+        _ => return None,
+    })
+}
+
+/// The semantic tokens of `query`, which like for `tokenize` may be
+/// incomplete: metric, label and function names, keywords, operators,
+/// strings, numbers, durations and comments.
+pub fn semantic_tokens(query: &str) -> Result<SemanticTokens, ParseError> {
+    let tokens = tokenize(query)?;
+    let (mut braces, mut parens) = (0usize, vec![]);
+    let (mut line, mut line_start, mut utf16, mut counted) = (0, 0, 0, 0);
+    let mut out = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        match token.token_type {
+            "left_brace" => braces += 1,
+            "right_brace" => braces = braces.saturating_sub(1),
+            "left_paren" => {
+                let before = i.checked_sub(1).map(|j| tokens[j].token_type);
+                parens.push(matches!(before, Some("by" | "without" | "on" | "ignoring" | "group_left" | "group_right")));
+            }
+            "right_paren" => {
+                parens.pop();
+            }
+            _ => {}
+        }
+        let label_list = braces == 0 && parens.last() == Some(&true);
+        let Some((token_type, modifiers)) = classify(&tokens, i, braces > 0, label_list) else {
+            continue;
+        };
+        for c in query[counted..token.start].chars() {
+            utf16 += c.len_utf16();
+            if c == '\n' {
+                (line, line_start) = (line + 1, utf16);
+            }
+        }
+        counted = token.start;
+        let from = utf16;
+        out.push(SemanticToken {
+            token_type,
+            modifiers,
+            start: token.start,
+            end: token.end,
+            from,
+            to: from + token.text.encode_utf16().count(),
+            line,
+            character: from - line_start,
+        });
+    }
+    Ok(SemanticTokens(out))
+}
+
+
+#[test]
+fn check_semantic_tokens() {
+    let query = "sum by (job) (holt_winters(x{a=~\"é.*\", \"b.c\"=\"d\"}[5m], 0.5, 0.5))\n  > bool up # ü";
+    let tokens = semantic_tokens(query).unwrap();
+    let types: Vec<(&str, &str)> = tokens.0.iter().map(|t| (t.token_type, &query[t.start..t.end])).collect();
+    assert_eq!(types, [
+        ("function", "sum"), ("keyword", "by"), ("label", "job"), ("function", "holt_winters"), ("metric", "x"),
+        ("label", "a"), ("operator", "=~"), ("string", "\"é.*\""), ("label", "\"b.c\""), ("operator", "="),
+        ("string", "\"d\""), ("duration", "5m"), ("number", "0.5"), ("number", "0.5"), ("operator", ">"),
+        ("keyword", "bool"), ("metric", "up"), ("comment", "# ü"),
+    ]);
+    assert_eq!((&tokens.0[0].modifiers, &tokens.0[3].modifiers, &tokens.0[7].modifiers), (&vec!["aggregation"], &vec!["deprecated"], &vec!["regex"]));
+    let dur = &tokens.0[11];
+    assert_eq!((dur.start, dur.from), (51, 50));
+    let comment = tokens.0.last().unwrap();
+    assert_eq!((comment.line, comment.character, comment.to - comment.from), (1, 12, 3));
+    let data = tokens.data();
+    assert_eq!(data[..10], [0, 0, 3, 2, 1, 0, 4, 2, 3, 0]);
+    assert_eq!(data[70..], [1, 2, 1, 4, 0, 0, 2, 4, 3, 0, 0, 5, 2, 0, 0, 0, 3, 3, 8, 0]);
+    assert_eq!(semantic_tokens("rate(x{a=\"b").unwrap().0.last().map(|t| t.token_type), Some("string"));
+}
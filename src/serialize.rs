@@ -0,0 +1,285 @@
+use std::time::{Duration, SystemTime};
+use promql_parser::label::{MatchOp, Matcher, Matchers};
+use promql_parser::parser::*;
+use promql_parser::parser::token::TokenType;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use crate::options::{DurationFormat, SerializeOptions};
+use crate::spans::{annotate, annotate_text, Span, SpanTree};
+use crate::timestamps::{self, TimestampFormat};
+use crate::{printer, ToSerde};
+
+/// An expression that serializes straight into the tree `serialize_ast`
+/// builds, with spans and, if the options ask, texts, but without a `Value`
+/// per node or field first.
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    query: &'a str,
+    expr: &'a Expr,
+    tree: Option<&'a SpanTree>,
+    format: Format,
+}
+
+/// The options a tree of `Node`s is written with, read once at its root.
+#[derive(Clone, Copy)]
+struct Format {
+    text: bool,
+    durations: DurationFormat,
+    timestamps: TimestampFormat,
+}
+
+impl<'a> Node<'a> {
+    /// `expr`, parsed from `query`, with `tree`, its `spans::spans`, to write
+    /// as `options` say.
+    pub fn new(query: &'a str, expr: &'a Expr, tree: Option<&'a SpanTree>, options: &SerializeOptions) -> Self {
+        let format = Format { text: options.text, durations: options.durations, timestamps: options.timestamps };
+        Node { query, expr, tree, format }
+    }
+
+    fn child(&self, i: usize, expr: &'a Expr) -> Node<'a> {
+        Node { expr, tree: self.tree.and_then(|tree| tree.children.get(i)), ..*self }
+    }
+
+    fn duration(&self, dur: &'a Duration, negative: bool) -> Field<'a> {
+        Field::Duration(dur, negative, self.format.durations)
+    }
+
+    fn offset(&self, offset: &'a Option<Offset>) -> Field<'a> {
+        match offset {
+            Some(Offset::Pos(dur)) => self.duration(dur, false),
+            Some(Offset::Neg(dur)) => self.duration(dur, true),
+            None => Field::Null,
+        }
+    }
+
+    fn at(&self, at: &'a Option<AtModifier>) -> Field<'a> {
+        match at {
+            Some(AtModifier::Start) => Field::Str("start"),
+            Some(AtModifier::End) => Field::Str("end"),
+            Some(AtModifier::At(time)) => Field::Time(time, self.format.timestamps),
+            None => Field::Null,
+        }
+    }
+
+    fn selector(&self, vs: &'a VectorSelector) -> [(&'static str, Field<'a>); 5] {
+        [
+            ("@type", Field::Str("vector_selector")),
+            ("at", self.at(&vs.at)),
+            ("matchers", Field::Matchers(&vs.matchers)),
+            ("name", vs.name.as_deref().map_or(Field::Null, Field::Str)),
+            ("offset", self.offset(&vs.offset)),
+        ]
+    }
+
+    /// Writes `fields`, sorted by key, and the `span` they were parsed from,
+    /// in the key order of a `serde_json` object.
+    fn object<S: Serializer>(&self, fields: &[(&str, Field)], span: Option<Span>, serializer: S) -> Result<S::Ok, S::Error> {
+        let text = span.and_then(|Span { start, end }| self.query.get(start..end)).filter(|_| self.format.text);
+        let spanned = span.map(|Span { start, end }| {
+            [("end", Field::Count(end)), ("start", Field::Count(start)), ("text", Field::Str(text.unwrap_or_default()))]
+        });
+        let spanned = match &spanned {
+            Some(spanned) if text.is_some() => &spanned[..],
+            Some(spanned) => &spanned[..2],
+            None => &[],
+        };
+        let mut spanned = spanned.iter().peekable();
+        let mut map = serializer.serialize_map(Some(fields.len() + spanned.len()))?;
+        for (key, field) in fields {
+            while let Some((span_key, span_field)) = spanned.next_if(|(span_key, _)| span_key < key) {
+                map.serialize_entry(span_key, span_field)?;
+            }
+            map.serialize_entry(key, field)?;
+        }
+        for (key, field) in spanned {
+            map.serialize_entry(key, field)?;
+        }
+        map.end()
+    }
+}
+
+/// A field of a node, written as its `ToSerde` value would be.
+enum Field<'a> {
+    Node(Node<'a>),
+    /// The arguments of the call `Node`.
+    Args(Node<'a>, &'a [Box<Expr>]),
+    Selector(Node<'a>, &'a VectorSelector, Option<Span>),
+    Null,
+    Str(&'a str),
+    Token(TokenType),
+    Number(f64),
+    Count(usize),
+    Bool(bool),
+    Duration(&'a Duration, bool, DurationFormat),
+    Time(&'a SystemTime, TimestampFormat),
+    Labels(&'a [String]),
+    Matchers(&'a Matchers),
+    Matcher(&'a Matcher),
+    Modifier(&'a Option<LabelModifier>),
+    BinModifier(&'a Option<BinModifier>),
+    Card(&'a VectorMatchCardinality),
+    Function(&'a Function),
+    Types(&'a [ValueType]),
+}
+
+/// Writes `fields`, sorted by key, as an object.
+fn entries<S: Serializer>(fields: &[(&str, Field)], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(fields.len()))?;
+    for (key, field) in fields {
+        map.serialize_entry(key, field)?;
+    }
+    map.end()
+}
+
+fn match_op(op: &MatchOp) -> &'static str {
+    match op {
+        MatchOp::Equal => "=",
+        MatchOp::NotEqual => "!=",
+        MatchOp::Re(_) => "=~",
+        MatchOp::NotRe(_) => "!~",
+    }
+}
+
+fn value_type(value_type: &ValueType) -> &'static str {
+    match value_type {
+        ValueType::Vector => "vector",
+        ValueType::Scalar => "scalar",
+        ValueType::Matrix => "matrix",
+        ValueType::String => "string",
+    }
+}
+
+impl Serialize for Field<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Field::Node(node) => node.serialize(serializer),
+            Field::Args(node, args) => serializer.collect_seq(args.iter().enumerate().map(|(i, arg)| node.child(i, arg))),
+            Field::Selector(node, vs, span) => node.object(&node.selector(vs), span, serializer),
+            Field::Null => serializer.serialize_unit(),
+            Field::Str(s) => serializer.serialize_str(s),
+            Field::Token(token) => serializer.collect_str(&token),
+            // JSON has no NaN or infinities, written as `f64::to_serde` does
+            Field::Number(val) if val.is_finite() => serializer.serialize_f64(val),
+            Field::Number(val) => serializer.serialize_str(&printer::number(val)),
+            Field::Count(count) => serializer.serialize_u64(count as u64),
+            Field::Bool(b) => serializer.serialize_bool(b),
+            Field::Duration(dur, negative, format) => format.serialize(dur, negative).serialize(serializer),
+            Field::Time(time, format) => timestamps::to_serde(time, format).serialize(serializer),
+            Field::Labels(labels) => labels.serialize(serializer),
+            Field::Matchers(matchers) => serializer.collect_seq(matchers.matchers.iter().map(Field::Matcher)),
+            Field::Matcher(matcher) => entries(&[
+                ("name", Field::Str(&matcher.name)),
+                ("op", Field::Str(match_op(&matcher.op))),
+                ("value", Field::Str(&matcher.value)),
+            ], serializer),
+            Field::Modifier(None) | Field::BinModifier(None) => serializer.serialize_unit(),
+            Field::Modifier(Some(LabelModifier::Include(labels))) => entries(&[("include", Field::Labels(&labels.labels))], serializer),
+            Field::Modifier(Some(LabelModifier::Exclude(labels))) => entries(&[("exclude", Field::Labels(&labels.labels))], serializer),
+            Field::BinModifier(Some(modifier)) => entries(&[
+                ("card", Field::Card(&modifier.card)),
+                ("matching", Field::Modifier(&modifier.matching)),
+                ("return_bool", Field::Bool(modifier.return_bool)),
+            ], serializer),
+            Field::Card(card) => match card {
+                VectorMatchCardinality::OneToOne => entries(&[("@type", Field::Str("one-to-one"))], serializer),
+                VectorMatchCardinality::ManyToOne(labels) =>
+                    entries(&[("@type", Field::Str("many-to-one")), ("labels", Field::Labels(&labels.labels))], serializer),
+                VectorMatchCardinality::OneToMany(labels) =>
+                    entries(&[("@type", Field::Str("one-to-many")), ("labels", Field::Labels(&labels.labels))], serializer),
+                VectorMatchCardinality::ManyToMany => entries(&[("@type", Field::Str("many-to-many"))], serializer),
+            },
+            Field::Types(types) => serializer.collect_seq(types.iter().map(value_type)),
+            Field::Function(func) => entries(&[
+                ("arg_types", Field::Types(&func.arg_types)),
+                ("name", Field::Str(func.name)),
+                ("return_type", Field::Str(value_type(&func.return_type))),
+                ("variadic", Field::Bool(func.variadic)),
+            ], serializer),
+        }
+    }
+}
+
+
+impl<'a> Serialize for Node<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let span = self.tree.map(|tree| tree.span);
+        let child = |i: usize, expr: &'a Expr| Field::Node(self.child(i, expr));
+        match self.expr {
+            Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => self.object(&[
+                ("@type", Field::Str("aggregate")),
+                ("expr", child(usize::from(param.is_some()), expr)),
+                ("modifier", Field::Modifier(modifier)),
+                ("op", Field::Token(*op)),
+                ("param", param.as_ref().map_or(Field::Null, |param| child(0, param))),
+            ], span, serializer),
+            Expr::Unary(UnaryExpr { expr }) =>
+                self.object(&[("@type", Field::Str("unary")), ("expr", child(0, expr))], span, serializer),
+            Expr::Binary(BinaryExpr { lhs, op, rhs, modifier }) => self.object(&[
+                ("@type", Field::Str("binary")),
+                ("is_right_assoc", Field::Bool(printer::is_right_assoc(op.id()))),
+                ("lhs", child(0, lhs)),
+                ("modifier", Field::BinModifier(modifier)),
+                ("op", Field::Token(*op)),
+                ("precedence", Field::Count(usize::from(printer::precedence(op.id())))),
+                ("rhs", child(1, rhs)),
+            ], span, serializer),
+            Expr::Paren(ParenExpr { expr }) =>
+                self.object(&[("@type", Field::Str("paren")), ("expr", child(0, expr))], span, serializer),
+            Expr::Subquery(SubqueryExpr { expr, offset, at, range, step }) => self.object(&[
+                ("@type", Field::Str("subquery")),
+                ("at", self.at(at)),
+                ("expr", child(0, expr)),
+                ("offset", self.offset(offset)),
+                ("range", self.duration(range, false)),
+                ("step", step.as_ref().map_or(Field::Null, |step| self.duration(step, false))),
+            ], span, serializer),
+            Expr::NumberLiteral(NumberLiteral { val }) =>
+                self.object(&[("@type", Field::Str("number")), ("value", Field::Number(*val))], span, serializer),
+            Expr::StringLiteral(StringLiteral { val }) =>
+                self.object(&[("@type", Field::Str("string")), ("value", Field::Str(val))], span, serializer),
+            Expr::VectorSelector(vs) => self.object(&self.selector(vs), span, serializer),
+            Expr::MatrixSelector(MatrixSelector { vs, range }) => self.object(&[
+                ("@type", Field::Str("matrix_selector")),
+                ("range", self.duration(range, false)),
+                ("vector", Field::Selector(*self, vs, self.tree.and_then(|tree| tree.selector))),
+            ], span, serializer),
+            Expr::Call(Call { func, args }) => self.object(&[
+                ("@type", Field::Str("call")),
+                ("args", Field::Args(*self, &args.args)),
+                ("function", Field::Function(func)),
+            ], span, serializer),
+            Expr::Extension(_) => {
+                let mut ast = self.expr.to_serde();
+                if let Some(tree) = self.tree {
+                    annotate(&mut ast, tree);
+                    if self.format.text {
+                        annotate_text(&mut ast, self.query);
+                    }
+                }
+                ast.serialize(serializer)
+            }
+        }
+    }
+}
+
+
+#[test]
+fn check_node() {
+    let queries = [
+        "sum by (job) (rate(http_requests_total{code=~\"5..\"}[5m] offset 1h @ 1700000000)) / on (job) group_left (team) count(up)",
+        "topk(3, -x) > bool 1 unless histogram_quantile(0.9, rate(y[1h:5m] @ start()))",
+        "label_replace(x, \"a\", \"$1\", \"b\", \"(.*)\") ^ 2 ^ (NaN)",
+        "count without (a) (x offset -5m) * ignoring (b) group_right () {__name__=~\"y\"} @ end() - -Inf",
+    ];
+    let formats = [(false, DurationFormat::String, TimestampFormat::Iso), (true, DurationFormat::Millis, TimestampFormat::Bigint)];
+    for &(text, durations, timestamps) in &formats {
+        let options = SerializeOptions { text, durations, timestamps, ..Default::default() };
+        for query in queries {
+            let expr = crate::errors::try_parse(query).unwrap();
+            let tree = crate::spans::spans(query, &expr);
+            let (direct, built) = crate::options::with_options(options.clone(), || {
+                (serde_json::to_value(Node::new(query, &expr, tree.as_ref(), &options)).unwrap(), crate::serialize_ast(query, &expr))
+            });
+            assert_eq!(direct, built, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::guard::LabelGuard;
+use crate::printer::{grouping, to_promql};
+use crate::rewrite::inject_matchers;
+use crate::visit::{children, children_mut, walk};
+use crate::ToSerde;
+
+/// Label whose matcher selects a shard, as in `__query_shard__="1_of_16"`.
+pub const SHARD_LABEL: &str = "__query_shard__";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ShardOptions {
+    pub shards: usize,
+    #[serde(default = "shard_label")]
+    pub label: String,
+}
+
+fn shard_label() -> String {
+    SHARD_LABEL.to_string()
+}
+
+/// An aggregation split across shards: one query per shard, whose results
+/// the merge query reads, concatenated, as `__query_shards_<part>__`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardPlan {
+    pub parts: Vec<Vec<String>>,
+    pub merge: String,
+    /// Why aggregations were left unsharded.
+    pub reasons: Vec<String>,
+}
+
+impl ToSerde for ShardPlan {
+    fn to_serde(&self) -> Value {
+        let shardable = !self.parts.is_empty();
+        json!({
+            "shardable": shardable,
+            "parts": self.parts,
+            "merge": if shardable { Some(&self.merge) } else { None },
+            "reasons": self.reasons,
+        })
+    }
+}
+
+fn placeholder(part: usize) -> String {
+    format!("__query_shards_{}__", part)
+}
+
+/// Whether each series `expr` returns only depends on input series with the
+/// same labels, so it can be computed per shard of the series.
+fn series_local(expr: &Expr) -> Result<(), String> {
+    match expr {
+        Expr::Aggregate(AggregateExpr { op, .. }) => return Err(format!("it aggregates a nested `{}`", op)),
+        Expr::Binary(BinaryExpr { op, lhs, rhs, .. })
+            if lhs.value_type() == ValueType::Vector && rhs.value_type() == ValueType::Vector =>
+            return Err(format!("it matches series of different shards with `{}`", op)),
+        Expr::Call(Call { func, .. }) if matches!(func.name, "absent" | "absent_over_time" | "scalar" | "vector") =>
+            return Err(format!("`{}` depends on all series", func.name)),
+        _ => {}
+    }
+    children(expr).into_iter().try_for_each(series_local)
+}
+
+fn has_selectors(expr: &Expr) -> bool {
+    let mut found = false;
+    walk(expr, &mut |e| found |= matches!(e, Expr::VectorSelector(_) | Expr::MatrixSelector(_)));
+    found
+}
+
+/// The aggregation that merges the per-shard results of `op`, if there is one.
+fn merge_op(op: &TokenType) -> Option<&'static str> {
+    match op.id() {
+        T_SUM | T_COUNT => Some("sum"),
+        T_MIN => Some("min"),
+        T_MAX => Some("max"),
+        T_GROUP => Some("group"),
+        T_TOPK => Some("topk"),
+        T_BOTTOMK => Some("bottomk"),
+        _ => None,
+    }
+}
+
+struct Planner<'a> {
+    options: &'a ShardOptions,
+    parts: Vec<Vec<String>>,
+    reasons: Vec<String>,
+}
+
+impl Planner<'_> {
+    fn shardable(agg: &AggregateExpr) -> Result<(), String> {
+        let AggregateExpr { op, expr, param, .. } = agg;
+        if op.id() != T_AVG && merge_op(op).is_none() {
+            return Err(format!("`{}` cannot be merged from shards", op));
+        }
+        if param.as_deref().is_some_and(has_selectors) {
+            return Err(format!("the parameter of `{}` reads series", op));
+        }
+        if !has_selectors(expr) {
+            return Err(format!("`{}` reads no series", op));
+        }
+        series_local(expr).map_err(|reason| format!("`{}` cannot be sharded: {}", op, reason))
+    }
+
+    /// Adds a part computing `op` per shard, and returns its placeholder.
+    fn part(&mut self, op: &str, agg: &AggregateExpr) -> Result<String, String> {
+        let param = agg.param.as_ref().map(|param| format!("{}, ", to_promql(param))).unwrap_or_default();
+        let shards = (1..=self.options.shards)
+            .map(|shard| {
+                let mut inner = (*agg.expr).clone();
+                let value = format!("{}_of_{}", shard, self.options.shards);
+                inject_matchers(&mut inner, &[Matcher::new(MatchOp::Equal, &self.options.label, &value)], &LabelGuard::default())?;
+                Ok(format!("{}{}({}{})", op, grouping(&agg.modifier), param, to_promql(&inner)))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        self.parts.push(shards);
+        Ok(placeholder(self.parts.len() - 1))
+    }
+
+    /// The merge of `agg`, whose parts are added.
+    fn merge(&mut self, agg: &AggregateExpr) -> Result<Expr, String> {
+        let by = grouping(&agg.modifier);
+        let text = match merge_op(&agg.op) {
+            Some(merge) => {
+                let param = agg.param.as_ref().map(|param| format!("{}, ", to_promql(param))).unwrap_or_default();
+                let part = self.part(&agg.op.to_string(), agg)?;
+                format!("{}{}({}{})", merge, by, param, part)
+            }
+            None => {
+                let (sum, count) = (self.part("sum", agg)?, self.part("count", agg)?);
+                format!("sum{}({}) / sum{}({})", by, sum, by, count)
+            }
+        };
+        parse(&text)
+    }
+
+    fn shard(&mut self, expr: &mut Expr) -> Result<(), String> {
+        if let Expr::Aggregate(agg) = expr {
+            match Self::shardable(agg) {
+                Ok(()) => {
+                    *expr = self.merge(agg)?;
+                    return Ok(());
+                }
+                Err(reason) => self.reasons.push(reason),
+            }
+        }
+        children_mut(expr).into_iter().try_for_each(|child| self.shard(child))
+    }
+}
+
+/// Splits the outermost aggregations of `expr` that can be computed per shard
+/// of its series, Mimir-style: each shard query adds a `label="i_of_n"`
+/// matcher to every selector, and the merge query aggregates their results
+/// again, `count` by `sum` and `avg` as a sum over a count. Aggregations of
+/// aggregations, of joins between vectors or of `absent`, `scalar` and
+/// `vector` are left whole, with the reasons.
+pub fn shard(expr: &Expr, options: &ShardOptions) -> Result<ShardPlan, String> {
+    if options.shards == 0 {
+        return Err("`shards` must be positive".to_string());
+    }
+    let mut planner = Planner { options, parts: vec![], reasons: vec![] };
+    let mut merge = expr.clone();
+    planner.shard(&mut merge)?;
+    if planner.parts.is_empty() && planner.reasons.is_empty() {
+        planner.reasons.push("the query has no aggregation".to_string());
+    }
+    Ok(ShardPlan { parts: planner.parts, merge: to_promql(&merge), reasons: planner.reasons })
+}
+
+
+#[test]
+fn check_sharding() {
+    let options = ShardOptions { shards: 2, label: SHARD_LABEL.to_string() };
+    let plan = shard(&parse("sum by (job) (rate(x[5m])) / avg(y{a=\"b\"})").unwrap(), &options).unwrap();
+    assert_eq!(plan.merge, "sum by (job) (__query_shards_0__) / (sum(__query_shards_1__) / sum(__query_shards_2__))");
+    assert_eq!(plan.parts, vec![
+        vec![
+            "sum by (job) (rate(x{__query_shard__=\"1_of_2\"}[5m]))".to_string(),
+            "sum by (job) (rate(x{__query_shard__=\"2_of_2\"}[5m]))".to_string(),
+        ],
+        vec!["sum(y{a=\"b\", __query_shard__=\"1_of_2\"})".to_string(), "sum(y{a=\"b\", __query_shard__=\"2_of_2\"})".to_string()],
+        vec!["count(y{a=\"b\", __query_shard__=\"1_of_2\"})".to_string(), "count(y{a=\"b\", __query_shard__=\"2_of_2\"})".to_string()],
+    ]);
+    assert!(plan.reasons.is_empty());
+    let nested = shard(&parse("max(sum by (a) (x)) and topk(3, y)").unwrap(), &options).unwrap();
+    assert_eq!(nested.merge, "max(sum by (a) (__query_shards_0__)) and topk(3, __query_shards_1__)");
+    assert_eq!(nested.reasons, vec!["`max` cannot be sharded: it aggregates a nested `sum`"]);
+    let unshardable = shard(&parse("quantile(0.9, x) + sum(a / b)").unwrap(), &options).unwrap();
+    assert!(unshardable.parts.is_empty());
+    assert_eq!(unshardable.to_serde()["merge"], Value::Null);
+    assert_eq!(unshardable.reasons, vec![
+        "`quantile` cannot be merged from shards",
+        "`sum` cannot be sharded: it matches series of different shards with `/`",
+    ]);
+    assert_eq!(shard(&parse("x").unwrap(), &options).unwrap().reasons, vec!["the query has no aggregation"]);
+}
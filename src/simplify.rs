@@ -0,0 +1,468 @@
+use std::collections::BTreeSet;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::Labels;
+use serde_json::{json, Value};
+use crate::guard::LabelGuard;
+use crate::printer::{number, to_promql};
+use crate::visit::children_mut;
+use crate::ToSerde;
+
+/// A single simplification applied to a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewrite {
+    pub before: String,
+    pub after: String,
+    pub reason: String,
+    pub warnings: Vec<String>,
+}
+
+impl ToSerde for Rewrite {
+    fn to_serde(&self) -> Value {
+        json!({
+            "before": self.before,
+            "after": self.after,
+            "reason": self.reason,
+            "warnings": self.warnings,
+        })
+    }
+}
+
+/// A rewritten query together with the steps that produced it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simplified {
+    pub query: String,
+    pub rewrites: Vec<Rewrite>,
+    /// Rewrites the label guard did not allow.
+    pub refused: Vec<String>,
+}
+
+impl ToSerde for Simplified {
+    fn to_serde(&self) -> Value {
+        json!({
+            "query": self.query,
+            "rewrites": self.rewrites.to_serde(),
+            "refused": self.refused,
+        })
+    }
+}
+
+struct Collapser<'a> {
+    guard: &'a LabelGuard,
+    rewrites: Vec<Rewrite>,
+    refused: Vec<String>,
+}
+
+fn strip_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(ParenExpr { expr }) => strip_parens(expr),
+        _ => expr,
+    }
+}
+
+fn label_set(labels: &Labels) -> BTreeSet<String> {
+    labels.labels.iter().cloned().collect()
+}
+
+fn labels_of(set: BTreeSet<String>) -> Labels {
+    Labels { labels: set.into_iter().collect() }
+}
+
+/// Grouping of `outer(inner(x))` expressed as a single aggregation over `x`,
+/// or `None` if the outer grouping does not only merge inner groups.
+fn combined_grouping(
+    outer: &Option<LabelModifier>,
+    inner: &Option<LabelModifier>,
+) -> Option<Option<LabelModifier>> {
+    match (outer, inner) {
+        (None, _) => Some(None),
+        (Some(LabelModifier::Include(a)), Some(LabelModifier::Include(b))) =>
+            label_set(a).is_subset(&label_set(b)).then(|| outer.clone()),
+        (Some(LabelModifier::Include(a)), Some(LabelModifier::Exclude(f))) =>
+            label_set(a).is_disjoint(&label_set(f)).then(|| outer.clone()),
+        (Some(LabelModifier::Include(a)), None) => a.is_empty().then_some(None),
+        (Some(LabelModifier::Exclude(e)), Some(LabelModifier::Include(b))) => {
+            let kept = label_set(b).difference(&label_set(e)).cloned().collect();
+            Some(Some(LabelModifier::Include(labels_of(kept))))
+        }
+        (Some(LabelModifier::Exclude(e)), Some(LabelModifier::Exclude(f))) => {
+            let dropped = label_set(e).union(&label_set(f)).cloned().collect();
+            Some(Some(LabelModifier::Exclude(labels_of(dropped))))
+        }
+        (Some(LabelModifier::Exclude(_)), None) => Some(None),
+    }
+}
+
+/// The operator of the collapsed aggregation and why collapsing is sound.
+fn collapsed_op(outer: &AggregateExpr, inner: &AggregateExpr) -> Option<(TokenId, String)> {
+    let (o, i) = (outer.op.id(), inner.op.id());
+    match (o, i) {
+        (T_SUM, T_SUM) =>
+            Some((T_SUM, "summing partial sums equals summing all series".to_string())),
+        (T_MIN, T_MIN) | (T_MAX, T_MAX) => Some((
+            o,
+            format!("the {} of per-group {}s is the {} of all series", outer.op, outer.op, outer.op),
+        )),
+        (T_SUM, T_COUNT) =>
+            Some((T_COUNT, "summing per-group counts equals counting all series".to_string())),
+        (T_GROUP, T_SUM | T_MIN | T_MAX | T_AVG | T_COUNT | T_GROUP | T_STDDEV | T_STDVAR) => Some((
+            T_GROUP,
+            format!("group only reports whether series exist, which {} preserves", inner.op),
+        )),
+        (T_TOPK, T_TOPK) | (T_BOTTOMK, T_BOTTOMK) => {
+            let k_outer = outer.param.as_ref()?.scalar_value()?;
+            let k_inner = inner.param.as_ref()?.scalar_value()?;
+            (k_outer <= k_inner && outer.modifier == inner.modifier).then(|| (
+                o,
+                format!(
+                    "the {} {} of the {} {} series per group are the {} {} series",
+                    outer.op, k_outer, inner.op, k_inner, outer.op, k_outer,
+                ),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn collapse(agg: &AggregateExpr, collapser: &mut Collapser) -> Option<AggregateExpr> {
+    let inner = match strip_parens(&agg.expr) {
+        Expr::Aggregate(inner) => inner,
+        _ => return None,
+    };
+    let (op, reason) = collapsed_op(agg, inner)?;
+    let modifier = if matches!(op, T_TOPK | T_BOTTOMK) {
+        agg.modifier.clone()
+    } else {
+        combined_grouping(&agg.modifier, &inner.modifier)?
+    };
+    let kept = modifier.as_ref().map(|m| label_set(m.labels())).unwrap_or_default();
+    let removed: Vec<&str> = inner
+        .modifier
+        .iter()
+        .flat_map(|m| m.labels().labels.iter())
+        .filter(|label| !kept.contains(*label))
+        .map(String::as_str)
+        .collect();
+    let collapsed = AggregateExpr {
+        op: TokenType::new(op),
+        expr: inner.expr.clone(),
+        param: agg.param.clone(),
+        modifier,
+    };
+    let before = to_promql(&Expr::Aggregate(agg.clone()));
+    let warnings = match collapser.guard.check("remove", removed) {
+        Ok(warnings) => warnings,
+        Err(refusal) => {
+            collapser.refused.push(format!("{}: {}", before, refusal));
+            return None;
+        }
+    };
+    let grouping = match (&agg.modifier, &inner.modifier) {
+        (None, _) => String::new(),
+        _ => ", and the outer grouping only merges groups formed by the inner one".to_string(),
+    };
+    collapser.rewrites.push(Rewrite {
+        before,
+        after: to_promql(&Expr::Aggregate(collapsed.clone())),
+        reason: format!("{}{}", reason, grouping),
+        warnings,
+    });
+    Some(collapsed)
+}
+
+fn boxed(expr: &Expr, collapser: &mut Collapser) -> Box<Expr> {
+    Box::new(collapse_expr(expr, collapser))
+}
+
+fn collapse_expr(expr: &Expr, collapser: &mut Collapser) -> Expr {
+    match expr {
+        Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => {
+            let mut agg = AggregateExpr {
+                op: *op,
+                expr: boxed(expr, collapser),
+                param: param.as_ref().map(|param| boxed(param, collapser)),
+                modifier: modifier.clone(),
+            };
+            while let Some(collapsed) = collapse(&agg, collapser) {
+                agg = collapsed;
+            }
+            Expr::Aggregate(agg)
+        }
+        Expr::Unary(UnaryExpr { expr }) => Expr::Unary(UnaryExpr { expr: boxed(expr, collapser) }),
+        Expr::Binary(BinaryExpr { lhs, op, rhs, modifier }) => Expr::Binary(BinaryExpr {
+            lhs: boxed(lhs, collapser),
+            op: *op,
+            rhs: boxed(rhs, collapser),
+            modifier: modifier.clone(),
+        }),
+        Expr::Paren(ParenExpr { expr }) => Expr::Paren(ParenExpr { expr: boxed(expr, collapser) }),
+        Expr::Subquery(subquery) => Expr::Subquery(SubqueryExpr {
+            expr: boxed(&subquery.expr, collapser),
+            ..subquery.clone()
+        }),
+        Expr::Call(Call { func, args }) => Expr::Call(Call {
+            func: func.clone(),
+            args: FunctionArgs {
+                args: args.args.iter().map(|arg| boxed(arg, collapser)).collect(),
+            },
+        }),
+        _ => expr.clone(),
+    }
+}
+
+/// Collapses directly nested aggregations that provably compute the same
+/// result as a single aggregation over the innermost operand.
+///
+/// Only aggregations nested in aggregations are collapsed: an aggregation
+/// over a `*_over_time` call such as `max(max_over_time(x[5m]))` has no
+/// single-call equivalent, and moving it into a subquery changes the samples
+/// it reads, so it is left as it is.
+///
+/// Collapsing drops the inner grouping, which `guard` may refuse.
+pub fn collapse_nested_aggregations(query: &str, expr: &Expr, guard: &LabelGuard) -> Simplified {
+    let mut collapser = Collapser { guard, rewrites: vec![], refused: vec![] };
+    let collapsed = collapse_expr(expr, &mut collapser);
+    Simplified {
+        query: if collapser.rewrites.is_empty() { query.to_string() } else { to_promql(&collapsed) },
+        rewrites: collapser.rewrites,
+        refused: collapser.refused,
+    }
+}
+
+
+/// The value of `expr`, if it is arithmetic on number literals alone.
+fn constant(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::NumberLiteral(NumberLiteral { val }) => Some(*val),
+        Expr::Paren(ParenExpr { expr }) => constant(expr),
+        Expr::Unary(UnaryExpr { expr }) => constant(expr).map(|val| -val),
+        Expr::Call(Call { func, args }) if func.name == "pi" && args.args.is_empty() => Some(std::f64::consts::PI),
+        Expr::Binary(BinaryExpr { lhs, op, rhs, modifier }) => {
+            let (lhs, rhs) = (constant(lhs)?, constant(rhs)?);
+            // scalars only compare with `bool`, to 0 or 1
+            let compared = |holds: bool| modifier.as_ref().filter(|m| m.return_bool).map(|_| holds as u8 as f64);
+            match op.id() {
+                T_ADD => Some(lhs + rhs),
+                T_SUB => Some(lhs - rhs),
+                T_MUL => Some(lhs * rhs),
+                T_DIV => Some(lhs / rhs),
+                T_MOD => Some(lhs % rhs),
+                T_POW => Some(lhs.powf(rhs)),
+                T_ATAN2 => Some(lhs.atan2(rhs)),
+                T_EQLC => compared(lhs == rhs),
+                T_NEQ => compared(lhs != rhs),
+                T_LSS => compared(lhs < rhs),
+                T_LTE => compared(lhs <= rhs),
+                T_GTR => compared(lhs > rhs),
+                T_GTE => compared(lhs >= rhs),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn fold(expr: &mut Expr, rewrites: &mut Vec<Rewrite>) {
+    if !matches!(expr, Expr::NumberLiteral(_)) {
+        if let Some(val) = constant(expr) {
+            rewrites.push(Rewrite {
+                before: to_promql(expr),
+                after: number(val),
+                reason: "arithmetic on constants is evaluated once, here".to_string(),
+                warnings: vec![],
+            });
+            *expr = Expr::NumberLiteral(NumberLiteral { val });
+            return;
+        }
+    }
+    for child in children_mut(expr) {
+        fold(child, rewrites);
+    }
+}
+
+/// Evaluates the scalar arithmetic of `expr` on number literals and `pi()`,
+/// as in `(60 * 60) / 2`, and replaces it by its value. Arithmetic that
+/// involves a vector, as in `x * 2 * 3`, is kept.
+pub fn fold_constants(query: &str, expr: &Expr) -> Simplified {
+    let mut folded = expr.clone();
+    let mut rewrites = vec![];
+    fold(&mut folded, &mut rewrites);
+    Simplified {
+        query: if rewrites.is_empty() { query.to_string() } else { to_promql(&folded) },
+        rewrites,
+        refused: vec![],
+    }
+}
+
+
+/// Whether the series of `expr` keep their metric name, which arithmetic and
+/// negation drop.
+fn keeps_name(expr: &Expr) -> bool {
+    match expr {
+        Expr::VectorSelector(_) | Expr::MatrixSelector(_) => true,
+        Expr::Paren(ParenExpr { expr }) => keeps_name(expr),
+        Expr::Call(Call { func, args }) => matches!(
+            func.name,
+            "sort" | "sort_desc" | "sort_by_label" | "sort_by_label_desc" | "label_replace" | "label_join"
+                | "last_over_time" | "first_over_time"
+        ) && args.args.first().is_some_and(|arg| keeps_name(arg)),
+        Expr::Aggregate(AggregateExpr { op, expr, .. }) =>
+            matches!(op.id(), T_TOPK | T_BOTTOMK) && keeps_name(expr),
+        Expr::Binary(BinaryExpr { lhs, op, rhs, .. }) => match op.id() {
+            T_LAND | T_LUNLESS => keeps_name(lhs),
+            T_LOR => keeps_name(lhs) || keeps_name(rhs),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The legs of a chain of `or` without vector matching clauses, in order.
+fn or_legs(expr: &Expr) -> Vec<&Expr> {
+    match strip_parens(expr) {
+        Expr::Binary(BinaryExpr { lhs, op, rhs, modifier })
+            if op.id() == T_LOR && modifier.as_ref().is_none_or(|m| m.matching.is_none()) =>
+        {
+            let mut legs = or_legs(lhs);
+            legs.extend(or_legs(rhs));
+            legs
+        }
+        expr => vec![expr],
+    }
+}
+
+/// `expr` without a redundant operation, with the reason, if it has one.
+fn redundancy(expr: &Expr) -> Option<(Expr, String)> {
+    let operand = |expr: &Expr| strip_parens(expr).clone();
+    match expr {
+        Expr::Binary(BinaryExpr { lhs, op, rhs, .. }) => {
+            let (left, right) = (constant(lhs), constant(rhs));
+            let kept = match op.id() {
+                T_MUL if right == Some(1.0) => Some((lhs, "* 1")),
+                T_MUL if left == Some(1.0) => Some((rhs, "1 *")),
+                T_DIV if right == Some(1.0) => Some((lhs, "/ 1")),
+                T_ADD if right == Some(0.0) => Some((lhs, "+ 0")),
+                T_ADD if left == Some(0.0) => Some((rhs, "0 +")),
+                T_SUB if right == Some(0.0) => Some((lhs, "- 0")),
+                _ => None,
+            };
+            if let Some((kept, removed)) = kept {
+                return Some((operand(kept), format!("`{}` leaves values unchanged", removed)));
+            }
+            let legs = or_legs(expr);
+            let mut seen = BTreeSet::new();
+            let unique: Vec<&Expr> = legs.iter().copied().filter(|leg| seen.insert(to_promql(leg))).collect();
+            if unique.len() == legs.len() {
+                return None;
+            }
+            let chain = unique.into_iter().cloned().reduce(|lhs, rhs| {
+                Expr::Binary(BinaryExpr { lhs: Box::new(lhs), op: *op, rhs: Box::new(rhs), modifier: None })
+            })?;
+            Some((chain, "a repeated `or` leg adds no series the first one did not".to_string()))
+        }
+        Expr::Unary(UnaryExpr { expr }) => match strip_parens(expr) {
+            Expr::Unary(UnaryExpr { expr }) => Some((operand(expr), "double negation cancels out".to_string())),
+            Expr::NumberLiteral(NumberLiteral { val }) if val.is_sign_negative() =>
+                Some((Expr::NumberLiteral(NumberLiteral { val: -val }), "double negation cancels out".to_string())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn simplify_binary_expr(expr: &mut Expr, rewrites: &mut Vec<Rewrite>) {
+    for child in children_mut(expr) {
+        simplify_binary_expr(child, rewrites);
+    }
+    while let Some((simplified, reason)) = redundancy(expr) {
+        let mut warnings = vec![];
+        if keeps_name(&simplified) && !keeps_name(expr) {
+            warnings.push("the result keeps the metric name, which the removed operation dropped".to_string());
+        }
+        rewrites.push(Rewrite { before: to_promql(expr), after: to_promql(&simplified), reason, warnings });
+        *expr = simplified;
+    }
+}
+
+/// Removes operations that leave values unchanged: `* 1`, `/ 1`, `+ 0`,
+/// `- 0`, double negation and repeated legs of `or` chains. Dropping
+/// arithmetic on a selector keeps its metric name, which is warned about.
+pub fn simplify_binary(query: &str, expr: &Expr) -> Simplified {
+    let mut simplified = expr.clone();
+    let mut rewrites = vec![];
+    simplify_binary_expr(&mut simplified, &mut rewrites);
+    Simplified {
+        query: if rewrites.is_empty() { query.to_string() } else { to_promql(&simplified) },
+        rewrites,
+        refused: vec![],
+    }
+}
+
+
+#[test]
+fn check_collapse_nested_aggregations() {
+    let payloads = vec![
+        ("sum(sum by (a) (x))", "sum(x)"),
+        ("sum by (a) ((sum by (a, b) (x)))", "sum by (a) (x)"),
+        ("max without (b) (max by (a, b) (x))", "max by (a) (x)"),
+        ("sum(count by (job) (up))", "count(up)"),
+        ("group by (a) (avg without (b) (x))", "group by (a) (x)"),
+        ("topk(3, topk(5, x))", "topk(3, x)"),
+        ("sum(sum(sum by (a) (x)))", "sum(x)"),
+        ("sum by (a) (sum by (b) (x))", "sum by (a) (sum by (b) (x))"),
+        ("avg(avg by (a) (x))", "avg(avg by (a) (x))"),
+        ("topk(5, topk(3, x))", "topk(5, topk(3, x))"),
+        ("sum(sum by (le) (x))", "sum(sum by (le) (x))"),
+        ("max(max_over_time(x[5m]))", "max(max_over_time(x[5m]))"),
+        ("max(max(max_over_time(x[5m])))", "max(max_over_time(x[5m]))"),
+    ];
+    for (query, expected) in payloads {
+        let simplified = collapse_nested_aggregations(query, &parse(query).unwrap(), &LabelGuard::default());
+        assert_eq!(simplified.query, expected, "{}", query);
+        assert_eq!(simplified.rewrites.is_empty(), query == expected);
+    }
+    let query = "sum(sum by (le) (x))";
+    let simplified = collapse_nested_aggregations(query, &parse(query).unwrap(), &LabelGuard::default());
+    assert_eq!(simplified.refused.len(), 1);
+}
+
+#[test]
+fn check_fold_constants() {
+    let payloads = vec![
+        ("(60*60)/2", "1800"),
+        ("rate(x[5m]) * (60 * 60)", "rate(x[5m]) * 3600"),
+        ("2 ^ 10 + 1 > bool 1000", "1"),
+        ("topk(2 * 5, x) / -(-3)", "topk(10, x) / 3"),
+        ("x offset 5m > 1 - 3", "x offset 5m > -2"),
+        ("1 / 0 + pi() * 0", "Inf"),
+        ("x * 2 * 3", "x * 2 * 3"),
+        ("-1", "-1"),
+    ];
+    for (query, expected) in payloads {
+        let folded = fold_constants(query, &parse(query).unwrap());
+        assert_eq!(folded.query, expected, "{}", query);
+        assert_eq!(folded.rewrites.is_empty(), query == expected, "{}", query);
+    }
+}
+
+#[test]
+fn check_simplify_binary() {
+    let payloads = vec![
+        ("sum(rate(x[5m])) * 1 + 0", "sum(rate(x[5m]))"),
+        ("1 * (a + b) / (2 - 1)", "a + b"),
+        ("-(-(rate(x[5m])))", "rate(x[5m])"),
+        ("-(-1)", "1"),
+        ("a or b or (a or c) or b", "a or b or c"),
+        ("a or on (x) a", "a or on (x) a"),
+        ("x - 0 > 0", "x > 0"),
+        ("x * 2", "x * 2"),
+    ];
+    for (query, expected) in payloads {
+        let simplified = simplify_binary(query, &parse(query).unwrap());
+        assert_eq!(simplified.query, expected, "{}", query);
+        assert_eq!(simplified.rewrites.is_empty(), query == expected, "{}", query);
+    }
+    let named = simplify_binary("x * 1", &parse("x * 1").unwrap());
+    assert_eq!(named.rewrites[0].warnings.len(), 1);
+    assert!(simplify_binary("sum(x) * 1", &parse("sum(x) * 1").unwrap()).rewrites[0].warnings.is_empty());
+}
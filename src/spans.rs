@@ -0,0 +1,364 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use serde_json::{json, Value};
+use crate::lexemes::{lex, Lexeme};
+use crate::printer::to_promql;
+use crate::visit::children;
+
+/// Byte range of a node in the query text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn contains(&self, other: &Span) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+}
+
+/// Spans of an expression and, in the order of `visit::children`, of its
+/// sub-expressions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanTree {
+    pub span: Span,
+    pub children: Vec<SpanTree>,
+    /// Of a matrix selector, the vector selector before the range.
+    pub selector: Option<Span>,
+}
+
+/// Walks the tokens of a query alongside its AST. The AST has no positions,
+/// so each node claims the tokens its syntax must have been written with.
+struct Cursor<'a> {
+    lexemes: &'a [Lexeme],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<TokenId> {
+        self.lexemes.get(self.pos).map(|l| l.id)
+    }
+
+    fn next(&mut self) -> Option<Lexeme> {
+        let lexeme = self.lexemes.get(self.pos).copied();
+        self.pos += 1;
+        lexeme
+    }
+
+    fn expect(&mut self, id: TokenId) -> Option<Lexeme> {
+        self.next().filter(|l| l.id == id)
+    }
+
+    fn eat(&mut self, id: TokenId) -> Option<Lexeme> {
+        if self.peek() == Some(id) {
+            self.next()
+        } else {
+            None
+        }
+    }
+
+    fn last_end(&self) -> usize {
+        self.lexemes[self.pos - 1].end
+    }
+
+    /// Skips a bracketed group, e.g. matchers or a label list, returning its end.
+    fn group(&mut self, open: TokenId, close: TokenId) -> Option<usize> {
+        self.expect(open)?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()?.id {
+                id if id == open => depth += 1,
+                id if id == close => depth -= 1,
+                _ => {}
+            }
+        }
+        Some(self.last_end())
+    }
+
+    fn labels(&mut self) -> Option<usize> {
+        self.group(T_LEFT_PAREN, T_RIGHT_PAREN)
+    }
+
+    /// `@` and `offset` modifiers, in either order.
+    fn modifiers(&mut self) -> Option<()> {
+        loop {
+            match self.peek() {
+                Some(T_AT) => {
+                    self.next();
+                    match self.next()?.id {
+                        T_START | T_END => {
+                            self.expect(T_LEFT_PAREN)?;
+                            self.expect(T_RIGHT_PAREN)?;
+                        }
+                        T_ADD | T_SUB => {
+                            self.expect(T_NUMBER)?;
+                        }
+                        T_NUMBER => {}
+                        _ => return None,
+                    }
+                }
+                Some(T_OFFSET) => {
+                    self.next();
+                    self.eat(T_SUB);
+                    self.expect(T_DURATION)?;
+                }
+                _ => return Some(()),
+            }
+        }
+    }
+
+    fn selector(&mut self, vs: &VectorSelector) -> Option<usize> {
+        let start = self.lexemes.get(self.pos)?.start;
+        if vs.name.is_some() && self.peek() != Some(T_LEFT_BRACE) {
+            self.next();
+        }
+        if self.peek() == Some(T_LEFT_BRACE) {
+            self.group(T_LEFT_BRACE, T_RIGHT_BRACE)?;
+        }
+        Some(start)
+    }
+
+    fn node(start: usize, end: usize, children: Vec<SpanTree>) -> SpanTree {
+        SpanTree { span: Span { start, end }, children, selector: None }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Option<SpanTree> {
+        // unary plus is dropped by the parser
+        let start = self.lexemes.get(self.pos)?.start;
+        if !matches!(expr, Expr::NumberLiteral(_)) {
+            while self.eat(T_ADD).is_some() {}
+        }
+        let tree = match expr {
+            Expr::NumberLiteral(_) => {
+                while matches!(self.peek(), Some(T_ADD | T_SUB)) {
+                    self.next();
+                }
+                self.expect(T_NUMBER)?;
+                Cursor::node(start, self.last_end(), vec![])
+            }
+            Expr::StringLiteral(_) => {
+                self.expect(T_STRING)?;
+                Cursor::node(start, self.last_end(), vec![])
+            }
+            Expr::VectorSelector(vs) => {
+                self.selector(vs)?;
+                self.modifiers()?;
+                Cursor::node(start, self.last_end(), vec![])
+            }
+            Expr::MatrixSelector(MatrixSelector { vs, .. }) => {
+                self.selector(vs)?;
+                let selector = Span { start, end: self.last_end() };
+                self.expect(T_LEFT_BRACKET)?;
+                self.expect(T_DURATION)?;
+                self.expect(T_RIGHT_BRACKET)?;
+                self.modifiers()?;
+                SpanTree { selector: Some(selector), ..Cursor::node(start, self.last_end(), vec![]) }
+            }
+            Expr::Paren(ParenExpr { expr }) => {
+                self.expect(T_LEFT_PAREN)?;
+                let inner = self.expr(expr)?;
+                self.expect(T_RIGHT_PAREN)?;
+                Cursor::node(start, self.last_end(), vec![inner])
+            }
+            Expr::Unary(UnaryExpr { expr }) => {
+                self.expect(T_SUB)?;
+                let inner = self.expr(expr)?;
+                Cursor::node(start, inner.span.end, vec![inner])
+            }
+            Expr::Subquery(SubqueryExpr { expr, step, .. }) => {
+                let inner = self.expr(expr)?;
+                self.expect(T_LEFT_BRACKET)?;
+                self.expect(T_DURATION)?;
+                self.expect(T_COLON)?;
+                if step.is_some() {
+                    self.expect(T_DURATION)?;
+                }
+                self.expect(T_RIGHT_BRACKET)?;
+                self.modifiers()?;
+                Cursor::node(start, self.last_end(), vec![inner])
+            }
+            Expr::Binary(BinaryExpr { lhs, op, rhs, .. }) => {
+                let lhs = self.expr(lhs)?;
+                self.expect(op.id())?;
+                self.eat(T_BOOL);
+                if self.eat(T_ON).or_else(|| self.eat(T_IGNORING)).is_some() {
+                    self.labels()?;
+                }
+                if self.eat(T_GROUP_LEFT).or_else(|| self.eat(T_GROUP_RIGHT)).is_some()
+                    && self.peek() == Some(T_LEFT_PAREN)
+                {
+                    self.labels()?;
+                }
+                let rhs = self.expr(rhs)?;
+                Cursor::node(start, rhs.span.end, vec![lhs, rhs])
+            }
+            Expr::Aggregate(AggregateExpr { op, expr, param, .. }) => {
+                self.expect(op.id())?;
+                if self.eat(T_BY).or_else(|| self.eat(T_WITHOUT)).is_some() {
+                    self.labels()?;
+                }
+                self.expect(T_LEFT_PAREN)?;
+                let mut children = vec![];
+                if let Some(param) = param {
+                    children.push(self.expr(param)?);
+                    self.expect(T_COMMA)?;
+                }
+                children.push(self.expr(expr)?);
+                self.expect(T_RIGHT_PAREN)?;
+                if self.eat(T_BY).or_else(|| self.eat(T_WITHOUT)).is_some() {
+                    self.labels()?;
+                }
+                Cursor::node(start, self.last_end(), children)
+            }
+            Expr::Call(Call { args, .. }) => {
+                self.expect(T_IDENTIFIER)?;
+                self.expect(T_LEFT_PAREN)?;
+                let mut children = vec![];
+                for (i, arg) in args.args.iter().enumerate() {
+                    if i > 0 {
+                        self.expect(T_COMMA)?;
+                    }
+                    children.push(self.expr(arg)?);
+                }
+                self.expect(T_RIGHT_PAREN)?;
+                Cursor::node(start, self.last_end(), children)
+            }
+            Expr::Extension(_) => return None,
+        };
+        Some(tree)
+    }
+}
+
+/// Recovers the span of every node of `expr`, which must have been parsed
+/// from `query`. `None` if the tokens cannot be lined up with the AST.
+pub fn spans(query: &str, expr: &Expr) -> Option<SpanTree> {
+    let lexemes = lex(query).ok()?;
+    let mut cursor = Cursor { lexemes: &lexemes, pos: 0 };
+    let tree = cursor.expr(expr)?;
+    (cursor.pos == lexemes.len()).then_some(tree)
+}
+
+fn texts(expr: &Expr, tree: &SpanTree, out: &mut Vec<(String, Span)>) {
+    out.push((to_promql(expr), tree.span));
+    out.push((expr.to_string(), tree.span));
+    for (child, tree) in children(expr).into_iter().zip(&tree.children) {
+        texts(child, tree, out);
+    }
+}
+
+/// The span of every node of `expr` by its text, both as printed by
+/// `to_promql` and by upstream, for analyses that report nodes as text.
+/// Outer nodes come first.
+pub fn spans_by_text(query: &str, expr: &Expr) -> Vec<(String, Span)> {
+    let mut out = vec![];
+    if let Some(tree) = spans(query, expr) {
+        texts(expr, &tree, &mut out);
+    }
+    out
+}
+
+/// Adds `start` and `end` byte offsets to every node of a serialized tree.
+pub fn annotate(value: &mut Value, tree: &SpanTree) {
+    let object = match value.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+    object.insert("start".to_string(), json!(tree.span.start));
+    object.insert("end".to_string(), json!(tree.span.end));
+    let keys = match object.get("@type").and_then(Value::as_str) {
+        Some("aggregate") if object.get("param").is_some_and(|param| !param.is_null()) => vec!["param", "expr"],
+        Some("aggregate") | Some("unary") | Some("paren") | Some("subquery") => vec!["expr"],
+        Some("binary") => vec!["lhs", "rhs"],
+        _ => vec![],
+    };
+    for (key, child) in keys.into_iter().zip(&tree.children) {
+        if let Some(value) = object.get_mut(key) {
+            annotate(value, child);
+        }
+    }
+    if let Some(Value::Array(args)) = object.get_mut("args") {
+        for (arg, child) in args.iter_mut().zip(&tree.children) {
+            annotate(arg, child);
+        }
+    }
+    if let (Some(vector), Some(span)) = (object.get_mut("vector"), tree.selector) {
+        annotate(vector, &Cursor::node(span.start, span.end, vec![]));
+    }
+}
+
+/// Adds the `text` of `query` between `start` and `end` to every annotated
+/// node of a serialized tree.
+pub fn annotate_text(value: &mut Value, query: &str) {
+    match value {
+        Value::Object(object) => {
+            let span = (object.get("start").and_then(Value::as_u64), object.get("end").and_then(Value::as_u64));
+            if let (Some(start), Some(end)) = span {
+                if let Some(text) = query.get(start as usize..end as usize) {
+                    object.insert("text".to_string(), json!(text));
+                }
+            }
+            object.values_mut().for_each(|field| annotate_text(field, query));
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| annotate_text(item, query)),
+        _ => {}
+    }
+}
+
+
+#[test]
+fn check_spans() {
+    fn texts<'a>(query: &'a str, tree: &SpanTree, out: &mut Vec<&'a str>) {
+        out.push(&query[tree.span.start..tree.span.end]);
+        for child in &tree.children {
+            texts(query, child, out);
+        }
+    }
+    let payloads = vec![
+        (
+            "sum by (a) (rate(x{b=\"c\"}[5m] offset 1m)) / on (a) group_left() y - 2",
+            vec![
+                "sum by (a) (rate(x{b=\"c\"}[5m] offset 1m)) / on (a) group_left() y - 2",
+                "sum by (a) (rate(x{b=\"c\"}[5m] offset 1m)) / on (a) group_left() y",
+                "sum by (a) (rate(x{b=\"c\"}[5m] offset 1m))",
+                "rate(x{b=\"c\"}[5m] offset 1m)",
+                "x{b=\"c\"}[5m] offset 1m",
+                "y",
+                "2",
+            ],
+        ),
+        (
+            "topk(3, max_over_time((a + b)[1h:] @ end())) by (x)",
+            vec![
+                "topk(3, max_over_time((a + b)[1h:] @ end())) by (x)",
+                "3",
+                "max_over_time((a + b)[1h:] @ end())",
+                "(a + b)[1h:] @ end()",
+                "(a + b)",
+                "a + b",
+                "a",
+                "b",
+            ],
+        ),
+        ("-x ^ 2", vec!["-x ^ 2", "x ^ 2", "x", "2"]),
+    ];
+    for (query, expected) in payloads {
+        let tree = spans(query, &parse(query).unwrap()).unwrap();
+        let mut found = vec![];
+        texts(query, &tree, &mut found);
+        assert_eq!(found, expected, "{}", query);
+    }
+    let query = "sum(rate(x{a=\"b\"}[5m])) by (c) / 2";
+    let expr = parse(query).unwrap();
+    let mut ast = crate::ToSerde::to_serde(&expr);
+    annotate(&mut ast, &spans(query, &expr).unwrap());
+    let text = |node: &Value| &query[node["start"].as_u64().unwrap() as usize..node["end"].as_u64().unwrap() as usize];
+    assert_eq!(text(&ast), query);
+    assert_eq!(text(&ast["lhs"]), "sum(rate(x{a=\"b\"}[5m])) by (c)");
+    assert_eq!(text(&ast["lhs"]["expr"]["args"][0]), "x{a=\"b\"}[5m]");
+    assert_eq!(text(&ast["lhs"]["expr"]["args"][0]["vector"]), "x{a=\"b\"}");
+    assert_eq!(text(&ast["rhs"]), "2");
+    annotate_text(&mut ast, query);
+    assert_eq!(ast["lhs"]["expr"]["args"][0]["vector"]["text"], json!("x{a=\"b\"}"));
+    assert_eq!(ast["text"], json!(query));
+}
@@ -0,0 +1,152 @@
+use promql_parser::parser::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::lookback::{time_bounds, EvalTimes, TimeBounds, LOOKBACK_DELTA};
+use crate::printer::to_promql;
+use crate::timestamps::from_millis;
+use crate::visit::children_mut;
+use crate::ToSerde;
+
+/// A range query to split, in milliseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SplitOptions {
+    pub start: f64,
+    pub end: f64,
+    /// Resolution of the query, in milliseconds.
+    pub step: f64,
+    /// Length of each split, in milliseconds, aligned to multiples of it
+    /// since the epoch. A day by default.
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    /// `--query.lookback-delta`, in seconds.
+    #[serde(default = "default_lookback_delta")]
+    pub lookback_delta: f64,
+}
+
+fn default_interval() -> f64 {
+    86_400_000.0
+}
+
+fn default_lookback_delta() -> f64 {
+    LOOKBACK_DELTA.as_secs_f64()
+}
+
+/// One sub-query: the steps from `start` to `end`, both included, and the
+/// samples it reads, lookback before `start` included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Split {
+    pub start: i128,
+    pub end: i128,
+    pub reads: Option<TimeBounds>,
+}
+
+impl ToSerde for Split {
+    fn to_serde(&self) -> Value {
+        let bounds = self.reads.to_serde();
+        json!({
+            "start": self.start as f64,
+            "end": self.end as f64,
+            "min_time": bounds["min_time"],
+            "max_time": bounds["max_time"],
+        })
+    }
+}
+
+/// The query every split runs and the splits, in time order, whose results
+/// concatenate into those of the whole range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitPlan {
+    pub query: String,
+    pub step: i128,
+    pub splits: Vec<Split>,
+}
+
+impl ToSerde for SplitPlan {
+    fn to_serde(&self) -> Value {
+        json!({
+            "query": self.query,
+            "step": self.step as f64,
+            "splits": self.splits.to_serde(),
+        })
+    }
+}
+
+/// Pins `@ start()` and `@ end()` to the start and end of the whole range,
+/// which they would otherwise take from each split. Whether any was pinned.
+fn pin_at(expr: &mut Expr, start: &AtModifier, end: &AtModifier) -> bool {
+    let at = match expr {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => vs.at.as_mut(),
+        Expr::Subquery(sq) => sq.at.as_mut(),
+        _ => None,
+    };
+    let pinned = match at {
+        Some(at @ AtModifier::Start) => {
+            *at = start.clone();
+            true
+        }
+        Some(at @ AtModifier::End) => {
+            *at = end.clone();
+            true
+        }
+        _ => false,
+    };
+    children_mut(expr).into_iter().fold(pinned, |pinned, child| pin_at(child, start, end) | pinned)
+}
+
+/// Plans `query`, evaluated from `options.start` to `options.end`, as
+/// sub-queries over consecutive intervals that a query frontend can run in
+/// parallel and stitch. Splits break at multiples of the interval and keep
+/// the steps of the whole range, so no step is dropped or evaluated twice.
+/// `@ start()` and `@ end()` are pinned to the whole range in `query`.
+pub fn split(query: &str, expr: &Expr, options: &SplitOptions) -> Result<SplitPlan, String> {
+    let (start, end) = (options.start.round() as i128, options.end.round() as i128);
+    let (step, interval) = (options.step.round() as i128, options.interval.round() as i128);
+    if step <= 0 || interval <= 0 {
+        return Err("`step` and `interval` must be positive".to_string());
+    }
+    if start > end {
+        return Err("`start` is after `end`".to_string());
+    }
+    let mut expr = expr.clone();
+    let pinned = pin_at(&mut expr, &AtModifier::At(from_millis(start)?), &AtModifier::At(from_millis(end)?));
+    let query = if pinned { to_promql(&expr) } else { query.to_string() };
+    let last = start + (end - start) / step * step;
+    let mut splits = vec![];
+    let mut from = start;
+    while from <= last {
+        let boundary = (from.div_euclid(interval) + 1) * interval;
+        let to = (from + (boundary - 1 - from) / step * step).min(last);
+        let times = EvalTimes {
+            time: None,
+            start: Some(from as f64),
+            end: Some(to as f64),
+            lookback_delta: options.lookback_delta,
+        };
+        splits.push(Split { start: from, end: to, reads: time_bounds(&expr, &times)? });
+        from = to + step;
+    }
+    Ok(SplitPlan { query, step, splits })
+}
+
+
+#[test]
+fn check_split() {
+    let hour = 3_600_000.0;
+    let options = SplitOptions {
+        start: 22.5 * hour,
+        end: 50.0 * hour,
+        step: 2.0 * hour,
+        interval: 24.0 * hour,
+        lookback_delta: 300.0,
+    };
+    let query = "rate(x[1h]) / y @ end()";
+    let plan = split(query, &parse(query).unwrap(), &options).unwrap();
+    assert_eq!(plan.query, "rate(x[1h]) / y @ 180000.000");
+    let ranges: Vec<(f64, f64)> = plan.splits.iter().map(|s| (s.start as f64 / hour, s.end as f64 / hour)).collect();
+    assert_eq!(ranges, vec![(22.5, 22.5), (24.5, 46.5), (48.5, 48.5)]);
+    let second = plan.splits[1].reads.unwrap();
+    assert_eq!((second.min as f64 / hour, second.max as f64 / hour), (23.5, 50.0));
+    let instant = SplitOptions { end: options.start, ..options.clone() };
+    assert_eq!(split("x", &parse("x").unwrap(), &instant).unwrap().splits.len(), 1);
+    assert!(split("x", &parse("x").unwrap(), &SplitOptions { step: 0.0, ..options }).is_err());
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use promql_parser::parser::*;
+use promql_parser::label::*;
+use serde_json::{json, Value};
+use crate::visit::{children, node_type};
+use crate::ToSerde;
+
+/// A cheap structural summary of a query.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Node counts by `@type`.
+    pub nodes: BTreeMap<&'static str, usize>,
+    pub max_depth: usize,
+    pub selectors: usize,
+    pub matchers: usize,
+    pub regex_matchers: usize,
+    pub subqueries: usize,
+    /// Sum of all range vector and subquery ranges, in seconds.
+    pub range_seconds: f64,
+    /// Widest single range vector or subquery range, in seconds.
+    pub widest_range_seconds: f64,
+}
+
+impl ToSerde for Stats {
+    fn to_serde(&self) -> Value {
+        json!({
+            "nodes": self.nodes,
+            "total_nodes": self.nodes.values().sum::<usize>(),
+            "max_depth": self.max_depth,
+            "selectors": self.selectors,
+            "matchers": self.matchers,
+            "regex_matchers": self.regex_matchers,
+            "subqueries": self.subqueries,
+            "range_seconds": self.range_seconds,
+            "widest_range_seconds": self.widest_range_seconds,
+        })
+    }
+}
+
+impl Stats {
+    fn selector(&mut self, vs: &VectorSelector) {
+        self.selectors += 1;
+        self.matchers += vs.matchers.matchers.len();
+        self.regex_matchers += vs
+            .matchers
+            .matchers
+            .iter()
+            .filter(|m| matches!(m.op, MatchOp::Re(_) | MatchOp::NotRe(_)))
+            .count();
+    }
+
+    fn range(&mut self, range: &std::time::Duration) {
+        self.range_seconds += range.as_secs_f64();
+        self.widest_range_seconds = self.widest_range_seconds.max(range.as_secs_f64());
+    }
+
+    fn add(&mut self, expr: &Expr, depth: usize) {
+        *self.nodes.entry(node_type(expr)).or_default() += 1;
+        self.max_depth = self.max_depth.max(depth);
+        match expr {
+            Expr::VectorSelector(vs) => self.selector(vs),
+            Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+                self.selector(vs);
+                self.range(range);
+            }
+            Expr::Subquery(SubqueryExpr { range, .. }) => {
+                self.subqueries += 1;
+                self.range(range);
+            }
+            _ => {}
+        }
+        for child in children(expr) {
+            self.add(child, depth + 1);
+        }
+    }
+}
+
+pub fn stats(expr: &Expr) -> Stats {
+    let mut stats = Stats::default();
+    stats.add(expr, 1);
+    stats
+}
+
+
+#[test]
+fn check_stats() {
+    let stats = stats(&parse("sum(rate(a{x=~\"y.*\", z=\"1\"}[5m])) / max_over_time(b[1h:1m]) > 2").unwrap());
+    assert_eq!(stats.nodes.values().sum::<usize>(), 9);
+    assert_eq!(stats.nodes["binary"], 2);
+    assert_eq!(stats.max_depth, 5);
+    assert_eq!((stats.selectors, stats.matchers, stats.regex_matchers), (2, 2, 1));
+    assert_eq!(stats.subqueries, 1);
+    assert_eq!((stats.range_seconds, stats.widest_range_seconds), (3900.0, 3600.0));
+}
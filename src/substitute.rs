@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use promql_parser::util::parse_duration;
+use serde_json::Value;
+
+/// Where in a query a variable is used, which decides how its value is
+/// written there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    /// Inside a string quoted with this character, e.g. an `=` matcher value.
+    Text(char),
+    /// Inside a string quoted with this character, after `=~` or `!~`.
+    Regex(char),
+    /// A range, subquery step or offset.
+    Duration,
+    /// An `@` time or a parameter functions take as a scalar, as in
+    /// `topk($n, x)`.
+    Number,
+    /// Anywhere else, e.g. a metric or label name, or a number operand.
+    Bare,
+}
+
+impl Context {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Context::Text(_) => "string",
+            Context::Regex(_) => "regex",
+            Context::Duration => "duration",
+            Context::Number => "number",
+            Context::Bare => "name",
+        }
+    }
+}
+
+/// The values of a variable: a string or number, or an array of them for
+/// multi-value variables.
+fn values(name: &str, value: &Value) -> Result<Vec<String>, String> {
+    let one = |value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => Err(format!("variable ${} must be a string, a number or an array of them", name)),
+    };
+    match value {
+        Value::Array(items) => items.iter().map(one).collect(),
+        value => one(value).map(|value| vec![value]),
+    }
+}
+
+/// `text` escaped for a string quoted with `quote`.
+fn quoted(name: &str, text: &str, quote: char) -> Result<String, String> {
+    if quote == '`' {
+        return match text.contains('`') {
+            true => Err(format!("variable ${} has a backquote, which a raw string cannot hold", name)),
+            false => Ok(text.to_string()),
+        };
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    Ok(escaped)
+}
+
+/// The values of variable `name` written for `context`.
+fn render(name: &str, values: &[String], context: Context) -> Result<String, String> {
+    let single = || match values {
+        [value] => Ok(value.as_str()),
+        _ => Err(format!("variable ${} must have a single value here", name)),
+    };
+    match context {
+        Context::Text(quote) => quoted(name, single()?, quote),
+        Context::Regex(quote) => {
+            let escaped: Vec<String> = values.iter().map(|value| regex_syntax::escape(value)).collect();
+            let pattern = match escaped.as_slice() {
+                [one] => one.clone(),
+                many => format!("(?:{})", many.join("|")),
+            };
+            quoted(name, &pattern, quote)
+        }
+        Context::Duration => {
+            let value = single()?;
+            parse_duration(value).map_err(|_| format!("variable ${} is not a duration: {}", name, value))?;
+            Ok(value.to_string())
+        }
+        Context::Number => {
+            let value = single()?;
+            match value.parse::<f64>() {
+                Ok(_) => Ok(value.to_string()),
+                Err(_) => Err(format!("variable ${} is not a number: {}", name, value)),
+            }
+        }
+        Context::Bare => {
+            let value = single()?;
+            let word = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | ':');
+            if value.starts_with(|c: char| word(c) && !c.is_ascii_digit()) && value.chars().all(word) {
+                Ok(value.to_string())
+            } else if value.parse::<f64>().is_ok() {
+                // a numeric operand, as in `x > $threshold`, parenthesized if
+                // signed so that `$n ^ 2` keeps its meaning
+                let number = render(name, values, Context::Number)?;
+                Ok(match number.starts_with(['+', '-']) {
+                    true => format!("({})", number),
+                    false => number,
+                })
+            } else {
+                Err(format!("variable ${} cannot be written safely outside a string: {}", name, value))
+            }
+        }
+    }
+}
+
+/// A `$name` or `${name}` reference at the start of `rest`, with its length;
+/// `${name:format}` formats are ignored, the context decides. Names do not
+/// start with a digit, so `label_replace` references like `$1` stay.
+fn reference(rest: &str) -> Option<(&str, usize)> {
+    let word = |s: &str| match s.starts_with(|c: char| c.is_ascii_digit()) {
+        true => 0,
+        false => s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len()),
+    };
+    let body = rest.strip_prefix('$')?;
+    if let Some(braced) = body.strip_prefix('{') {
+        let close = braced.find('}')?;
+        let name = braced[..close].split(':').next()?;
+        return (!name.is_empty() && word(name) == name.len()).then_some((name, close + 3));
+    }
+    let len = word(body);
+    (len > 0).then(|| (&body[..len], len + 1))
+}
+
+/// Whether argument `index` of `function` is a scalar.
+fn scalar_param(function: &str, index: usize) -> bool {
+    match index {
+        0 => matches!(
+            function,
+            "topk" | "bottomk" | "quantile" | "limitk" | "limit_ratio" | "histogram_quantile"
+                | "histogram_fraction" | "quantile_over_time" | "vector"
+        ),
+        1 => matches!(
+            function,
+            "clamp" | "clamp_min" | "clamp_max" | "round" | "predict_linear" | "histogram_fraction"
+                | "holt_winters" | "double_exponential_smoothing"
+        ),
+        2 => matches!(function, "clamp" | "holt_winters" | "double_exponential_smoothing"),
+        _ => false,
+    }
+}
+
+/// An open parenthesis of a query being scanned.
+struct Paren {
+    /// The function called, empty if none.
+    function: String,
+    /// The argument the scan is in.
+    arg: usize,
+    /// The aggregation of a `by` or `without` label list.
+    grouping: Option<String>,
+}
+
+/// The context of a variable outside strings after `before`, the query up to
+/// it, inside `brackets` levels of `[` and the parenthesis `paren`, if any.
+fn bare_context(before: &str, brackets: usize, paren: Option<&Paren>) -> Context {
+    let before = before.trim_end();
+    let last_word = before.rsplit(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).next().unwrap_or("");
+    let argument = before.ends_with('(') || before.ends_with(',');
+    if brackets > 0 || last_word.eq_ignore_ascii_case("offset") {
+        Context::Duration
+    } else if before.ends_with('@') || argument && paren.is_some_and(|paren| scalar_param(&paren.function, paren.arg)) {
+        Context::Number
+    } else {
+        Context::Bare
+    }
+}
+
+/// A `$name` or `${name}` variable of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name: String,
+    /// Byte range of the reference in the query.
+    pub start: usize,
+    pub end: usize,
+    pub context: Context,
+}
+
+/// The Grafana-style `$name` and `${name}` variables of `query`, with where
+/// each one is. Comments are left alone.
+pub fn references(query: &str) -> Vec<Reference> {
+    let mut references = vec![];
+    // the quote of the string the scan is in, and whether it is a regex
+    let mut string: Option<(char, bool)> = None;
+    let mut brackets = 0usize;
+    let mut parens: Vec<Paren> = vec![];
+    // the aggregation of the `by` or `without` list just closed
+    let mut grouped: Option<String> = None;
+    let mut pos = 0;
+    while let Some(c) = query[pos..].chars().next() {
+        let rest = &query[pos..];
+        if c == '$' {
+            if let Some((name, len)) = reference(rest) {
+                let context = match string {
+                    Some((quote, true)) => Context::Regex(quote),
+                    Some((quote, false)) => Context::Text(quote),
+                    None => bare_context(&query[..pos], brackets, parens.last()),
+                };
+                references.push(Reference { name: name.to_string(), start: pos, end: pos + len, context });
+                pos += len;
+                continue;
+            }
+        }
+        let mut len = c.len_utf8();
+        match (string, c) {
+            (Some((quote, _)), '\\') if quote != '`' => len += rest[1..].chars().next().map_or(0, char::len_utf8),
+            (Some((quote, _)), c) if c == quote => string = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => {
+                let before = query[..pos].trim_end();
+                string = Some((c, before.ends_with("=~") || before.ends_with("!~")));
+            }
+            (None, '[') => brackets += 1,
+            (None, ']') => brackets = brackets.saturating_sub(1),
+            (None, '(') => {
+                let before = query[..pos].trim_end();
+                let last_word = |text: &str| text.rsplit(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).next().unwrap_or("").to_string();
+                let word = last_word(before);
+                let paren = match word.as_str() {
+                    "by" | "without" => {
+                        let aggregation = last_word(before[..before.len() - word.len()].trim_end());
+                        Paren { function: String::new(), arg: 0, grouping: Some(aggregation) }
+                    }
+                    "" if before.ends_with(')') => Paren { function: grouped.clone().unwrap_or_default(), arg: 0, grouping: None },
+                    _ => Paren { function: word, arg: 0, grouping: None },
+                };
+                parens.push(paren);
+            }
+            (None, ')') => grouped = parens.pop().and_then(|paren| paren.grouping),
+            (None, ',') => {
+                if let Some(paren) = parens.last_mut() {
+                    paren.arg += 1;
+                }
+            }
+            (None, '#') => len = rest.find('\n').unwrap_or(rest.len()),
+            (None, _) => {}
+        }
+        pos += len;
+    }
+    references
+}
+
+/// Replaces the Grafana-style `$name` and `${name}` variables of `query` with
+/// their `vars` values, escaped for where they are: string values, regexes
+/// (multiple values as an alternation), durations, numbers, or bare names,
+/// `[a-zA-Z_:][a-zA-Z0-9_:]*`, and numbers checked to be nothing more.
+/// Comments are left alone.
+pub fn substitute(query: &str, vars: &BTreeMap<String, Value>) -> Result<String, String> {
+    let mut out = String::with_capacity(query.len());
+    let mut copied = 0;
+    for reference in references(query) {
+        let name = reference.name.as_str();
+        let value = vars.get(name).ok_or_else(|| format!("unknown variable ${}", name))?;
+        out.push_str(&query[copied..reference.start]);
+        out.push_str(&render(name, &values(name, value)?, reference.context)?);
+        copied = reference.end;
+    }
+    out.push_str(&query[copied..]);
+    Ok(out)
+}
+
+#[test]
+fn check_substitute() {
+    use serde_json::json;
+    let vars: BTreeMap<String, Value> = serde_json::from_value(json!({
+        "job": "api \"v2\"",
+        "pods": ["a.1", "b"],
+        "interval": "5m",
+        "metric": "http_requests_total",
+        "q": 0.99,
+        "bad": "x) or vector(1",
+        "minus": "up-node_load1",
+        "plus": "up+1",
+        "neg": -1,
+    })).unwrap();
+    let payloads = vec![
+        (
+            "sum(rate($metric{job=\"$job\", pod=~\"${pods}\"}[$interval])) # $nope",
+            Ok("sum(rate(http_requests_total{job=\"api \\\"v2\\\"\", pod=~\"(?:a\\\\.1|b)\"}[5m])) # $nope"),
+        ),
+        ("histogram_quantile($q, x offset $interval)", Ok("histogram_quantile(0.99, x offset 5m)")),
+        ("x{a='${job:raw}'}", Ok("x{a='api \"v2\"'}")),
+        ("x[$interval:$interval]", Ok("x[5m:5m]")),
+        ("topk by (job) ($q, sum without (a) (x)) @ $q", Ok("topk by (job) (0.99, sum without (a) (x)) @ 0.99")),
+        ("topk($job, x)", Err("variable $job is not a number: api \"v2\"")),
+        ("label_replace(x, \"a\", \"$1\", \"b\", \"(.*)$\")", Ok("label_replace(x, \"a\", \"$1\", \"b\", \"(.*)$\")")),
+        ("x{a=\"$pods\"}", Err("variable $pods must have a single value here")),
+        ("x[$job]", Err("variable $job is not a duration: api \"v2\"")),
+        ("sum($bad)", Err("variable $bad cannot be written safely outside a string: x) or vector(1")),
+        ("x{a=\"$other\"}", Err("unknown variable $other")),
+        ("$minus{job=\"a\"}", Err("variable $minus cannot be written safely outside a string: up-node_load1")),
+        ("sum($plus)", Err("variable $plus cannot be written safely outside a string: up+1")),
+        ("x > $q and $neg ^ 2 < x", Ok("x > 0.99 and (-1) ^ 2 < x")),
+    ];
+    for (query, expected) in payloads {
+        assert_eq!(substitute(query, &vars), expected.map(str::to_string).map_err(str::to_string), "{}", query);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::catalog::{aggregators, functions};
+
+/// Keywords and operator words a misspelled word in a query may have been.
+const KEYWORDS: [&str; 14] = [
+    "and", "atan2", "bool", "by", "end", "group_left", "group_right", "ignoring", "offset", "on", "or", "start", "unless",
+    "without",
+];
+
+/// The optimal string alignment distance of `a` and `b`: insertions,
+/// deletions, substitutions and transpositions of adjacent characters.
+pub fn distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1).min(row[j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+/// The up to three `candidates` nearest to `word`, nearest first, within a
+/// distance of a third of its length, at least one and at most three.
+pub fn nearest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let limit = (word.chars().count() / 3).clamp(1, 3);
+    let mut near: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .filter(|candidate| *candidate != word)
+        .map(|candidate| (distance(word, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    near.sort();
+    near.dedup();
+    near.into_iter().take(3).map(|(_, candidate)| candidate.to_string()).collect()
+}
+
+/// What the `start..end` text of `query`, where an error of `code` is, may
+/// have been meant as: function names for unknown functions, keywords and
+/// aggregation operators for a word the grammar did not expect.
+pub fn suggestions(query: &str, code: &str, start: usize, end: usize) -> Vec<String> {
+    let word = &query[start..end];
+    if word.is_empty() || !word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return vec![];
+    }
+    let (functions, aggregators) = (functions(), aggregators());
+    let names = aggregators.iter().map(|entry| entry.name);
+    match code {
+        "unknown-function" => nearest(word, functions.iter().map(|entry| entry.name).chain(names)),
+        "invalid-syntax" => nearest(word, KEYWORDS.iter().copied().chain(names)),
+        _ => vec![],
+    }
+}
+
+
+#[test]
+fn check_suggestions() {
+    assert_eq!((distance("hstogram_quantile", "histogram_quantile"), distance("offest", "offset")), (1, 1));
+    let suggested = |query: &str| crate::errors::try_parse(query).unwrap_err().suggestions;
+    assert_eq!(suggested("hstogram_quantile(0.9, x)"), ["histogram_quantile"]);
+    assert_eq!(suggested("summ(x)"), ["sum"]);
+    assert_eq!(suggested("rate(x[5m]) offest 1m"), ["offset"]);
+    assert_eq!(suggested("sum(x) withot (job)"), ["without"]);
+    assert_eq!(suggested("x unles y"), ["unless"]);
+    assert!(suggested("x @ foo").is_empty() && suggested("abs(x, y)").is_empty());
+    let error = crate::experimental::parse_experimental("sort_by_label(hstogram_quantile(0.9, x))", &crate::errors::try_parse).unwrap_err();
+    assert_eq!((error.start, error.suggestions), (14, vec!["histogram_quantile".to_string()]));
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeSet;
+use regex::Regex;
+use serde_json::{json, Value};
+use crate::output_labels::output_labels;
+use crate::rules::{errors, RuleError, RuleFile};
+use crate::ToSerde;
+
+/// A `$labels` reference in an alert template to a label the alert never has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateFinding {
+    pub rule: usize,
+    pub alert: String,
+    /// `annotations.<name>` or `labels.<name>`.
+    pub field: String,
+    pub label: String,
+    pub message: String,
+}
+
+impl ToSerde for TemplateFinding {
+    fn to_serde(&self) -> Value {
+        json!({
+            "rule": self.rule,
+            "alert": self.alert,
+            "field": self.field,
+            "label": self.label,
+            "message": self.message,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateReport {
+    pub findings: Vec<TemplateFinding>,
+    pub errors: Vec<RuleError>,
+}
+
+impl ToSerde for TemplateReport {
+    fn to_serde(&self) -> Value {
+        json!({
+            "findings": self.findings.to_serde(),
+            "errors": self.errors.to_serde(),
+        })
+    }
+}
+
+/// Label names referenced as `$labels.x`, `.Labels.x` or `index $labels "x"`.
+pub fn referenced_labels(template: &str) -> BTreeSet<String> {
+    let dotted = Regex::new(r"(?:\$labels|\.Labels)\.([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+    let indexed = Regex::new(r#"index\s+(?:\$labels|\.Labels)\s+"([^"]+)""#).unwrap();
+    dotted
+        .captures_iter(template)
+        .chain(indexed.captures_iter(template))
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// Cross-checks the `$labels` references in alert annotations and labels
+/// against the labels each alert expression can produce.
+pub fn check_templates(file: &RuleFile) -> TemplateReport {
+    let rules = file.parsed();
+    let mut findings = vec![];
+    for r in &rules {
+        let (alert, expr) = match (&r.rule.alert, &r.expr) {
+            (Some(alert), Ok(expr)) => (alert, expr),
+            _ => continue,
+        };
+        // templates see the labels of the alerting series, before the rule's
+        // own labels and `alertname` are added
+        let produced = output_labels(expr);
+        let templates = r
+            .rule
+            .annotations
+            .iter()
+            .map(|(name, template)| (format!("annotations.{}", name), template))
+            .chain(r.rule.labels.iter().map(|(name, template)| (format!("labels.{}", name), template)));
+        for (field, template) in templates {
+            for label in referenced_labels(template) {
+                if !produced.can_have(&label) {
+                    findings.push(TemplateFinding {
+                        rule: r.id,
+                        alert: alert.clone(),
+                        message: format!("{} references label `{}`, which `{}` never produces", field, label, r.rule.expr),
+                        field: field.clone(),
+                        label,
+                    });
+                }
+            }
+        }
+    }
+    TemplateReport { findings, errors: errors(&rules) }
+}
+
+
+#[test]
+fn check_alert_templates() {
+    let file: RuleFile = serde_json::from_value(json!({
+        "groups": [{
+            "name": "alerts",
+            "rules": [{
+                "alert": "HighErrorRate",
+                "expr": "sum by (job) (rate(errors_total[5m])) > 1",
+                "labels": { "severity": "page", "team": "{{ $labels.team }}" },
+                "annotations": {
+                    "summary": "{{ $labels.job }} on {{ $labels.instance }} ({{ $labels.severity }})",
+                    "runbook": "{{ index $labels \"job\" }}",
+                },
+            }],
+        }],
+    }))
+    .unwrap();
+    let labels: Vec<(String, String)> =
+        check_templates(&file).findings.into_iter().map(|f| (f.field, f.label)).collect();
+    assert_eq!(labels, vec![
+        ("annotations.summary".to_string(), "instance".to_string()),
+        ("annotations.summary".to_string(), "severity".to_string()),
+        ("labels.team".to_string(), "team".to_string()),
+    ]);
+}
@@ -0,0 +1,134 @@
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+use iso8601_timestamp::Timestamp;
+use promql_parser::parser::AtModifier;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Serialized form of `@` timestamps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampFormat {
+    /// ISO 8601 text, or milliseconds as a string outside of years 0000-9999.
+    #[default]
+    Iso,
+    /// Seconds since the epoch as a number, with a fraction for milliseconds,
+    /// as the Prometheus HTTP API takes them.
+    Seconds,
+    /// Milliseconds since the epoch as a number, exact up to 2^53.
+    Millis,
+    /// Milliseconds since the epoch as a decimal string, always exact.
+    String,
+    /// Like `String`, converted to a `BigInt` on the JS side.
+    Bigint,
+}
+
+/// Latest instant ISO 8601 text without an extended year can express.
+const ISO_MAX_MILLIS: i128 = 253_402_300_799_999;
+/// 0000-01-01T00:00:00Z
+const ISO_MIN_MILLIS: i128 = -62_167_219_200_000;
+
+/// Milliseconds since the epoch, negative before it.
+pub fn millis(time: &SystemTime) -> i128 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(dur) => dur.as_millis() as i128,
+        Err(err) => -(err.duration().as_millis() as i128),
+    }
+}
+
+pub fn to_serde(time: &SystemTime, format: TimestampFormat) -> Value {
+    let ms = millis(time);
+    match format {
+        TimestampFormat::Iso if (ISO_MIN_MILLIS..=ISO_MAX_MILLIS).contains(&ms) => json!(Timestamp::from(*time)),
+        TimestampFormat::Seconds if ms % 1000 == 0 => match i64::try_from(ms / 1000) {
+            Ok(secs) => json!(secs),
+            Err(_) => json!((ms / 1000).to_string()),
+        },
+        TimestampFormat::Seconds => json!(ms as f64 / 1000.0),
+        TimestampFormat::Millis => match i64::try_from(ms) {
+            Ok(ms) => json!(ms),
+            Err(_) => json!(ms.to_string()),
+        },
+        _ => json!(ms.to_string()),
+    }
+}
+
+pub fn from_millis(ms: i128) -> Result<SystemTime, String> {
+    let dur = Duration::from_millis(u64::try_from(ms.abs()).map_err(|_| format!("timestamp out of range: {}", ms))?);
+    let time = if ms < 0 {
+        SystemTime::UNIX_EPOCH.checked_sub(dur)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_add(dur)
+    };
+    time.ok_or_else(|| format!("timestamp out of range: {}", ms))
+}
+
+/// Reads an `@` modifier back from any of its serialized forms: `"start"`,
+/// `"end"`, ISO 8601 text, or milliseconds as a number or decimal string,
+/// seconds if `format` is `Seconds`. Fractional milliseconds are rounded, as
+/// the parser does.
+pub fn parse_at(value: &Value, format: TimestampFormat) -> Result<AtModifier, String> {
+    let invalid = || format!("invalid @ timestamp: {}", value);
+    if let (TimestampFormat::Seconds, Value::Number(secs)) = (format, value) {
+        return match secs.as_f64() {
+            Some(secs) if secs.is_finite() => from_millis((secs * 1000.0).round() as i128).map(AtModifier::At),
+            _ => Err(invalid()),
+        };
+    }
+    match value {
+        Value::String(s) if s == "start" => Ok(AtModifier::Start),
+        Value::String(s) if s == "end" => Ok(AtModifier::End),
+        Value::String(s) => match s.parse::<i128>() {
+            Ok(ms) => from_millis(ms).map(AtModifier::At),
+            Err(_) => match s.parse::<f64>() {
+                Ok(ms) if ms.is_finite() => from_millis(ms.round() as i128).map(AtModifier::At),
+                _ => {
+                    let ts = Timestamp::parse(s).ok_or_else(invalid)?;
+                    from_millis(ts.duration_since(Timestamp::UNIX_EPOCH).whole_milliseconds()).map(AtModifier::At)
+                }
+            },
+        },
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(ms), _) => from_millis(ms as i128).map(AtModifier::At),
+            (None, Some(ms)) if ms.is_finite() => from_millis(ms.round() as i128).map(AtModifier::At),
+            _ => Err(invalid()),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+
+#[test]
+fn check_timestamps() {
+    use promql_parser::parser::{parse, Expr};
+    let at = |query: &str| match parse(query).unwrap() {
+        Expr::VectorSelector(vs) => match vs.at.unwrap() {
+            AtModifier::At(time) => time,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    let fractional = at("x @ 1.5");
+    assert_eq!(to_serde(&fractional, TimestampFormat::Iso), json!("1970-01-01T00:00:01.500Z"));
+    assert_eq!(to_serde(&fractional, TimestampFormat::Millis), json!(1500));
+    let far = at("x @ 9007199254740992");
+    assert_eq!(to_serde(&far, TimestampFormat::Iso), json!("9007199254740992000"));
+    assert_eq!(to_serde(&fractional, TimestampFormat::Seconds), json!(1.5));
+    assert_eq!(to_serde(&at("x @ 1700000000"), TimestampFormat::Seconds), json!(1700000000));
+    let formats = [
+        TimestampFormat::Iso,
+        TimestampFormat::Seconds,
+        TimestampFormat::Millis,
+        TimestampFormat::String,
+        TimestampFormat::Bigint,
+    ];
+    for format in formats {
+        for time in [fractional, far, at("x @ -1.25")] {
+            assert_eq!(parse_at(&to_serde(&time, format), format), Ok(AtModifier::At(time)), "{:?}", format);
+        }
+    }
+    assert_eq!(parse_at(&json!(1500.4), TimestampFormat::Iso), Ok(AtModifier::At(fractional)));
+    assert_eq!(parse_at(&json!(1.5), TimestampFormat::Seconds), Ok(AtModifier::At(fractional)));
+    assert_eq!(parse_at(&json!("end"), TimestampFormat::Iso), Ok(AtModifier::End));
+    assert!(parse_at(&json!("tomorrow"), TimestampFormat::Iso).is_err());
+}
@@ -0,0 +1,95 @@
+use std::cell::RefCell;
+use serde_json::{json, Value};
+use crate::ToSerde;
+
+/// Milliseconds since an arbitrary origin.
+#[cfg(target_arch = "wasm32")]
+fn now() -> f64 {
+    // `Instant` panics on wasm32-unknown-unknown, use the host's `performance.now()`
+    use js_sys::{Function, Reflect};
+    use wasm_bindgen::JsCast;
+    let performance = Reflect::get(&js_sys::global(), &"performance".into()).unwrap_or_default();
+    Reflect::get(&performance, &"now".into())
+        .ok()
+        .and_then(|now| now.dyn_into::<Function>().ok())
+        .and_then(|now| now.call0(&performance).ok())
+        .and_then(|ms| ms.as_f64())
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> f64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    ORIGIN.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    pub ms: f64,
+}
+
+/// Wall clock time spent in each phase of processing a query, in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    pub phases: Vec<Phase>,
+}
+
+impl Timings {
+    pub fn total_ms(&self) -> f64 {
+        self.phases.iter().map(|phase| phase.ms).sum()
+    }
+}
+
+impl ToSerde for Timings {
+    fn to_serde(&self) -> Value {
+        json!({
+            "phases": self.phases
+                .iter()
+                .map(|phase| json!({ "phase": phase.name, "ms": phase.ms }))
+                .collect::<Vec<Value>>(),
+            "total_ms": self.total_ms(),
+        })
+    }
+}
+
+thread_local! {
+    static RECORDING: RefCell<Option<Timings>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, recording how long it took as `name` when within `record`.
+pub fn time<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    if RECORDING.with(|recording| recording.borrow().is_none()) {
+        return f();
+    }
+    let start = now();
+    let result = f();
+    let phase = Phase { name: name.to_string(), ms: now() - start };
+    RECORDING.with(|recording| recording.borrow_mut().iter_mut().for_each(|timings| timings.phases.push(phase.clone())));
+    result
+}
+
+/// Runs `f`, with the phases it `time`s.
+pub fn record<T>(f: impl FnOnce() -> T) -> (T, Timings) {
+    let previous = RECORDING.with(|recording| recording.replace(Some(Timings::default())));
+    let result = f();
+    let timings = RECORDING.with(|recording| recording.replace(previous)).unwrap_or_default();
+    (result, timings)
+}
+
+
+#[test]
+fn check_profile() {
+    use crate::options::SerializeOptions;
+    let query = "sum by (job) (rate(x{a=~\"b|c\"}[5m]))";
+    let (ast, timings) = record(|| crate::parse_serialized(query, &SerializeOptions::default()));
+    assert!(ast.is_ok());
+    let names: Vec<&str> = timings.phases.iter().map(|phase| phase.name.as_str()).collect();
+    assert_eq!(names, ["parse", "serialize"]);
+    assert!(timings.phases.iter().all(|phase| phase.ms >= 0.0));
+    let (_, timings) = record(|| crate::parse_serialized("sum(", &SerializeOptions::default()));
+    assert_eq!(timings.phases.len(), 1);
+    assert_eq!(time("untimed", || 1), 1);
+}
@@ -0,0 +1,72 @@
+use serde_json::{json, Value};
+use crate::cst::cst;
+use crate::errors::{parse_error, ParseError};
+use crate::lenient::closing;
+use crate::lexemes::{kind, name};
+use crate::ToSerde;
+
+/// A token or comment of a query, for highlighting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// `lexemes::name` of the token type, or `"comment"`.
+    pub token_type: &'static str,
+    /// `lexemes::kind` of the token type, or `"comment"`.
+    pub kind: &'static str,
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ToSerde for Token {
+    fn to_serde(&self) -> Value {
+        json!({
+            "type": self.token_type,
+            "kind": self.kind,
+            "text": self.text,
+            "start": self.start,
+            "end": self.end,
+        })
+    }
+}
+
+/// The tokens and comments of `query`, without parsing it. Unterminated
+/// strings and unclosed brackets at the end, as while typing, are fine.
+pub fn tokenize(query: &str) -> Result<Vec<Token>, ParseError> {
+    let closed = format!("{}{}", query, closing(query));
+    let cst = cst(&closed).map_err(|message| parse_error(query, message))?;
+    let token = |token_type, kind, start, end: usize| {
+        let end = end.min(query.len());
+        Token { token_type, kind, text: query[start..end].to_string(), start, end }
+    };
+    let mut tokens = vec![];
+    for cst_token in &cst.tokens {
+        for comment in cst_token.leading.iter().filter(|t| t.kind == "comment") {
+            tokens.push(token("comment", "comment", comment.start, comment.end));
+        }
+        let lexeme = cst_token.lexeme;
+        if lexeme.start < query.len() {
+            tokens.push(token(name(lexeme.id), kind(lexeme.id), lexeme.start, lexeme.end));
+        }
+    }
+    for comment in cst.trailing.iter().filter(|t| t.kind == "comment" && t.start < query.len()) {
+        tokens.push(token("comment", "comment", comment.start, comment.end));
+    }
+    Ok(tokens)
+}
+
+
+#[test]
+fn check_tokenize() {
+    let tokens = tokenize("sum(rate(x{a=~\"b\"}[5m])) > bool 1 # done").unwrap();
+    let types: Vec<&str> = tokens.iter().map(|t| t.token_type).collect();
+    assert_eq!(types, vec![
+        "sum", "left_paren", "identifier", "left_paren", "identifier", "left_brace", "identifier", "eql_regex",
+        "string", "right_brace", "left_bracket", "duration", "right_bracket", "right_paren", "right_paren", "gtr",
+        "bool", "number", "comment",
+    ]);
+    assert_eq!((tokens[8].kind, tokens[8].text.as_str(), tokens[8].start), ("string", "\"b\"", 14));
+    let typing: Vec<(&str, String)> = tokenize("rate(x{a=\"b").unwrap().into_iter().map(|t| (t.kind, t.text)).collect();
+    assert_eq!(typing.last(), Some(&("string", "\"b".to_string())));
+    assert_eq!(typing.len(), 7);
+    assert_eq!(tokenize("x{a=1}").unwrap_err().code, "unexpected-character");
+}
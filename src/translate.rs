@@ -0,0 +1,206 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use promql_parser::label::{Matchers, METRIC_NAME};
+use promql_parser::parser::{
+    AggregateExpr, AtModifier, BinaryExpr, Call, Expr, LabelModifier, MatrixSelector, NumberLiteral, Offset,
+    ParenExpr, StringLiteral, SubqueryExpr, UnaryExpr, ValueType, VectorMatchCardinality, VectorSelector,
+};
+use serde_json::{json, Value};
+use crate::printer::unescape;
+use crate::ToSerde;
+
+fn millis(duration: &Duration) -> i64 {
+    duration.as_millis() as i64
+}
+
+fn offset_millis(offset: &Option<Offset>) -> i64 {
+    match offset {
+        Some(Offset::Pos(duration)) => millis(duration),
+        Some(Offset::Neg(duration)) => -millis(duration),
+        None => 0,
+    }
+}
+
+/// The `timestamp` and `startOrEnd` of an `@` modifier.
+fn at(at: &Option<AtModifier>) -> (Value, Value) {
+    let since_epoch = |time: &SystemTime| match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => millis(&after),
+        Err(before) => -millis(&before.duration()),
+    };
+    match at {
+        Some(AtModifier::At(time)) => (json!(since_epoch(time)), Value::Null),
+        Some(AtModifier::Start) => (Value::Null, json!("start")),
+        Some(AtModifier::End) => (Value::Null, json!("end")),
+        None => (Value::Null, Value::Null),
+    }
+}
+
+/// The matchers of a selector as Go keeps them, with one for a metric name
+/// given outside the braces last and values unescaped.
+fn matchers(name: &Option<String>, matchers: &Matchers) -> Value {
+    let mut out: Vec<Value> = matchers
+        .matchers
+        .iter()
+        .map(|matcher| {
+            json!({
+                "type": matcher.op.to_serde(),
+                "name": matcher.name,
+                "value": unescape(&matcher.value),
+            })
+        })
+        .collect();
+    if let Some(name) = name {
+        out.push(json!({ "type": "=", "name": METRIC_NAME, "value": name }));
+    }
+    json!(out)
+}
+
+fn selector(kind: &str, vs: &VectorSelector) -> Value {
+    let (timestamp, start_or_end) = at(&vs.at);
+    json!({
+        "type": kind,
+        "name": vs.name.clone().unwrap_or_default(),
+        "offset": offset_millis(&vs.offset),
+        "matchers": matchers(&vs.name, &vs.matchers),
+        "timestamp": timestamp,
+        "startOrEnd": start_or_end,
+    })
+}
+
+/// A number as Go's `strconv.FormatFloat(val, 'f', -1, 64)` writes it.
+fn format_float(val: f64) -> String {
+    match val {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        _ => val.to_string(),
+    }
+}
+
+/// Go's `Variadic` count of a function: how many optional arguments it takes
+/// after its argument types, -1 for any number.
+fn variadic(name: &str, variadic: bool) -> i64 {
+    match (name, variadic) {
+        (_, false) => 0,
+        ("label_join" | "sort_by_label" | "sort_by_label_desc", true) => -1,
+        (_, true) => 1,
+    }
+}
+
+fn labels(labels: &[String]) -> Value {
+    json!(labels)
+}
+
+/// The `matching` of a binary expression, which Go only keeps between
+/// instant vectors.
+fn matching(binary: &BinaryExpr) -> Value {
+    if binary.lhs.value_type() != ValueType::Vector || binary.rhs.value_type() != ValueType::Vector {
+        return Value::Null;
+    }
+    let set_operator = matches!(binary.op.to_string().as_str(), "and" | "or" | "unless");
+    let (card, include) = match binary.modifier.as_ref().map(|modifier| &modifier.card) {
+        Some(VectorMatchCardinality::ManyToOne(include)) => ("many-to-one", labels(&include.labels)),
+        Some(VectorMatchCardinality::OneToMany(include)) => ("one-to-many", labels(&include.labels)),
+        Some(VectorMatchCardinality::ManyToMany) => ("many-to-many", json!([])),
+        _ if set_operator => ("many-to-many", json!([])),
+        _ => ("one-to-one", json!([])),
+    };
+    let (on, matching) = match binary.modifier.as_ref().and_then(|modifier| modifier.matching.as_ref()) {
+        Some(LabelModifier::Include(on)) => (true, labels(&on.labels)),
+        Some(LabelModifier::Exclude(ignoring)) => (false, labels(&ignoring.labels)),
+        None => (false, json!([])),
+    };
+    json!({ "card": card, "labels": matching, "on": on, "include": include })
+}
+
+/// `expr` as Prometheus' `/api/v1/parse_query` returns it, from the Go
+/// parser's tree: `aggregation`, `binaryExpr`, `call`, `matrixSelector`,
+/// `subquery`, `numberLiteral`, `parenExpr`, `stringLiteral`, `unaryExpr`
+/// and `vectorSelector` nodes, durations and times in milliseconds.
+pub fn translate(expr: &Expr) -> Value {
+    match expr {
+        Expr::Aggregate(AggregateExpr { op, expr, param, modifier }) => {
+            let (grouping, without) = match modifier {
+                Some(LabelModifier::Include(by)) => (labels(&by.labels), false),
+                Some(LabelModifier::Exclude(without)) => (labels(&without.labels), true),
+                None => (json!([]), false),
+            };
+            json!({
+                "type": "aggregation",
+                "op": op.to_string(),
+                "expr": translate(expr),
+                "param": param.as_deref().map_or(Value::Null, translate),
+                "grouping": grouping,
+                "without": without,
+            })
+        }
+        Expr::Binary(binary) => json!({
+            "type": "binaryExpr",
+            "op": binary.op.to_string(),
+            "lhs": translate(&binary.lhs),
+            "rhs": translate(&binary.rhs),
+            "matching": matching(binary),
+            "bool": binary.modifier.as_ref().is_some_and(|modifier| modifier.return_bool),
+        }),
+        Expr::Call(Call { func, args }) => json!({
+            "type": "call",
+            "func": {
+                "name": func.name,
+                "argTypes": func.arg_types.to_serde(),
+                "variadic": variadic(func.name, func.variadic),
+                "returnType": func.return_type.to_serde(),
+            },
+            "args": args.args.iter().map(|arg| translate(arg)).collect::<Vec<Value>>(),
+        }),
+        Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+            let mut node = selector("matrixSelector", vs);
+            node["range"] = json!(millis(range));
+            node
+        }
+        Expr::Subquery(SubqueryExpr { expr, offset, at: modifier, range, step }) => {
+            let (timestamp, start_or_end) = at(modifier);
+            json!({
+                "type": "subquery",
+                "expr": translate(expr),
+                "range": millis(range),
+                "offset": offset_millis(offset),
+                "step": step.as_ref().map_or(0, millis),
+                "timestamp": timestamp,
+                "startOrEnd": start_or_end,
+            })
+        }
+        Expr::NumberLiteral(NumberLiteral { val }) => json!({ "type": "numberLiteral", "val": format_float(*val) }),
+        Expr::Paren(ParenExpr { expr }) => json!({ "type": "parenExpr", "expr": translate(expr) }),
+        Expr::StringLiteral(StringLiteral { val }) => json!({ "type": "stringLiteral", "val": unescape(val) }),
+        Expr::Unary(UnaryExpr { expr }) => json!({ "type": "unaryExpr", "op": "-", "expr": translate(expr) }),
+        Expr::VectorSelector(vs) => selector("vectorSelector", vs),
+        Expr::Extension(_) => expr.to_serde(),
+    }
+}
+
+
+#[test]
+fn check_translate() {
+    let expr = crate::errors::try_parse("sum without (a) (rate(x{job=\"a\\tb\"}[5m] offset -1m)) / on (b) group_left (c) -y @ 10 > bool 0.5").unwrap();
+    let tree = translate(&expr);
+    assert_eq!((&tree["type"], &tree["op"], &tree["bool"]), (&json!("binaryExpr"), &json!(">"), &json!(true)));
+    assert_eq!((&tree["matching"], &tree["rhs"]), (&Value::Null, &json!({ "type": "numberLiteral", "val": "0.5" })));
+    let division = &tree["lhs"];
+    assert_eq!(division["matching"], json!({ "card": "many-to-one", "labels": ["b"], "on": true, "include": ["c"] }));
+    assert_eq!((&division["lhs"]["grouping"], &division["lhs"]["without"]), (&json!(["a"]), &json!(true)));
+    let call = &division["lhs"]["expr"];
+    assert_eq!(call["func"], json!({ "name": "rate", "argTypes": ["matrix"], "variadic": 0, "returnType": "vector" }));
+    assert_eq!(call["args"][0], json!({
+        "type": "matrixSelector",
+        "name": "x",
+        "range": 300000,
+        "offset": -60000,
+        "matchers": [{ "type": "=", "name": "job", "value": "a\tb" }, { "type": "=", "name": "__name__", "value": "x" }],
+        "timestamp": null,
+        "startOrEnd": null,
+    }));
+    let unary = &division["rhs"];
+    assert_eq!((&unary["type"], &unary["expr"]["timestamp"]), (&json!("unaryExpr"), &json!(10000)));
+    let tree = translate(&crate::errors::try_parse("a and max_over_time(b[1h:] @ end())").unwrap());
+    assert_eq!(tree["matching"], json!({ "card": "many-to-many", "labels": [], "on": false, "include": [] }));
+    let subquery = &tree["rhs"]["args"][0];
+    assert_eq!((&subquery["range"], &subquery["step"], &subquery["startOrEnd"]), (&json!(3600000), &json!(0), &json!("end")));
+}
@@ -0,0 +1,220 @@
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use serde_json::{json, Value};
+use crate::errors::try_parse;
+use crate::lint::Severity;
+use crate::spans::{spans, Span, SpanTree};
+use crate::visit::children;
+use crate::ToSerde;
+
+/// A semantic problem of a query, about the `start..end` bytes of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeDiagnostic {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ToSerde for TypeDiagnostic {
+    fn to_serde(&self) -> Value {
+        json!({
+            "code": self.code,
+            "severity": self.severity.as_str(),
+            "message": self.message,
+            "start": self.start,
+            "end": self.end,
+        })
+    }
+}
+
+/// The diagnostics of a query, valid if none is an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeCheck {
+    pub diagnostics: Vec<TypeDiagnostic>,
+}
+
+impl ToSerde for TypeCheck {
+    fn to_serde(&self) -> Value {
+        json!({
+            "valid": self.diagnostics.iter().all(|d| d.severity != Severity::Error),
+            "diagnostics": self.diagnostics.to_serde(),
+        })
+    }
+}
+
+struct Checker {
+    /// Span of the whole query, for nodes whose span is unknown.
+    whole: Span,
+    diagnostics: Vec<TypeDiagnostic>,
+}
+
+/// A string argument, if it is a literal.
+fn string(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::StringLiteral(StringLiteral { val }) => Some(val),
+        Expr::Paren(ParenExpr { expr }) => string(expr),
+        _ => None,
+    }
+}
+
+impl Checker {
+    fn report(&mut self, span: Span, severity: Severity, code: &'static str, message: String) {
+        self.diagnostics.push(TypeDiagnostic { code, severity, message, start: span.start, end: span.end });
+    }
+
+    /// A quantile literal outside `[0, 1]`, which Prometheus answers with infinities.
+    fn quantile(&mut self, phi: &Expr, span: Span, function: &str) {
+        if let Some(phi) = phi.scalar_value().filter(|phi| !(0.0..=1.0).contains(phi)) {
+            let result = if phi < 0.0 { "-Inf" } else { "+Inf" };
+            let message = format!("quantile {} of {}() is outside [0, 1], every result is {}", phi, function, result);
+            self.report(span, Severity::Warning, "out-of-range", message);
+        }
+    }
+
+    fn label_name(&mut self, name: &Expr, span: Span, function: &str) {
+        if string(name) == Some("") {
+            let message = format!("{}() needs a non-empty label name", function);
+            self.report(span, Severity::Error, "invalid-label-name", message);
+        }
+    }
+
+    fn call(&mut self, call: &Call, spans: &[Span]) {
+        let args: Vec<&Expr> = call.args.args.iter().map(|arg| arg.as_ref()).collect();
+        let name = call.func.name;
+        match (name, args.as_slice()) {
+            ("label_replace", [_, dst, _, _, regex]) => {
+                self.label_name(dst, spans[1], name);
+                if let Some(regex) = string(regex) {
+                    if let Err(err) = regex::Regex::new(&format!("^(?s:{})$", regex)) {
+                        let message = format!("invalid regular expression in label_replace(): {}", err);
+                        self.report(spans[4], Severity::Error, "invalid-regex", message);
+                    }
+                }
+            }
+            ("label_join", [_, dst, ..]) => self.label_name(dst, spans[1], name),
+            ("holt_winters" | "double_exponential_smoothing", [_, sf, tf]) => {
+                for (i, (factor, label)) in [(sf, "smoothing factor"), (tf, "trend factor")].iter().enumerate() {
+                    if let Some(val) = factor.scalar_value().filter(|val| !(*val > 0.0 && *val < 1.0)) {
+                        let message = format!("invalid {} of {}(), expected 0 < {} < 1, got {}", label, name, label, val);
+                        self.report(spans[i + 1], Severity::Error, "invalid-argument", message);
+                    }
+                }
+            }
+            ("quantile_over_time" | "histogram_quantile", [phi, _]) => self.quantile(phi, spans[0], name),
+            ("clamp", [_, min, max]) => {
+                if let (Some(min), Some(max)) = (min.scalar_value(), max.scalar_value()) {
+                    if min > max {
+                        let message = format!("clamp() minimum {} is above its maximum {}, the result is always empty", min, max);
+                        self.report(self.whole_or(spans), Severity::Warning, "always-empty", message);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The span of the call whose argument spans are `spans`, roughly.
+    fn whole_or(&self, spans: &[Span]) -> Span {
+        match (spans.first(), spans.last()) {
+            (Some(first), Some(last)) => Span { start: first.start, end: last.end },
+            _ => self.whole,
+        }
+    }
+
+    fn aggregate(&mut self, aggregate: &AggregateExpr, param_span: Span) {
+        let param = match &aggregate.param {
+            Some(param) => param,
+            None => return,
+        };
+        let op = aggregate.op.to_string();
+        match aggregate.op.id() {
+            T_COUNT_VALUES => self.label_name(param, param_span, &op),
+            T_QUANTILE => self.quantile(param, param_span, &op),
+            T_TOPK | T_BOTTOMK => match param.scalar_value() {
+                Some(k) if k < 1.0 => {
+                    let message = format!("{}() with k = {} always returns nothing", op, k);
+                    self.report(param_span, Severity::Warning, "always-empty", message);
+                }
+                Some(k) if k.fract() != 0.0 => {
+                    let message = format!("{}() truncates k = {} to {}", op, k, k.trunc());
+                    self.report(param_span, Severity::Warning, "invalid-argument", message);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn binary(&mut self, binary: &BinaryExpr, rhs_span: Span) {
+        if matches!(binary.op.id(), T_DIV | T_MOD) && binary.rhs.scalar_value() == Some(0.0) {
+            let message = format!("`{}` by zero makes every result NaN or infinite", binary.op);
+            self.report(rhs_span, Severity::Warning, "division-by-zero", message);
+        }
+    }
+
+    fn check(&mut self, expr: &Expr, tree: Option<&SpanTree>) {
+        let children = children(expr);
+        // the spans of the children, the whole node's if unknown
+        let span = tree.map_or(self.whole, |tree| tree.span);
+        let child_trees: Vec<Option<&SpanTree>> = match tree {
+            Some(tree) if tree.children.len() == children.len() => tree.children.iter().map(Some).collect(),
+            _ => vec![None; children.len()],
+        };
+        let child_spans: Vec<Span> = child_trees.iter().map(|tree| tree.map_or(span, |tree| tree.span)).collect();
+        match expr {
+            Expr::Call(call) => self.call(call, &child_spans),
+            Expr::Aggregate(aggregate) => self.aggregate(aggregate, child_spans[0]),
+            Expr::Binary(binary) => self.binary(binary, child_spans[1]),
+            _ => {}
+        }
+        for (child, tree) in children.into_iter().zip(child_trees) {
+            self.check(child, tree);
+        }
+    }
+}
+
+/// Checks `query` beyond its grammar: the errors upstream finds once parsed,
+/// like argument types, plus arguments and operands that parse but fail or
+/// misbehave when Prometheus evaluates them.
+pub fn typecheck(query: &str) -> TypeCheck {
+    let expr = match try_parse(query) {
+        Ok(expr) => expr,
+        Err(error) => {
+            let diagnostic = TypeDiagnostic {
+                code: error.code,
+                severity: Severity::Error,
+                message: error.message,
+                start: error.start,
+                end: error.end,
+            };
+            return TypeCheck { diagnostics: vec![diagnostic] };
+        }
+    };
+    let tree = spans(query, &expr);
+    let mut checker = Checker { whole: Span { start: 0, end: query.len() }, diagnostics: vec![] };
+    checker.check(&expr, tree.as_ref());
+    checker.diagnostics.sort_by_key(|d| d.start);
+    TypeCheck { diagnostics: checker.diagnostics }
+}
+
+
+#[test]
+fn check_typecheck() {
+    let payloads = vec![
+        ("sum by (job) (rate(x[5m]))", vec![]),
+        ("topk(\"a\", x)", vec![("type-mismatch", "topk(\"a\", x)")]),
+        ("label_replace(up, \"\", \"$1\", \"a\", \"(\")", vec![("invalid-label-name", "\"\""), ("invalid-regex", "\"(\"")]),
+        ("holt_winters(x[10m], 1.5, 0.5)", vec![("invalid-argument", "1.5")]),
+        ("quantile(1.5, x) / 0", vec![("out-of-range", "1.5"), ("division-by-zero", "0")]),
+        ("topk(0, x) + count_values(\"\", y)", vec![("always-empty", "0"), ("invalid-label-name", "\"\"")]),
+        ("clamp(x, 2, 1)", vec![("always-empty", "x, 2, 1")]),
+    ];
+    for (query, expected) in payloads {
+        let found: Vec<(&str, &str)> = typecheck(query).diagnostics.iter().map(|d| (d.code, &query[d.start..d.end])).collect();
+        assert_eq!(found, expected, "{}", query);
+    }
+    assert_eq!(typecheck("quantile(2, x)").to_serde()["valid"], json!(true));
+    assert_eq!(typecheck("abs(\"a\")").to_serde()["valid"], json!(false));
+}
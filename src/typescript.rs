@@ -0,0 +1,159 @@
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const AST_TYPES: &'static str = r#"
+/** Seconds, milliseconds or a Prometheus duration string, per the `durations` option. */
+export type Duration = number | string;
+/** Like `Duration`, negative for negative offsets. */
+export type Offset = number | string;
+/** `start`, `end`, ISO 8601 text, seconds or milliseconds since the epoch, per the `timestamps` option. */
+export type Timestamp = "start" | "end" | string | number | bigint;
+export type ValueType = "vector" | "scalar" | "matrix" | "string";
+export type LabelModifier = { include: string[] } | { exclude: string[] };
+
+export interface Matcher {
+  name: string;
+  op: "=" | "!=" | "=~" | "!~";
+  value: string;
+}
+
+export interface FunctionInfo {
+  name: string;
+  arg_types: ValueType[];
+  variadic: boolean;
+  return_type: ValueType;
+}
+
+export type VectorMatchCardinality =
+  | { "@type": "one-to-one" }
+  | { "@type": "many-to-one"; labels: string[] }
+  | { "@type": "one-to-many"; labels: string[] }
+  | { "@type": "many-to-many" };
+
+export interface BinModifier {
+  card: VectorMatchCardinality;
+  matching: LabelModifier | null;
+  return_bool: boolean;
+}
+
+/** Duration arithmetic a range, step or offset was computed from, with the `duration_expressions` option. */
+export type DurationExpr =
+  | { "@type": "duration_literal"; value: Duration }
+  | { "@type": "duration_number"; /** Seconds. */ value: number }
+  | { "@type": "duration_negation"; expr: DurationExpr }
+  | { "@type": "duration_binary"; op: "+" | "-" | "*" | "/" | "%" | "^"; lhs: DurationExpr; rhs: DurationExpr };
+
+/** Byte offsets of a node in the query, when they could be recovered. */
+export interface NodeSpan {
+  start?: number;
+  end?: number;
+  /** The query text of the node, with the `text` option. */
+  text?: string;
+}
+
+export interface AggregateNode extends NodeSpan {
+  "@type": "aggregate";
+  op: string;
+  expr: AstNode;
+  param: AstNode | null;
+  modifier: LabelModifier | null;
+}
+
+export interface UnaryNode extends NodeSpan {
+  "@type": "unary";
+  expr: AstNode;
+}
+
+export interface BinaryNode extends NodeSpan {
+  "@type": "binary";
+  lhs: AstNode;
+  op: string;
+  rhs: AstNode;
+  modifier: BinModifier | null;
+  /** Binding strength of `op`, from 1 (`or`) to 6 (`^`), higher binds tighter. */
+  precedence: number;
+  /** Only `^` groups right to left. */
+  is_right_assoc: boolean;
+}
+
+export interface ParenNode extends NodeSpan {
+  "@type": "paren";
+  expr: AstNode;
+}
+
+export interface SubqueryNode extends NodeSpan {
+  "@type": "subquery";
+  expr: AstNode;
+  offset: Offset | null;
+  at: Timestamp | null;
+  range: Duration;
+  step: Duration | null;
+  range_expr?: DurationExpr;
+  step_expr?: DurationExpr;
+  offset_expr?: DurationExpr;
+}
+
+export interface NumberNode extends NodeSpan {
+  "@type": "number";
+  /** `"NaN"`, `"Inf"` and `"-Inf"` for NaN and infinities. */
+  value: number | "NaN" | "Inf" | "-Inf";
+}
+
+export interface StringNode extends NodeSpan {
+  "@type": "string";
+  value: string;
+}
+
+export interface VectorSelectorNode extends NodeSpan {
+  "@type": "vector_selector";
+  name: string | null;
+  matchers: Matcher[];
+  offset: Offset | null;
+  at: Timestamp | null;
+  offset_expr?: DurationExpr;
+}
+
+export interface MatrixSelectorNode extends NodeSpan {
+  "@type": "matrix_selector";
+  vector: VectorSelectorNode;
+  range: Duration;
+  range_expr?: DurationExpr;
+}
+
+export interface CallNode extends NodeSpan {
+  "@type": "call";
+  function: FunctionInfo;
+  args: AstNode[];
+}
+
+/** A node of a dialect extension, which plain PromQL never yields. */
+export interface ExtensionNode extends NodeSpan {
+  "@type": "extension";
+  name: string;
+  value_type: ValueType;
+  children: AstNode[];
+  /** Debug representation of the extension. */
+  text: string;
+}
+
+/** A node of the `promql_parse` AST, discriminated by `@type`. */
+export type AstNode =
+  | AggregateNode
+  | UnaryNode
+  | BinaryNode
+  | ParenNode
+  | SubqueryNode
+  | NumberNode
+  | StringNode
+  | VectorSelectorNode
+  | MatrixSelectorNode
+  | CallNode
+  | ExtensionNode;
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    /// A JS value typed as the `AstNode` union in the TypeScript definitions.
+    #[wasm_bindgen(typescript_type = "AstNode")]
+    pub type AstNode;
+}
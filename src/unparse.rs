@@ -0,0 +1,272 @@
+use std::time::Duration;
+use promql_parser::parser;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde_json::Value;
+use crate::options::{DurationFormat, SerializeOptions};
+use crate::printer::{at, bin_modifier, duration, grouping, label_name, number, offset, operand_precedence, precedence, quote, selector_text};
+use crate::timestamps::{parse_at, TimestampFormat};
+
+/// Text of a node and how tightly it binds as an operand.
+struct Rendered {
+    text: String,
+    binds: u8,
+}
+
+impl Rendered {
+    fn atom(text: String) -> Rendered {
+        Rendered { text, binds: u8::MAX }
+    }
+
+    fn operand(&self, min: u8) -> String {
+        if self.binds < min {
+            format!("({})", self.text)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+fn field<'a>(node: &'a Value, key: &str) -> Result<&'a Value, String> {
+    node.get(key).ok_or_else(|| format!("missing `{}` in {}", key, node))
+}
+
+fn text<'a>(node: &'a Value, key: &str) -> Result<&'a str, String> {
+    field(node, key)?.as_str().ok_or_else(|| format!("`{}` is not a string in {}", key, node))
+}
+
+/// An optional field, `None` if missing or `null`.
+fn child<'a>(node: &'a Value, key: &str) -> Option<&'a Value> {
+    node.get(key).filter(|value| !value.is_null())
+}
+
+fn label_list(value: &Value) -> Result<Labels, String> {
+    let labels = value
+        .as_array()
+        .ok_or_else(|| format!("expected label names, got {}", value))?
+        .iter()
+        .map(|label| label.as_str().map(str::to_string).ok_or_else(|| format!("invalid label name {}", label)))
+        .collect::<Result<Vec<String>, String>>()?;
+    Ok(Labels { labels })
+}
+
+fn label_modifier(value: Option<&Value>) -> Result<Option<LabelModifier>, String> {
+    match value {
+        None => Ok(None),
+        Some(modifier) => match (modifier.get("include"), modifier.get("exclude")) {
+            (Some(labels), _) => Ok(Some(LabelModifier::Include(label_list(labels)?))),
+            (_, Some(labels)) => Ok(Some(LabelModifier::Exclude(label_list(labels)?))),
+            _ => Err(format!("invalid label modifier {}", modifier)),
+        },
+    }
+}
+
+fn card(value: &Value) -> Result<VectorMatchCardinality, String> {
+    match text(value, "@type")? {
+        "one-to-one" => Ok(VectorMatchCardinality::OneToOne),
+        "many-to-one" => Ok(VectorMatchCardinality::ManyToOne(label_list(field(value, "labels")?)?)),
+        "one-to-many" => Ok(VectorMatchCardinality::OneToMany(label_list(field(value, "labels")?)?)),
+        "many-to-many" => Ok(VectorMatchCardinality::ManyToMany),
+        other => Err(format!("invalid vector matching cardinality {}", other)),
+    }
+}
+
+fn binary_operator(op: &str) -> Result<TokenId, String> {
+    (T_OPERATORS_START..T_OPERATORS_END)
+        .find(|t| TokenType::new(*t).to_string() == op && precedence(*t) > 0)
+        .ok_or_else(|| format!("invalid binary operator {}", op))
+}
+
+struct Unparser {
+    timestamps: TimestampFormat,
+    durations: DurationFormat,
+}
+
+impl Unparser {
+    fn duration(&self, value: &Value) -> Result<Duration, String> {
+        match self.durations.parse(value)? {
+            (dur, false) => Ok(dur),
+            (_, true) => Err(format!("negative duration {}", value)),
+        }
+    }
+
+    /// The `@` and `offset` modifiers of a selector or subquery, if any.
+    fn modifiers(&self, node: &Value) -> Result<String, String> {
+        let mut s = String::new();
+        if let Some(value) = child(node, "at") {
+            s.push(' ');
+            s.push_str(&at(&parse_at(value, self.timestamps)?));
+        }
+        if let Some(value) = child(node, "offset") {
+            let offset = match self.durations.parse(value)? {
+                (dur, false) => Offset::Pos(dur),
+                (dur, true) => Offset::Neg(dur),
+            };
+            s.push(' ');
+            s.push_str(&self::offset(&offset));
+        }
+        Ok(s)
+    }
+
+    fn selector(&self, node: &Value) -> Result<String, String> {
+        let name = child(node, "name").and_then(Value::as_str).filter(|name| !name.is_empty());
+        let matchers = match child(node, "matchers") {
+            Some(matchers) => matchers.as_array().ok_or_else(|| format!("invalid matchers {}", matchers))?.clone(),
+            None => vec![],
+        };
+        let matchers = matchers
+            .iter()
+            .map(|m| match text(m, "op")? {
+                op @ ("=" | "!=" | "=~" | "!~") =>
+                    Ok(format!("{}{}{}", label_name(text(m, "name")?), op, quote(text(m, "value")?))),
+                op => Err(format!("invalid matcher operator {}", op)),
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(selector_text(name, matchers))
+    }
+
+    fn render(&self, node: &Value) -> Result<Rendered, String> {
+        let expr = |key: &str| self.render(field(node, key)?);
+        match text(node, "@type")? {
+            "number" => {
+                let val = match field(node, "value")? {
+                    Value::Number(n) => n.as_f64().unwrap_or(f64::NAN),
+                    // JSON has no NaN or infinities, the serializer writes `"NaN"`, `"Inf"` and `"-Inf"`
+                    Value::String(s) => parser::parse(s)
+                        .ok()
+                        .and_then(|expr| match expr {
+                            Expr::NumberLiteral(NumberLiteral { val }) => Some(val),
+                            _ => None,
+                        })
+                        .ok_or_else(|| format!("invalid number {}", s))?,
+                    other => return Err(format!("invalid number {}", other)),
+                };
+                let binds = if val.is_sign_negative() && !val.is_nan() { precedence(T_MUL) } else { u8::MAX };
+                Ok(Rendered { text: number(val), binds })
+            }
+            "string" => Ok(Rendered::atom(quote(text(node, "value")?))),
+            "vector_selector" => Ok(Rendered::atom(format!("{}{}", self.selector(node)?, self.modifiers(node)?))),
+            "matrix_selector" => {
+                let vector = field(node, "vector")?;
+                let range = duration(&self.duration(field(node, "range")?)?);
+                Ok(Rendered::atom(format!("{}[{}]{}", self.selector(vector)?, range, self.modifiers(vector)?)))
+            }
+            "aggregate" => {
+                let mut s = format!("{}{}(", text(node, "op")?, grouping(&label_modifier(child(node, "modifier"))?));
+                if let Some(param) = child(node, "param") {
+                    s.push_str(&self.render(param)?.text);
+                    s.push_str(", ");
+                }
+                s.push_str(&expr("expr")?.text);
+                s.push(')');
+                Ok(Rendered::atom(s))
+            }
+            "unary" => Ok(Rendered {
+                text: format!("-{}", expr("expr")?.operand(precedence(T_POW))),
+                binds: precedence(T_MUL),
+            }),
+            "binary" => {
+                let op = binary_operator(text(node, "op")?)?;
+                let modifier = match child(node, "modifier") {
+                    Some(modifier) => bin_modifier(&BinModifier {
+                        card: card(field(modifier, "card")?)?,
+                        matching: label_modifier(child(modifier, "matching"))?,
+                        return_bool: child(modifier, "return_bool")
+                            .or_else(|| child(modifier, "returnBool"))
+                            .and_then(Value::as_bool)
+                            .unwrap_or(false),
+                    }),
+                    None => String::new(),
+                };
+                let (lhs_min, rhs_min) = operand_precedence(op);
+                Ok(Rendered {
+                    text: format!(
+                        "{} {}{} {}",
+                        expr("lhs")?.operand(lhs_min),
+                        TokenType::new(op),
+                        modifier,
+                        expr("rhs")?.operand(rhs_min),
+                    ),
+                    binds: precedence(op),
+                })
+            }
+            "paren" => Ok(Rendered::atom(format!("({})", expr("expr")?.text))),
+            "subquery" => {
+                let step = child(node, "step").map(|step| self.duration(step)).transpose()?;
+                Ok(Rendered::atom(format!(
+                    "{}[{}:{}]{}",
+                    expr("expr")?.operand(u8::MAX),
+                    duration(&self.duration(field(node, "range")?)?),
+                    step.as_ref().map(duration).unwrap_or_default(),
+                    self.modifiers(node)?,
+                )))
+            }
+            "call" => {
+                let function = field(node, "function")?;
+                let name = function.as_str().map_or_else(|| text(function, "name"), Ok)?;
+                let args: Vec<String> = match field(node, "args")? {
+                    Value::Array(args) => args.iter().map(|arg| self.render(arg).map(|arg| arg.text)).collect(),
+                    other => Err(format!("invalid arguments {}", other)),
+                }?;
+                Ok(Rendered::atom(format!("{}({})", name, args.join(", "))))
+            }
+            other => Err(format!("unsupported node type {}", other)),
+        }
+    }
+}
+
+/// Renders a JSON AST as produced by `promql_parse`, possibly edited, back
+/// into PromQL, checking that the result parses. Numeric timestamps and
+/// durations are read as `options` wrote them; omitted nulls and camelCase
+/// keys are fine.
+pub fn unparse(ast: &Value, options: &SerializeOptions) -> Result<String, String> {
+    let query = Unparser { timestamps: options.timestamps, durations: options.durations }.render(ast)?.text;
+    parser::parse(&query).map_err(|err| format!("{}: {}", err, query))?;
+    Ok(query)
+}
+
+
+#[test]
+fn check_unparse() {
+    use crate::options::with_options;
+    use crate::printer::to_promql;
+    use crate::ToSerde;
+    let payloads = vec![
+        "sum by (job) (rate(http_requests_total{code=~\"5..\", job!=\"\"}[5m] offset -1m30s))",
+        "topk(3, x) / on (a) group_left (b) y > bool 1",
+        "-(a + b) ^ 2 - -1",
+        "max_over_time((a - b)[1h:30s] @ 1.500 offset 5m)",
+        "x @ end() unless ignoring (c) z",
+        "label_replace(up, \"a\", \"$1\", \"b\", \"(.*)\")",
+        "count_values without () (\"v\", {__name__=~\"x.*\"})",
+    ];
+    let formats = vec![
+        (TimestampFormat::Iso, DurationFormat::Seconds),
+        (TimestampFormat::Millis, DurationFormat::Millis),
+        (TimestampFormat::Seconds, DurationFormat::Seconds),
+        (TimestampFormat::String, DurationFormat::String),
+    ];
+    for query in payloads {
+        let expr = parser::parse(query).unwrap();
+        for (timestamps, durations) in &formats {
+            let options = SerializeOptions { timestamps: *timestamps, durations: *durations, ..SerializeOptions::default() };
+            let ast = with_options(options.clone(), || expr.to_serde());
+            assert_eq!(unparse(&ast, &options).unwrap(), to_promql(&expr), "{} {:?}", query, options);
+        }
+    }
+    for query in ["Inf", "-Inf", "NaN"] {
+        let expr = parser::parse(query).unwrap();
+        assert_eq!(expr.to_serde()["value"], query);
+        let ast = crate::serialize::Node::new(query, &expr, None, &SerializeOptions::default());
+        let ast = serde_json::to_value(ast).unwrap();
+        assert_eq!(ast["value"], query);
+        assert_eq!(unparse(&ast, &SerializeOptions::default()).unwrap(), query);
+    }
+    let mut ast = parser::parse("rate(x[5m])").unwrap().to_serde();
+    ast["args"][0]["vector"]["name"] = "y".into();
+    ast["args"][0]["range"] = 60.into();
+    assert_eq!(unparse(&ast, &SerializeOptions::default()).unwrap(), "rate(y[1m])");
+    ast["args"][0]["@type"] = "vector_selector".into();
+    assert!(unparse(&ast, &SerializeOptions::default()).is_err());
+}
@@ -0,0 +1,77 @@
+use serde_json::{json, Value};
+use crate::edits::{apply, original_error, original_spans, Edit};
+use crate::errors::ParseError;
+use crate::spans::annotate_text;
+use crate::substitute::{references, Context};
+
+/// Identifiers stand in for variables as names and in strings; their numbers
+/// are closed by `__`, for `__grafana_var_1__` not to start `..._10__`.
+const PREFIX: &str = "__grafana_var_";
+
+/// Stand-ins for variables in durations and numbers, which keep no trace.
+const DURATION: &str = "1m";
+const NUMBER: &str = "1";
+
+/// Puts `(placeholder, reference)` pairs back in the strings of `value`.
+fn restore(value: &mut Value, names: &[(String, &str)]) {
+    match value {
+        Value::String(text) if text.contains(PREFIX) => {
+            for (placeholder, reference) in names {
+                *text = text.replace(placeholder.as_str(), reference);
+            }
+        }
+        Value::Object(object) => object.values_mut().for_each(|field| restore(field, names)),
+        Value::Array(items) => items.iter_mut().for_each(|item| restore(item, names)),
+        _ => {}
+    }
+}
+
+/// Parses `query` with `parse`, tolerating the Grafana-style `$name` and
+/// `${name}` variables of dashboards: they are parsed in place of stand-ins
+/// for where they are, kept as written in names and strings, and listed, as
+/// `variables`, on the root of the tree. Durations stand for 1 minute and
+/// numbers for 1. Adds the `text` of nodes, as written, if `text`.
+pub fn parse_variables(query: &str, text: bool, parse: impl Fn(&str) -> Result<Value, ParseError>) -> Result<Value, ParseError> {
+    let references = references(query);
+    if references.is_empty() {
+        return parse(query);
+    }
+    let mut names = vec![];
+    let edits: Vec<Edit> = references
+        .iter()
+        .enumerate()
+        .map(|(i, reference)| {
+            let text = match reference.context {
+                Context::Duration => DURATION.to_string(),
+                Context::Number => NUMBER.to_string(),
+                _ => format!("{}{}__", PREFIX, i),
+            };
+            if text.starts_with(PREFIX) {
+                names.push((text.clone(), &query[reference.start..reference.end]));
+            }
+            Edit { start: reference.start, end: reference.end, text }
+        })
+        .collect();
+    let edited = apply(query, &edits);
+    let mut ast = parse(&edited).map_err(|error| original_error(query, &edits, error))?;
+    original_spans(&mut ast, &edits);
+    restore(&mut ast, &names);
+    if text {
+        annotate_text(&mut ast, query);
+    }
+    if let Value::Object(root) = &mut ast {
+        let variables: Vec<Value> = references
+            .iter()
+            .map(|reference| {
+                json!({
+                    "name": reference.name,
+                    "context": reference.context.name(),
+                    "start": reference.start,
+                    "end": reference.end,
+                })
+            })
+            .collect();
+        root.insert("variables".to_string(), json!(variables));
+    }
+    Ok(ast)
+}
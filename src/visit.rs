@@ -0,0 +1,105 @@
+use promql_parser::parser::*;
+
+/// Direct sub-expressions of `expr` in source order.
+///
+/// `promql_parser::util::walk_expr` stops after the left-hand side of a binary
+/// expression, so traversals in this crate go through here instead.
+pub fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => match param {
+            Some(param) => vec![param, expr],
+            None => vec![expr],
+        },
+        Expr::Unary(UnaryExpr { expr }) => vec![expr],
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => vec![lhs, rhs],
+        Expr::Paren(ParenExpr { expr }) => vec![expr],
+        Expr::Subquery(SubqueryExpr { expr, .. }) => vec![expr],
+        Expr::Call(Call { args, .. }) => args.args.iter().map(|arg| arg.as_ref()).collect(),
+        Expr::Extension(Extension { expr }) => expr.children().iter().collect(),
+        Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::VectorSelector(_)
+        | Expr::MatrixSelector(_) => vec![],
+    }
+}
+
+/// Like `children`, mutably. Extensions, shared upstream, have none.
+pub fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => match param {
+            Some(param) => vec![param, expr],
+            None => vec![expr],
+        },
+        Expr::Unary(UnaryExpr { expr }) => vec![expr],
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => vec![lhs, rhs],
+        Expr::Paren(ParenExpr { expr }) => vec![expr],
+        Expr::Subquery(SubqueryExpr { expr, .. }) => vec![expr],
+        Expr::Call(Call { args, .. }) => args.args.iter_mut().map(|arg| arg.as_mut()).collect(),
+        Expr::Extension(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::VectorSelector(_)
+        | Expr::MatrixSelector(_) => vec![],
+    }
+}
+
+/// The `@type` of `expr` in the serialized tree.
+pub fn node_type(expr: &Expr) -> &'static str {
+    match expr {
+        Expr::Aggregate(_) => "aggregate",
+        Expr::Unary(_) => "unary",
+        Expr::Binary(_) => "binary",
+        Expr::Paren(_) => "paren",
+        Expr::Subquery(_) => "subquery",
+        Expr::NumberLiteral(_) => "number",
+        Expr::StringLiteral(_) => "string",
+        Expr::VectorSelector(_) => "vector_selector",
+        Expr::MatrixSelector(_) => "matrix_selector",
+        Expr::Call(_) => "call",
+        Expr::Extension(_) => "extension",
+    }
+}
+
+/// Calls `f` on `expr` and every sub-expression, parents first.
+pub fn walk<'a>(expr: &'a Expr, f: &mut impl FnMut(&'a Expr)) {
+    f(expr);
+    for child in children(expr) {
+        walk(child, f);
+    }
+}
+
+/// Calls `f` on every vector selector of `expr`, including those of matrix
+/// selectors, in source order. Extensions are left alone.
+pub fn selectors_mut(expr: &mut Expr, f: &mut impl FnMut(&mut VectorSelector)) {
+    match expr {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) => f(vs),
+        Expr::Aggregate(AggregateExpr { expr, param, .. }) => {
+            if let Some(param) = param {
+                selectors_mut(param, f);
+            }
+            selectors_mut(expr, f);
+        }
+        Expr::Binary(BinaryExpr { lhs, rhs, .. }) => {
+            selectors_mut(lhs, f);
+            selectors_mut(rhs, f);
+        }
+        Expr::Unary(UnaryExpr { expr })
+        | Expr::Paren(ParenExpr { expr })
+        | Expr::Subquery(SubqueryExpr { expr, .. }) => selectors_mut(expr, f),
+        Expr::Call(Call { args, .. }) => args.args.iter_mut().for_each(|arg| selectors_mut(arg, f)),
+        Expr::NumberLiteral(_) | Expr::StringLiteral(_) | Expr::Extension(_) => {}
+    }
+}
+
+
+#[test]
+fn check_walk() {
+    let expr = parse("sum(rate(a[5m])) / on(x) topk(3, b) + -c").unwrap();
+    let mut selectors = vec![];
+    walk(&expr, &mut |e| match e {
+        Expr::VectorSelector(vs) | Expr::MatrixSelector(MatrixSelector { vs, .. }) =>
+            selectors.push(vs.name.clone().unwrap()),
+        _ => {}
+    });
+    assert_eq!(selectors, vec!["a", "b", "c"]);
+}
@@ -0,0 +1,444 @@
+use promql_parser::parser;
+use promql_parser::parser::*;
+use promql_parser::parser::token::*;
+use promql_parser::label::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::printer::{duration, is_right_assoc, labels, number, precedence, quote};
+use crate::ToSerde;
+
+/// A visual query builder model, shaped after Grafana's PromQL builder.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuilderModel {
+    #[serde(default)]
+    pub metric: String,
+    #[serde(default)]
+    pub labels: Vec<LabelFilter>,
+    /// Applied in order, innermost first.
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    /// Combined left to right with the result of `operations`.
+    #[serde(default)]
+    pub binary_queries: Vec<BinaryQuery>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelFilter {
+    pub label: String,
+    pub op: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BinaryQuery {
+    pub operator: String,
+    /// `on` or `ignoring`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector_matches_type: Option<String>,
+    /// Comma separated label names.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector_matches: Option<String>,
+    pub query: BuilderModel,
+}
+
+impl ToSerde for BuilderModel {
+    fn to_serde(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// The builder model of a query, or why the query has none.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Representation {
+    Model(BuilderModel),
+    NotRepresentable(String),
+}
+
+impl ToSerde for Representation {
+    fn to_serde(&self) -> Value {
+        match self {
+            Representation::Model(model) => json!({
+                "representable": true,
+                "model": model.to_serde(),
+            }),
+            Representation::NotRepresentable(reason) => json!({
+                "representable": false,
+                "reason": reason,
+            }),
+        }
+    }
+}
+
+const AGGREGATORS: [TokenId; 12] = [
+    T_SUM, T_AVG, T_COUNT, T_MIN, T_MAX, T_GROUP, T_STDDEV, T_STDVAR,
+    T_TOPK, T_BOTTOMK, T_QUANTILE, T_COUNT_VALUES,
+];
+
+/// Operations between a vector and a number, by builder operation id.
+const SCALAR_OPERATIONS: [(&str, TokenId); 12] = [
+    ("__addition", T_ADD),
+    ("__subtraction", T_SUB),
+    ("__multiply_by", T_MUL),
+    ("__divide_by", T_DIV),
+    ("__modulo", T_MOD),
+    ("__exponent", T_POW),
+    ("__equal_to", T_EQLC),
+    ("__not_equal_to", T_NEQ),
+    ("__greater_than", T_GTR),
+    ("__less_than", T_LSS),
+    ("__greater_or_equal", T_GTE),
+    ("__less_or_equal", T_LTE),
+];
+
+const RANGE_FUNCTIONS: [&str; 10] = [
+    "changes", "delta", "deriv", "holt_winters", "idelta", "increase", "irate",
+    "predict_linear", "rate", "resets",
+];
+
+/// Functions taking their vector argument last instead of first.
+const VECTOR_LAST: [&str; 3] = ["histogram_fraction", "histogram_quantile", "quantile_over_time"];
+
+fn is_range_function(name: &str) -> bool {
+    name.ends_with("_over_time") || RANGE_FUNCTIONS.contains(&name)
+}
+
+fn strip_parens(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Paren(ParenExpr { expr }) => strip_parens(expr),
+        _ => expr,
+    }
+}
+
+fn literal(expr: &Expr) -> Option<Value> {
+    match strip_parens(expr) {
+        Expr::NumberLiteral(NumberLiteral { val }) => Some(json!(val)),
+        Expr::StringLiteral(StringLiteral { val }) => Some(json!(val)),
+        _ => None,
+    }
+}
+
+fn selector_model(vs: &VectorSelector) -> Result<BuilderModel, String> {
+    if vs.offset.is_some() || vs.at.is_some() {
+        return Err("offset and @ modifiers have no builder equivalent".to_string());
+    }
+    Ok(BuilderModel {
+        metric: vs.name.clone().unwrap_or_default(),
+        labels: vs
+            .matchers
+            .matchers
+            .iter()
+            .map(|m| LabelFilter { label: m.name.clone(), op: m.op.to_string(), value: m.value.clone() })
+            .collect(),
+        ..BuilderModel::default()
+    })
+}
+
+/// The model of the operand of an operation, which must not combine queries.
+fn operand_model(expr: &Expr) -> Result<BuilderModel, String> {
+    let model = to_model(expr)?;
+    if !model.binary_queries.is_empty() {
+        return Err("operations over a binary expression have no builder equivalent".to_string());
+    }
+    Ok(model)
+}
+
+fn call_model(func: &Function, args: &FunctionArgs) -> Result<BuilderModel, String> {
+    let mut operand = None;
+    let mut params = vec![];
+    for arg in &args.args {
+        match literal(arg) {
+            Some(param) => params.push(param),
+            None if operand.is_none() => operand = Some(arg),
+            None => return Err(format!("{}() has more than one non-literal argument", func.name)),
+        }
+    }
+    let operand = operand.ok_or_else(|| format!("{}() has no vector argument", func.name))?;
+    let mut model = match strip_parens(operand) {
+        Expr::MatrixSelector(MatrixSelector { vs, range }) => {
+            params.insert(0, json!(duration(range)));
+            selector_model(vs)?
+        }
+        Expr::Subquery(_) => return Err("subqueries have no builder equivalent".to_string()),
+        operand => operand_model(operand)?,
+    };
+    model.operations.push(Operation { id: func.name.to_string(), params });
+    Ok(model)
+}
+
+fn aggregate_model(agg: &AggregateExpr) -> Result<BuilderModel, String> {
+    let mut model = operand_model(&agg.expr)?;
+    let mut params = vec![];
+    if let Some(param) = &agg.param {
+        params.push(literal(param).ok_or_else(|| format!("{} has a non-literal parameter", agg.op))?);
+    }
+    let id = match &agg.modifier {
+        Some(LabelModifier::Include(ls)) if !ls.is_empty() => format!("__{}_by", agg.op),
+        Some(LabelModifier::Exclude(_)) => format!("__{}_without", agg.op),
+        _ => agg.op.to_string(),
+    };
+    if let Some(modifier) = &agg.modifier {
+        params.extend(modifier.labels().labels.iter().map(|l| json!(l)));
+    }
+    model.operations.push(Operation { id, params });
+    Ok(model)
+}
+
+fn binary_model(binary: &BinaryExpr) -> Result<BuilderModel, String> {
+    let BinaryExpr { lhs, op, rhs, modifier } = binary;
+    let return_bool = modifier.as_ref().is_some_and(|m| m.return_bool);
+    if let (ValueType::Vector, Some(Value::Number(n))) = (lhs.value_type(), literal(rhs)) {
+        let (id, _) = SCALAR_OPERATIONS
+            .iter()
+            .find(|(_, token)| *token == op.id())
+            .ok_or_else(|| format!("`{}` with a number has no builder equivalent", op))?;
+        let mut model = operand_model(lhs)?;
+        let mut params = vec![Value::Number(n)];
+        if return_bool {
+            params.push(json!(true));
+        }
+        model.operations.push(Operation { id: id.to_string(), params });
+        return Ok(model);
+    }
+    if lhs.value_type() != ValueType::Vector || rhs.value_type() != ValueType::Vector {
+        return Err(format!("`{}` between these operands has no builder equivalent", op));
+    }
+    let grouped = modifier.as_ref().is_some_and(|m| {
+        !matches!(m.card, VectorMatchCardinality::OneToOne | VectorMatchCardinality::ManyToMany)
+    });
+    if grouped || return_bool {
+        return Err("group modifiers and bool have no builder equivalent".to_string());
+    }
+    let (vector_matches_type, vector_matches) = match modifier.as_ref().and_then(|m| m.matching.as_ref()) {
+        Some(LabelModifier::Include(ls)) => (Some("on".to_string()), Some(ls.labels.join(","))),
+        Some(LabelModifier::Exclude(ls)) if !ls.is_empty() =>
+            (Some("ignoring".to_string()), Some(ls.labels.join(","))),
+        _ => (None, None),
+    };
+    let mut model = to_model(lhs)?;
+    model.binary_queries.push(BinaryQuery {
+        operator: op.to_string(),
+        vector_matches_type,
+        vector_matches,
+        query: to_model(rhs)?,
+    });
+    Ok(model)
+}
+
+/// Converts `expr` into a builder model, failing with the reason if some part
+/// of it cannot be expressed as one.
+pub fn to_model(expr: &Expr) -> Result<BuilderModel, String> {
+    match strip_parens(expr) {
+        Expr::VectorSelector(vs) => selector_model(vs),
+        Expr::Call(Call { func, args }) => call_model(func, args),
+        Expr::Aggregate(agg) => aggregate_model(agg),
+        Expr::Binary(binary) => binary_model(binary),
+        Expr::MatrixSelector(_) => Err("range vectors need a function such as rate()".to_string()),
+        Expr::Subquery(_) => Err("subqueries have no builder equivalent".to_string()),
+        Expr::Unary(_) => Err("unary minus has no builder equivalent".to_string()),
+        _ => Err("only vector queries have a builder equivalent".to_string()),
+    }
+}
+
+pub fn representation(expr: &Expr) -> Representation {
+    match to_model(expr) {
+        Ok(model) => Representation::Model(model),
+        Err(reason) => Representation::NotRepresentable(reason),
+    }
+}
+
+/// PromQL text together with the precedence of its outermost operator.
+struct Rendered {
+    text: String,
+    binds: u8,
+}
+
+impl Rendered {
+    fn atom(text: String) -> Rendered {
+        Rendered { text, binds: u8::MAX }
+    }
+
+    fn operand(&self, min: u8) -> String {
+        if self.binds < min {
+            format!("({})", self.text)
+        } else {
+            self.text.clone()
+        }
+    }
+
+    fn binary(self, op: TokenId, modifier: &str, rhs: &Rendered) -> Rendered {
+        let prec = precedence(op);
+        let (lhs_min, rhs_min) = if is_right_assoc(op) { (prec + 1, prec) } else { (prec, prec + 1) };
+        Rendered {
+            text: format!("{} {}{} {}", self.operand(lhs_min), TokenType::new(op), modifier, rhs.operand(rhs_min)),
+            binds: prec,
+        }
+    }
+}
+
+fn param_text(param: &Value) -> Result<String, String> {
+    match param {
+        Value::Number(n) => Ok(number(n.as_f64().unwrap_or(f64::NAN))),
+        Value::String(s) => Ok(quote(s)),
+        _ => Err(format!("unsupported parameter {}", param)),
+    }
+}
+
+fn label_names(params: &[Value]) -> Result<String, String> {
+    let names = params
+        .iter()
+        .map(|p| p.as_str().map(str::to_string).ok_or_else(|| format!("invalid label name {}", p)))
+        .collect::<Result<Vec<String>, String>>()?;
+    Ok(labels(&Labels { labels: names }))
+}
+
+fn aggregator(name: &str) -> Option<TokenType> {
+    AGGREGATORS.iter().map(|t| TokenType::new(*t)).find(|t| t.to_string() == name)
+}
+
+fn operation(current: Rendered, range_ok: bool, op: &Operation) -> Result<Rendered, String> {
+    let id = op.id.as_str();
+    if let Some((_, token)) = SCALAR_OPERATIONS.iter().find(|(name, _)| *name == id) {
+        let (value, return_bool) = match op.params.as_slice() {
+            [value] => (value, false),
+            [value, Value::Bool(b)] => (value, *b),
+            _ => return Err(format!("{} takes a number and an optional bool", id)),
+        };
+        let modifier = if return_bool { " bool" } else { "" };
+        return Ok(current.binary(*token, modifier, &Rendered::atom(param_text(value)?)));
+    }
+    let grouped = id.strip_prefix("__").and_then(|rest| {
+        rest.strip_suffix("_by")
+            .map(|name| (name, "by"))
+            .or_else(|| rest.strip_suffix("_without").map(|name| (name, "without")))
+    });
+    let (name, grouping) = match grouped {
+        Some((name, grouping)) => (name, Some(grouping)),
+        None => (id, None),
+    };
+    if let Some(agg) = aggregator(name) {
+        let mut params = op.params.as_slice();
+        let mut text = agg.to_string();
+        let mut param = None;
+        if agg.is_aggregator_with_param() {
+            let (first, rest) = params.split_first().ok_or_else(|| format!("{} needs a parameter", name))?;
+            param = Some(param_text(first)?);
+            params = rest;
+        }
+        if let Some(grouping) = grouping {
+            text.push_str(&format!(" {} {} ", grouping, label_names(params)?));
+        } else if !params.is_empty() {
+            return Err(format!("{} takes no label parameters", id));
+        }
+        text.push('(');
+        if let Some(param) = param {
+            text.push_str(&format!("{}, ", param));
+        }
+        text.push_str(&current.text);
+        text.push(')');
+        return Ok(Rendered::atom(text));
+    }
+    if id.starts_with("__") {
+        return Err(format!("unknown operation {}", id));
+    }
+    let mut params = op.params.as_slice();
+    let mut operand = current.text;
+    if is_range_function(id) {
+        if !range_ok {
+            return Err(format!("{} needs a range vector and must directly follow the selector", id));
+        }
+        let (range, rest) = params.split_first().ok_or_else(|| format!("{} needs a range", id))?;
+        let range = range.as_str().ok_or_else(|| format!("invalid range {}", range))?;
+        operand.push_str(&format!("[{}]", range));
+        params = rest;
+    }
+    let mut args = params.iter().map(param_text).collect::<Result<Vec<String>, String>>()?;
+    if VECTOR_LAST.contains(&id) {
+        args.push(operand);
+    } else {
+        args.insert(0, operand);
+    }
+    Ok(Rendered::atom(format!("{}({})", id, args.join(", "))))
+}
+
+fn render(model: &BuilderModel) -> Result<Rendered, String> {
+    let mut selector = model.metric.clone();
+    if !model.labels.is_empty() || selector.is_empty() {
+        let filters: Vec<String> = model
+            .labels
+            .iter()
+            .map(|f| match f.op.as_str() {
+                "=" | "!=" | "=~" | "!~" => Ok(format!("{}{}{}", f.label, f.op, quote(&f.value))),
+                op => Err(format!("invalid label filter operator {}", op)),
+            })
+            .collect::<Result<_, String>>()?;
+        selector.push_str(&format!("{{{}}}", filters.join(", ")));
+    }
+    let mut current = Rendered::atom(selector);
+    for (i, op) in model.operations.iter().enumerate() {
+        current = operation(current, i == 0, op)?;
+    }
+    for binary in &model.binary_queries {
+        let token = (T_OPERATORS_START..T_OPERATORS_END)
+            .find(|t| TokenType::new(*t).to_string() == binary.operator && precedence(*t) > 0)
+            .ok_or_else(|| format!("invalid binary operator {}", binary.operator))?;
+        let modifier = match (binary.vector_matches_type.as_deref(), &binary.vector_matches) {
+            (None, _) => String::new(),
+            (Some(kind @ ("on" | "ignoring")), matches) => {
+                let names: Vec<Value> = matches
+                    .iter()
+                    .flat_map(|m| m.split(','))
+                    .map(str::trim)
+                    .filter(|m| !m.is_empty())
+                    .map(|m| json!(m))
+                    .collect();
+                format!(" {} {}", kind, label_names(&names)?)
+            }
+            (Some(kind), _) => return Err(format!("invalid vector matching {}", kind)),
+        };
+        current = current.binary(token, &modifier, &render(&binary.query)?);
+    }
+    Ok(current)
+}
+
+/// Renders a builder model as PromQL, checking that the result parses.
+pub fn from_model(model: &BuilderModel) -> Result<String, String> {
+    let query = render(model)?.text;
+    parser::parse(&query).map_err(|err| format!("{}: {}", err, query))?;
+    Ok(query)
+}
+
+
+#[test]
+fn check_builder_model() {
+    let payloads = vec![
+        "sum by (job) (rate(http_requests_total{code=~\"5..\"}[5m]))",
+        "histogram_quantile(0.9, sum by (le) (rate(x_bucket[1m]))) * 1000",
+        "topk(5, quantile_over_time(0.5, x[10m])) > bool 3",
+        "label_replace(up{job=\"a\"}, \"dst\", \"$1\", \"src\", \"(.*)\")",
+        "sum(rate(a[5m])) / on (job) sum(rate(b[5m])) + c",
+        "a - (b + c)",
+        "{job=\"a\"}",
+    ];
+    for query in payloads {
+        let model = to_model(&parser::parse(query).unwrap()).unwrap();
+        assert_eq!(from_model(&model).unwrap(), query);
+    }
+    for query in ["rate(x[5m] offset 1m)", "a * on (x) group_left b", "sum(a + b)", "max_over_time(x[5m:1m])"] {
+        assert!(to_model(&parser::parse(query).unwrap()).is_err(), "{}", query);
+    }
+    let model: BuilderModel = serde_json::from_value(json!({
+        "metric": "x",
+        "operations": [{"id": "__sum_without", "params": ["a"]}, {"id": "__divide_by", "params": [2]}],
+    }))
+    .unwrap();
+    assert_eq!(from_model(&model).unwrap(), "sum without (a) (x) / 2");
+}